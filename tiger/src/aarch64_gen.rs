@@ -0,0 +1,395 @@
+//! AArch64 的**指令选择**：用最大吞进(maximal munch)覆盖规范化之后的IR树，生成GNU语法的A64
+//! 指令。A64的算术指令有三个运算数，不需要像x86-64那样先把左运算数传送到目标寄存器。
+//!
+//! 立即数受编码的限制：加减法的立即数是12位无符号数，访存的偏移量要么在`ldur`/`stur`的
+//! `[-256, 256)`之内，要么是8的非负倍数；其他常量用`mov`和`movk`每次构造16位。超出范围的偏移量
+//! 先在x16中计算：它是过程调用使用的临时寄存器(IP0)，不参与寄存器分配，所以寄存器分配器生成的
+//! 溢出代码也不需要新的临时变量。
+
+use asm::Instruction;
+use frame::aarch64::{Aarch64, SP, X0, X16};
+use frame::Frame;
+use ir::{BinOp, Exp, RelationalOp, Statement};
+use temp::Temp;
+
+pub struct Gen {
+    instructions: Vec<Instruction>,
+}
+
+impl Gen {
+    pub fn new() -> Self {
+        Self {
+            instructions: vec![],
+        }
+    }
+
+    pub fn emit(&mut self, instruction: Instruction) {
+        self.instructions.push(instruction);
+    }
+
+    fn operation(&mut self, assembly: String, destination: Vec<Temp>, source: Vec<Temp>) {
+        self.emit(Instruction::Operation {
+            assembly,
+            destination,
+            source,
+            jump: None,
+        });
+    }
+
+    /// Move the arguments of a call to the argument registers, and store the other ones at the
+    /// bottom of the stack.
+    fn munch_args(&mut self, arguments: Vec<Exp>) -> Vec<Temp> {
+        let temps: Vec<_> = arguments
+            .into_iter()
+            .map(|argument| self.munch_expression(argument))
+            .collect();
+
+        let stack_arguments_size = Aarch64::stack_arguments_size(temps.len());
+        if stack_arguments_size != 0 {
+            self.operation(
+                format!("sub 'd0, 's0, #{}", stack_arguments_size),
+                vec![SP],
+                vec![SP],
+            );
+        }
+        let arg_registers = Aarch64::arg_registers();
+        for (index, &temp) in temps.iter().enumerate().skip(arg_registers.len()) {
+            let offset = (index - arg_registers.len()) as i64 * Aarch64::WORD_SIZE;
+            self.operation(
+                format!("str 's0, ['s1, #{}]", offset),
+                vec![],
+                vec![temp, SP],
+            );
+        }
+
+        let mut registers = vec![];
+        for (&temp, register) in temps.iter().zip(arg_registers) {
+            self.emit(Instruction::Move {
+                assembly: "mov 'd0, 's0".to_string(),
+                source: vec![temp],
+                destination: vec![register],
+            });
+            registers.push(register);
+        }
+        registers
+    }
+
+    /// The operand of a load or a store, with its source registers numbered from 0.
+    fn munch_address(&mut self, address: Exp) -> (String, Vec<Temp>) {
+        match address {
+            Exp::BinOp {
+                op: BinOp::Plus,
+                left: base,
+                right: box Exp::Const(offset),
+            }
+            | Exp::BinOp {
+                op: BinOp::Plus,
+                left: box Exp::Const(offset),
+                right: base,
+            } => {
+                let base = self.munch_expression(*base);
+                if is_offset(offset) {
+                    (format!("['s0, #{}]", offset), vec![base])
+                } else {
+                    self.constant(X16, offset);
+                    self.operation("add 'd0, 's0, 's1".to_string(), vec![X16], vec![base, X16]);
+                    ("['s0]".to_string(), vec![X16])
+                }
+            }
+            address => ("['s0]".to_string(), vec![self.munch_expression(address)]),
+        }
+    }
+
+    /// Move `num` to `temp`, 16 bits at a time when it does not fit in one instruction.
+    fn constant(&mut self, temp: Temp, num: i64) {
+        for (index, assembly) in immediate_moves("'d0", num).into_iter().enumerate() {
+            // movk keeps the other bits of the register.
+            let source = if index == 0 { vec![] } else { vec![temp] };
+            self.operation(assembly, vec![temp], source);
+        }
+    }
+
+    fn call(&mut self, temp: Temp, assembly: String, source: Vec<Temp>, destination: Vec<Temp>) {
+        self.operation(assembly, destination, source);
+        self.emit(Instruction::Move {
+            assembly: "mov 'd0, 's0".to_string(),
+            source: vec![X0],
+            destination: vec![temp],
+        });
+    }
+
+    fn release_stack_arguments(&mut self, argument_count: usize) {
+        let stack_arguments_size = Aarch64::stack_arguments_size(argument_count);
+        if stack_arguments_size != 0 {
+            self.operation(
+                format!("add 'd0, 's0, #{}", stack_arguments_size),
+                vec![SP],
+                vec![SP],
+            );
+        }
+    }
+
+    pub fn munch_expression(&mut self, expr: Exp) -> Temp {
+        let temp = Temp::new();
+        match expr {
+            // Error cases:
+            Exp::Error | Exp::ExpSequence(_, _) => unreachable!(),
+
+            Exp::Temp(temp) => return temp,
+            Exp::Const(num) => self.constant(temp, num),
+            Exp::Name(label) => {
+                self.operation(format!("adrp 'd0, {}", label), vec![temp], vec![]);
+                self.operation(
+                    format!("add 'd0, 's0, :lo12:{}", label),
+                    vec![temp],
+                    vec![temp],
+                );
+            }
+            Exp::Mem(address) => {
+                let (address, source) = self.munch_address(*address);
+                self.operation(format!("ldr 'd0, {}", address), vec![temp], source);
+            }
+            Exp::BinOp {
+                op: op @ BinOp::Plus,
+                left: expr,
+                right: box Exp::Const(num),
+            }
+            | Exp::BinOp {
+                op: op @ BinOp::Plus,
+                left: box Exp::Const(num),
+                right: expr,
+            }
+            | Exp::BinOp {
+                op: op @ BinOp::Minus,
+                left: expr,
+                right: box Exp::Const(num),
+            } if is_arithmetic_immediate(num)
+                || num.checked_neg().is_some_and(is_arithmetic_immediate) =>
+            {
+                // x + -8 is x - 8.
+                let (opcode, num) = match (op, num >= 0) {
+                    (BinOp::Plus, true) | (BinOp::Minus, false) => ("add", num.abs()),
+                    _ => ("sub", num.abs()),
+                };
+                let source = self.munch_expression(*expr);
+                self.operation(
+                    format!("{} 'd0, 's0, #{}", opcode, num),
+                    vec![temp],
+                    vec![source],
+                );
+            }
+            Exp::BinOp {
+                op: BinOp::Minus,
+                left: box Exp::Const(0),
+                right: expr,
+            } => {
+                let source = self.munch_expression(*expr);
+                self.operation("neg 'd0, 's0".to_string(), vec![temp], vec![source]);
+            }
+            Exp::BinOp {
+                op: op @ BinOp::ShiftLeft,
+                left: expr,
+                right: box Exp::Const(num),
+            }
+            | Exp::BinOp {
+                op: op @ BinOp::ShiftRight,
+                left: expr,
+                right: box Exp::Const(num),
+            }
+            | Exp::BinOp {
+                op: op @ BinOp::ArithmeticShiftRight,
+                left: expr,
+                right: box Exp::Const(num),
+            } if (0..64).contains(&num) => {
+                let source = self.munch_expression(*expr);
+                self.operation(
+                    format!("{} 'd0, 's0, #{}", opcode(&op), num),
+                    vec![temp],
+                    vec![source],
+                );
+            }
+            Exp::BinOp { op, left, right } => {
+                let left = self.munch_expression(*left);
+                let right = self.munch_expression(*right);
+                self.operation(
+                    format!("{} 'd0, 's0, 's1", opcode(&op)),
+                    vec![temp],
+                    vec![left, right],
+                );
+            }
+            Exp::Call(box Exp::Name(label), arguments) => {
+                let argument_count = arguments.len();
+                let source = self.munch_args(arguments);
+                let assembly = format!("bl {}", label);
+                self.call(temp, assembly, source, Aarch64::calldefs_of(&label));
+                self.release_stack_arguments(argument_count);
+            }
+            Exp::Call(function, arguments) => {
+                let argument_count = arguments.len();
+                let mut source = vec![self.munch_expression(*function)];
+                source.extend(self.munch_args(arguments));
+                self.call(temp, "blr 's0".to_string(), source, Aarch64::calldefs());
+                self.release_stack_arguments(argument_count);
+            }
+        }
+
+        temp
+    }
+
+    pub fn munch_statement(&mut self, statement: Statement) {
+        match statement {
+            Statement::Sequence(statement1, statement2) => {
+                self.munch_statement(*statement1);
+                self.munch_statement(*statement2);
+            }
+            Statement::Move(Exp::Mem(address), source) => {
+                let source = self.munch_expression(source);
+                let (address, mut sources) = self.munch_address(*address);
+                let assembly = format!("str 's{}, {}", sources.len(), address);
+                sources.push(source);
+                self.operation(assembly, vec![], sources);
+            }
+            // The loads and the constants go directly to the destination: the spill code of the
+            // register allocators must not need other temporaries.
+            Statement::Move(Exp::Temp(temp), Exp::Mem(address)) => {
+                let (address, source) = self.munch_address(*address);
+                self.operation(format!("ldr 'd0, {}", address), vec![temp], source);
+            }
+            Statement::Move(Exp::Temp(temp), Exp::Const(num)) => self.constant(temp, num),
+            Statement::Move(Exp::Temp(temp), source) => {
+                let source = self.munch_expression(source);
+                self.emit(Instruction::Move {
+                    assembly: "mov 'd0, 's0".to_string(),
+                    source: vec![source],
+                    destination: vec![temp],
+                });
+            }
+            Statement::Label(label) => {
+                self.emit(Instruction::Label {
+                    assembly: format!("{}:", label),
+                    label,
+                });
+            }
+            Statement::Exp(Exp::Const(_)) => (), // Nop statement.
+            Statement::Exp(exp) => {
+                self.munch_expression(exp);
+            }
+            Statement::Jump(exp, labels) => match exp {
+                // Tail call: the arguments passed on the stack are already in place, and
+                // proc_entry_exit3 releases the frame before the jump.
+                Exp::Call(box Exp::Name(label), arguments) => {
+                    let source = self.munch_args(arguments);
+                    self.emit(Instruction::Operation {
+                        assembly: format!("b {}", label),
+                        source,
+                        destination: vec![],
+                        jump: Some(vec![]),
+                    });
+                }
+                Exp::Name(label) => {
+                    self.emit(Instruction::Operation {
+                        assembly: format!("b {}", label),
+                        source: vec![],
+                        destination: vec![],
+                        jump: Some(labels),
+                    });
+                }
+                _ => panic!("Unexpected jump expression: {:?}", exp),
+            },
+            Statement::CondJump {
+                op,
+                left,
+                right,
+                false_label,
+                true_label,
+            } => {
+                let left = self.munch_expression(left);
+                match right {
+                    Exp::Const(num) if is_arithmetic_immediate(num) => {
+                        self.operation(format!("cmp 's0, #{}", num), vec![], vec![left]);
+                    }
+                    Exp::Const(num) if num.checked_neg().is_some_and(is_arithmetic_immediate) => {
+                        self.operation(format!("cmn 's0, #{}", -num), vec![], vec![left]);
+                    }
+                    right => {
+                        let right = self.munch_expression(right);
+                        self.operation("cmp 's0, 's1".to_string(), vec![], vec![left, right]);
+                    }
+                }
+
+                let condition = match op {
+                    RelationalOp::Equal => "eq",
+                    RelationalOp::NotEqual => "ne",
+                    RelationalOp::LesserThan => "lt",
+                    RelationalOp::GreaterThan => "gt",
+                    RelationalOp::LesserOrEqual => "le",
+                    RelationalOp::GreaterOrEqual => "ge",
+                    RelationalOp::UnsignedLesserThan => "lo",
+                    RelationalOp::UnsignedLesserOrEqual => "ls",
+                    RelationalOp::UnsignedGreaterThan => "hi",
+                    RelationalOp::UnsignedGreaterOrEqual => "hs",
+                };
+                self.emit(Instruction::Operation {
+                    assembly: format!("b.{} {}", condition, true_label),
+                    source: vec![],
+                    destination: vec![],
+                    jump: Some(vec![false_label, true_label]),
+                });
+            }
+
+            // Error cases:
+            Statement::Move(Exp::Const(_), _)
+            | Statement::Move(Exp::Error, _)
+            | Statement::Move(Exp::Name(_), _)
+            | Statement::Move(Exp::BinOp { .. }, _)
+            | Statement::Move(Exp::Call(_, _), _)
+            | Statement::Move(Exp::ExpSequence(_, _), _) => unreachable!("{:#?}", statement),
+        }
+    }
+
+    pub fn get_result(self) -> Vec<Instruction> {
+        self.instructions
+    }
+}
+
+fn opcode(op: &BinOp) -> &'static str {
+    match *op {
+        BinOp::Plus => "add",
+        BinOp::Minus => "sub",
+        BinOp::Mul => "mul",
+        BinOp::Div => "sdiv",
+        BinOp::And => "and",
+        BinOp::Or => "orr",
+        BinOp::Xor => "eor",
+        BinOp::ShiftLeft => "lsl",
+        BinOp::ShiftRight => "lsr",
+        BinOp::ArithmeticShiftRight => "asr",
+    }
+}
+
+/// The immediate of `add`, `sub` and `cmp`, without the optional shift by 12 bits.
+fn is_arithmetic_immediate(num: i64) -> bool {
+    (0..0x1000).contains(&num)
+}
+
+/// The offsets of `ldr` and `str`: unscaled (`ldur`, `stur`) or scaled by the size of a register.
+fn is_offset(offset: i64) -> bool {
+    (-256..256).contains(&offset) || ((0..0x8000).contains(&offset) && offset % 8 == 0)
+}
+
+/// The assembly moving `num` to `register`: one `mov` when it fits in 16 bits, either directly or
+/// inverted by `movn`, otherwise a `movz` followed by a `movk` for each other non-zero 16 bits.
+pub fn immediate_moves(register: &str, num: i64) -> Vec<String> {
+    if (-0x10000..0x10000).contains(&num) {
+        return vec![format!("mov {}, #{}", register, num)];
+    }
+    let bits = num as u64;
+    let mut moves = vec![format!("movz {}, #{}", register, bits & 0xFFFF)];
+    for shift in &[16, 32, 48] {
+        let part = (bits >> shift) & 0xFFFF;
+        if part != 0 {
+            moves.push(format!("movk {}, #{}, lsl #{}", register, part, shift));
+        }
+    }
+    moves
+}
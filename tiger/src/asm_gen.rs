@@ -1,6 +1,6 @@
 use asm::Instruction;
 use frame::x86_64::X86_64;
-use frame::x86_64::{RAX, RDX, RSP};
+use frame::x86_64::{RAX, RCX, RDX, RSP};
 use frame::Frame;
 use ir::{BinOp, Exp, RelationalOp, Statement};
 use temp::Temp;
@@ -23,6 +23,7 @@ impl Gen {
     fn munch_args(&mut self, arguments: Vec<Exp>) -> Vec<Temp> {
         let mut temps = vec![];
 
+        let stack_arguments_size = X86_64::stack_arguments_size(arguments.len());
         let mut arguments = arguments.into_iter();
 
         for register in X86_64::arg_registers() {
@@ -50,6 +51,16 @@ impl Gen {
             .rev() // Arguments are pushed backwards.
             .collect();
 
+        // The padding keeps the stack aligned on 16 bytes at the call.
+        let padding = stack_arguments_size - instructions.len() as i64 * X86_64::WORD_SIZE;
+        if padding != 0 {
+            self.emit(Instruction::Operation {
+                assembly: format!("sub 'd0, {}", padding),
+                source: vec![RSP],
+                destination: vec![RSP],
+                jump: None,
+            });
+        }
         for instruction in instructions {
             self.emit(instruction);
         }
@@ -130,7 +141,7 @@ impl Gen {
                 op: BinOp::Plus,
                 left: box Exp::Const(num),
                 right: expr,
-            } => {
+            } if is_immediate(num) => {
                 let instruction = Instruction::Move {
                     assembly: "mov 'd0, 's0".to_string(),
                     source: vec![self.munch_expression(*expr)],
@@ -149,7 +160,7 @@ impl Gen {
                 op: BinOp::Minus,
                 left: expr,
                 right: box Exp::Const(num),
-            } => {
+            } if is_immediate(num) => {
                 let instruction = Instruction::Move {
                     assembly: "mov 'd0, 's0".to_string(),
                     source: vec![self.munch_expression(*expr)],
@@ -219,75 +230,6 @@ impl Gen {
                 };
                 self.emit(instruction);
             }
-            Exp::BinOp {
-                op: BinOp::Div,
-                left: expr,
-                right: box Exp::Const(num),
-            } => {
-                let instruction = Instruction::Move {
-                    assembly: "mov 'd0, 0".to_string(),
-                    source: vec![],
-                    destination: vec![RDX],
-                };
-                self.emit(instruction);
-                let instruction = Instruction::Move {
-                    assembly: "mov 'd0, 's0".to_string(),
-                    source: vec![self.munch_expression(*expr)],
-                    destination: vec![RAX],
-                };
-                self.emit(instruction);
-                let immediate = Temp::new();
-                let instruction = Instruction::Move {
-                    assembly: format!("mov 'd0, {}", num),
-                    source: vec![],
-                    destination: vec![immediate],
-                };
-                self.emit(instruction);
-                let instruction = Instruction::Operation {
-                    assembly: "idiv 's0".to_string(),
-                    source: vec![immediate, RAX, RDX],
-                    destination: vec![RAX, RDX],
-                    jump: None,
-                };
-                self.emit(instruction);
-                let instruction = Instruction::Move {
-                    assembly: "mov 'd0, 's0".to_string(),
-                    source: vec![RAX],
-                    destination: vec![temp],
-                };
-                self.emit(instruction);
-            }
-            Exp::BinOp {
-                op: BinOp::Div,
-                left: box Exp::Const(num),
-                right: expr,
-            } => {
-                let instruction = Instruction::Move {
-                    assembly: "mov 'd0, 0".to_string(),
-                    source: vec![],
-                    destination: vec![RDX],
-                };
-                self.emit(instruction);
-                let instruction = Instruction::Move {
-                    assembly: format!("mov 'd0, {}", num),
-                    source: vec![],
-                    destination: vec![RAX],
-                };
-                self.emit(instruction);
-                let instruction = Instruction::Operation {
-                    assembly: "idiv 's0".to_string(),
-                    source: vec![self.munch_expression(*expr), RAX, RDX],
-                    destination: vec![RAX, RDX],
-                    jump: None,
-                };
-                self.emit(instruction);
-                let instruction = Instruction::Move {
-                    assembly: "mov 'd0, 's0".to_string(),
-                    source: vec![RAX],
-                    destination: vec![temp],
-                };
-                self.emit(instruction);
-            }
             Exp::BinOp {
                 op: BinOp::And,
                 left: expr,
@@ -297,7 +239,7 @@ impl Gen {
                 op: BinOp::And,
                 left: box Exp::Const(num),
                 right: expr,
-            } => {
+            } if is_immediate(num) => {
                 let instruction = Instruction::Move {
                     assembly: "mov 'd0, 's0".to_string(),
                     source: vec![self.munch_expression(*expr)],
@@ -306,7 +248,7 @@ impl Gen {
                 self.emit(instruction);
                 let instruction = Instruction::Operation {
                     assembly: format!("and 'd0, {}", num),
-                    source: vec![],
+                    source: vec![temp],
                     destination: vec![temp],
                     jump: None,
                 };
@@ -321,7 +263,7 @@ impl Gen {
                 op: BinOp::Or,
                 left: box Exp::Const(num),
                 right: expr,
-            } => {
+            } if is_immediate(num) => {
                 let instruction = Instruction::Move {
                     assembly: "mov 'd0, 's0".to_string(),
                     source: vec![self.munch_expression(*expr)],
@@ -330,7 +272,7 @@ impl Gen {
                 self.emit(instruction);
                 let instruction = Instruction::Operation {
                     assembly: format!("or 'd0, {}", num),
-                    source: vec![],
+                    source: vec![temp],
                     destination: vec![temp],
                     jump: None,
                 };
@@ -340,12 +282,7 @@ impl Gen {
                 op: BinOp::ShiftLeft,
                 left: expr,
                 right: box Exp::Const(num),
-            }
-            | Exp::BinOp {
-                op: BinOp::ShiftLeft,
-                left: box Exp::Const(num),
-                right: expr,
-            } => {
+            } if (0..64).contains(&num) => {
                 let instruction = Instruction::Move {
                     assembly: "mov 'd0, 's0".to_string(),
                     source: vec![self.munch_expression(*expr)],
@@ -354,7 +291,7 @@ impl Gen {
                 self.emit(instruction);
                 let instruction = Instruction::Operation {
                     assembly: format!("sal 'd0, {}", num),
-                    source: vec![],
+                    source: vec![temp],
                     destination: vec![temp],
                     jump: None,
                 };
@@ -364,12 +301,7 @@ impl Gen {
                 op: BinOp::ArithmeticShiftRight,
                 left: expr,
                 right: box Exp::Const(num),
-            }
-            | Exp::BinOp {
-                op: BinOp::ArithmeticShiftRight,
-                left: box Exp::Const(num),
-                right: expr,
-            } => {
+            } if (0..64).contains(&num) => {
                 let instruction = Instruction::Move {
                     assembly: "mov 'd0, 's0".to_string(),
                     source: vec![self.munch_expression(*expr)],
@@ -378,7 +310,7 @@ impl Gen {
                 self.emit(instruction);
                 let instruction = Instruction::Operation {
                     assembly: format!("sar 'd0, {}", num),
-                    source: vec![],
+                    source: vec![temp],
                     destination: vec![temp],
                     jump: None,
                 };
@@ -388,12 +320,7 @@ impl Gen {
                 op: BinOp::ShiftRight,
                 left: expr,
                 right: box Exp::Const(num),
-            }
-            | Exp::BinOp {
-                op: BinOp::ShiftRight,
-                left: box Exp::Const(num),
-                right: expr,
-            } => {
+            } if (0..64).contains(&num) => {
                 let instruction = Instruction::Move {
                     assembly: "mov 'd0, 's0".to_string(),
                     source: vec![self.munch_expression(*expr)],
@@ -402,7 +329,7 @@ impl Gen {
                 self.emit(instruction);
                 let instruction = Instruction::Operation {
                     assembly: format!("shr 'd0, {}", num),
-                    source: vec![],
+                    source: vec![temp],
                     destination: vec![temp],
                     jump: None,
                 };
@@ -417,7 +344,7 @@ impl Gen {
                 op: BinOp::Xor,
                 left: box Exp::Const(num),
                 right: expr,
-            } => {
+            } if is_immediate(num) => {
                 let instruction = Instruction::Move {
                     assembly: "mov 'd0, 's0".to_string(),
                     source: vec![self.munch_expression(*expr)],
@@ -426,7 +353,7 @@ impl Gen {
                 self.emit(instruction);
                 let instruction = Instruction::Operation {
                     assembly: format!("xor 'd0, {}", num),
-                    source: vec![],
+                    source: vec![temp],
                     destination: vec![temp],
                     jump: None,
                 };
@@ -517,21 +444,24 @@ impl Gen {
                 left,
                 right,
             } => {
-                let instruction = Instruction::Move {
-                    assembly: "mov 'd0, 0".to_string(),
-                    source: vec![],
-                    destination: vec![RDX],
-                };
-                self.emit(instruction);
+                let divisor = self.munch_expression(*right);
                 let instruction = Instruction::Move {
                     assembly: "mov 'd0, 's0".to_string(),
                     source: vec![self.munch_expression(*left)],
                     destination: vec![RAX],
                 };
                 self.emit(instruction);
+                // Sign-extend rax into rdx.
+                let instruction = Instruction::Operation {
+                    assembly: "cqo".to_string(),
+                    source: vec![RAX],
+                    destination: vec![RDX],
+                    jump: None,
+                };
+                self.emit(instruction);
                 let instruction = Instruction::Operation {
                     assembly: "idiv 's0".to_string(),
-                    source: vec![self.munch_expression(*right), RAX, RDX],
+                    source: vec![divisor, RAX, RDX],
                     destination: vec![RAX, RDX],
                     jump: None,
                 };
@@ -556,7 +486,7 @@ impl Gen {
                 self.emit(instruction);
                 let instruction = Instruction::Operation {
                     assembly: "and 'd0, 's0".to_string(),
-                    source: vec![self.munch_expression(*right)],
+                    source: vec![self.munch_expression(*right), temp],
                     destination: vec![temp],
                     jump: None,
                 };
@@ -575,7 +505,7 @@ impl Gen {
                 self.emit(instruction);
                 let instruction = Instruction::Operation {
                     assembly: "or 'd0, 's0".to_string(),
-                    source: vec![self.munch_expression(*right)],
+                    source: vec![self.munch_expression(*right), temp],
                     destination: vec![temp],
                     jump: None,
                 };
@@ -592,9 +522,10 @@ impl Gen {
                     destination: vec![temp],
                 };
                 self.emit(instruction);
+                self.shift_count(*right);
                 let instruction = Instruction::Operation {
-                    assembly: "sal 'd0, 's0".to_string(),
-                    source: vec![self.munch_expression(*right)],
+                    assembly: "sal 'd0, cl".to_string(),
+                    source: vec![RCX, temp],
                     destination: vec![temp],
                     jump: None,
                 };
@@ -611,9 +542,10 @@ impl Gen {
                     destination: vec![temp],
                 };
                 self.emit(instruction);
+                self.shift_count(*right);
                 let instruction = Instruction::Operation {
-                    assembly: "sar 'd0, 's0".to_string(),
-                    source: vec![self.munch_expression(*right)],
+                    assembly: "sar 'd0, cl".to_string(),
+                    source: vec![RCX, temp],
                     destination: vec![temp],
                     jump: None,
                 };
@@ -630,9 +562,10 @@ impl Gen {
                     destination: vec![temp],
                 };
                 self.emit(instruction);
+                self.shift_count(*right);
                 let instruction = Instruction::Operation {
-                    assembly: "shr 'd0, 's0".to_string(),
-                    source: vec![self.munch_expression(*right)],
+                    assembly: "shr 'd0, cl".to_string(),
+                    source: vec![RCX, temp],
                     destination: vec![temp],
                     jump: None,
                 };
@@ -651,7 +584,7 @@ impl Gen {
                 self.emit(instruction);
                 let instruction = Instruction::Operation {
                     assembly: "xor 'd0, 's0".to_string(),
-                    source: vec![self.munch_expression(*right)],
+                    source: vec![self.munch_expression(*right), temp],
                     destination: vec![temp],
                     jump: None,
                 };
@@ -664,7 +597,7 @@ impl Gen {
                 let instruction = Instruction::Operation {
                     assembly: format!("call {}", label),
                     source,
-                    destination: X86_64::calldefs(),
+                    destination: X86_64::calldefs_of(&label),
                     jump: None,
                 };
                 self.emit(instruction);
@@ -674,15 +607,11 @@ impl Gen {
                     destination: vec![temp],
                 };
                 self.emit(instruction);
-                let arg_register_count = X86_64::arg_registers().len();
-                if argument_count > arg_register_count {
-                    let stack_arg_count = argument_count - X86_64::arg_registers().len();
+                let stack_arguments_size = X86_64::stack_arguments_size(argument_count);
+                if stack_arguments_size != 0 {
                     let instruction = Instruction::Operation {
-                        assembly: format!(
-                            "add 'd0, {}",
-                            stack_arg_count as i64 * X86_64::WORD_SIZE
-                        ),
-                        source: vec![],
+                        assembly: format!("add 'd0, {}", stack_arguments_size),
+                        source: vec![RSP],
                         destination: vec![RSP],
                         jump: None,
                     };
@@ -706,15 +635,11 @@ impl Gen {
                     destination: vec![temp],
                 };
                 self.emit(instruction);
-                let arg_register_count = X86_64::arg_registers().len();
-                if argument_count > arg_register_count {
-                    let stack_arg_count = argument_count - X86_64::arg_registers().len();
+                let stack_arguments_size = X86_64::stack_arguments_size(argument_count);
+                if stack_arguments_size != 0 {
                     let instruction = Instruction::Operation {
-                        assembly: format!(
-                            "add 'd0, {}",
-                            stack_arg_count as i64 * X86_64::WORD_SIZE
-                        ),
-                        source: vec![],
+                        assembly: format!("add 'd0, {}", stack_arguments_size),
+                        source: vec![RSP],
                         destination: vec![RSP],
                         jump: None,
                     };
@@ -813,7 +738,8 @@ impl Gen {
                     right: box Exp::Const(num),
                 }) = source
                 {
-                    // TODO: should that optimization be removed in favor of loophole optimization?
+                    // simplest_allocate生成溢出代码时不会再为新的临时变量分配寄存器，因此这里必须直接载入机器寄存器。
+                    // 其余经由临时变量中转的载入交给窥孔优化合并。
                     if <X86_64 as Frame>::registers().contains(&temp) {
                         Instruction::Move {
                             assembly: format!("mov 'd0, ['s0 + {}]", num),
@@ -841,6 +767,18 @@ impl Gen {
                 self.munch_expression(exp);
             }
             Statement::Jump(exp, labels) => match exp {
+                // Tail call: the arguments passed on the stack are already in place, and
+                // proc_entry_exit3 releases the frame before the jump.
+                Exp::Call(box Exp::Name(label), arguments) => {
+                    let source = self.munch_args(arguments);
+                    let instruction = Instruction::Operation {
+                        assembly: format!("jmp {}", label),
+                        source,
+                        destination: vec![],
+                        jump: Some(vec![]),
+                    };
+                    self.emit(instruction);
+                }
                 Exp::Name(label) => {
                     let instruction = Instruction::Operation {
                         assembly: format!("jmp {}", label),
@@ -898,7 +836,22 @@ impl Gen {
         }
     }
 
+    /// A shift count which is not an immediate must be in cl.
+    fn shift_count(&mut self, count: Exp) {
+        let instruction = Instruction::Move {
+            assembly: "mov 'd0, 's0".to_string(),
+            source: vec![self.munch_expression(count)],
+            destination: vec![RCX],
+        };
+        self.emit(instruction);
+    }
+
     pub fn get_result(self) -> Vec<Instruction> {
         self.instructions
     }
 }
+
+/// Most x86-64 instructions only take a sign-extended 32-bit immediate.
+pub fn is_immediate(num: i64) -> bool {
+    num >= i64::from(i32::MIN) && num <= i64::from(i32::MAX)
+}
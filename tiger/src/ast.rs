@@ -3,6 +3,10 @@ use symbol::{Symbol, SymbolWithPos};
 
 #[derive(Clone, Debug)]
 pub enum Declaration {
+    Exception {
+        name: Symbol,
+    },
+    ExternFunction(ExternFuncDeclaration),
     Function(Vec<FuncDeclarationWithPos>),
     Type(Vec<TypeDecWithPos>),
     VariableDeclaration {
@@ -49,6 +53,9 @@ pub enum Expr {
         oper: OperatorWithPos,
         right: Box<ExprWithPos>,
     },
+    Raise {
+        exception: SymbolWithPos,
+    },
     Record {
         fields: Vec<RecordFieldWithPos>,
         typ: SymbolWithPos,
@@ -57,6 +64,14 @@ pub enum Expr {
     Str {
         value: String,
     },
+    Try {
+        body: Box<ExprWithPos>,
+        handlers: Vec<HandlerWithPos>,
+    },
+    Unary {
+        expr: Box<ExprWithPos>,
+        oper: UnaryOperatorWithPos,
+    },
     Variable(VarWithPos),
     While {
         body: Box<ExprWithPos>,
@@ -66,6 +81,16 @@ pub enum Expr {
 
 pub type ExprWithPos = WithPos<Expr>;
 
+/// A function implemented outside of Tiger, called with the C calling convention and without a
+/// static link.
+#[derive(Clone, Debug)]
+pub struct ExternFuncDeclaration {
+    pub name: Symbol,
+    pub params: Vec<FieldWithPos>,
+    pub result: Option<SymbolWithPos>,
+    pub symbol: String,
+}
+
 #[derive(Clone, Debug)]
 pub struct Field {
     pub escape: bool,
@@ -78,6 +103,8 @@ pub type FieldWithPos = WithPos<Field>;
 #[derive(Clone, Debug)]
 pub struct FuncDeclaration {
     pub body: ExprWithPos,
+    /// Callable from C through a wrapper without the static link.
+    pub exported: bool,
     pub name: Symbol,
     pub params: Vec<FieldWithPos>,
     pub result: Option<SymbolWithPos>,
@@ -85,6 +112,14 @@ pub struct FuncDeclaration {
 
 pub type FuncDeclarationWithPos = WithPos<FuncDeclaration>;
 
+#[derive(Clone, Debug)]
+pub struct Handler {
+    pub body: ExprWithPos,
+    pub exception: SymbolWithPos,
+}
+
+pub type HandlerWithPos = WithPos<Handler>;
+
 #[derive(Clone, Copy, Debug)]
 pub enum Operator {
     And,
@@ -95,14 +130,27 @@ pub enum Operator {
     Le,
     Lt,
     Minus,
+    Modulo,
     Neq,
     Or,
     Plus,
+    ShiftLeft,
+    ShiftRight,
     Times,
+    UnsignedShiftRight,
+    Xor,
 }
 
 pub type OperatorWithPos = WithPos<Operator>;
 
+#[derive(Clone, Copy, Debug)]
+pub enum UnaryOperator {
+    Neg,
+    Not,
+}
+
+pub type UnaryOperatorWithPos = WithPos<UnaryOperator>;
+
 #[derive(Clone, Debug)]
 pub struct RecordField {
     pub expr: ExprWithPos,
@@ -81,9 +81,10 @@ pub fn basic_blocks(statements: Vec<Statement>) -> (Vec<Vec<Statement>>, Label)
     (basic_blocks, done)
 }
 
-pub fn trace_schedule(mut basic_blocks: Vec<Vec<Statement>>, done_label: Label) -> Vec<Statement> {
+/// The traces of the basic blocks, as lists of block indices, in the order they are laid out.
+pub fn traces(basic_blocks: &[Vec<Statement>], done_label: &Label) -> Vec<Vec<usize>> {
     let mut label_mapping = HashMap::new();
-    label_mapping.insert(&done_label, usize::max_value());
+    label_mapping.insert(done_label, usize::MAX);
     for (index, basic_block) in basic_blocks.iter().enumerate() {
         match basic_block
             .first()
@@ -136,6 +137,12 @@ pub fn trace_schedule(mut basic_blocks: Vec<Vec<Statement>>, done_label: Label)
         }
     }
 
+    traces
+}
+
+pub fn trace_schedule(mut basic_blocks: Vec<Vec<Statement>>, done_label: Label) -> Vec<Statement> {
+    let traces = traces(&basic_blocks, &done_label);
+
     let mut statements = VecDeque::new();
 
     for trace in traces {
@@ -227,7 +234,7 @@ pub fn trace_schedule(mut basic_blocks: Vec<Vec<Statement>>, done_label: Label)
     new_statements
 }
 
-fn negate_condition(op: RelationalOp) -> RelationalOp {
+pub fn negate_condition(op: RelationalOp) -> RelationalOp {
     match op {
         RelationalOp::Equal => RelationalOp::NotEqual,
         RelationalOp::GreaterOrEqual => RelationalOp::LesserThan,
@@ -370,6 +377,17 @@ fn do_statement(statement: Statement) -> Statement {
         Statement::Sequence(statement1, statement2) => {
             append(do_statement(*statement1), do_statement(*statement2))
         }
+        // Tail call.
+        Statement::Jump(Exp::Call(function, arguments), labels) => {
+            let mut exprs = VecDeque::new();
+            exprs.push_back(*function);
+            exprs.extend(arguments);
+            reorder_statement(exprs, |mut exprs| {
+                let function = exprs.pop_front().expect("pop front");
+                let exprs = exprs.into_iter().collect();
+                Statement::Jump(Exp::Call(Box::new(function), exprs), labels)
+            })
+        }
         Statement::Jump(expr, labels) => {
             reorder_statement1(expr, |expr| Statement::Jump(expr, labels))
         }
@@ -25,9 +25,12 @@
 //! 从而使得有更多的结点可简化。然后，重新开始简化和合并阶段。
 //!
 //! **溢出**：如果没有低度数的结点，选择一个潜在可能溢出的高度数结点并将它压入栈。
+//! 选择溢出代价除以度数最小的结点：溢出代价是临时变量的定值和使用次数之和，每一次按所在
+//! 循环的嵌套深度乘以`10^depth`。
 //!
 //! **选择**：弹出整个栈并指派颜色。
 
+use std::cmp::Ordering;
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::marker::PhantomData;
 
@@ -37,22 +40,40 @@ use liveness::InterferenceGraph;
 use reg_alloc::Allocation;
 use temp::Temp;
 
+/// The result of coloring the interference graph.
+pub struct Coloring {
+    pub allocation: Allocation,
+    /// The spilled temporaries, grouped by stack slot: the temporaries of a slot do not interfere.
+    pub spill_slots: Vec<Vec<Temp>>,
+    pub colored_nodes: BTreeSet<Temp>,
+    pub coalesced_nodes: BTreeSet<Temp>,
+    pub coalesced_moves: usize,
+    pub frozen_moves: usize,
+}
+
+/// `spill_costs` is the cost of spilling each temporary; the one with the lowest cost per
+/// interference is spilled first.
 pub fn color<F: Frame>(
     interference_graph: InterferenceGraph,
     initial: Vec<Temp>,
-) -> (Allocation, Vec<Temp>, BTreeSet<Temp>, BTreeSet<Temp>) {
+    spill_costs: HashMap<Temp, f64>,
+) -> Coloring {
     let nodes = interference_graph.nodes().to_vec();
     let mut allocator = Allocator::<F>::new(
         interference_graph.move_list,
         interference_graph.worklist_moves,
+        spill_costs,
+        interference_graph.setjmp_live,
     );
     let allocation = allocator.allocate(initial, &nodes);
-    (
+    Coloring {
         allocation,
-        allocator.spill_nodes,
-        allocator.colored_nodes,
-        allocator.coalesced_nodes,
-    )
+        spill_slots: allocator.spill_slots(),
+        colored_nodes: allocator.colored_nodes,
+        coalesced_nodes: allocator.coalesced_nodes,
+        coalesced_moves: allocator.coalesced_moves.len(),
+        frozen_moves: allocator.frozen_moves.len(),
+    }
 }
 
 struct Allocator<F> {
@@ -71,7 +92,9 @@ struct Allocator<F> {
     precolored: HashMap<Temp, &'static str>,
     register_count: usize,
     select_stack: Vec<Temp>,
+    setjmp_live: HashSet<Temp>,
     simplify_worklist: BTreeSet<Temp>,
+    spill_costs: HashMap<Temp, f64>,
     spill_nodes: Vec<Temp>,
     spill_worklist: BTreeSet<Temp>,
     worklist_moves: BTreeSet<(Temp, Temp)>,
@@ -82,6 +105,8 @@ impl<F: Frame> Allocator<F> {
     fn new(
         move_list: HashMap<Temp, BTreeSet<(Temp, Temp)>>,
         worklist_moves: BTreeSet<(Temp, Temp)>,
+        spill_costs: HashMap<Temp, f64>,
+        setjmp_live: HashSet<Temp>,
     ) -> Self {
         Self {
             active_moves: BTreeSet::new(),
@@ -99,7 +124,9 @@ impl<F: Frame> Allocator<F> {
             precolored: F::temp_map(),
             register_count: F::register_count(),
             select_stack: vec![],
+            setjmp_live,
             simplify_worklist: BTreeSet::new(),
+            spill_costs,
             spill_nodes: vec![],
             spill_worklist: BTreeSet::new(),
             worklist_moves,
@@ -408,7 +435,11 @@ impl<F: Frame> Allocator<F> {
         let temp = self
             .spill_worklist
             .iter()
-            .min_by_key(|node| self.spill_cost(**node))
+            .min_by(|&&node1, &&node2| {
+                self.spill_cost(node1)
+                    .partial_cmp(&self.spill_cost(node2))
+                    .unwrap_or(Ordering::Equal)
+            })
             .expect("empty spill_worklist");
         let temp = *temp;
         self.spill_worklist.remove(&temp);
@@ -428,8 +459,36 @@ impl<F: Frame> Allocator<F> {
         }
     }
 
-    fn spill_cost(&self, node: Temp) -> i32 {
-        // FIXME: The number of uses and defs weighted by occurrence in loops and nested loops.
-        -(self.degree[&node] as i32)
+    /// The uses and definitions weighted by the loop depth, per interference: spilling a
+    /// temporary with many neighbors frees more registers.
+    fn spill_cost(&self, node: Temp) -> f64 {
+        let cost = self.spill_costs.get(&node).cloned().unwrap_or(0.0);
+        cost / self.degree[&node] as f64
+    }
+
+    /// Give a stack slot to the spilled temporaries: a temporary shares the first slot whose
+    /// temporaries do not interfere with it. A temporary live across `_setjmp` gets its own slot,
+    /// since the interferences miss the second return of `_setjmp` into a handler.
+    fn spill_slots(&self) -> Vec<Vec<Temp>> {
+        let setjmp_live: HashSet<Temp> = self
+            .setjmp_live
+            .iter()
+            .map(|&temp| self.get_alias(temp))
+            .collect();
+        let mut slots: Vec<Vec<Temp>> = vec![];
+        for &spill in &self.spill_nodes {
+            let slot = slots.iter_mut().find(|slot| {
+                !setjmp_live.contains(&spill)
+                    && slot.iter().all(|&other| {
+                        !setjmp_live.contains(&other)
+                            && !self.adjacency_set.contains(&(spill, other))
+                    })
+            });
+            match slot {
+                Some(slot) => slot.push(spill),
+                None => slots.push(vec![spill]),
+            }
+        }
+        slots
     }
 }
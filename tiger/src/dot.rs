@@ -0,0 +1,166 @@
+//! **控制流图的可视化**。`--emit=cfg`把每个函数的控制流图写成 Graphviz 的 DOT 文件：
+//! `<文件名>.<函数>.ir.dot`是`canon::basic_blocks`之后的基本块，`<文件名>.<函数>.asm.dot`是
+//! 指令选择之后的指令。每个结点是一个基本块，标注它在轨迹(trace)排列中的位置，以及入口活跃和
+//! 出口活跃的临时变量：中间表示的活跃性由`opt`的数据流分析求出，指令的活跃性由
+//! `liveness::Liveness`求出。
+//!
+//! 每条边标注它连接的两个基本块在轨迹排列中的位置：顺序执行到下一个基本块的边是实线，需要
+//! 跳转的边是虚线。
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use asm::Instruction;
+use canon::traces;
+use flow::instructions_to_graph;
+use frame::Frame;
+use ir::Statement;
+use liveness::Liveness;
+use opt::{solve, ControlFlowGraph, LiveTemps};
+use temp::{Label, Temp};
+
+struct Block {
+    lines: Vec<String>,
+    live_in: HashSet<Temp>,
+    live_out: HashSet<Temp>,
+    successors: Vec<usize>,
+}
+
+/// The graph of the basic blocks, before they are scheduled in traces.
+pub fn ir_graph<F: Frame>(
+    function: &Label,
+    basic_blocks: &[Vec<Statement>],
+    done_label: &Label,
+) -> String {
+    let statements: Vec<Statement> = basic_blocks.iter().flatten().cloned().collect();
+    // Every basic block starts with its label and contains no other label: the blocks of the
+    // graph are the basic blocks.
+    let graph = ControlFlowGraph::new(&statements);
+    let liveness = solve(&LiveTemps, &graph, &statements);
+    let blocks: Vec<_> = graph
+        .blocks
+        .iter()
+        .enumerate()
+        .map(|(index, block)| Block {
+            lines: statements[block.start..block.end]
+                .iter()
+                .map(|statement| statement.to_string::<F>())
+                .collect(),
+            live_in: liveness.block_in[index].clone(),
+            live_out: liveness.block_out[index].clone(),
+            successors: block.successors.clone(),
+        })
+        .collect();
+    let order: Vec<usize> = traces(basic_blocks, done_label)
+        .into_iter()
+        .flatten()
+        .collect();
+    to_dot::<F>(function, &blocks, &order)
+}
+
+/// The graph of the basic blocks of the instructions, which are already in the order of the traces.
+pub fn instruction_graph<F: Frame>(function: &Label, instructions: &[Instruction]) -> String {
+    let flow_graph = instructions_to_graph::<F>(instructions);
+    let liveness = Liveness::new(&flow_graph);
+    let node = |index: usize| flow_graph.instruction_node(index).map(|node| node.index());
+
+    // A block starts at a label or after a jump.
+    let mut starts = vec![];
+    let mut labels = HashMap::new();
+    for (index, instruction) in instructions.iter().enumerate() {
+        let after_jump = match instructions[..index].last() {
+            Some(Instruction::Operation { jump, .. }) => jump.is_some(),
+            _ => false,
+        };
+        if let Instruction::Label { ref label, .. } = *instruction {
+            if starts.last() != Some(&index) {
+                starts.push(index);
+            }
+            labels.insert(label.clone(), starts.len() - 1);
+        } else if index == 0 || after_jump {
+            starts.push(index);
+        }
+    }
+
+    let mut blocks = vec![];
+    for (block_index, &start) in starts.iter().enumerate() {
+        let end = starts
+            .get(block_index + 1)
+            .cloned()
+            .unwrap_or(instructions.len());
+        let mut successors = vec![];
+        let falls_through = match instructions[end - 1] {
+            Instruction::Operation {
+                ref assembly,
+                ref jump,
+                ..
+            } => {
+                for label in jump.iter().flatten() {
+                    successors.extend(labels.get(label));
+                }
+                !F::is_unconditional_jump(assembly)
+            }
+            _ => true,
+        };
+        if falls_through && end < instructions.len() {
+            successors.push(block_index + 1);
+        }
+        blocks.push(Block {
+            lines: instructions[start..end]
+                .iter()
+                .map(|instruction| instruction.to_string::<F>())
+                .collect(),
+            live_in: node(start).map_or_else(HashSet::new, |node| liveness.live_in(node).collect()),
+            live_out: node(end - 1)
+                .map_or_else(HashSet::new, |node| liveness.live_out(node).collect()),
+            successors,
+        });
+    }
+    let order: Vec<_> = (0..blocks.len()).collect();
+    to_dot::<F>(function, &blocks, &order)
+}
+
+/// `order` lists the blocks in the order of the traces.
+fn to_dot<F: Frame>(function: &Label, blocks: &[Block], order: &[usize]) -> String {
+    let mut positions = vec![0; blocks.len()];
+    for (position, &block) in order.iter().enumerate() {
+        positions[block] = position;
+    }
+    let temps = |temps: &HashSet<Temp>| {
+        let temps: BTreeSet<_> = temps.iter().collect();
+        let names: Vec<_> = temps.iter().map(|temp| temp.to_string::<F>()).collect();
+        names.join(" ")
+    };
+
+    let mut dot = format!("digraph \"{}\" {{\n", escape(&function.to_string()));
+    dot.push_str("    node [shape=box, fontname=monospace];\n");
+    for (index, block) in blocks.iter().enumerate() {
+        let mut label = format!("trace order {}\\l", positions[index]);
+        label.push_str(&format!("in: {}\\l", temps(&block.live_in)));
+        for line in &block.lines {
+            label.push_str(&format!("    {}\\l", escape(line)));
+        }
+        label.push_str(&format!("out: {}\\l", temps(&block.live_out)));
+        dot.push_str(&format!("    b{} [label=\"{}\"];\n", index, label));
+    }
+    for (index, block) in blocks.iter().enumerate() {
+        // A conditional jump can also fall through to one of its labels.
+        let successors: BTreeSet<_> = block.successors.iter().collect();
+        for &successor in successors {
+            let style = if positions[successor] == positions[index] + 1 {
+                ""
+            } else {
+                ", style=dashed"
+            };
+            dot.push_str(&format!(
+                "    b{} -> b{} [label=\"{} → {}\"{}];\n",
+                index, successor, positions[index], positions[successor], style
+            ));
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
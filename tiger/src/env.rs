@@ -5,6 +5,7 @@ use escape::EscapeEnv;
 use frame::Frame;
 use gen;
 use gen::{Access, Level};
+use position::Pos;
 use symbol::{Strings, Symbol, Symbols};
 use temp::Label;
 use types::Type;
@@ -12,6 +13,8 @@ use types::Type;
 #[derive(Clone)]
 pub enum Entry<F: Clone + Frame> {
     Fun {
+        /// Exported functions can be called from the other exported functions.
+        exported: bool,
         external: bool,
         label: Label,
         level: Level<F>,
@@ -27,6 +30,7 @@ pub enum Entry<F: Clone + Frame> {
 
 pub struct Env<F: Clone + Frame> {
     escape_env: EscapeEnv,
+    exception_env: Symbols<Label>,
     type_env: Symbols<Type>,
     var_env: Symbols<Entry<F>>,
 }
@@ -39,9 +43,16 @@ impl<F: Clone + Frame> Env<F> {
         let string_symbol = type_env.symbol("string");
         type_env.enter(string_symbol, Type::String);
 
+        let mut exception_env = Symbols::new(Rc::clone(strings));
+        for (name, label) in external_exceptions() {
+            let symbol = exception_env.symbol(name);
+            exception_env.enter(symbol, Label::with_name(label));
+        }
+
         let var_env = Symbols::new(Rc::clone(strings));
         let mut env = Self {
             escape_env,
+            exception_env,
             type_env,
             var_env,
         };
@@ -56,6 +67,7 @@ impl<F: Clone + Frame> Env<F> {
     fn add_function(&mut self, name: &str, parameters: Vec<Type>, result: Type) {
         let symbol = self.var_env.symbol(name);
         let entry = Entry::Fun {
+            exported: false,
             external: true,
             label: Label::with_name(name),
             level: gen::outermost(), // FIXME: Might want to create a new level.
@@ -66,15 +78,21 @@ impl<F: Clone + Frame> Env<F> {
     }
 
     pub fn begin_scope(&mut self) {
+        self.exception_env.begin_scope();
         self.type_env.begin_scope();
         self.var_env.begin_scope();
     }
 
     pub fn end_scope(&mut self) {
+        self.exception_env.end_scope();
         self.type_env.end_scope();
         self.var_env.end_scope();
     }
 
+    pub fn enter_exception(&mut self, symbol: Symbol, label: Label) {
+        self.exception_env.enter(symbol, label);
+    }
+
     pub fn enter_type(&mut self, symbol: Symbol, typ: Type) {
         self.type_env.enter(symbol, typ);
     }
@@ -83,8 +101,12 @@ impl<F: Clone + Frame> Env<F> {
         self.var_env.enter(symbol, data);
     }
 
-    pub fn look_escape(&self, symbol: Symbol) -> bool {
-        self.escape_env.look(symbol).expect("escape").escape
+    pub fn look_escape(&self, pos: Pos) -> bool {
+        self.escape_env.escapes(pos)
+    }
+
+    pub fn look_exception(&self, symbol: Symbol) -> Option<&Label> {
+        self.exception_env.look(symbol)
     }
 
     pub fn look_type(&self, symbol: Symbol) -> Option<&Type> {
@@ -126,11 +148,33 @@ pub fn external_functions() -> HashMap<&'static str, (Vec<Type>, Type)> {
         (vec![Type::String, Type::Int, Type::Int], Type::String),
     );
     functions.insert("concat", (vec![Type::String, Type::String], Type::String));
-    functions.insert("not", (vec![Type::Int], Type::Int));
     functions.insert("exit", (vec![Type::Int], Type::Unit));
     functions.insert("stringEqual", (vec![Type::String, Type::String], Type::Int));
 
     functions.insert("malloc", (vec![Type::Int], Type::Int));
     functions.insert("initArray", (vec![Type::Int, Type::Int], Type::Int));
+
+    functions.insert("pushHandler", (vec![], Type::Int));
+    functions.insert("popHandler", (vec![], Type::Unit));
+    functions.insert("_setjmp", (vec![Type::Int], Type::Int));
+    functions.insert("currentException", (vec![], Type::Int));
+    functions.insert(
+        "raiseException",
+        (
+            vec![Type::Int, Type::String, Type::Int, Type::Int],
+            Type::Unit,
+        ),
+    );
     functions
 }
+
+/// Exceptions raised by the runtime, mapped to the runtime symbol identifying them.
+pub fn external_exceptions() -> HashMap<&'static str, &'static str> {
+    let mut exceptions = HashMap::new();
+    exceptions.insert("DivisionByZero", "exceptionDivisionByZero");
+    exceptions.insert("IndexOutOfBounds", "exceptionIndexOutOfBounds");
+    exceptions.insert("InvalidCharacter", "exceptionInvalidCharacter");
+    exceptions.insert("NilAccess", "exceptionNilAccess");
+    exceptions.insert("Overflow", "exceptionOverflow");
+    exceptions
+}
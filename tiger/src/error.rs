@@ -21,6 +21,10 @@ pub enum Error {
         pos: Pos,
         typ: Type,
     },
+    CapturedByExport {
+        ident: String,
+        pos: Pos,
+    },
     Cycle {
         pos: Pos,
     },
@@ -29,6 +33,9 @@ pub enum Error {
         pos: Pos,
     },
     Eof,
+    ExportNotTopLevel {
+        pos: Pos,
+    },
     ExtraField {
         ident: String,
         pos: Pos,
@@ -38,6 +45,9 @@ pub enum Error {
         escape: String,
         pos: Pos,
     },
+    InvalidUtf8 {
+        pos: Pos,
+    },
     MissingField {
         ident: String,
         pos: Pos,
@@ -49,6 +59,10 @@ pub enum Error {
         pos: Pos,
         typ: Type,
     },
+    NotExported {
+        ident: String,
+        pos: Pos,
+    },
     RecordType {
         pos: Pos,
     },
@@ -116,6 +130,15 @@ impl Error {
                 );
                 pos.show(symbols, terminal)
             }
+            CapturedByExport { ref ident, pos } => {
+                eprintln!(
+                    "Exported function cannot use the variable `{}` of the main program{}",
+                    ident,
+                    terminal.end_bold()
+                );
+                pos.show(symbols, terminal);
+                highlight_line(pos, symbols, terminal)?;
+            }
             Cycle { pos } => {
                 eprintln!("Type cycle detected:{}", terminal.end_bold());
                 pos.show(symbols, terminal);
@@ -126,6 +149,14 @@ impl Error {
                 highlight_line(pos, symbols, terminal)?;
             }
             Eof => eprintln!("end of file"),
+            ExportNotTopLevel { pos } => {
+                eprintln!(
+                    "Only the functions declared at the top level can be exported{}",
+                    terminal.end_bold()
+                );
+                pos.show(symbols, terminal);
+                highlight_line(pos, symbols, terminal)?;
+            }
             ExtraField {
                 ref ident,
                 pos,
@@ -144,6 +175,11 @@ impl Error {
                 pos.show(symbols, terminal);
                 highlight_line(pos, symbols, terminal)?;
             }
+            InvalidUtf8 { pos } => {
+                eprintln!("Invalid UTF-8 in string literal{}", terminal.end_bold());
+                pos.show(symbols, terminal);
+                highlight_line(pos, symbols, terminal)?;
+            }
             MissingField {
                 ref ident,
                 pos,
@@ -168,6 +204,15 @@ impl Error {
                 pos.show(symbols, terminal);
                 highlight_line(pos, symbols, terminal)?;
             }
+            NotExported { ref ident, pos } => {
+                eprintln!(
+                    "Exported function cannot call `{}`, which is not exported{}",
+                    ident,
+                    terminal.end_bold()
+                );
+                pos.show(symbols, terminal);
+                highlight_line(pos, symbols, terminal)?;
+            }
             Error::RecordType { pos } => {
                 eprintln!("Expecting type when value is nil{}", terminal.end_bold());
                 pos.show(symbols, terminal);
@@ -308,6 +353,12 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<String> for Error {
+    fn from(error: String) -> Self {
+        Msg(error)
+    }
+}
+
 impl<'a> From<&'a Error> for Error {
     fn from(error: &'a Error) -> Self {
         error.clone()
@@ -1,29 +1,82 @@
+//! **逃逸分析**。若变量在比声明它的函数更深的嵌套函数中被使用，或者在`try`的主体中被赋值，
+//! 则它逃逸，必须保存在栈帧中。
+//!
+//! 嵌套函数通过静态链访问外层函数的变量：从使用处到声明处，沿途每个函数的静态链都要从它的
+//! 栈帧中读取，所以这些函数的静态链也逃逸。其他函数的静态链可以保存在寄存器中。
+
+use std::collections::HashSet;
 use std::rc::Rc;
 
 use ast::{
     Declaration, DeclarationWithPos, Expr, ExprWithPos, FuncDeclaration, Operator, Var, VarWithPos,
 };
-use position::WithPos;
-use symbol::{Strings, Symbols};
+use position::{Pos, WithPos};
+use symbol::{Strings, Symbol, Symbols};
 
-pub struct DepthEscape {
+struct DepthEscape {
     depth: u32,
-    pub escape: bool,
+    /// Position of the declaration, identifying it in the result.
+    pos: Pos,
+    /// Number of try bodies around the declaration.
+    try_depth: u32,
+}
+
+/// The declarations of the variables, parameters and static links (by function declaration) that
+/// escape.
+pub struct EscapeEnv {
+    escapes: HashSet<(Symbol, u64)>,
 }
 
-pub type EscapeEnv = Symbols<DepthEscape>;
+impl EscapeEnv {
+    pub fn escapes(&self, pos: Pos) -> bool {
+        self.escapes.contains(&(pos.file, pos.byte))
+    }
+}
 
 struct EscapeFinder {
-    env: EscapeEnv,
+    env: Symbols<DepthEscape>,
+    escapes: HashSet<(Symbol, u64)>,
+    /// The declarations of the functions around the expression: the body of the function at
+    /// index `i` is at depth `i + 1`.
+    functions: Vec<Pos>,
+    try_depth: u32,
 }
 
 impl EscapeFinder {
     fn new(strings: Rc<Strings>) -> Self {
         Self {
             env: Symbols::new(strings),
+            escapes: HashSet::new(),
+            functions: vec![],
+            try_depth: 0,
         }
     }
 
+    fn escape(&mut self, pos: Pos) {
+        self.escapes.insert((pos.file, pos.byte));
+    }
+
+    /// Reaching the frame at `declaration_depth` from `depth` reads the static links of the
+    /// functions in between.
+    fn escape_static_links(&mut self, declaration_depth: u32, depth: u32) {
+        for index in declaration_depth as usize..(depth as usize).saturating_sub(1) {
+            let pos = self.functions[index];
+            self.escape(pos);
+        }
+    }
+
+    fn enter(&mut self, symbol: Symbol, pos: Pos, depth: u32) {
+        let try_depth = self.try_depth;
+        self.env.enter(
+            symbol,
+            DepthEscape {
+                depth,
+                pos,
+                try_depth,
+            },
+        );
+    }
+
     fn visit_binary_op(&mut self, left: &ExprWithPos, right: &ExprWithPos, depth: u32) {
         self.visit_exp(left, depth);
         self.visit_exp(right, depth);
@@ -31,7 +84,15 @@ impl EscapeFinder {
 
     fn visit_dec(&mut self, declaration: &DeclarationWithPos, depth: u32) {
         match declaration.node {
+            Declaration::Exception { .. } | Declaration::ExternFunction(_) => (),
             Declaration::Function(ref declarations) => {
+                for &WithPos {
+                    node: FuncDeclaration { name, .. },
+                    pos,
+                } in declarations
+                {
+                    self.enter(name, pos, depth);
+                }
                 for &WithPos {
                     node:
                         FuncDeclaration {
@@ -39,31 +100,23 @@ impl EscapeFinder {
                             ref body,
                             ..
                         },
-                    ..
+                    pos,
                 } in declarations
                 {
+                    self.env.begin_scope();
+                    self.functions.push(pos);
                     for param in params {
-                        self.env.enter(
-                            param.node.name,
-                            DepthEscape {
-                                depth,
-                                escape: false,
-                            },
-                        );
+                        self.enter(param.node.name, param.pos, depth + 1);
                     }
                     self.visit_exp(body, depth + 1);
+                    self.functions.pop();
+                    self.env.end_scope();
                 }
             }
             Declaration::Type(_) => (),
             Declaration::VariableDeclaration { ref init, name, .. } => {
-                self.visit_exp(init, depth + 1);
-                self.env.enter(
-                    name,
-                    DepthEscape {
-                        depth,
-                        escape: false,
-                    },
-                );
+                self.visit_exp(init, depth);
+                self.enter(name, declaration.pos, depth);
             }
         }
     }
@@ -77,11 +130,28 @@ impl EscapeFinder {
                 self.visit_exp(init, depth);
             }
             Expr::Assign { ref expr, ref var } => {
+                // After a raise, the handler sees the variables as they were when setjmp was
+                // called: a variable assigned in a try body must live in memory for the handler
+                // to see the new value.
+                if let Var::Simple { ref ident } = var.node {
+                    let declaration = self
+                        .env
+                        .look(ident.node)
+                        .filter(|var| self.try_depth > var.try_depth)
+                        .map(|var| var.pos);
+                    if let Some(pos) = declaration {
+                        self.escape(pos);
+                    }
+                }
                 self.visit_var(var, depth);
                 self.visit_exp(expr, depth);
             }
             Expr::Break => {}
-            Expr::Call { ref args, .. } => {
+            Expr::Call { ref args, function } => {
+                let declaration_depth = self.env.look(function).map(|function| function.depth);
+                if let Some(declaration_depth) = declaration_depth {
+                    self.escape_static_links(declaration_depth, depth);
+                }
                 for arg in args {
                     self.visit_exp(arg, depth);
                 }
@@ -102,10 +172,12 @@ impl EscapeFinder {
                 ref body,
                 ref declarations,
             } => {
+                self.env.begin_scope();
                 for declaration in declarations {
                     self.visit_dec(declaration, depth);
                 }
                 self.visit_exp(body, depth);
+                self.env.end_scope();
             }
             Expr::Nil => (),
             Expr::Oper {
@@ -187,6 +259,51 @@ impl EscapeFinder {
                         ..
                     },
                 ref right,
+            }
+            | Expr::Oper {
+                ref left,
+                oper:
+                    WithPos {
+                        node: Operator::Modulo,
+                        ..
+                    },
+                ref right,
+            }
+            | Expr::Oper {
+                ref left,
+                oper:
+                    WithPos {
+                        node: Operator::ShiftLeft,
+                        ..
+                    },
+                ref right,
+            }
+            | Expr::Oper {
+                ref left,
+                oper:
+                    WithPos {
+                        node: Operator::ShiftRight,
+                        ..
+                    },
+                ref right,
+            }
+            | Expr::Oper {
+                ref left,
+                oper:
+                    WithPos {
+                        node: Operator::UnsignedShiftRight,
+                        ..
+                    },
+                ref right,
+            }
+            | Expr::Oper {
+                ref left,
+                oper:
+                    WithPos {
+                        node: Operator::Xor,
+                        ..
+                    },
+                ref right,
             } => self.visit_binary_op(left, right, depth),
             Expr::Oper {
                 ref left,
@@ -209,6 +326,7 @@ impl EscapeFinder {
                 self.visit_exp(left, depth);
                 self.visit_exp(right, depth);
             }
+            Expr::Raise { .. } => (),
             Expr::Record { ref fields, .. } => {
                 for field in fields {
                     self.visit_exp(&field.node.expr, depth);
@@ -223,6 +341,18 @@ impl EscapeFinder {
                 }
             }
             Expr::Str { .. } => (),
+            Expr::Try {
+                ref body,
+                ref handlers,
+            } => {
+                self.try_depth += 1;
+                self.visit_exp(body, depth);
+                self.try_depth -= 1;
+                for handler in handlers {
+                    self.visit_exp(&handler.node.body, depth);
+                }
+            }
+            Expr::Unary { ref expr, .. } => self.visit_exp(expr, depth),
             Expr::Variable(ref var) => self.visit_var(var, depth),
             Expr::While { ref body, ref test } => {
                 self.visit_exp(test, depth);
@@ -233,10 +363,13 @@ impl EscapeFinder {
 
     fn visit_var(&mut self, var: &VarWithPos, depth: u32) {
         match var.node {
-            Var::Field { ref ident, .. } | Var::Simple { ref ident } => {
-                if let Some(ref mut var) = self.env.look_mut(ident.node) {
-                    if depth > var.depth {
-                        var.escape = true;
+            Var::Field { ref this, .. } => self.visit_var(this, depth),
+            Var::Simple { ref ident } => {
+                let declaration = self.env.look(ident.node).map(|var| (var.depth, var.pos));
+                if let Some((declaration_depth, pos)) = declaration {
+                    if depth > declaration_depth {
+                        self.escape(pos);
+                        self.escape_static_links(declaration_depth, depth);
                     }
                 }
             }
@@ -251,5 +384,7 @@ impl EscapeFinder {
 pub fn find_escapes(exp: &ExprWithPos, strings: Rc<Strings>) -> EscapeEnv {
     let mut finder = EscapeFinder::new(strings);
     finder.visit_exp(exp, 0);
-    finder.env
+    EscapeEnv {
+        escapes: finder.escapes,
+    }
 }
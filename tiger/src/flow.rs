@@ -1,7 +1,9 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use asm::Instruction;
+use frame::Frame;
 use graph::{self, Entry, Graph};
+use opt::DominatorTree;
 use temp::{Label, Temp};
 
 #[derive(Debug)]
@@ -9,16 +11,68 @@ pub struct Node {
     pub defines: HashSet<Temp>,
     pub uses: HashSet<Temp>,
     pub is_move: bool,
+    /// The instruction calls `_setjmp`, which returns a second time when an exception is raised.
+    pub is_setjmp: bool,
 }
 
 pub struct FlowGraph {
     control_flow_graph: Graph<Node>,
+    /// Node of each reachable instruction, by index in the instruction sequence.
+    instruction_nodes: HashMap<usize, Entry>,
 }
 
 impl FlowGraph {
     pub fn nodes(&self) -> &[graph::Node<Node>] {
         self.control_flow_graph.nodes()
     }
+
+    /// The node of the instruction at `index`, if it is reachable.
+    pub fn instruction_node(&self, index: usize) -> Option<Entry> {
+        self.instruction_nodes.get(&index).cloned()
+    }
+
+    /// The loop nesting depth of each node: the number of natural loops containing it. A back edge
+    /// goes to a node dominating its source; the nodes reaching the source without going through
+    /// this header form the loop.
+    pub fn loop_depths(&self) -> Vec<usize> {
+        let nodes = self.nodes();
+        let mut depths = vec![0; nodes.len()];
+        if nodes.is_empty() {
+            return depths;
+        }
+        let successors: Vec<Vec<usize>> = nodes
+            .iter()
+            .map(|node| node.successors().iter().map(Entry::index).collect())
+            .collect();
+        let predecessors: Vec<Vec<usize>> = nodes
+            .iter()
+            .map(|node| node.predecessors().iter().map(Entry::index).collect())
+            .collect();
+        // The first instruction is the entry.
+        let tree = DominatorTree::new(0, &successors, &predecessors);
+        let mut loops: BTreeMap<usize, HashSet<usize>> = BTreeMap::new();
+        for (index, node_successors) in successors.iter().enumerate() {
+            for &header in node_successors {
+                if !tree.dominates(header, index) {
+                    continue;
+                }
+                let body = loops.entry(header).or_default();
+                body.insert(header);
+                let mut stack = vec![index];
+                while let Some(node) = stack.pop() {
+                    if body.insert(node) {
+                        stack.extend(&predecessors[node]);
+                    }
+                }
+            }
+        }
+        for body in loops.values() {
+            for &node in body {
+                depths[node] += 1;
+            }
+        }
+        depths
+    }
 }
 
 struct GraphBuilder<'a> {
@@ -26,75 +80,92 @@ struct GraphBuilder<'a> {
     control_flow_graph: &'a mut Graph<Node>,
     label_map: HashMap<Label, usize>,
     visited: HashMap<usize, Entry>,
+    is_unconditional_jump: fn(&str) -> bool,
 }
 
 impl<'a> GraphBuilder<'a> {
     /// # 构建控制流图
+    /// 用一个栈代替递归按深度优先的顺序遍历指令，以免很长的函数耗尽调用栈。栈中的每一项是
+    /// 一条指令的索引和它的前驱。
     /// 1. 如果当前索引已经被访问过，那么创建一条 `predecessor -> current_index` 的边。
     /// 2. 获取当前索引对应的指令。
-    /// 3. 判断指令是否是`Move`指令。如果是，`is_move`置为`true`。
+    /// 3. 判断指令是否是`Move`指令。如果是，`is_move`置为`true`。如果指令调用`_setjmp`，
+    ///    `is_setjmp`置为`true`。
     /// 4. 计算指令的`defines`集合。指令的`destination`就是`defines`集合。
     /// 5. 计算指令的`uses`集合。指令的`source`就是`uses`集合。
     /// 6. 将节点添加到控制流图中。
     /// 7. 将节点标记为已访问。
     /// 8. 如果前驱`predecessor`不为空，在控制流图中添加一条**predecessor -> 当前节点**的边。
-    fn build(&mut self, current_index: usize, predecessor: Option<Entry>) {
-        if let Some(&entry) = self.visited.get(&current_index) {
+    /// 9. 将跳转标签和下一条指令压入栈中，当前节点是它们的前驱。
+    fn build(&mut self) {
+        let mut stack = vec![(0, None)];
+        while let Some((current_index, predecessor)) = stack.pop() {
+            if let Some(&entry) = self.visited.get(&current_index) {
+                if let Some(predecessor) = predecessor {
+                    self.control_flow_graph.link(predecessor, entry);
+                }
+                continue;
+            }
+
+            let instruction = &self.instructions[current_index];
+            let is_move = matches!(instruction, Instruction::Move { .. });
+            let is_setjmp = matches!(instruction, Instruction::Operation { assembly, .. }
+                if assembly.ends_with(" _setjmp"));
+            let defines = match instruction {
+                Instruction::Move { destination, .. }
+                | Instruction::Operation { destination, .. } => {
+                    destination.iter().cloned().collect()
+                }
+                _ => HashSet::new(),
+            };
+            let uses = match instruction {
+                Instruction::Move { source, .. } | Instruction::Operation { source, .. } => {
+                    source.iter().cloned().collect()
+                }
+                _ => HashSet::new(),
+            };
+            let node = Node {
+                defines,
+                uses,
+                is_move,
+                is_setjmp,
+            };
+            let entry = self.control_flow_graph.insert(node);
+            self.visited.insert(current_index, entry);
             if let Some(predecessor) = predecessor {
                 self.control_flow_graph.link(predecessor, entry);
             }
-            return;
-        }
 
-        let instruction = &self.instructions[current_index];
-        let is_move = match instruction {
-            Instruction::Move { .. } => true,
-            _ => false,
-        };
-        let defines = match instruction {
-            Instruction::Move { destination, .. } | Instruction::Operation { destination, .. } => {
-                destination.iter().cloned().collect()
-            }
-            _ => HashSet::new(),
-        };
-        let uses = match instruction {
-            Instruction::Move { source, .. } | Instruction::Operation { source, .. } => {
-                source.iter().cloned().collect()
-            }
-            _ => HashSet::new(),
-        };
-        let node = Node {
-            defines,
-            uses,
-            is_move,
-        };
-        let entry = self.control_flow_graph.insert(node);
-        self.visited.insert(current_index, entry);
-        if let Some(predecessor) = predecessor {
-            self.control_flow_graph.link(predecessor, entry);
-        }
-        if let Instruction::Operation {
-            ref assembly,
-            ref jump,
-            ..
-        } = instruction
-        {
-            // 将当前节点作为前驱，从跳转标签继续遍历控制流图。
-            if let Some(ref jump) = *jump {
-                for jump in jump {
-                    self.build(self.label_map[jump], Some(entry));
+            let mut successors = vec![];
+            let mut falls_through = true;
+            if let Instruction::Operation {
+                ref assembly,
+                ref jump,
+                ..
+            } = instruction
+            {
+                // 将当前节点作为前驱，从跳转标签继续遍历控制流图。
+                if let Some(ref jump) = *jump {
+                    for jump in jump {
+                        successors.push(self.label_map[jump]);
+                    }
                 }
-            }
 
-            // 如果是无条件跳转指令，直接返回。
-            if assembly.starts_with("jmp ") {
-                return; // Do not fallthrough for unconditional jump.
+                // 如果是无条件跳转指令，不会顺序执行到下一条指令。
+                if (self.is_unconditional_jump)(assembly) {
+                    falls_through = false; // Do not fallthrough for unconditional jump.
+                }
             }
-        }
 
-        // 从下一条指令开始继续构建控制流图，当前指令是下一条指令的前驱。
-        if current_index + 1 < self.instructions.len() {
-            self.build(current_index + 1, Some(entry));
+            // 从下一条指令开始继续构建控制流图，当前指令是下一条指令的前驱。
+            if falls_through && current_index + 1 < self.instructions.len() {
+                successors.push(current_index + 1);
+            }
+            // The first successor is on the top of the stack: it is visited first, like in the
+            // recursive traversal.
+            for successor in successors.into_iter().rev() {
+                stack.push((successor, Some(entry)));
+            }
         }
     }
 }
@@ -102,7 +173,7 @@ impl<'a> GraphBuilder<'a> {
 /// # 根据指令序列生成控制流图
 /// 1. 遍历一遍指令序列，建立一个`(标签，标签索引)`的`K/V`键值对。
 /// 2. 构建控制流图。
-pub fn instructions_to_graph(instructions: &[Instruction]) -> FlowGraph {
+pub fn instructions_to_graph<F: Frame>(instructions: &[Instruction]) -> FlowGraph {
     let mut label_map = HashMap::new();
 
     for (index, instruction) in instructions.iter().enumerate() {
@@ -117,8 +188,13 @@ pub fn instructions_to_graph(instructions: &[Instruction]) -> FlowGraph {
         control_flow_graph: &mut control_flow_graph,
         label_map,
         visited: HashMap::new(),
+        is_unconditional_jump: F::is_unconditional_jump,
     };
-    graph_builder.build(0, None);
+    graph_builder.build();
+    let instruction_nodes = graph_builder.visited;
 
-    FlowGraph { control_flow_graph }
+    FlowGraph {
+        control_flow_graph,
+        instruction_nodes,
+    }
 }
@@ -0,0 +1,438 @@
+use std::collections::{HashMap, HashSet};
+
+use super::{gnu_prelude, Frame};
+use aarch64_gen::{immediate_moves, Gen};
+use asm::{Instruction, Subroutine};
+use ir::BinOp::Plus;
+use ir::Exp::{self, BinOp, Call, Const, Mem, Name};
+use ir::Statement;
+use temp::{Label, Temp};
+
+use self::Access::{InFrame, InReg};
+
+const POINTER_SIZE: i64 = 8;
+
+/// The frame of a function following the AAPCS64: x29 is the frame pointer, saved with the return
+/// address of x30 at the top of the frame.
+#[derive(Clone)]
+pub struct Aarch64 {
+    formals: Vec<Access>, // Representation of parameters.
+    name: Label,
+    pointer: i64,
+}
+
+impl PartialEq for Aarch64 {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Access {
+    InFrame(i64),
+    InReg(Temp),
+}
+
+pub const X0: Temp = Temp { num: 1 };
+pub const X1: Temp = Temp { num: 2 };
+pub const X2: Temp = Temp { num: 3 };
+pub const X3: Temp = Temp { num: 4 };
+pub const X4: Temp = Temp { num: 5 };
+pub const X5: Temp = Temp { num: 6 };
+pub const X6: Temp = Temp { num: 7 };
+pub const X7: Temp = Temp { num: 8 };
+pub const X8: Temp = Temp { num: 9 };
+pub const X9: Temp = Temp { num: 10 };
+pub const X10: Temp = Temp { num: 11 };
+pub const X11: Temp = Temp { num: 12 };
+pub const X12: Temp = Temp { num: 13 };
+pub const X13: Temp = Temp { num: 14 };
+pub const X14: Temp = Temp { num: 15 };
+pub const X15: Temp = Temp { num: 16 };
+pub const X16: Temp = Temp { num: 17 };
+pub const X19: Temp = Temp { num: 20 };
+pub const X20: Temp = Temp { num: 21 };
+pub const X21: Temp = Temp { num: 22 };
+pub const X22: Temp = Temp { num: 23 };
+pub const X23: Temp = Temp { num: 24 };
+pub const X24: Temp = Temp { num: 25 };
+pub const X25: Temp = Temp { num: 26 };
+pub const X26: Temp = Temp { num: 27 };
+pub const X27: Temp = Temp { num: 28 };
+pub const X28: Temp = Temp { num: 29 };
+pub const X29: Temp = Temp { num: 30 };
+pub const SP: Temp = Temp { num: 32 };
+
+const REGISTER_NAMES: [&str; 32] = [
+    "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11", "x12", "x13", "x14",
+    "x15", "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26", "x27",
+    "x28", "x29", "x30", "sp",
+];
+
+impl Aarch64 {
+    fn callee_saved_registers() -> Vec<Temp> {
+        vec![X19, X20, X21, X22, X23, X24, X25, X26, X27, X28, X29]
+    }
+
+    fn special_registers() -> Vec<Temp> {
+        vec![X0, SP]
+    }
+
+    /// The registers which a function can overwrite. x16 and x17 are the scratch registers of the
+    /// instruction selector and of the linker veneers, x18 is reserved for the platform, and x30
+    /// only holds the return address: they are never allocated.
+    fn caller_saved_registers() -> Vec<Temp> {
+        vec![
+            X0, X1, X2, X3, X4, X5, X6, X7, X8, X9, X10, X11, X12, X13, X14, X15,
+        ]
+    }
+
+    /// The registers overwritten by a call, to a Tiger function, to the runtime or to a C function.
+    pub fn calldefs() -> Vec<Temp> {
+        Self::caller_saved_registers()
+    }
+
+    pub fn calldefs_of(function: &Label) -> Vec<Temp> {
+        let mut registers = Self::calldefs();
+        if *function == Label::with_name("_setjmp") {
+            // _setjmp returns a second time when an exception is raised, after any register could
+            // have been overwritten, so no value can be kept in a register across this call.
+            registers.extend(
+                Self::callee_saved_registers()
+                    .into_iter()
+                    .filter(|&register| register != X29),
+            );
+        }
+        registers
+    }
+
+    /// The size of the arguments passed on the stack by a call, rounded up so that sp stays
+    /// aligned on 16 bytes. The caller releases this space after the call.
+    pub fn stack_arguments_size(argument_count: usize) -> i64 {
+        let stack_argument_count = argument_count.saturating_sub(Self::arg_registers().len());
+        align(stack_argument_count as i64 * POINTER_SIZE)
+    }
+}
+
+impl Frame for Aarch64 {
+    type Access = Access;
+
+    const WORD_SIZE: i64 = 8;
+
+    const MOVE: &'static str = "mov 'd0, 's0";
+
+    fn registers() -> Vec<Temp> {
+        let mut registers = Self::arg_registers();
+        registers.push(Self::return_value());
+        registers.extend(Self::callee_saved_registers());
+        registers.extend(Self::special_registers());
+        registers.extend(Self::caller_saved_registers());
+        // The argument registers and x0 are also in the other lists.
+        let mut seen = HashSet::new();
+        registers.retain(|&register| seen.insert(register));
+        registers
+    }
+
+    fn register_count() -> usize {
+        Self::registers().len() - [SP, X29].len()
+    }
+
+    fn temp_map() -> HashMap<Temp, &'static str> {
+        REGISTER_NAMES
+            .iter()
+            .enumerate()
+            .map(|(index, &name)| {
+                (
+                    Temp {
+                        num: index as u32 + 1,
+                    },
+                    name,
+                )
+            })
+            .collect()
+    }
+
+    fn special_name(temp: Temp) -> Option<&'static str> {
+        if temp.num >= 1 && temp.num as usize <= REGISTER_NAMES.len() {
+            Some(REGISTER_NAMES[temp.num as usize - 1])
+        } else {
+            None
+        }
+    }
+
+    fn arg_registers() -> Vec<Temp> {
+        vec![X0, X1, X2, X3, X4, X5, X6, X7]
+    }
+
+    /// The instruction selector never names x9 and x10, which are only overwritten by the calls.
+    fn scratch_registers() -> [Temp; 2] {
+        [X9, X10]
+    }
+
+    fn fp() -> Temp {
+        X29
+    }
+
+    fn return_value() -> Temp {
+        X0
+    }
+
+    fn new(name: Label, formals: Vec<bool>) -> Self {
+        let mut frame = Aarch64 {
+            formals: vec![],
+            name,
+            pointer: 0,
+        };
+        let formals = formals
+            .iter()
+            .map(|&escape| frame.alloc_local(escape))
+            .collect();
+        frame.formals = formals;
+        frame
+    }
+
+    fn name(&self) -> Label {
+        self.name.clone()
+    }
+
+    fn formals(&self) -> &[Self::Access] {
+        &self.formals
+    }
+
+    fn alloc_local(&mut self, escape: bool) -> Self::Access {
+        if escape {
+            self.pointer -= POINTER_SIZE;
+            InFrame(self.pointer)
+        } else {
+            InReg(Temp::new())
+        }
+    }
+
+    fn locals_size(&self) -> i64 {
+        -self.pointer
+    }
+
+    fn exp(&self, access: Self::Access, stack_frame: Exp) -> Exp {
+        match access {
+            InFrame(pos) => Mem(Box::new(BinOp {
+                op: Plus,
+                left: Box::new(stack_frame),
+                right: Box::new(Const(pos)),
+            })),
+            InReg(reg) => Exp::Temp(reg),
+        }
+    }
+
+    fn external_call(name: &str, arguments: Vec<Exp>) -> Exp {
+        Call(Box::new(Name(Label::with_name(name))), arguments)
+    }
+
+    fn proc_entry_exit2(&self, mut instructions: Vec<Instruction>) -> Vec<Instruction> {
+        for instruction in &mut instructions {
+            if let Instruction::Operation {
+                ref mut source,
+                jump: Some(ref labels),
+                ..
+            } = *instruction
+            {
+                if labels.is_empty() {
+                    // A tail call releases the frame, which needs x29, before jumping.
+                    source.push(X29);
+                    source.push(SP);
+                }
+            }
+        }
+
+        let source = Self::special_registers();
+        let instruction = Instruction::Operation {
+            assembly: String::new(),
+            source,
+            destination: vec![],
+            jump: Some(vec![]),
+        };
+        instructions.push(instruction);
+
+        for instruction in &mut instructions {
+            match *instruction {
+                Instruction::Label { .. } => (),
+                Instruction::Move {
+                    ref mut destination,
+                    ..
+                }
+                | Instruction::Operation {
+                    ref mut destination,
+                    ..
+                } => {
+                    destination.push(X29);
+                    destination.push(SP);
+                    break;
+                }
+            }
+        }
+
+        for instruction in instructions.iter_mut().rev() {
+            match *instruction {
+                Instruction::Label { .. } => (),
+                Instruction::Move { ref mut source, .. }
+                | Instruction::Operation { ref mut source, .. } => {
+                    source.push(X29);
+                    source.push(SP);
+                    break;
+                }
+            }
+        }
+
+        instructions
+    }
+
+    fn proc_entry_exit3(&self, body: Vec<Instruction>) -> Subroutine {
+        // Only the callee-saved registers used by the allocator need to be preserved.
+        let saved_registers: Vec<_> = Self::callee_saved_registers()
+            .into_iter()
+            .filter(|&register| {
+                register != X29 && body.iter().any(|instruction| writes(instruction, register))
+            })
+            .collect();
+        let locals_size = self.locals_size();
+        // The last instruction is the sink of proc_entry_exit2, and the tail calls only need x29 to
+        // release the frame.
+        let uses_frame_pointer = body[..body.len().saturating_sub(1)]
+            .iter()
+            .any(|instruction| !is_tail_call(instruction) && reads(instruction, X29));
+        let is_leaf = !body.iter().any(is_call);
+
+        let mut prolog = vec![format!("{}:", self.name())];
+        let mut epilog = vec![];
+        if is_leaf && locals_size == 0 && saved_registers.is_empty() && !uses_frame_pointer {
+            // A leaf function without a frame keeps the stack as it is, and its return address in
+            // x30.
+        } else {
+            // The callee-saved registers are at the bottom of the frame, below the locals.
+            let stack_size = align(locals_size + POINTER_SIZE * saved_registers.len() as i64);
+            prolog.push("stp x29, x30, [sp, #-16]!".to_string());
+            prolog.push("mov x29, sp".to_string());
+            if stack_size != 0 {
+                if stack_size < 0x1000 {
+                    prolog.push(format!("sub sp, sp, #{}", stack_size));
+                } else {
+                    prolog.extend(immediate_moves("x16", stack_size));
+                    prolog.push("sub sp, sp, x16".to_string());
+                }
+            }
+            for (index, register) in saved_registers.iter().enumerate() {
+                let register = register.to_string::<Self>();
+                let offset = POINTER_SIZE * index as i64;
+                prolog.push(format!("str {}, [sp, #{}]", register, offset));
+                epilog.push(format!("ldr {}, [sp, #{}]", register, offset));
+            }
+            epilog.push("mov sp, x29".to_string());
+            epilog.push("ldp x29, x30, [sp], #16".to_string());
+        }
+
+        // A tail call releases the frame before jumping.
+        let mut instructions = vec![];
+        for instruction in body {
+            if is_tail_call(&instruction) {
+                for assembly in &epilog {
+                    instructions.push(Instruction::Operation {
+                        assembly: assembly.clone(),
+                        source: vec![],
+                        destination: vec![],
+                        jump: None,
+                    });
+                }
+            }
+            instructions.push(instruction);
+        }
+        epilog.push("ret".to_string());
+
+        Subroutine {
+            prolog: prolog.join("\n    "),
+            body: instructions,
+            epilog: epilog.join("\n    "),
+        }
+    }
+
+    fn codegen(statement: Statement) -> Vec<Instruction> {
+        let mut generator = Gen::new();
+        generator.munch_statement(statement);
+        generator.get_result()
+    }
+
+    fn is_unconditional_jump(assembly: &str) -> bool {
+        assembly.starts_with("b ")
+    }
+
+    fn is_cheap(instruction: &Instruction, temp: Temp) -> bool {
+        match *instruction {
+            // mov 'd0, #42
+            // sub 'd0, 's0, #8
+            Instruction::Operation {
+                ref assembly,
+                ref destination,
+                ref source,
+                jump: None,
+            } => {
+                destination[..] == [temp]
+                    && (source.is_empty() && assembly.starts_with("mov ")
+                        || source[..] == [X29]
+                            && (assembly.starts_with("add ") || assembly.starts_with("sub ")))
+            }
+            _ => false,
+        }
+    }
+
+    fn prelude(
+        global_symbols: &[String],
+        _external_symbols: &[String],
+        strings: &[(&Label, &str)],
+    ) -> String {
+        gnu_prelude(global_symbols, strings)
+    }
+}
+
+fn align(size: i64) -> i64 {
+    (size + 0xF) & !0xF
+}
+
+fn writes(instruction: &Instruction, register: Temp) -> bool {
+    match *instruction {
+        Instruction::Label { .. } => false,
+        Instruction::Move {
+            ref destination, ..
+        }
+        | Instruction::Operation {
+            ref destination, ..
+        } => destination.contains(&register),
+    }
+}
+
+fn reads(instruction: &Instruction, register: Temp) -> bool {
+    match *instruction {
+        Instruction::Label { .. } => false,
+        Instruction::Move { ref source, .. } | Instruction::Operation { ref source, .. } => {
+            source.contains(&register)
+        }
+    }
+}
+
+fn is_call(instruction: &Instruction) -> bool {
+    match *instruction {
+        Instruction::Operation { ref assembly, .. } => {
+            assembly.starts_with("bl ") || assembly.starts_with("blr ")
+        }
+        _ => false,
+    }
+}
+
+/// The jumps out of the function, except the sink of proc_entry_exit2.
+fn is_tail_call(instruction: &Instruction) -> bool {
+    match *instruction {
+        Instruction::Operation {
+            ref assembly,
+            jump: Some(ref labels),
+            ..
+        } => labels.is_empty() && !assembly.is_empty(),
+        _ => false,
+    }
+}
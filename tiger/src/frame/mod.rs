@@ -4,12 +4,27 @@ use std::fmt::Debug;
 use std::rc::Rc;
 
 use asm::{Instruction, Subroutine};
-use ir::{Exp, Statement};
+use ir::BinOp::Plus;
+use ir::Exp::{self, BinOp, Call, Const, Mem, Name};
+use ir::Statement;
 use temp::{Label, Temp};
+use types::Type;
 
+pub mod aarch64;
+pub mod rv64;
+pub mod wasm32;
 pub mod x86_64;
 
 pub enum Fragment<F: Frame> {
+    /// Global symbol of a function callable from C, with the types of its parameters for the C
+    /// header.
+    Export {
+        name: Label,
+        parameters: Vec<(String, Type)>,
+        result: Type,
+    },
+    /// Symbol defined outside of the program, to be resolved by the linker.
+    Extern(Label),
     Function {
         body: Statement,
         frame: Rc<RefCell<F>>,
@@ -17,16 +32,29 @@ pub enum Fragment<F: Frame> {
     Str(Label, String),
 }
 
+/// The frame of a function and the machine details of a target. The stack arguments are above the
+/// saved frame pointer and the return address, at `fp + 2 * WORD_SIZE`.
 pub trait Frame: Clone {
     type Access: Clone + Debug;
 
     const WORD_SIZE: i64;
 
+    /// The assembly of a move between two registers, removed by the register allocator when both
+    /// get the same register.
+    const MOVE: &'static str;
+
     fn registers() -> Vec<Temp>;
     fn register_count() -> usize;
     fn temp_map() -> HashMap<Temp, &'static str>;
     fn special_name(temp: Temp) -> Option<&'static str>;
 
+    /// The registers of the first arguments of a call, the other ones are passed on the stack.
+    fn arg_registers() -> Vec<Temp>;
+
+    /// Two registers never used by the instructions of `codegen`, in which the allocator spilling
+    /// every temporary loads the spilled operands.
+    fn scratch_registers() -> [Temp; 2];
+
     fn fp() -> Temp;
     fn return_value() -> Temp;
 
@@ -38,11 +66,150 @@ pub trait Frame: Clone {
 
     fn alloc_local(&mut self, escape: bool) -> Self::Access;
 
+    /// Size in bytes of the locals allocated in the frame, including the spilled temporaries.
+    fn locals_size(&self) -> i64;
+
+    /// Address the frame from the stack pointer instead of the frame pointer, when possible. The
+    /// targets which always set up the frame pointer ignore it.
+    fn set_omit_frame_pointer(&mut self, _omit_frame_pointer: bool) {}
+
     fn exp(&self, access: Self::Access, stack_frame: Exp) -> Exp;
 
     fn external_call(name: &str, arguments: Vec<Exp>) -> Exp;
 
-    fn proc_entry_exit1(&mut self, statement: Statement) -> Statement;
+    /// The statement leaving the function to `function`, which returns directly to the caller, or
+    /// None when the arguments passed on the stack do not fit in the ones of this function.
+    fn tail_call(&self, function: Label, arguments: Vec<Exp>) -> Option<Statement> {
+        let arg_registers_len = Self::arg_registers().len();
+        let stack_arguments = arguments.len().saturating_sub(arg_registers_len);
+        if stack_arguments > self.formals().len().saturating_sub(arg_registers_len) {
+            return None;
+        }
+
+        let temps: Vec<_> = arguments.iter().map(|_| Temp::new()).collect();
+        let mut statements: Vec<_> = temps
+            .iter()
+            .zip(arguments)
+            .map(|(&temp, argument)| Statement::Move(Exp::Temp(temp), argument))
+            .collect();
+        // The arguments passed on the stack replace the ones of this function, above its return
+        // address.
+        for (index, &temp) in temps.iter().enumerate().skip(arg_registers_len) {
+            statements.push(Statement::Move(
+                Mem(Box::new(BinOp {
+                    left: Box::new(Exp::Temp(Self::fp())),
+                    op: Plus,
+                    right: Box::new(Const(
+                        Self::WORD_SIZE * (index - arg_registers_len + 2) as i64,
+                    )),
+                })),
+                Exp::Temp(temp),
+            ));
+        }
+        // The jump releases the frame (see proc_entry_exit3): the call only keeps the arguments
+        // passed in registers.
+        let arguments = temps
+            .into_iter()
+            .take(arg_registers_len)
+            .map(Exp::Temp)
+            .collect();
+        statements.push(Statement::Jump(
+            Call(Box::new(Name(function)), arguments),
+            vec![],
+        ));
+
+        let mut statements = statements.into_iter();
+        let first = statements.next().expect("jump");
+        Some(statements.fold(first, |sequence, statement| {
+            Statement::Sequence(Box::new(sequence), Box::new(statement))
+        }))
+    }
+
+    fn proc_entry_exit1(&mut self, mut statement: Statement) -> Statement {
+        // The callee-saved registers used by the function are saved by proc_entry_exit3, once the
+        // register allocation is done.
+        let mut start_statements = vec![];
+
+        let arg_registers = Self::arg_registers();
+        let arg_registers_len = arg_registers.len();
+        for (formal, arg_register) in self.formals().iter().zip(arg_registers) {
+            let destination = self.exp(formal.clone(), Exp::Temp(Self::fp()));
+            start_statements.push(Statement::Move(destination, Exp::Temp(arg_register)));
+        }
+        for (index, formal) in self.formals().iter().skip(arg_registers_len).enumerate() {
+            let destination = self.exp(formal.clone(), Exp::Temp(Self::fp()));
+            start_statements.push(Statement::Move(
+                destination,
+                Mem(Box::new(BinOp {
+                    left: Box::new(Exp::Temp(Self::fp())),
+                    op: Plus,
+                    right: Box::new(Const(Self::WORD_SIZE * (index + 2) as i64)),
+                })),
+            ));
+        }
+
+        for new_statement in start_statements.into_iter().rev() {
+            statement = Statement::Sequence(Box::new(new_statement), Box::new(statement));
+        }
+
+        statement
+    }
+
     fn proc_entry_exit2(&self, instructions: Vec<Instruction>) -> Vec<Instruction>;
     fn proc_entry_exit3(&self, body: Vec<Instruction>) -> Subroutine;
+
+    /// The instructions of a statement, also used for the spills of the register allocators.
+    fn codegen(statement: Statement) -> Vec<Instruction>;
+
+    /// The instructions of the statements of a function.
+    fn select(statements: &[Statement]) -> Vec<Instruction> {
+        statements.iter().cloned().flat_map(Self::codegen).collect()
+    }
+
+    /// Whether the execution never continues with the instruction after this one.
+    fn is_unconditional_jump(assembly: &str) -> bool;
+
+    /// Whether `instruction`, the only definition of `temp`, only computes a constant or an address
+    /// in the frame, so that it can be repeated before each use instead of spilling `temp`.
+    fn is_cheap(instruction: &Instruction, temp: Temp) -> bool;
+
+    /// The start of the assembly file: the global and external symbols, the strings, and the start
+    /// of the code.
+    fn prelude(
+        global_symbols: &[String],
+        external_symbols: &[String],
+        strings: &[(&Label, &str)],
+    ) -> String;
+}
+
+/// The start of an assembly file in the GNU syntax: the external symbols need no declaration.
+pub fn gnu_prelude(global_symbols: &[String], strings: &[(&Label, &str)]) -> String {
+    let mut prelude = String::new();
+    for symbol in global_symbols {
+        prelude.push_str(&format!("    .global {}\n", symbol));
+    }
+    prelude.push_str("\n    .section .rodata\n");
+    for &(label, string) in strings {
+        prelude.push_str(&format!(
+            "{}:\n    .asciz {}\n",
+            label,
+            to_gnu_string(string)
+        ));
+    }
+    prelude.push_str("\n    .text\n    .p2align 2\n");
+    prelude
+}
+
+/// Printable ASCII characters are kept in quotes, other bytes of the UTF-8 encoding are written as
+/// octal escapes.
+fn to_gnu_string(string: &str) -> String {
+    let mut quoted = String::new();
+    for &byte in string.as_bytes() {
+        if (b' '..=b'~').contains(&byte) && byte != b'"' && byte != b'\\' {
+            quoted.push(byte as char);
+        } else {
+            quoted.push_str(&format!("\\{:03o}", byte));
+        }
+    }
+    format!("\"{}\"", quoted)
 }
@@ -0,0 +1,441 @@
+use std::collections::{HashMap, HashSet};
+
+use super::{gnu_prelude, Frame};
+use asm::{Instruction, Subroutine};
+use ir::BinOp::Plus;
+use ir::Exp::{self, BinOp, Call, Const, Mem, Name};
+use ir::Statement;
+use rv64_gen::Gen;
+use temp::{Label, Temp};
+
+use self::Access::{InFrame, InReg};
+
+const POINTER_SIZE: i64 = 8;
+
+/// The frame of a function following the LP64 ABI of RISC-V: s0 is the frame pointer, saved with
+/// the return address of ra at the top of the frame. s0 points to the saved s0 instead of the
+/// stack pointer at the entry, so that the stack arguments are at `s0 + 16` as on the other
+/// targets.
+#[derive(Clone)]
+pub struct Rv64 {
+    formals: Vec<Access>, // Representation of parameters.
+    name: Label,
+    pointer: i64,
+}
+
+impl PartialEq for Rv64 {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Access {
+    InFrame(i64),
+    InReg(Temp),
+}
+
+// The number of a register is its number in the ISA: x0 is the constant zero, which is not a
+// temporary.
+pub const SP: Temp = Temp { num: 2 };
+pub const T0: Temp = Temp { num: 5 };
+pub const T1: Temp = Temp { num: 6 };
+pub const T2: Temp = Temp { num: 7 };
+pub const S0: Temp = Temp { num: 8 };
+pub const S1: Temp = Temp { num: 9 };
+pub const A0: Temp = Temp { num: 10 };
+pub const A1: Temp = Temp { num: 11 };
+pub const A2: Temp = Temp { num: 12 };
+pub const A3: Temp = Temp { num: 13 };
+pub const A4: Temp = Temp { num: 14 };
+pub const A5: Temp = Temp { num: 15 };
+pub const A6: Temp = Temp { num: 16 };
+pub const A7: Temp = Temp { num: 17 };
+pub const S2: Temp = Temp { num: 18 };
+pub const S3: Temp = Temp { num: 19 };
+pub const S4: Temp = Temp { num: 20 };
+pub const S5: Temp = Temp { num: 21 };
+pub const S6: Temp = Temp { num: 22 };
+pub const S7: Temp = Temp { num: 23 };
+pub const S8: Temp = Temp { num: 24 };
+pub const S9: Temp = Temp { num: 25 };
+pub const S10: Temp = Temp { num: 26 };
+pub const S11: Temp = Temp { num: 27 };
+pub const T3: Temp = Temp { num: 28 };
+pub const T4: Temp = Temp { num: 29 };
+pub const T5: Temp = Temp { num: 30 };
+pub const T6: Temp = Temp { num: 31 };
+
+const REGISTER_NAMES: [&str; 31] = [
+    "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5", "a6",
+    "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+impl Rv64 {
+    fn callee_saved_registers() -> Vec<Temp> {
+        vec![S0, S1, S2, S3, S4, S5, S6, S7, S8, S9, S10, S11]
+    }
+
+    fn special_registers() -> Vec<Temp> {
+        vec![A0, SP]
+    }
+
+    /// The registers which a function can overwrite. t6 is the scratch register of the instruction
+    /// selector, gp and tp are reserved for the platform, and ra only holds the return address:
+    /// they are never allocated.
+    fn caller_saved_registers() -> Vec<Temp> {
+        vec![A0, A1, A2, A3, A4, A5, A6, A7, T0, T1, T2, T3, T4, T5]
+    }
+
+    /// The registers overwritten by a call, to a Tiger function, to the runtime or to a C function.
+    pub fn calldefs() -> Vec<Temp> {
+        Self::caller_saved_registers()
+    }
+
+    pub fn calldefs_of(function: &Label) -> Vec<Temp> {
+        let mut registers = Self::calldefs();
+        if *function == Label::with_name("_setjmp") {
+            // _setjmp returns a second time when an exception is raised, after any register could
+            // have been overwritten, so no value can be kept in a register across this call.
+            registers.extend(
+                Self::callee_saved_registers()
+                    .into_iter()
+                    .filter(|&register| register != S0),
+            );
+        }
+        registers
+    }
+
+    /// The size of the arguments passed on the stack by a call, rounded up so that sp stays
+    /// aligned on 16 bytes. The caller releases this space after the call.
+    pub fn stack_arguments_size(argument_count: usize) -> i64 {
+        let stack_argument_count = argument_count.saturating_sub(Self::arg_registers().len());
+        align(stack_argument_count as i64 * POINTER_SIZE)
+    }
+}
+
+impl Frame for Rv64 {
+    type Access = Access;
+
+    const WORD_SIZE: i64 = 8;
+
+    const MOVE: &'static str = "mv 'd0, 's0";
+
+    fn registers() -> Vec<Temp> {
+        let mut registers = Self::arg_registers();
+        registers.push(Self::return_value());
+        registers.extend(Self::callee_saved_registers());
+        registers.extend(Self::special_registers());
+        registers.extend(Self::caller_saved_registers());
+        // The argument registers and a0 are also in the other lists.
+        let mut seen = HashSet::new();
+        registers.retain(|&register| seen.insert(register));
+        registers
+    }
+
+    fn register_count() -> usize {
+        Self::registers().len() - [SP, S0].len()
+    }
+
+    fn temp_map() -> HashMap<Temp, &'static str> {
+        REGISTER_NAMES
+            .iter()
+            .enumerate()
+            .map(|(index, &name)| {
+                (
+                    Temp {
+                        num: index as u32 + 1,
+                    },
+                    name,
+                )
+            })
+            .collect()
+    }
+
+    fn special_name(temp: Temp) -> Option<&'static str> {
+        if temp.num >= 1 && temp.num as usize <= REGISTER_NAMES.len() {
+            Some(REGISTER_NAMES[temp.num as usize - 1])
+        } else {
+            None
+        }
+    }
+
+    fn arg_registers() -> Vec<Temp> {
+        vec![A0, A1, A2, A3, A4, A5, A6, A7]
+    }
+
+    /// The instruction selector never names t0 and t1, which are only overwritten by the calls.
+    fn scratch_registers() -> [Temp; 2] {
+        [T0, T1]
+    }
+
+    fn fp() -> Temp {
+        S0
+    }
+
+    fn return_value() -> Temp {
+        A0
+    }
+
+    fn new(name: Label, formals: Vec<bool>) -> Self {
+        let mut frame = Rv64 {
+            formals: vec![],
+            name,
+            pointer: 0,
+        };
+        let formals = formals
+            .iter()
+            .map(|&escape| frame.alloc_local(escape))
+            .collect();
+        frame.formals = formals;
+        frame
+    }
+
+    fn name(&self) -> Label {
+        self.name.clone()
+    }
+
+    fn formals(&self) -> &[Self::Access] {
+        &self.formals
+    }
+
+    fn alloc_local(&mut self, escape: bool) -> Self::Access {
+        if escape {
+            self.pointer -= POINTER_SIZE;
+            InFrame(self.pointer)
+        } else {
+            InReg(Temp::new())
+        }
+    }
+
+    fn locals_size(&self) -> i64 {
+        -self.pointer
+    }
+
+    fn exp(&self, access: Self::Access, stack_frame: Exp) -> Exp {
+        match access {
+            InFrame(pos) => Mem(Box::new(BinOp {
+                op: Plus,
+                left: Box::new(stack_frame),
+                right: Box::new(Const(pos)),
+            })),
+            InReg(reg) => Exp::Temp(reg),
+        }
+    }
+
+    fn external_call(name: &str, arguments: Vec<Exp>) -> Exp {
+        Call(Box::new(Name(Label::with_name(name))), arguments)
+    }
+
+    fn proc_entry_exit2(&self, mut instructions: Vec<Instruction>) -> Vec<Instruction> {
+        for instruction in &mut instructions {
+            if let Instruction::Operation {
+                ref mut source,
+                jump: Some(ref labels),
+                ..
+            } = *instruction
+            {
+                if labels.is_empty() {
+                    // A tail call releases the frame, which needs s0, before jumping.
+                    source.push(S0);
+                    source.push(SP);
+                }
+            }
+        }
+
+        let source = Self::special_registers();
+        let instruction = Instruction::Operation {
+            assembly: String::new(),
+            source,
+            destination: vec![],
+            jump: Some(vec![]),
+        };
+        instructions.push(instruction);
+
+        for instruction in &mut instructions {
+            match *instruction {
+                Instruction::Label { .. } => (),
+                Instruction::Move {
+                    ref mut destination,
+                    ..
+                }
+                | Instruction::Operation {
+                    ref mut destination,
+                    ..
+                } => {
+                    destination.push(S0);
+                    destination.push(SP);
+                    break;
+                }
+            }
+        }
+
+        for instruction in instructions.iter_mut().rev() {
+            match *instruction {
+                Instruction::Label { .. } => (),
+                Instruction::Move { ref mut source, .. }
+                | Instruction::Operation { ref mut source, .. } => {
+                    source.push(S0);
+                    source.push(SP);
+                    break;
+                }
+            }
+        }
+
+        instructions
+    }
+
+    fn proc_entry_exit3(&self, body: Vec<Instruction>) -> Subroutine {
+        // Only the callee-saved registers used by the allocator need to be preserved.
+        let saved_registers: Vec<_> = Self::callee_saved_registers()
+            .into_iter()
+            .filter(|&register| {
+                register != S0 && body.iter().any(|instruction| writes(instruction, register))
+            })
+            .collect();
+        let locals_size = self.locals_size();
+        // The last instruction is the sink of proc_entry_exit2, and the tail calls only need s0 to
+        // release the frame.
+        let uses_frame_pointer = body[..body.len().saturating_sub(1)]
+            .iter()
+            .any(|instruction| !is_tail_call(instruction) && reads(instruction, S0));
+        let is_leaf = !body.iter().any(is_call);
+
+        let mut prolog = vec![format!("{}:", self.name())];
+        let mut epilog = vec![];
+        if is_leaf && locals_size == 0 && saved_registers.is_empty() && !uses_frame_pointer {
+            // A leaf function without a frame keeps the stack as it is, and its return address in
+            // ra.
+        } else {
+            // The callee-saved registers are at the bottom of the frame, below the locals.
+            let stack_size = align(locals_size + POINTER_SIZE * saved_registers.len() as i64);
+            prolog.push("addi sp, sp, -16".to_string());
+            prolog.push("sd ra, 8(sp)".to_string());
+            prolog.push("sd s0, 0(sp)".to_string());
+            prolog.push("mv s0, sp".to_string());
+            if stack_size != 0 {
+                if stack_size <= 0x800 {
+                    prolog.push(format!("addi sp, sp, -{}", stack_size));
+                } else {
+                    prolog.push(format!("li t6, {}", stack_size));
+                    prolog.push("sub sp, sp, t6".to_string());
+                }
+            }
+            for (index, register) in saved_registers.iter().enumerate() {
+                let register = register.to_string::<Self>();
+                let offset = POINTER_SIZE * index as i64;
+                prolog.push(format!("sd {}, {}(sp)", register, offset));
+                epilog.push(format!("ld {}, {}(sp)", register, offset));
+            }
+            epilog.push("mv sp, s0".to_string());
+            epilog.push("ld ra, 8(sp)".to_string());
+            epilog.push("ld s0, 0(sp)".to_string());
+            epilog.push("addi sp, sp, 16".to_string());
+        }
+
+        // A tail call releases the frame before jumping.
+        let mut instructions = vec![];
+        for instruction in body {
+            if is_tail_call(&instruction) {
+                for assembly in &epilog {
+                    instructions.push(Instruction::Operation {
+                        assembly: assembly.clone(),
+                        source: vec![],
+                        destination: vec![],
+                        jump: None,
+                    });
+                }
+            }
+            instructions.push(instruction);
+        }
+        epilog.push("ret".to_string());
+
+        Subroutine {
+            prolog: prolog.join("\n    "),
+            body: instructions,
+            epilog: epilog.join("\n    "),
+        }
+    }
+
+    fn codegen(statement: Statement) -> Vec<Instruction> {
+        let mut generator = Gen::new();
+        generator.munch_statement(statement);
+        generator.get_result()
+    }
+
+    fn is_unconditional_jump(assembly: &str) -> bool {
+        assembly.starts_with("j ") || assembly.starts_with("tail ")
+    }
+
+    fn is_cheap(instruction: &Instruction, temp: Temp) -> bool {
+        match *instruction {
+            // li 'd0, 42
+            // addi 'd0, 's0, -8
+            Instruction::Operation {
+                ref assembly,
+                ref destination,
+                ref source,
+                jump: None,
+            } => {
+                destination[..] == [temp]
+                    && (source.is_empty() && assembly.starts_with("li ")
+                        || source[..] == [S0] && assembly.starts_with("addi "))
+            }
+            _ => false,
+        }
+    }
+
+    fn prelude(
+        global_symbols: &[String],
+        _external_symbols: &[String],
+        strings: &[(&Label, &str)],
+    ) -> String {
+        gnu_prelude(global_symbols, strings)
+    }
+}
+
+fn align(size: i64) -> i64 {
+    (size + 0xF) & !0xF
+}
+
+fn writes(instruction: &Instruction, register: Temp) -> bool {
+    match *instruction {
+        Instruction::Label { .. } => false,
+        Instruction::Move {
+            ref destination, ..
+        }
+        | Instruction::Operation {
+            ref destination, ..
+        } => destination.contains(&register),
+    }
+}
+
+fn reads(instruction: &Instruction, register: Temp) -> bool {
+    match *instruction {
+        Instruction::Label { .. } => false,
+        Instruction::Move { ref source, .. } | Instruction::Operation { ref source, .. } => {
+            source.contains(&register)
+        }
+    }
+}
+
+fn is_call(instruction: &Instruction) -> bool {
+    match *instruction {
+        Instruction::Operation { ref assembly, .. } => {
+            assembly.starts_with("call ") || assembly.starts_with("jalr ")
+        }
+        _ => false,
+    }
+}
+
+/// The jumps out of the function, except the sink of proc_entry_exit2.
+fn is_tail_call(instruction: &Instruction) -> bool {
+    match *instruction {
+        Instruction::Operation {
+            ref assembly,
+            jump: Some(ref labels),
+            ..
+        } => labels.is_empty() && !assembly.is_empty(),
+        _ => false,
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+
+use super::Frame;
+use asm::{Instruction, Subroutine};
+use ir::BinOp::Plus;
+use ir::Exp::{self, BinOp, Call, Const, Mem, Name};
+use ir::Statement;
+use temp::{Label, Temp};
+
+use self::Access::{InFrame, InReg};
+
+const POINTER_SIZE: i64 = 8;
+
+/// The frame of a function compiled to WebAssembly. The temporaries are the locals of the function,
+/// and the escaping variables are in a shadow stack in the linear memory, whose stack pointer is a
+/// global: fp is a local holding the stack pointer at the entry, so that the stack arguments are at
+/// `fp + 16` as on the other targets.
+#[derive(Clone)]
+pub struct Wasm32 {
+    formals: Vec<Access>, // Representation of parameters.
+    name: Label,
+    pointer: i64,
+}
+
+impl PartialEq for Wasm32 {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Access {
+    InFrame(i64),
+    InReg(Temp),
+}
+
+// These temporaries are the same locals in every function: the first arguments are its
+// parameters.
+pub const FP: Temp = Temp { num: 1 };
+pub const RV: Temp = Temp { num: 2 };
+pub const A0: Temp = Temp { num: 3 };
+pub const A1: Temp = Temp { num: 4 };
+pub const A2: Temp = Temp { num: 5 };
+pub const A3: Temp = Temp { num: 6 };
+pub const A4: Temp = Temp { num: 7 };
+pub const A5: Temp = Temp { num: 8 };
+pub const A6: Temp = Temp { num: 9 };
+pub const A7: Temp = Temp { num: 10 };
+
+const REGISTER_NAMES: [&str; 10] = ["fp", "rv", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7"];
+
+impl Wasm32 {
+    /// The size of the arguments passed on the shadow stack by a call, below the two words which
+    /// stand for the saved frame pointer and the return address of the other targets.
+    pub fn stack_arguments_size(argument_count: usize) -> i64 {
+        let stack_argument_count = argument_count.saturating_sub(Self::arg_registers().len());
+        if stack_argument_count == 0 {
+            0
+        } else {
+            POINTER_SIZE * (stack_argument_count as i64 + 2)
+        }
+    }
+
+    /// The size of the frame in the shadow stack, kept aligned on 16 bytes.
+    pub fn frame_size(&self) -> i64 {
+        (self.locals_size() + 0xF) & !0xF
+    }
+
+    /// The parameters of the function in WebAssembly: the arguments of the first argument
+    /// registers.
+    pub fn parameters(&self) -> Vec<Temp> {
+        Self::arg_registers()
+            .into_iter()
+            .take(self.formals.len())
+            .collect()
+    }
+}
+
+/// The module is generated from the IR by `wasm_gen`, without instruction selection nor register
+/// allocation: the methods of the assembly are never called.
+impl Frame for Wasm32 {
+    type Access = Access;
+
+    const WORD_SIZE: i64 = 8;
+
+    const MOVE: &'static str = "";
+
+    fn registers() -> Vec<Temp> {
+        let mut registers = vec![FP, RV];
+        registers.extend(Self::arg_registers());
+        registers
+    }
+
+    fn register_count() -> usize {
+        Self::registers().len()
+    }
+
+    fn temp_map() -> HashMap<Temp, &'static str> {
+        REGISTER_NAMES
+            .iter()
+            .enumerate()
+            .map(|(index, &name)| {
+                (
+                    Temp {
+                        num: index as u32 + 1,
+                    },
+                    name,
+                )
+            })
+            .collect()
+    }
+
+    fn special_name(temp: Temp) -> Option<&'static str> {
+        if temp.num >= 1 && temp.num as usize <= REGISTER_NAMES.len() {
+            Some(REGISTER_NAMES[temp.num as usize - 1])
+        } else {
+            None
+        }
+    }
+
+    fn arg_registers() -> Vec<Temp> {
+        vec![A0, A1, A2, A3, A4, A5, A6, A7]
+    }
+
+    fn scratch_registers() -> [Temp; 2] {
+        unreachable!("WebAssembly has no registers")
+    }
+
+    fn fp() -> Temp {
+        FP
+    }
+
+    fn return_value() -> Temp {
+        RV
+    }
+
+    fn new(name: Label, formals: Vec<bool>) -> Self {
+        let mut frame = Wasm32 {
+            formals: vec![],
+            name,
+            pointer: 0,
+        };
+        let formals = formals
+            .iter()
+            .map(|&escape| frame.alloc_local(escape))
+            .collect();
+        frame.formals = formals;
+        frame
+    }
+
+    fn name(&self) -> Label {
+        self.name.clone()
+    }
+
+    fn formals(&self) -> &[Self::Access] {
+        &self.formals
+    }
+
+    fn alloc_local(&mut self, escape: bool) -> Self::Access {
+        if escape {
+            self.pointer -= POINTER_SIZE;
+            InFrame(self.pointer)
+        } else {
+            InReg(Temp::new())
+        }
+    }
+
+    fn locals_size(&self) -> i64 {
+        -self.pointer
+    }
+
+    fn exp(&self, access: Self::Access, stack_frame: Exp) -> Exp {
+        match access {
+            InFrame(pos) => Mem(Box::new(BinOp {
+                op: Plus,
+                left: Box::new(stack_frame),
+                right: Box::new(Const(pos)),
+            })),
+            InReg(reg) => Exp::Temp(reg),
+        }
+    }
+
+    fn external_call(name: &str, arguments: Vec<Exp>) -> Exp {
+        Call(Box::new(Name(Label::with_name(name))), arguments)
+    }
+
+    fn proc_entry_exit2(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        instructions
+    }
+
+    fn proc_entry_exit3(&self, _body: Vec<Instruction>) -> Subroutine {
+        unreachable!("WebAssembly has no assembly")
+    }
+
+    fn codegen(_statement: Statement) -> Vec<Instruction> {
+        unreachable!("WebAssembly has no assembly")
+    }
+
+    fn is_unconditional_jump(_assembly: &str) -> bool {
+        unreachable!("WebAssembly has no assembly")
+    }
+
+    fn is_cheap(_instruction: &Instruction, _temp: Temp) -> bool {
+        unreachable!("WebAssembly has no assembly")
+    }
+
+    fn prelude(
+        _global_symbols: &[String],
+        _external_symbols: &[String],
+        _strings: &[(&Label, &str)],
+    ) -> String {
+        unreachable!("WebAssembly has no assembly")
+    }
+}
@@ -1,11 +1,13 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use super::Frame;
 use asm::{Instruction, Subroutine};
+use asm_gen::Gen;
 use ir::BinOp::Plus;
 use ir::Exp::{self, BinOp, Call, Const, Mem, Name};
 use ir::Statement;
 use temp::{Label, Temp};
+use tiling::select;
 
 use self::Access::{InFrame, InReg};
 
@@ -16,6 +18,8 @@ pub struct X86_64 {
     formals: Vec<Access>, // Representation of parameters.
     name: Label,
     pointer: i64,
+    /// Address the frame from `rsp` instead of saving and setting up `rbp`, when possible.
+    omit_frame_pointer: bool,
 }
 
 impl PartialEq for X86_64 {
@@ -48,10 +52,6 @@ pub const R14: Temp = Temp { num: 15 };
 pub const R15: Temp = Temp { num: 16 };
 
 impl X86_64 {
-    pub fn arg_registers() -> Vec<Temp> {
-        vec![RDI, RSI, RDX, RCX, R8, R9]
-    }
-
     fn callee_saved_registers() -> Vec<Temp> {
         vec![RBX, RBP, R12, R13, R14, R15]
     }
@@ -60,16 +60,37 @@ impl X86_64 {
         vec![RAX, RSP]
     }
 
+    /// The registers which a function can overwrite, following the System V AMD64 ABI.
     fn caller_saved_registers() -> Vec<Temp> {
-        vec![R10, R11]
+        vec![RAX, RDI, RSI, RDX, RCX, R8, R9, R10, R11]
     }
 
+    /// The registers overwritten by a call, to a Tiger function, to the runtime or to a C function.
     pub fn calldefs() -> Vec<Temp> {
-        let mut registers = Self::caller_saved_registers();
-        registers.extend(Self::arg_registers());
-        registers.push(Self::return_value());
+        Self::caller_saved_registers()
+    }
+
+    pub fn calldefs_of(function: &Label) -> Vec<Temp> {
+        let mut registers = Self::calldefs();
+        if *function == Label::with_name("_setjmp") {
+            // _setjmp returns a second time when an exception is raised, after any register could
+            // have been overwritten, so no value can be kept in a register across this call.
+            registers.extend(
+                Self::callee_saved_registers()
+                    .into_iter()
+                    .filter(|&register| register != RBP),
+            );
+        }
         registers
     }
+
+    /// The size of the arguments passed on the stack by a call, including the padding pushed
+    /// before them so that the stack stays aligned on 16 bytes at the call. The caller releases
+    /// this space after the call.
+    pub fn stack_arguments_size(argument_count: usize) -> i64 {
+        let stack_argument_count = argument_count.saturating_sub(Self::arg_registers().len());
+        align(stack_argument_count as i64 * POINTER_SIZE)
+    }
 }
 
 impl Frame for X86_64 {
@@ -77,12 +98,17 @@ impl Frame for X86_64 {
 
     const WORD_SIZE: i64 = 8;
 
+    const MOVE: &'static str = "mov 'd0, 's0";
+
     fn registers() -> Vec<Temp> {
         let mut registers = Self::arg_registers();
         registers.push(Self::return_value());
         registers.extend(Self::callee_saved_registers());
         registers.extend(Self::special_registers());
         registers.extend(Self::caller_saved_registers());
+        // The argument registers and rax are also in the other lists.
+        let mut seen = HashSet::new();
+        registers.retain(|&register| seen.insert(register));
         registers
     }
 
@@ -115,6 +141,15 @@ impl Frame for X86_64 {
         Self::temp_map().get(&temp).map(|&str| str)
     }
 
+    fn arg_registers() -> Vec<Temp> {
+        vec![RDI, RSI, RDX, RCX, R8, R9]
+    }
+
+    /// Maximal munch never names r10 and r11, which are only overwritten by the calls.
+    fn scratch_registers() -> [Temp; 2] {
+        [R10, R11]
+    }
+
     fn fp() -> Temp {
         RBP
     }
@@ -128,6 +163,7 @@ impl Frame for X86_64 {
             formals: vec![],
             name,
             pointer: 0,
+            omit_frame_pointer: false,
         };
         let formals = formals
             .iter()
@@ -154,6 +190,14 @@ impl Frame for X86_64 {
         }
     }
 
+    fn locals_size(&self) -> i64 {
+        -self.pointer
+    }
+
+    fn set_omit_frame_pointer(&mut self, omit_frame_pointer: bool) {
+        self.omit_frame_pointer = omit_frame_pointer;
+    }
+
     fn exp(&self, access: Self::Access, stack_frame: Exp) -> Exp {
         match access {
             InFrame(pos) => Mem(Box::new(BinOp {
@@ -169,58 +213,23 @@ impl Frame for X86_64 {
         Call(Box::new(Name(Label::with_name(name))), arguments)
     }
 
-    fn proc_entry_exit1(&mut self, mut statement: Statement) -> Statement {
-        let mut start_statements = vec![];
-        let mut end_statements = vec![];
-
-        let mut saved_register_locations = vec![];
-        for register in Self::callee_saved_registers().into_iter() {
-            let local = Temp::new();
-            let memory = Exp::Temp(local);
-            saved_register_locations.push(memory.clone());
-            start_statements.push(Statement::Move(memory, Exp::Temp(register)));
-        }
-
-        let arg_registers = Self::arg_registers();
-        let arg_registers_len = arg_registers.len();
-        for (formal, arg_register) in self.formals.iter().zip(arg_registers) {
-            let destination = self.exp(formal.clone(), Exp::Temp(Self::fp()));
-            start_statements.push(Statement::Move(destination, Exp::Temp(arg_register)));
-        }
-        for (index, formal) in self.formals.iter().skip(arg_registers_len).enumerate() {
-            let destination = self.exp(formal.clone(), Exp::Temp(Self::fp()));
-            start_statements.push(Statement::Move(
-                destination,
-                Exp::Mem(Box::new(Exp::BinOp {
-                    left: Box::new(Exp::Temp(Self::fp())),
-                    op: Plus,
-                    right: Box::new(Exp::Const(Self::WORD_SIZE * (index + 2) as i64)),
-                })),
-            ));
-        }
-
-        for (register, location) in Self::callee_saved_registers()
-            .into_iter()
-            .zip(saved_register_locations)
-        {
-            end_statements.push(Statement::Move(Exp::Temp(register), location));
-        }
-
-        let mut end_statement = Statement::Exp(Exp::Const(0));
-        for statement in end_statements {
-            end_statement = Statement::Sequence(Box::new(end_statement), Box::new(statement));
-        }
-
-        for new_statement in start_statements.into_iter().rev() {
-            statement = Statement::Sequence(Box::new(new_statement), Box::new(statement));
+    fn proc_entry_exit2(&self, mut instructions: Vec<Instruction>) -> Vec<Instruction> {
+        for instruction in &mut instructions {
+            if let Instruction::Operation {
+                ref mut source,
+                jump: Some(ref labels),
+                ..
+            } = *instruction
+            {
+                if labels.is_empty() {
+                    // A tail call releases the frame, which needs rbp, before jumping.
+                    source.push(RBP);
+                    source.push(RSP);
+                }
+            }
         }
 
-        Statement::Sequence(Box::new(statement), Box::new(end_statement))
-    }
-
-    fn proc_entry_exit2(&self, mut instructions: Vec<Instruction>) -> Vec<Instruction> {
-        let mut source = Self::callee_saved_registers();
-        source.extend(Self::special_registers());
+        let source = Self::special_registers();
         let instruction = Instruction::Operation {
             assembly: String::new(),
             source,
@@ -263,26 +272,299 @@ impl Frame for X86_64 {
     }
 
     fn proc_entry_exit3(&self, body: Vec<Instruction>) -> Subroutine {
-        let mut stack_size = -self.pointer;
-        if stack_size % 16 != 0 {
-            // Align the stack of 16 bytes.
-            stack_size = (stack_size & !0xF) + 0x10;
+        // Only the callee-saved registers used by the allocator need to be preserved.
+        let saved_registers: Vec<_> = Self::callee_saved_registers()
+            .into_iter()
+            .filter(|&register| {
+                register != RBP && body.iter().any(|instruction| writes(instruction, register))
+            })
+            .collect();
+        let locals_size = self.locals_size();
+        // The last instruction is the sink of proc_entry_exit2, and the tail calls only need rbp to
+        // release the frame.
+        let uses_frame_pointer = body[..body.len().saturating_sub(1)]
+            .iter()
+            .any(|instruction| !is_tail_call(instruction) && reads(instruction, RBP));
+        let is_leaf = !body.iter().any(is_call);
+        let saved_size = POINTER_SIZE * saved_registers.len() as i64;
+
+        let mut prolog = vec![format!("{}:", self.name())];
+        let mut epilog = vec![];
+        let mut body = body;
+        if is_leaf && locals_size == 0 && saved_registers.is_empty() && !uses_frame_pointer {
+            // A leaf function without a frame keeps the stack as it is.
+        } else if let Some(rsp_relative_body) = self.rsp_relative(&body, locals_size, saved_size) {
+            let stack_size = rsp_relative_stack_size(locals_size, saved_size);
+            for register in &saved_registers {
+                prolog.push(format!("push {}", register.to_string::<Self>()));
+            }
+            if stack_size != 0 {
+                prolog.push(format!("sub rsp, {}", stack_size));
+                epilog.push(format!("add rsp, {}", stack_size));
+            }
+            for register in saved_registers.iter().rev() {
+                epilog.push(format!("pop {}", register.to_string::<Self>()));
+            }
+            body = rsp_relative_body;
+        } else {
+            // After pushing rbp, the stack is aligned on 16 bytes: keep it aligned for the calls
+            // below the saved registers.
+            let stack_size = align(locals_size + saved_size) - saved_size;
+            prolog.push("push rbp".to_string());
+            prolog.push("mov rbp, rsp".to_string());
+            if stack_size != 0 {
+                prolog.push(format!("sub rsp, {}", stack_size));
+            }
+            for register in &saved_registers {
+                prolog.push(format!("push {}", register.to_string::<Self>()));
+            }
+            for register in saved_registers.iter().rev() {
+                epilog.push(format!("pop {}", register.to_string::<Self>()));
+            }
+            epilog.push("leave".to_string());
+        }
+
+        // A tail call releases the frame before jumping.
+        let mut instructions = vec![];
+        for instruction in body {
+            if is_tail_call(&instruction) {
+                for assembly in &epilog {
+                    instructions.push(Instruction::Operation {
+                        assembly: assembly.clone(),
+                        source: vec![],
+                        destination: vec![],
+                        jump: None,
+                    });
+                }
+            }
+            instructions.push(instruction);
         }
+        epilog.push("ret".to_string());
 
         Subroutine {
-            // FIXME: saving to rbp is apparently not needed in 64-bit.
-            prolog: format!(
-                "{}:
-    push rbp
-    mov rbp, rsp
-    sub rsp, {}",
-                self.name(),
-                stack_size
-            ),
-            body,
-            epilog: "leave
-    ret"
-            .to_string(),
+            prolog: prolog.join("\n    "),
+            body: instructions,
+            epilog: epilog.join("\n    "),
         }
     }
+
+    fn codegen(statement: Statement) -> Vec<Instruction> {
+        let mut generator = Gen::new();
+        generator.munch_statement(statement);
+        generator.get_result()
+    }
+
+    fn select(statements: &[Statement]) -> Vec<Instruction> {
+        select(statements)
+    }
+
+    fn is_unconditional_jump(assembly: &str) -> bool {
+        assembly.starts_with("jmp ")
+    }
+
+    fn is_cheap(instruction: &Instruction, temp: Temp) -> bool {
+        match *instruction {
+            // mov 'd0, 42
+            // mov 'd0, l7
+            Instruction::Move {
+                ref assembly,
+                ref destination,
+                ref source,
+            } => destination[..] == [temp] && source.is_empty() && !assembly.contains('['),
+            // lea 'd0, ['s0 + -8]
+            Instruction::Operation {
+                ref assembly,
+                ref destination,
+                ref source,
+                jump: None,
+            } => destination[..] == [temp] && source[..] == [RBP] && assembly.starts_with("lea "),
+            _ => false,
+        }
+    }
+
+    fn prelude(
+        global_symbols: &[String],
+        external_symbols: &[String],
+        strings: &[(&Label, &str)],
+    ) -> String {
+        let mut prelude = String::new();
+        for symbol in global_symbols {
+            prelude.push_str(&format!("global {}\n", symbol));
+        }
+        prelude.push('\n');
+        for symbol in external_symbols {
+            prelude.push_str(&format!("extern {}\n", symbol));
+        }
+        prelude.push('\n');
+        prelude.push_str("section .data\n");
+        prelude.push_str("    align 2\n");
+        for &(label, string) in strings {
+            prelude.push_str(&format!("    {}: db {}\n", label, to_nasm(string)));
+        }
+        prelude.push_str("\nsection .text\n");
+        prelude
+    }
+}
+
+impl X86_64 {
+    /// Rewrite the memory operands `[rbp + offset]` of the body as `[rsp + offset']`, if the frame
+    /// pointer is omitted and it is not used otherwise (for instance as a static link). The
+    /// frame pointer stays the address below the return address, as if `rbp` had been pushed.
+    fn rsp_relative(
+        &self,
+        body: &[Instruction],
+        locals_size: i64,
+        saved_size: i64,
+    ) -> Option<Vec<Instruction>> {
+        if !self.omit_frame_pointer {
+            return None;
+        }
+        let stack_size = rsp_relative_stack_size(locals_size, saved_size);
+        let rbp_position = stack_size + saved_size - POINTER_SIZE;
+        let (last, body) = body.split_last()?;
+        let mut instructions = vec![];
+        // The arguments pushed for a call, and their padding, move rsp until the call returns.
+        let mut pushed_size = 0;
+        for instruction in body {
+            let mut instruction = instruction.clone();
+            match instruction {
+                Instruction::Label { .. } => (),
+                Instruction::Move {
+                    ref mut assembly,
+                    ref mut source,
+                    ref mut destination,
+                }
+                | Instruction::Operation {
+                    ref mut assembly,
+                    ref mut source,
+                    ref mut destination,
+                    ..
+                } => {
+                    destination.retain(|&register| register != RBP);
+                    for (index, register) in source.iter_mut().enumerate() {
+                        if *register == RBP {
+                            *assembly = rebase(assembly, index, |offset| {
+                                if offset < 0 {
+                                    pushed_size + stack_size + offset
+                                } else {
+                                    pushed_size + rbp_position + offset
+                                }
+                            })?;
+                            *register = RSP;
+                        }
+                    }
+                    if assembly.starts_with("push ") {
+                        pushed_size += POINTER_SIZE;
+                    } else if *destination == [RSP] && assembly.starts_with("sub 'd0, ") {
+                        pushed_size += assembly["sub 'd0, ".len()..].parse::<i64>().ok()?;
+                    } else if *destination == [RSP] && assembly.starts_with("add 'd0, ") {
+                        pushed_size -= assembly["add 'd0, ".len()..].parse::<i64>().ok()?;
+                    }
+                }
+            }
+            instructions.push(instruction);
+        }
+        instructions.push(last.clone());
+        Some(instructions)
+    }
+}
+
+/// The size to subtract from `rsp`, below the saved registers, for the locals with the stack
+/// aligned on 16 bytes, when `rbp` is not pushed.
+fn rsp_relative_stack_size(locals_size: i64, saved_size: i64) -> i64 {
+    // The return address is on the stack at the entry.
+    align(locals_size + saved_size + POINTER_SIZE) - saved_size - POINTER_SIZE
+}
+
+fn align(size: i64) -> i64 {
+    (size + 0xF) & !0xF
+}
+
+/// Replace the offsets of the memory operands `['sindex + offset]` and `['sindex]`, or None if the
+/// source is used otherwise.
+fn rebase<O: Fn(i64) -> i64>(assembly: &str, index: usize, rebase_offset: O) -> Option<String> {
+    let operand = format!("'s{}", index);
+    let mut result = String::new();
+    let mut rest = assembly;
+    while let Some(position) = rest.find(&operand) {
+        let (before, after) = rest.split_at(position);
+        let after = &after[operand.len()..];
+        if !before.ends_with('[') || after.starts_with(|char: char| char.is_ascii_digit()) {
+            return None;
+        }
+        let end = after.find(']')?;
+        let offset = match after[..end].trim() {
+            "" => 0,
+            offset if offset.starts_with('+') => offset[1..].trim().parse().ok()?,
+            _ => return None,
+        };
+        result.push_str(before);
+        result.push_str(&format!("{} + {}", operand, rebase_offset(offset)));
+        rest = &after[end..];
+    }
+    result.push_str(rest);
+    Some(result)
+}
+
+fn writes(instruction: &Instruction, register: Temp) -> bool {
+    match *instruction {
+        Instruction::Label { .. } => false,
+        Instruction::Move {
+            ref destination, ..
+        }
+        | Instruction::Operation {
+            ref destination, ..
+        } => destination.contains(&register),
+    }
+}
+
+fn reads(instruction: &Instruction, register: Temp) -> bool {
+    match *instruction {
+        Instruction::Label { .. } => false,
+        Instruction::Move { ref source, .. } | Instruction::Operation { ref source, .. } => {
+            source.contains(&register)
+        }
+    }
+}
+
+fn is_call(instruction: &Instruction) -> bool {
+    match *instruction {
+        Instruction::Operation { ref assembly, .. } => assembly.starts_with("call "),
+        _ => false,
+    }
+}
+
+/// The jumps out of the function, except the sink of proc_entry_exit2.
+fn is_tail_call(instruction: &Instruction) -> bool {
+    match *instruction {
+        Instruction::Operation {
+            ref assembly,
+            jump: Some(ref labels),
+            ..
+        } => labels.is_empty() && !assembly.is_empty(),
+        _ => false,
+    }
+}
+
+/// Printable ASCII characters are kept in quotes, other bytes of the UTF-8 encoding are written as
+/// numbers.
+fn to_nasm(string: &str) -> String {
+    let mut items = vec![];
+    let mut quoted = String::new();
+    for &byte in string.as_bytes() {
+        if (b' '..=b'~').contains(&byte) && byte != b'\'' {
+            quoted.push(byte as char);
+        } else {
+            if !quoted.is_empty() {
+                items.push(format!("'{}'", quoted));
+                quoted.clear();
+            }
+            items.push(byte.to_string());
+        }
+    }
+    if !quoted.is_empty() {
+        items.push(format!("'{}'", quoted));
+    }
+    items.push("0".to_string());
+    items.join(", ")
 }
@@ -1,16 +1,21 @@
 use std::cell::RefCell;
+use std::collections::HashSet;
 use std::rc::Rc;
 
 use ast::Operator;
 use frame::{Fragment, Frame};
 use ir;
-use ir::BinOp::{And, Div, Minus, Mul, Or, Plus};
+use ir::BinOp::{
+    And, ArithmeticShiftRight, Div, Minus, Mul, Or, Plus, ShiftLeft, ShiftRight, Xor,
+};
 use ir::Exp::{self, BinOp, Call, Const, ExpSequence, Mem, Name};
 use ir::RelationalOp::{
     self, Equal, GreaterOrEqual, GreaterThan, LesserOrEqual, LesserThan, NotEqual,
+    UnsignedLesserThan,
 };
 use ir::Statement::{self, CondJump, Jump, Move, Sequence};
 use temp::{Label, Temp};
+use types::Type;
 
 #[allow(type_alias_bounds)]
 pub type Access<F: Frame> = (Level<F>, F::Access);
@@ -18,6 +23,8 @@ pub type Access<F: Frame> = (Level<F>, F::Access);
 pub struct Level<F> {
     pub current: Rc<RefCell<F>>,
     parent: Option<Box<Level<F>>>,
+    /// Start of the body, where the tail calls of the function to itself jump.
+    entry: Label,
 }
 
 impl<F> Clone for Level<F> {
@@ -25,6 +32,7 @@ impl<F> Clone for Level<F> {
         Self {
             current: self.current.clone(),
             parent: self.parent.clone(),
+            entry: self.entry.clone(),
         }
     }
 }
@@ -39,15 +47,22 @@ pub fn outermost<F: Frame>() -> Level<F> {
     Level {
         current: Rc::new(RefCell::new(F::new(Label::new(), vec![]))),
         parent: None,
+        entry: Label::new(),
     }
 }
 
 impl<F: Frame> Level<F> {
-    pub fn new(parent: &Level<F>, name: Label, mut formals: Vec<bool>) -> Level<F> {
-        formals.push(true); // for the static link.
+    pub fn new(
+        parent: &Level<F>,
+        name: Label,
+        mut formals: Vec<bool>,
+        static_link_escapes: bool,
+    ) -> Level<F> {
+        formals.push(static_link_escapes);
         Level {
             current: Rc::new(RefCell::new(F::new(name, formals))),
             parent: Some(Box::new(parent.clone())),
+            entry: Label::new(),
         }
     }
 
@@ -59,6 +74,17 @@ impl<F: Frame> Level<F> {
             .map(|access| (self.clone(), access.clone()))
             .collect()
     }
+
+    /// The level of the main function, whose parent is the outermost level.
+    pub fn is_main(&self) -> bool {
+        self.parent
+            .as_ref()
+            .is_some_and(|parent| parent.parent.is_none())
+    }
+
+    pub fn parent(&self) -> Option<&Level<F>> {
+        self.parent.as_deref()
+    }
 }
 
 pub fn alloc_local<F: Frame>(level: &Level<F>, escape: bool) -> Access<F> {
@@ -67,16 +93,43 @@ pub fn alloc_local<F: Frame>(level: &Level<F>, escape: bool) -> Access<F> {
     (level, frame_local)
 }
 
-pub fn array_subscript<F: Frame>(var: Exp, subscript: Exp) -> Exp {
-    Mem(Box::new(BinOp {
-        op: Plus,
-        left: Box::new(var),
-        right: Box::new(BinOp {
-            op: Mul,
-            left: Box::new(subscript),
-            right: Box::new(Const(F::WORD_SIZE)),
-        }),
-    }))
+pub fn array_subscript<F: Frame>(var: Exp, subscript: Exp, trap: Exp) -> Exp {
+    let array = Temp::new();
+    let index = Temp::new();
+    let in_bounds_label = Label::new();
+    let out_of_bounds_label = Label::new();
+    let check = sequence(vec![
+        Move(Exp::Temp(array), var),
+        Move(Exp::Temp(index), subscript),
+        // The length of the array is stored in the word preceding its first element.
+        // Comparing as unsigned also rejects the negative indices.
+        CondJump {
+            op: UnsignedLesserThan,
+            left: Exp::Temp(index),
+            right: Mem(Box::new(BinOp {
+                op: Plus,
+                left: Box::new(Exp::Temp(array)),
+                right: Box::new(Const(-F::WORD_SIZE)),
+            })),
+            true_label: in_bounds_label.clone(),
+            false_label: out_of_bounds_label.clone(),
+        },
+        Statement::Label(out_of_bounds_label),
+        Statement::Exp(trap),
+        Statement::Label(in_bounds_label),
+    ]);
+    ExpSequence(
+        Box::new(check),
+        Box::new(Mem(Box::new(BinOp {
+            op: Plus,
+            left: Box::new(Exp::Temp(array)),
+            right: Box::new(BinOp {
+                op: Mul,
+                left: Box::new(Exp::Temp(index)),
+                right: Box::new(Const(F::WORD_SIZE)),
+            }),
+        }))),
+    )
 }
 
 pub fn binary_oper(op: Operator, left: Exp, right: Exp) -> Exp {
@@ -87,12 +140,219 @@ pub fn binary_oper(op: Operator, left: Exp, right: Exp) -> Exp {
     }
 }
 
-pub fn field_access<F: Frame>(var: Exp, field_index: usize) -> Exp {
-    Mem(Box::new(BinOp {
-        op: Plus,
-        left: Box::new(var),
-        right: Box::new(Const(F::WORD_SIZE * field_index as i64)),
-    }))
+/// Addition, subtraction or multiplication calling `overflow` when the result does not fit in an
+/// int.
+pub fn checked_arithmetic(oper: Operator, left: Exp, right: Exp, overflow: Exp) -> Exp {
+    let left_temp = Exp::Temp(Temp::new());
+    let right_temp = Exp::Temp(Temp::new());
+    let result = Exp::Temp(Temp::new());
+    let overflow_label = Label::new();
+    let end_label = Label::new();
+    let mut statements = vec![
+        Move(left_temp.clone(), left),
+        Move(right_temp.clone(), right),
+        Move(
+            result.clone(),
+            binary_oper(oper, left_temp.clone(), right_temp.clone()),
+        ),
+    ];
+    let xor = |left: &Exp, right: &Exp| BinOp {
+        op: Xor,
+        left: Box::new(left.clone()),
+        right: Box::new(right.clone()),
+    };
+    let sign_check = |left: Exp, right: Exp| CondJump {
+        op: LesserThan,
+        left: BinOp {
+            op: And,
+            left: Box::new(left),
+            right: Box::new(right),
+        },
+        right: Const(0),
+        true_label: overflow_label.clone(),
+        false_label: end_label.clone(),
+    };
+    match oper {
+        // The sum overflows when its sign differs from the sign of both operands.
+        Operator::Plus => statements.push(sign_check(
+            xor(&left_temp, &result),
+            xor(&right_temp, &result),
+        )),
+        // The difference overflows when the operands have different signs and the sign of the
+        // result differs from the sign of the left operand.
+        Operator::Minus => statements.push(sign_check(
+            xor(&left_temp, &right_temp),
+            xor(&left_temp, &result),
+        )),
+        // The product overflows when dividing it by the left operand does not give back the right
+        // operand. Multiplying by -1 is checked separately since the division itself could
+        // overflow.
+        Operator::Times => {
+            let minus_one_label = Label::new();
+            let not_minus_one_label = Label::new();
+            let non_zero_label = Label::new();
+            statements.extend(vec![
+                CondJump {
+                    op: Equal,
+                    left: left_temp.clone(),
+                    right: Const(-1),
+                    true_label: minus_one_label.clone(),
+                    false_label: not_minus_one_label.clone(),
+                },
+                Statement::Label(minus_one_label),
+                CondJump {
+                    op: Equal,
+                    left: right_temp.clone(),
+                    right: Const(i64::MIN),
+                    true_label: overflow_label.clone(),
+                    false_label: end_label.clone(),
+                },
+                Statement::Label(not_minus_one_label),
+                CondJump {
+                    op: Equal,
+                    left: left_temp.clone(),
+                    right: Const(0),
+                    true_label: end_label.clone(),
+                    false_label: non_zero_label.clone(),
+                },
+                Statement::Label(non_zero_label),
+                CondJump {
+                    op: NotEqual,
+                    left: BinOp {
+                        op: Div,
+                        left: Box::new(result.clone()),
+                        right: Box::new(left_temp),
+                    },
+                    right: right_temp,
+                    true_label: overflow_label.clone(),
+                    false_label: end_label.clone(),
+                },
+            ]);
+        }
+        _ => panic!("{:?} is not a checked arithmetic operator", oper),
+    }
+    statements.push(Statement::Label(overflow_label));
+    statements.push(Statement::Exp(overflow));
+    statements.push(Statement::Label(end_label));
+    ExpSequence(Box::new(sequence(statements)), Box::new(result))
+}
+
+/// Division or modulo calling `division_by_zero` when the divisor is 0. Dividing the minimum int by
+/// -1 wraps around, unless an `overflow` trap is given.
+pub fn checked_division(
+    oper: Operator,
+    left: Exp,
+    right: Exp,
+    division_by_zero: Exp,
+    overflow: Option<Exp>,
+) -> Exp {
+    if let Const(divisor) = right {
+        if divisor != 0 && divisor != -1 {
+            return match oper {
+                Operator::Modulo => {
+                    let dividend = Exp::Temp(Temp::new());
+                    ExpSequence(
+                        Box::new(Move(dividend.clone(), left)),
+                        Box::new(remainder(dividend, right)),
+                    )
+                }
+                _ => binary_oper(oper, left, right),
+            };
+        }
+    }
+    let dividend = Exp::Temp(Temp::new());
+    let divisor = Exp::Temp(Temp::new());
+    let result = Exp::Temp(Temp::new());
+    let zero_label = Label::new();
+    let non_zero_label = Label::new();
+    let minus_one_label = Label::new();
+    let divide_label = Label::new();
+    let end_label = Label::new();
+    let mut statements = vec![
+        Move(dividend.clone(), left),
+        Move(divisor.clone(), right),
+        CondJump {
+            op: Equal,
+            left: divisor.clone(),
+            right: Const(0),
+            true_label: zero_label.clone(),
+            false_label: non_zero_label.clone(),
+        },
+        Statement::Label(zero_label),
+        Statement::Exp(division_by_zero),
+        Statement::Label(non_zero_label),
+        // idiv faults when the quotient does not fit, so dividing by -1 is done by a negation.
+        CondJump {
+            op: Equal,
+            left: divisor.clone(),
+            right: Const(-1),
+            true_label: minus_one_label.clone(),
+            false_label: divide_label.clone(),
+        },
+        Statement::Label(minus_one_label),
+    ];
+    if let (Operator::Divide, Some(overflow)) = (oper, overflow) {
+        let overflow_label = Label::new();
+        let negate_label = Label::new();
+        statements.extend(vec![
+            CondJump {
+                op: Equal,
+                left: dividend.clone(),
+                right: Const(i64::MIN),
+                true_label: overflow_label.clone(),
+                false_label: negate_label.clone(),
+            },
+            Statement::Label(overflow_label),
+            Statement::Exp(overflow),
+            Statement::Label(negate_label),
+        ]);
+    }
+    let (minus_one_result, result_exp) = match oper {
+        Operator::Modulo => (Const(0), remainder(dividend, divisor)),
+        _ => (
+            BinOp {
+                op: Minus,
+                left: Box::new(Const(0)),
+                right: Box::new(dividend.clone()),
+            },
+            binary_oper(oper, dividend, divisor),
+        ),
+    };
+    statements.extend(vec![
+        Move(result.clone(), minus_one_result),
+        Jump(Name(end_label.clone()), vec![end_label.clone()]),
+        Statement::Label(divide_label),
+        Move(result.clone(), result_exp),
+        Statement::Label(end_label),
+    ]);
+    ExpSequence(Box::new(sequence(statements)), Box::new(result))
+}
+
+pub fn field_access<F: Frame>(var: Exp, field_index: usize, trap: Exp) -> Exp {
+    let record = Temp::new();
+    let not_nil_label = Label::new();
+    let nil_label = Label::new();
+    let check = sequence(vec![
+        Move(Exp::Temp(record), var),
+        CondJump {
+            op: NotEqual,
+            left: Exp::Temp(record),
+            right: Const(0),
+            true_label: not_nil_label.clone(),
+            false_label: nil_label.clone(),
+        },
+        Statement::Label(nil_label),
+        Statement::Exp(trap),
+        Statement::Label(not_nil_label),
+    ]);
+    ExpSequence(
+        Box::new(check),
+        Box::new(Mem(Box::new(BinOp {
+            op: Plus,
+            left: Box::new(Exp::Temp(record)),
+            right: Box::new(Const(F::WORD_SIZE * field_index as i64)),
+        }))),
+    )
 }
 
 pub fn function_call<F: Clone + Frame + PartialEq>(
@@ -302,6 +562,70 @@ pub fn string_equality<F: Frame>(oper: Operator, left: Exp, right: Exp) -> Exp {
     }
 }
 
+/// Install a handler with setjmp before evaluating the body: when an exception is raised, the
+/// runtime longjmps back with a non-zero status and the exception is compared against the handled
+/// ones.
+pub fn try_expression<F: Clone + Frame>(
+    body: Exp,
+    handlers: Vec<(Label, Exp)>,
+    level: &Level<F>,
+) -> Exp {
+    let result = alloc_local(level, false);
+    let frame = level.current.borrow();
+    let result = frame.exp(result.1, Exp::Temp(F::fp()));
+    let buffer = Temp::new();
+    let status = Temp::new();
+    let exception = Temp::new();
+    let body_label = Label::new();
+    let handler_label = Label::new();
+    let end_label = Label::new();
+    let mut statements = vec![
+        Move(Exp::Temp(buffer), F::external_call("pushHandler", vec![])),
+        Move(
+            Exp::Temp(status),
+            F::external_call("_setjmp", vec![Exp::Temp(buffer)]),
+        ),
+        CondJump {
+            op: Equal,
+            left: Exp::Temp(status),
+            right: Const(0),
+            true_label: body_label.clone(),
+            false_label: handler_label.clone(),
+        },
+        Statement::Label(body_label),
+        Move(result.clone(), body),
+        Statement::Exp(F::external_call("popHandler", vec![])),
+        Jump(Name(end_label.clone()), vec![end_label.clone()]),
+        Statement::Label(handler_label),
+        Move(
+            Exp::Temp(exception),
+            F::external_call("currentException", vec![]),
+        ),
+    ];
+    for (exception_label, handler) in handlers {
+        let matched_label = Label::new();
+        let next_label = Label::new();
+        statements.push(CondJump {
+            op: Equal,
+            left: Exp::Temp(exception),
+            right: Name(exception_label),
+            true_label: matched_label.clone(),
+            false_label: next_label.clone(),
+        });
+        statements.push(Statement::Label(matched_label));
+        statements.push(Move(result.clone(), handler));
+        statements.push(Jump(Name(end_label.clone()), vec![end_label.clone()]));
+        statements.push(Statement::Label(next_label));
+    }
+    // No handler matched: propagate the exception to the enclosing handler.
+    statements.push(Statement::Exp(F::external_call(
+        "raiseException",
+        vec![Exp::Temp(exception), Const(0), Const(0), Const(0)],
+    )));
+    statements.push(Statement::Label(end_label));
+    ExpSequence(Box::new(sequence(statements)), Box::new(result))
+}
+
 pub fn unit() -> Exp {
     Const(0)
 }
@@ -361,6 +685,31 @@ pub fn while_loop(done_label: &Label, test_expr: Exp, body: Exp) -> Exp {
     )
 }
 
+/// The remainder has the sign of the dividend, like the truncating division.
+fn remainder(dividend: Exp, divisor: Exp) -> Exp {
+    BinOp {
+        op: Minus,
+        left: Box::new(dividend.clone()),
+        right: Box::new(BinOp {
+            op: Mul,
+            left: Box::new(BinOp {
+                op: Div,
+                left: Box::new(dividend),
+                right: Box::new(divisor.clone()),
+            }),
+            right: Box::new(divisor),
+        }),
+    }
+}
+
+fn sequence(statements: Vec<Statement>) -> Statement {
+    let mut statements = statements.into_iter();
+    let first = statements.next().expect("first statement");
+    statements.fold(first, |sequence, statement| {
+        Sequence(Box::new(sequence), Box::new(statement))
+    })
+}
+
 fn to_ir_op(op: Operator) -> ir::BinOp {
     match op {
         Operator::Plus => Plus,
@@ -369,6 +718,10 @@ fn to_ir_op(op: Operator) -> ir::BinOp {
         Operator::And => And,
         Operator::Or => Or,
         Operator::Divide => Div,
+        Operator::ShiftLeft => ShiftLeft,
+        Operator::ShiftRight => ArithmeticShiftRight,
+        Operator::UnsignedShiftRight => ShiftRight,
+        Operator::Xor => Xor,
         _ => panic!("{:?} is not a binary operator", op),
     }
 }
@@ -387,11 +740,59 @@ fn to_ir_rel_op(op: Operator) -> RelationalOp {
 
 pub struct Gen<F: Frame> {
     fragments: Vec<Fragment<F>>,
+    /// The entries of the functions that call themselves in tail position.
+    loops: HashSet<Label>,
 }
 
-impl<F: Frame> Gen<F> {
+impl<F: Clone + Frame + PartialEq> Gen<F> {
     pub fn new() -> Self {
-        Self { fragments: vec![] }
+        Self {
+            fragments: vec![],
+            loops: HashSet::new(),
+        }
+    }
+
+    /// An exception is identified at runtime by the address of its name.
+    pub fn exception(&mut self, name: String) -> Label {
+        let label = Label::new();
+        self.fragments.push(Fragment::Str(label.clone(), name));
+        label
+    }
+
+    pub fn external_function(&mut self, label: Label) {
+        self.fragments.push(Fragment::Extern(label));
+    }
+
+    /// Export the function as the global symbol `name`: a wrapper called with the C calling
+    /// convention passes a null static link, since an exported function does not use the frame of
+    /// the main function.
+    pub fn export_function(
+        &mut self,
+        name: Label,
+        function: &Label,
+        parameters: Vec<(String, Type)>,
+        result: Type,
+    ) {
+        let frame = F::new(name.clone(), vec![false; parameters.len()]);
+        let mut arguments: Vec<_> = frame
+            .formals()
+            .iter()
+            .map(|formal| frame.exp(formal.clone(), Exp::Temp(F::fp())))
+            .collect();
+        arguments.push(Const(0));
+        let body = Move(
+            Exp::Temp(F::return_value()),
+            Call(Box::new(Name(function.clone())), arguments),
+        );
+        self.fragments.push(Fragment::Function {
+            body,
+            frame: Rc::new(RefCell::new(frame)),
+        });
+        self.fragments.push(Fragment::Export {
+            name,
+            parameters,
+            result,
+        });
     }
 
     pub fn get_result(self) -> Vec<Fragment<F>> {
@@ -399,13 +800,64 @@ impl<F: Frame> Gen<F> {
     }
 
     pub fn proc_entry_exit(&mut self, level: &Level<F>, body: Exp) {
-        let body = Move(Exp::Temp(F::return_value()), body);
+        let mut body = Move(Exp::Temp(F::return_value()), body);
+        if self.loops.remove(&level.entry) {
+            body = Sequence(
+                Box::new(Statement::Label(level.entry.clone())),
+                Box::new(body),
+            );
+        }
         self.fragments.push(Fragment::Function {
             body,
             frame: level.current.clone(),
         });
     }
 
+    /// A call whose result is the result of the calling function. A call of the function to
+    /// itself becomes a loop to the start of its body; a call to another function reuses the
+    /// frame, unless the callee needs it for its static link.
+    pub fn tail_call(
+        &mut self,
+        label: &Label,
+        args: Vec<Exp>,
+        parent_level: &Level<F>,
+        current_level: &Level<F>,
+    ) -> Exp {
+        if *current_level == *parent_level {
+            let frame = current_level.current.borrow();
+            let temps: Vec<_> = args.iter().map(|_| Temp::new()).collect();
+            let mut statements: Vec<_> = temps
+                .iter()
+                .zip(args)
+                .map(|(&temp, arg)| Move(Exp::Temp(temp), arg))
+                .collect();
+            // The static link, the last formal, stays the same.
+            for (formal, &temp) in frame.formals().iter().zip(&temps) {
+                statements.push(Move(
+                    frame.exp(formal.clone(), Exp::Temp(F::fp())),
+                    Exp::Temp(temp),
+                ));
+            }
+            let entry = current_level.entry.clone();
+            self.loops.insert(entry.clone());
+            statements.push(Jump(Name(entry.clone()), vec![entry]));
+            return ExpSequence(Box::new(sequence(statements)), Box::new(unit()));
+        }
+        match function_call(label, args, parent_level, current_level) {
+            Call(function, arguments) => {
+                if arguments.last() == Some(&Exp::Temp(F::fp())) {
+                    return Call(function, arguments);
+                }
+                let frame = parent_level.current.borrow();
+                match frame.tail_call(label.clone(), arguments.clone()) {
+                    Some(statement) => ExpSequence(Box::new(statement), Box::new(unit())),
+                    None => Call(function, arguments),
+                }
+            }
+            exp => exp,
+        }
+    }
+
     pub fn string_literal(&mut self, string: String) -> Exp {
         let label = Label::new();
         self.fragments.push(Fragment::Str(label.clone(), string));
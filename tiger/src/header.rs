@@ -0,0 +1,65 @@
+//! **C 头文件**。为导出的函数(`export function`)生成 C 语言的函数原型：`int`对应`int64_t`，
+//! 字符串是以 NUL 结尾的 UTF-8，对应`const char *`，记录和数组对 C 来说是不透明的指针`void *`，
+//! 没有返回值的函数返回`void`。
+
+use temp::Label;
+use types::Type;
+
+fn c_type(typ: &Type) -> &'static str {
+    match *typ {
+        Type::Int => "int64_t",
+        Type::String => "const char *",
+        Type::Array(..) | Type::Nil | Type::Record(..) => "void *",
+        Type::Name(_, Some(ref typ)) => c_type(typ),
+        Type::Unit => "void",
+        Type::Name(_, None) | Type::Bottom | Type::Error => {
+            unreachable!("type of exported function")
+        }
+    }
+}
+
+pub fn prototype(name: &Label, parameters: &[(String, Type)], result: &Type) -> String {
+    let parameters: Vec<_> = parameters
+        .iter()
+        .map(|(name, typ)| {
+            let typ = c_type(typ);
+            if typ.ends_with('*') {
+                format!("{}{}", typ, name)
+            } else {
+                format!("{} {}", typ, name)
+            }
+        })
+        .collect();
+    let parameters = if parameters.is_empty() {
+        "void".to_string()
+    } else {
+        parameters.join(", ")
+    };
+    let result = c_type(result);
+    let separator = if result.ends_with('*') { "" } else { " " };
+    format!("{}{}{}({});", result, separator, name, parameters)
+}
+
+/// The header `<file>.h` declaring the functions exported by `<file>.tig`.
+pub fn header(file_stem: &str, prototypes: &[String]) -> String {
+    let guard: String = file_stem
+        .chars()
+        .map(|char| {
+            if char.is_ascii_alphanumeric() {
+                char.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let mut header = format!(
+        "/* Functions exported by {}.tig. */\n\n#ifndef {}_H\n#define {}_H\n\n#include <stdint.h>\n\n",
+        file_stem, guard, guard
+    );
+    for prototype in prototypes {
+        header.push_str(prototype);
+        header.push('\n');
+    }
+    header.push_str("\n#endif\n");
+    header
+}
@@ -1,8 +1,9 @@
 #![allow(dead_code)]
 
+use frame::Frame;
 use temp::{Label, Temp};
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum Exp {
     Const(i64),
     /// Dummy expression to return when there is an error.
@@ -19,7 +20,7 @@ pub enum Exp {
     ExpSequence(Box<Statement>, Box<Exp>),
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum Statement {
     /// Move(dst, src)
     Move(Exp, Exp),
@@ -36,7 +37,7 @@ pub enum Statement {
     Label(Label),
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum BinOp {
     Plus,
     Minus,
@@ -50,7 +51,7 @@ pub enum BinOp {
     Xor,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum RelationalOp {
     Equal,
     NotEqual,
@@ -63,3 +64,110 @@ pub enum RelationalOp {
     UnsignedGreaterThan,
     UnsignedGreaterOrEqual,
 }
+
+impl Exp {
+    pub fn to_string<F: Frame>(&self) -> String {
+        match *self {
+            Exp::Const(num) => num.to_string(),
+            Exp::Error => "error".to_string(),
+            Exp::Name(ref label) => format!("name {}", label),
+            Exp::Temp(temp) => temp.to_string::<F>(),
+            Exp::BinOp {
+                ref op,
+                ref left,
+                ref right,
+            } => format!(
+                "({} {} {})",
+                left.to_string::<F>(),
+                op.symbol(),
+                right.to_string::<F>()
+            ),
+            Exp::Mem(ref exp) => format!("mem[{}]", exp.to_string::<F>()),
+            Exp::Call(ref function, ref arguments) => {
+                let arguments: Vec<_> = arguments.iter().map(Exp::to_string::<F>).collect();
+                format!(
+                    "call {}({})",
+                    function.to_string::<F>(),
+                    arguments.join(", ")
+                )
+            }
+            Exp::ExpSequence(ref statement, ref exp) => format!(
+                "eseq({}, {})",
+                statement.to_string::<F>(),
+                exp.to_string::<F>()
+            ),
+        }
+    }
+}
+
+impl Statement {
+    pub fn to_string<F: Frame>(&self) -> String {
+        match *self {
+            Statement::Move(ref destination, ref source) => format!(
+                "{} <- {}",
+                destination.to_string::<F>(),
+                source.to_string::<F>()
+            ),
+            Statement::Exp(ref exp) => exp.to_string::<F>(),
+            Statement::Jump(Exp::Name(ref label), _) => format!("jump {}", label),
+            Statement::Jump(ref exp, ref labels) => {
+                let labels: Vec<_> = labels.iter().map(ToString::to_string).collect();
+                format!("jump {} [{}]", exp.to_string::<F>(), labels.join(", "))
+            }
+            Statement::CondJump {
+                ref op,
+                ref left,
+                ref right,
+                ref true_label,
+                ref false_label,
+            } => format!(
+                "cjump {} {} {}, {}, {}",
+                left.to_string::<F>(),
+                op.symbol(),
+                right.to_string::<F>(),
+                true_label,
+                false_label
+            ),
+            Statement::Sequence(ref first, ref second) => format!(
+                "seq({}, {})",
+                first.to_string::<F>(),
+                second.to_string::<F>()
+            ),
+            Statement::Label(ref label) => format!("{}:", label),
+        }
+    }
+}
+
+impl BinOp {
+    fn symbol(&self) -> &'static str {
+        match *self {
+            BinOp::Plus => "+",
+            BinOp::Minus => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::And => "&",
+            BinOp::Or => "|",
+            BinOp::ShiftLeft => "<<",
+            BinOp::ShiftRight => ">>>",
+            BinOp::ArithmeticShiftRight => ">>",
+            BinOp::Xor => "^",
+        }
+    }
+}
+
+impl RelationalOp {
+    fn symbol(&self) -> &'static str {
+        match *self {
+            RelationalOp::Equal => "=",
+            RelationalOp::NotEqual => "<>",
+            RelationalOp::LesserThan => "<",
+            RelationalOp::GreaterThan => ">",
+            RelationalOp::LesserOrEqual => "<=",
+            RelationalOp::GreaterOrEqual => ">=",
+            RelationalOp::UnsignedLesserThan => "<u",
+            RelationalOp::UnsignedLesserOrEqual => "<=u",
+            RelationalOp::UnsignedGreaterThan => ">u",
+            RelationalOp::UnsignedGreaterOrEqual => ">=u",
+        }
+    }
+}
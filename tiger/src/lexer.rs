@@ -2,9 +2,10 @@ use std::char;
 use std::io::{Bytes, Read};
 use std::iter::Peekable;
 use std::result;
+use std::str;
 
 use error::num_text_size;
-use error::Error::{self, Eof, InvalidEscape, Msg, Unclosed, UnknownToken};
+use error::Error::{self, Eof, InvalidEscape, InvalidUtf8, Unclosed, UnknownToken};
 use position::Pos;
 use symbol::Symbol;
 use token::Tok::*;
@@ -36,8 +37,11 @@ impl<R: Read> Lexer<R> {
             }
             Some(Err(error)) => return Err(error.into()),
             None => return Err(Eof),
-            _ => {
-                self.pos.column += 1;
+            Some(Ok(byte)) => {
+                // Columns count characters: the continuation bytes of UTF-8 sequences are skipped.
+                if byte & 0xC0 != 0x80 {
+                    self.pos.column += 1;
+                }
                 self.pos.byte += 1;
             }
         }
@@ -104,31 +108,39 @@ impl<R: Read> Lexer<R> {
         self.advance()
     }
 
+    fn equal_or_arrow(&mut self) -> Result<Token> {
+        self.two_char_token(vec![('>', Arrow)], Equal)
+    }
+
+    /// `\ddd`: the character with the decimal code point ddd, which has exactly three digits.
     fn escape_ascii_code(&mut self, mut pos: Pos) -> Result<char> {
-        let buffer = self.take_while(char::is_numeric)?;
-        if buffer.len() == 3 {
-            // The buffer only contains digit, hence unwrap().
-            let ascii_code = buffer.parse().unwrap();
-            match char::from_u32(ascii_code) {
-                Some(ch) => Ok(ch),
-                None => Err(Msg(format!("Invalid ascii code {}", ascii_code))),
+        let mut buffer = String::new();
+        while buffer.len() < 3 && self.current_char()?.is_ascii_digit() {
+            buffer.push(self.current_char()?);
+            self.advance()?;
+        }
+        pos.length = buffer.len() + 1; // + 1 for the leading slash.
+        match buffer.parse() {
+            Ok(code) if buffer.len() == 3 && code != 0 && code <= 255 => {
+                Ok(char::from_u32(code).expect("latin-1 code point"))
             }
-        } else {
-            pos.length = buffer.len() + 1; // + 1 for the leading slash.
-            Err(InvalidEscape {
+            _ => Err(InvalidEscape {
                 escape: buffer,
                 pos,
-            })
+            }),
         }
     }
 
     fn escape_char(&mut self, mut pos: Pos) -> Result<char> {
         let escaped_char = match self.current_char()? {
             'n' => '\n',
+            'r' => '\r',
             't' => '\t',
             '\\' => '\\',
             '"' => '"',
-            ch if ch.is_digit(10) => return self.escape_ascii_code(pos),
+            '^' => return self.escape_control(pos),
+            'u' => return self.escape_unicode(pos),
+            ch if ch.is_ascii_digit() => return self.escape_ascii_code(pos),
             escape => {
                 pos.length = 2;
                 return Err(InvalidEscape {
@@ -141,8 +153,64 @@ impl<R: Read> Lexer<R> {
         Ok(escaped_char)
     }
 
-    fn greater_or_greater_equal(&mut self) -> Result<Token> {
-        self.two_char_token(vec![('=', GreaterOrEqual)], Greater)
+    /// `\^c`: the control character c, from `\^@` to `\^_` (letters in either case), and `\^?` for
+    /// delete.
+    fn escape_control(&mut self, mut pos: Pos) -> Result<char> {
+        self.eat('^')?;
+        let ch = self.current_char()?;
+        let control = match ch {
+            'A'..='Z' | '[' | '\\' | ']' | '^' | '_' => ch as u8 - b'@',
+            'a'..='z' => ch as u8 - b'`',
+            '?' => 0x7F,
+            _ => {
+                pos.length = 2;
+                return Err(InvalidEscape {
+                    escape: "^".to_string(),
+                    pos,
+                });
+            }
+        };
+        self.advance()?;
+        Ok(control as char)
+    }
+
+    /// `\u{h...}`: the character with the hexadecimal code point h..., from 1 to 6 digits.
+    fn escape_unicode(&mut self, mut pos: Pos) -> Result<char> {
+        self.eat('u')?;
+        let mut escape = "u".to_string();
+        let mut digits = String::new();
+        let mut closed = false;
+        if self.current_char()? == '{' {
+            escape.push('{');
+            self.advance()?;
+            while digits.len() <= 6 && self.current_char()?.is_ascii_hexdigit() {
+                digits.push(self.current_char()?);
+                self.advance()?;
+            }
+            escape.push_str(&digits);
+            if self.current_char()? == '}' {
+                escape.push('}');
+                self.advance()?;
+                closed = true;
+            }
+        }
+        pos.length = escape.len() + 1; // + 1 for the leading slash.
+        let code = u32::from_str_radix(&digits, 16).ok();
+        match code.and_then(char::from_u32) {
+            Some(ch) if closed && digits.len() <= 6 && ch != '\0' => Ok(ch),
+            _ => Err(InvalidEscape { escape, pos }),
+        }
+    }
+
+    fn greater_or_greater_equal_or_shift_right(&mut self) -> Result<Token> {
+        let token = self.two_char_token(vec![('=', GreaterOrEqual), ('>', ShiftRight)], Greater)?;
+        if let ShiftRight = token.token {
+            if let Some(&Ok(b'>')) = self.bytes_iter.peek() {
+                self.advance()?;
+                return self.make_token(UnsignedShiftRight, 3);
+            }
+        }
+        Ok(token)
     }
 
     fn identifier(&mut self) -> Result<Token> {
@@ -154,15 +222,22 @@ impl<R: Read> Lexer<R> {
             "do" => Do,
             "else" => Else,
             "end" => End,
+            "exception" => Exception,
+            "export" => Export,
+            "extern" => Extern,
             "for" => For,
             "function" => Function,
+            "handle" => Handle,
             "if" => If,
             "in" => In,
             "let" => Let,
             "nil" => Nil,
+            "not" => Not,
             "of" => Of,
+            "raise" => Raise,
             "then" => Then,
             "to" => To,
+            "try" => Try,
             "type" => Type,
             "var" => Var,
             "while" => While,
@@ -178,8 +253,11 @@ impl<R: Read> Lexer<R> {
         self.make_token(Int(num), num_text_size(num))
     }
 
-    fn lesser_or_lesser_equal_or_not_equal(&mut self) -> Result<Token> {
-        self.two_char_token(vec![('=', LesserOrEqual), ('>', NotEqual)], Lesser)
+    fn lesser_or_lesser_equal_or_not_equal_or_shift_left(&mut self) -> Result<Token> {
+        self.two_char_token(
+            vec![('=', LesserOrEqual), ('>', NotEqual), ('<', ShiftLeft)],
+            Lesser,
+        )
     }
 
     fn make_token(&self, token: Tok, length: usize) -> Result<Token> {
@@ -240,11 +318,13 @@ impl<R: Read> Lexer<R> {
         }
     }
 
+    /// Strings are sequences of Unicode characters: the source is read as UTF-8 and escapes denote
+    /// code points.
     fn string(&mut self) -> Result<Token> {
+        self.save_start();
+        let start_pos = self.current_pos();
         let result = (|| {
-            self.save_start();
             let mut string = String::new();
-            let start = self.current_pos().byte;
             self.eat('"')?;
             let mut ch = self.current_char()?;
             while ch != '"' {
@@ -258,18 +338,22 @@ impl<R: Read> Lexer<R> {
                         string.push(self.escape_char(pos)?);
                     }
                 } else {
-                    string.push(ch);
-                    self.advance()?;
+                    string.push(self.utf8_char()?);
                 }
                 ch = self.current_char()?;
             }
             self.eat('"')?;
-            let len = self.current_pos().byte - start;
-            self.make_token(Str(string), len as usize)
+            let len = self.current_pos().byte - start_pos.byte;
+            let mut pos = start_pos;
+            pos.length = len as usize;
+            Ok(Token {
+                pos,
+                token: Str(string),
+            })
         })();
         match result {
             Err(Eof) => {
-                let mut pos = self.saved_pos.clone();
+                let mut pos = start_pos;
                 pos.length = 1;
                 Err(Unclosed {
                     pos,
@@ -303,9 +387,11 @@ impl<R: Read> Lexer<R> {
                     self.advance()?;
                     self.token()
                 }
-                b'=' => self.simple_token(Equal),
+                b'=' => self.equal_or_arrow(),
                 b'&' => self.simple_token(Ampersand),
                 b'|' => self.simple_token(Pipe),
+                b'^' => self.simple_token(Caret),
+                b'%' => self.simple_token(Percent),
                 b'.' => self.simple_token(Dot),
                 b',' => self.simple_token(Comma),
                 b';' => self.simple_token(Semicolon),
@@ -319,8 +405,8 @@ impl<R: Read> Lexer<R> {
                 b'[' => self.simple_token(OpenSquare),
                 b']' => self.simple_token(CloseSquare),
                 b':' => self.colon_and_optional_equal(),
-                b'>' => self.greater_or_greater_equal(),
-                b'<' => self.lesser_or_lesser_equal_or_not_equal(),
+                b'>' => self.greater_or_greater_equal_or_shift_right(),
+                b'<' => self.lesser_or_lesser_equal_or_not_equal_or_shift_left(),
                 b'/' => self.slash_or_comment(),
                 b'"' => self.string(),
                 _ => {
@@ -340,6 +426,30 @@ impl<R: Read> Lexer<R> {
         }
     }
 
+    fn utf8_char(&mut self) -> Result<char> {
+        let mut pos = self.current_pos();
+        let first_byte = self.current_char()? as u8;
+        let length = match first_byte {
+            0x00..=0x7F => 1,
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            _ => 4,
+        };
+        let mut bytes = vec![first_byte];
+        self.advance()?;
+        while bytes.len() < length && self.current_char()? as u8 & 0xC0 == 0x80 {
+            bytes.push(self.current_char()? as u8);
+            self.advance()?;
+        }
+        match str::from_utf8(&bytes) {
+            Ok(string) if first_byte != 0 => Ok(string.chars().next().expect("utf-8 char")),
+            _ => {
+                pos.length = 1;
+                Err(InvalidUtf8 { pos })
+            }
+        }
+    }
+
     fn two_char_token(&mut self, tokens: Vec<(char, Tok)>, default: Tok) -> Result<Token> {
         self.save_start();
         self.advance()?;
@@ -9,53 +9,85 @@ nasm -f elf64 tests/hello.s
                      -lgcc_s --no-as-needed /usr/lib/crtn.o
 */
 
+use std::borrow::Cow;
+use std::char;
 use std::ffi::{CStr, CString};
-//use std::process;
 use std::io::{stdin, stdout, Read, Write};
+use std::os::raw::{c_char, c_int};
+use std::process;
+use std::ptr;
 
 /*extern {
     fn main();
 }*/
 
+/*
+ * Strings.
+ * A string is a sequence of Unicode characters, stored as NUL-terminated UTF-8. Sizes, indices and
+ * the values of ord and chr count characters (code points), not bytes.
+ */
+
+fn to_str<'a>(string: *const i8) -> Cow<'a, str> {
+    unsafe { CStr::from_ptr(string) }.to_string_lossy()
+}
+
+fn to_tiger_string(string: &str) -> *const i8 {
+    let cstring = CString::new(string).expect("CString::new");
+    cstring.into_raw()
+}
+
+/// The code point of the first character, or -1 for the empty string.
 #[no_mangle]
 extern "C" fn ord(string: *const i8) -> i64 {
-    let cstring = unsafe { CStr::from_ptr(string) };
-    cstring
-        .to_str()
-        .expect("cstr to_str")
-        .chars()
-        .next()
-        .expect("ord string is empty") as i64
+    to_str(string).chars().next().map_or(-1, |char| char as i64)
 }
 
 #[no_mangle]
 extern "C" fn chr(num: i64) -> *const i8 {
-    let char = num as u8 as char;
-    let cstring = CString::new(char.to_string()).expect("CString::new");
-    cstring.into_raw()
+    let char = if num > 0 && num <= 0x10FFFF {
+        char::from_u32(num as u32)
+    } else {
+        None
+    };
+    match char {
+        Some(char) => to_tiger_string(&char.to_string()),
+        None => {
+            let exception = exceptionInvalidCharacter.as_ptr() as *const c_char;
+            raiseException(exception, ptr::null(), 0, 0)
+        }
+    }
 }
 
+/// Read one character from the standard input, or return the empty string at the end of the input.
 #[no_mangle]
 extern "C" fn getchar() -> *const i8 {
     let stdin = stdin();
-    let char = stdin
-        .bytes()
-        .next()
-        .expect("next char")
-        .expect("read stdin") as char;
-    let cstring = CString::new(char.to_string()).expect("CString::new");
-    cstring.into_raw()
+    let mut bytes = stdin.lock().bytes();
+    let first_byte = match bytes.next() {
+        Some(Ok(byte)) => byte,
+        _ => return to_tiger_string(""),
+    };
+    let length = match first_byte {
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => 1,
+    };
+    let mut buffer = vec![first_byte];
+    while buffer.len() < length {
+        match bytes.next() {
+            Some(Ok(byte)) => buffer.push(byte),
+            _ => break,
+        }
+    }
+    to_tiger_string(&String::from_utf8_lossy(&buffer))
 }
 
 #[no_mangle]
 extern "C" fn concat(string1: *const i8, string2: *const i8) -> *const i8 {
-    let cstring1 = unsafe { CStr::from_ptr(string1) };
-    let cstring2 = unsafe { CStr::from_ptr(string2) };
-    let mut string1 = cstring1.to_str().expect("to_str").to_string();
-    let string2 = cstring2.to_str().expect("to_str").to_string();
-    string1.push_str(&string2);
-    let cstring = CString::new(string1).expect("CString::new");
-    cstring.into_raw()
+    let mut string1 = to_str(string1).into_owned();
+    string1.push_str(&to_str(string2));
+    to_tiger_string(&string1)
 }
 
 #[no_mangle]
@@ -67,23 +99,162 @@ extern "C" fn stringEqual(string1: *const i8, string2: *const i8) -> i64 {
 
 #[no_mangle]
 extern "C" fn initArray(length: usize, init_value: i64) -> i64 {
-    Box::into_raw(vec![init_value; length].into_boxed_slice()) as *mut i64 as i64
+    // The length is stored before the first element for the bounds checks.
+    let mut array = vec![init_value; length + 1];
+    array[0] = length as i64;
+    let array = Box::into_raw(array.into_boxed_slice()) as *mut i64;
+    unsafe { array.offset(1) as i64 }
 }
 
+/*
+ * Exceptions.
+ * An exception is identified by the address of its name.
+ */
+
 #[no_mangle]
-extern "C" fn print(string: *const i8) {
-    let cstring = unsafe { CStr::from_ptr(string) };
-    if let Ok(string) = cstring.to_str() {
-        print!("{}", string);
+pub static exceptionDivisionByZero: [u8; 15] = *b"DivisionByZero\0";
+#[no_mangle]
+pub static exceptionIndexOutOfBounds: [u8; 17] = *b"IndexOutOfBounds\0";
+#[no_mangle]
+pub static exceptionInvalidCharacter: [u8; 17] = *b"InvalidCharacter\0";
+#[no_mangle]
+pub static exceptionNilAccess: [u8; 10] = *b"NilAccess\0";
+#[no_mangle]
+pub static exceptionOverflow: [u8; 9] = *b"Overflow\0";
+
+// Big enough for the jmp_buf of glibc (200 bytes on x86-64).
+const JUMP_BUFFER_SIZE: usize = 32;
+
+type JumpBuffer = [u64; JUMP_BUFFER_SIZE];
+
+struct Handler {
+    jump_buffer: JumpBuffer,
+    previous: *mut Handler,
+}
+
+struct RaisePosition {
+    file: *const c_char,
+    line: i64,
+    column: i64,
+}
+
+static mut EXCEPTION: *const c_char = ptr::null();
+static mut HANDLERS: *mut Handler = ptr::null_mut();
+static mut JUMP_BUFFER: JumpBuffer = [0; JUMP_BUFFER_SIZE];
+static mut RAISE_POSITION: RaisePosition = RaisePosition {
+    file: ptr::null(),
+    line: 0,
+    column: 0,
+};
+
+extern "C" {
+    fn longjmp(env: *mut u64, value: c_int) -> !;
+}
+
+/// Install a new handler and return the buffer to give to setjmp.
+#[no_mangle]
+extern "C" fn pushHandler() -> *mut u64 {
+    let handler = Box::into_raw(Box::new(Handler {
+        jump_buffer: [0; JUMP_BUFFER_SIZE],
+        previous: unsafe { HANDLERS },
+    }));
+    unsafe {
+        HANDLERS = handler;
+        (*handler).jump_buffer.as_mut_ptr()
+    }
+}
+
+#[no_mangle]
+extern "C" fn popHandler() {
+    unsafe {
+        let handler = Box::from_raw(HANDLERS);
+        HANDLERS = handler.previous;
+    }
+}
+
+#[no_mangle]
+extern "C" fn currentException() -> *const c_char {
+    unsafe { EXCEPTION }
+}
+
+/// Jump to the innermost handler. A null file means the exception is re-raised by a handler that
+/// does not handle it, so the position of the original raise is kept.
+#[no_mangle]
+extern "C" fn raiseException(
+    exception: *const c_char,
+    file: *const c_char,
+    line: i64,
+    column: i64,
+) -> ! {
+    unsafe {
+        EXCEPTION = exception;
+        if !file.is_null() {
+            RAISE_POSITION = RaisePosition { file, line, column };
+        }
+        if HANDLERS.is_null() {
+            let _ = stdout().flush();
+            let name = CStr::from_ptr(exception).to_string_lossy();
+            let position = &*ptr::addr_of!(RAISE_POSITION);
+            if position.file.is_null() {
+                eprintln!("Uncaught exception {}", name);
+            } else {
+                let file = CStr::from_ptr(position.file).to_string_lossy();
+                eprintln!(
+                    "{}:{}:{}: uncaught exception {}",
+                    file, position.line, position.column, name
+                );
+            }
+            process::exit(1);
+        }
+        let handler = Box::from_raw(HANDLERS);
+        HANDLERS = handler.previous;
+        // The handler is freed before jumping, so its buffer is copied first.
+        JUMP_BUFFER = handler.jump_buffer;
+        drop(handler);
+        longjmp(ptr::addr_of_mut!(JUMP_BUFFER) as *mut u64, 1);
     }
-    let _ = stdout().flush();
 }
 
 #[no_mangle]
-extern "C" fn printi(num: i32) {
+extern "C" fn print(string: *const i8) {
+    let bytes = unsafe { CStr::from_ptr(string) }.to_bytes();
+    let mut stdout = stdout();
+    let _ = stdout.write_all(bytes);
+    let _ = stdout.flush();
+}
+
+#[no_mangle]
+extern "C" fn printi(num: i64) {
     println!("{}", num);
 }
 
+#[no_mangle]
+extern "C" fn flush() {
+    let _ = stdout().flush();
+}
+
+#[no_mangle]
+extern "C" fn size(string: *const i8) -> i64 {
+    to_str(string).chars().count() as i64
+}
+
+/// The `length` characters starting at the character `first`.
+#[no_mangle]
+extern "C" fn substring(string: *const i8, first: i64, length: i64) -> *const i8 {
+    let string = to_str(string);
+    let count = string.chars().count() as i64;
+    if first < 0 || length < 0 || first.checked_add(length).is_none_or(|end| end > count) {
+        let exception = exceptionIndexOutOfBounds.as_ptr() as *const c_char;
+        raiseException(exception, ptr::null(), 0, 0);
+    }
+    let substring: String = string
+        .chars()
+        .skip(first as usize)
+        .take(length as usize)
+        .collect();
+    to_tiger_string(&substring)
+}
+
 /*#[no_mangle]
 extern fn _start() {
     unsafe {
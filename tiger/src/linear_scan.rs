@@ -0,0 +1,315 @@
+//! **线性扫描寄存器分配**（Poletto和Sarkar）。图着色分配需要构造冲突图并反复简化、合并，在
+//! 有成千上万个临时变量的函数上很慢。线性扫描把指令排成一行，为每个临时变量计算一个活跃区间：
+//! 从它第一次活跃的位置到最后一次活跃的位置。区间按起点排序后依次扫描，已经结束的区间释放它们的
+//! 寄存器；没有空闲寄存器时，溢出结束得最晚的那个区间。
+//!
+//! 每条指令占两个位置：在`2i`读取源操作数，在`2i + 1`写入目标操作数，所以一条指令最后一次使用的
+//! 临时变量可以和它定值的临时变量共用一个寄存器。机器寄存器（参数、返回值、被调用破坏的寄存器）
+//! 不形成区间，而是记录它们活跃的所有位置：只有在区间内不活跃的机器寄存器才能分配给这个区间。
+//!
+//! 活跃区间由`flow::FlowGraph`上的活跃分析得到。溢出的临时变量在每条使用它的指令中换成一个
+//! 新的临时变量，在指令之前从栈帧载入、之后存回，然后重新分配；这些新的临时变量不会再被溢出。
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use asm::Instruction;
+use flow::instructions_to_graph;
+use frame::Frame;
+use ir::{Exp, Statement};
+use liveness::Liveness;
+use reg_alloc::{replace_allocation, temp_count, Allocation, RegisterAllocator, Statistics};
+use temp::Temp;
+
+/// Linear scan on the live intervals of the temporaries.
+pub struct LinearScan;
+
+impl<F: Frame> RegisterAllocator<F> for LinearScan {
+    fn allocate(
+        &self,
+        instructions: Vec<Instruction>,
+        frame: &mut F,
+    ) -> (Vec<Instruction>, Statistics) {
+        let mut statistics = Statistics {
+            temps: temp_count::<F>(&instructions),
+            ..Statistics::default()
+        };
+        let mut instructions = instructions;
+        let mut unspillable = BTreeSet::new();
+        loop {
+            let (allocation, spills) = scan::<F>(&instructions, &unspillable);
+            statistics.iterations += 1;
+            if spills.is_empty() {
+                // The moves between temporaries given the same register are removed.
+                let count = instructions.len();
+                let instructions = replace_allocation::<F>(instructions, allocation);
+                statistics.coalesced_moves = count - instructions.len();
+                return (instructions, statistics);
+            }
+            statistics.spills += spills.len();
+            let old_temps = temps(&instructions);
+            instructions = rewrite_program(instructions, &spills, frame);
+            unspillable.extend(temps(&instructions).difference(&old_temps));
+        }
+    }
+}
+
+fn temps(instructions: &[Instruction]) -> BTreeSet<Temp> {
+    let mut temps = BTreeSet::new();
+    for instruction in instructions {
+        let (source, destination) = operands(instruction);
+        temps.extend(source.iter().chain(destination));
+    }
+    temps
+}
+
+fn operands(instruction: &Instruction) -> (&[Temp], &[Temp]) {
+    match *instruction {
+        Instruction::Label { .. } => (&[], &[]),
+        Instruction::Move {
+            ref source,
+            ref destination,
+            ..
+        }
+        | Instruction::Operation {
+            ref source,
+            ref destination,
+            ..
+        } => (source, destination),
+    }
+}
+
+/// Replace each spilled temporary by a new temporary in every instruction using it, loaded from
+/// the frame before the instruction and stored after it. Unlike `reg_alloc::rewrite_program`, the
+/// spilled temporary does not stay in the instructions: its interval would still cover all its uses.
+fn rewrite_program<F: Frame>(
+    instructions: Vec<Instruction>,
+    spills: &[Temp],
+    frame: &mut F,
+) -> Vec<Instruction> {
+    let mut memory = HashMap::new();
+    for &spill in spills {
+        let local = frame.alloc_local(true);
+        memory.insert(spill, frame.exp(local, Exp::Temp(F::fp())));
+    }
+    let mut result = vec![];
+    for mut instruction in instructions {
+        let (loads, stores) = match instruction {
+            Instruction::Label { .. } => (vec![], vec![]),
+            Instruction::Move {
+                ref mut source,
+                ref mut destination,
+                ..
+            }
+            | Instruction::Operation {
+                ref mut source,
+                ref mut destination,
+                ..
+            } => {
+                let mut new_temps = HashMap::new();
+                let mut rename = |temps: &mut Vec<Temp>| {
+                    let mut renamed = vec![];
+                    for temp in temps {
+                        if memory.contains_key(temp) {
+                            let new_temp = *new_temps.entry(*temp).or_insert_with(Temp::new);
+                            renamed.push((*temp, new_temp));
+                            *temp = new_temp;
+                        }
+                    }
+                    renamed
+                };
+                (rename(source), rename(destination))
+            }
+        };
+        for (spill, temp) in loads {
+            result.extend(F::codegen(Statement::Move(
+                Exp::Temp(temp),
+                memory[&spill].clone(),
+            )));
+        }
+        result.push(instruction);
+        for (spill, temp) in stores {
+            result.extend(F::codegen(Statement::Move(
+                memory[&spill].clone(),
+                Exp::Temp(temp),
+            )));
+        }
+    }
+    result
+}
+
+#[derive(Clone, Copy)]
+struct Interval {
+    temp: Temp,
+    start: usize,
+    end: usize,
+}
+
+/// The positions where each machine register is live, in increasing order.
+struct FixedRegisters {
+    positions: HashMap<Temp, Vec<usize>>,
+}
+
+impl FixedRegisters {
+    fn is_live_in(&self, register: Temp, interval: &Interval) -> bool {
+        match self.positions.get(&register) {
+            Some(positions) => {
+                let first = positions
+                    .binary_search(&interval.start)
+                    .unwrap_or_else(|index| index);
+                positions
+                    .get(first)
+                    .is_some_and(|&position| position <= interval.end)
+            }
+            None => false,
+        }
+    }
+}
+
+/// Compute the live intervals of the temporaries and the positions of the machine registers.
+fn intervals<F: Frame>(instructions: &[Instruction]) -> (Vec<Interval>, FixedRegisters) {
+    let flow_graph = instructions_to_graph::<F>(instructions);
+    let liveness = Liveness::new(&flow_graph);
+    let precolored = F::temp_map();
+    let mut ranges: HashMap<Temp, (usize, usize)> = HashMap::new();
+    let mut fixed_registers = FixedRegisters {
+        positions: HashMap::new(),
+    };
+    {
+        let mut add = |temp: Temp, position: usize| {
+            if precolored.contains_key(&temp) {
+                let positions = fixed_registers.positions.entry(temp).or_default();
+                if positions.last() != Some(&position) {
+                    positions.push(position);
+                }
+            } else {
+                let range = ranges.entry(temp).or_insert((position, position));
+                range.1 = position;
+            }
+        };
+        for (index, instruction) in instructions.iter().enumerate() {
+            let (source, destination) = operands(instruction);
+            for &temp in source {
+                add(temp, 2 * index);
+            }
+            for &temp in destination {
+                add(temp, 2 * index + 1);
+            }
+            // The unreachable instructions have no node: they still need registers, but nothing
+            // is live across them.
+            if let Some(node) = flow_graph.instruction_node(index) {
+                for temp in liveness.live_in(node.index()) {
+                    add(temp, 2 * index);
+                }
+                for temp in liveness.live_out(node.index()) {
+                    add(temp, 2 * index + 1);
+                }
+            }
+        }
+    }
+
+    let mut intervals: Vec<_> = ranges
+        .into_iter()
+        .map(|(temp, (start, end))| Interval { temp, start, end })
+        .collect();
+    intervals.sort_by_key(|interval| (interval.start, interval.temp));
+    (intervals, fixed_registers)
+}
+
+/// The register of the other side of the moves of each temporary: giving the same register removes
+/// the move.
+fn move_hints(instructions: &[Instruction]) -> HashMap<Temp, Vec<Temp>> {
+    let mut hints: HashMap<Temp, Vec<Temp>> = HashMap::new();
+    for instruction in instructions {
+        if let Instruction::Move {
+            ref source,
+            ref destination,
+            ..
+        } = *instruction
+        {
+            if let (Some(&source), Some(&destination)) = (source.first(), destination.first()) {
+                hints.entry(source).or_default().push(destination);
+                hints.entry(destination).or_default().push(source);
+            }
+        }
+    }
+    hints
+}
+
+fn scan<F: Frame>(
+    instructions: &[Instruction],
+    unspillable: &BTreeSet<Temp>,
+) -> (Allocation, Vec<Temp>) {
+    let (intervals, fixed_registers) = intervals::<F>(instructions);
+    let hints = move_hints(instructions);
+    let registers = F::registers();
+    let mut allocation = Allocation::new();
+    for register in F::temp_map().keys() {
+        allocation.insert(*register, *register);
+    }
+    let mut spills = vec![];
+    // The intervals which have a register, by increasing end.
+    let mut active: Vec<Interval> = vec![];
+    for interval in intervals {
+        active.retain(|active| active.end >= interval.start);
+        let used: HashSet<_> = active
+            .iter()
+            .map(|active| allocation[&active.temp])
+            .collect();
+        let free: Vec<_> = registers
+            .iter()
+            .cloned()
+            .filter(|register| {
+                !used.contains(register) && !fixed_registers.is_live_in(*register, &interval)
+            })
+            .collect();
+        let hint = hints
+            .get(&interval.temp)
+            .into_iter()
+            .flatten()
+            .filter_map(|temp| allocation.get(temp))
+            .find(|register| free.contains(register))
+            .cloned();
+        if let Some(register) = hint.or_else(|| free.first().cloned()) {
+            allocation.insert(interval.temp, register);
+            insert_active(&mut active, interval);
+            continue;
+        }
+
+        // Spill the interval ending last, if its register can hold the current interval.
+        let victim = active
+            .iter()
+            .enumerate()
+            .filter(|&(_, active)| {
+                !unspillable.contains(&active.temp)
+                    && !fixed_registers.is_live_in(allocation[&active.temp], &interval)
+            })
+            .max_by_key(|&(_, active)| active.end)
+            .map(|(index, active)| (index, *active));
+        match victim {
+            Some((index, victim))
+                if victim.end > interval.end || unspillable.contains(&interval.temp) =>
+            {
+                let register = allocation
+                    .remove(&victim.temp)
+                    .expect("register of active interval");
+                active.remove(index);
+                spills.push(victim.temp);
+                allocation.insert(interval.temp, register);
+                insert_active(&mut active, interval);
+            }
+            _ if unspillable.contains(&interval.temp) => {
+                panic!("No register for the spill temporary {:?}", interval.temp)
+            }
+            _ => spills.push(interval.temp),
+        }
+    }
+    (allocation, spills)
+}
+
+fn insert_active(active: &mut Vec<Interval>, interval: Interval) {
+    let index = active
+        .iter()
+        .position(|active| active.end > interval.end)
+        .unwrap_or(active.len());
+    active.insert(index, interval);
+}
@@ -7,10 +7,11 @@
 //! 2. 如果一个变量在结点`n`是入口活跃的，那么它在所有属于`pred[n]`的结点`m`中都是出口活跃的。
 //! 3. 如果一个变量在结点`n`是出口活跃的，而且不属于`def[n]`，则该变量在结点`n`是入口活跃的。也就是说，如果变量`a`的值在语句`n`结束后还需使用，但是`n`并没有对`a`赋值，则`a`的值在进入`n`的入口时就是需要使用的。
 
-use std::collections::{BTreeSet, HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::iter;
 
 use flow::FlowGraph;
-use frame::{x86_64::X86_64, Frame};
+use frame::Frame;
 use graph::{Entry, Graph, Node};
 use temp::Temp;
 
@@ -23,6 +24,9 @@ pub struct InterferenceGraph {
     graph: Graph<Temp>,
     _temp_nodes: HashMap<Temp, Entry>,
     pub move_list: HashMap<Temp, BTreeSet<(Temp, Temp)>>,
+    /// The temporaries live across a call of `_setjmp`: a handler reads them after the second
+    /// return, which the flow graph does not show.
+    pub setjmp_live: HashSet<Temp>,
     pub worklist_moves: BTreeSet<(Temp, Temp)>,
 }
 
@@ -31,78 +35,299 @@ impl InterferenceGraph {
         self.graph.nodes()
     }
 
-    pub fn _show(&self) {
+    /// The graph in the DOT language of Graphviz, named after the function: the machine registers
+    /// are filled and the moves are dashed edges.
+    pub fn to_dot<F: Frame>(&self, name: &str) -> String {
+        let precolored = F::temp_map();
         let nodes = self.graph.nodes();
-        for node in self.graph.nodes() {
-            let name = X86_64::special_name(node.get().clone())
-                .map(ToString::to_string)
-                .unwrap_or_else(|| format!("{:?}", node.get()));
-            println!("Node: {}", name);
-            for &neighbor in node.predecessors() {
-                let name = X86_64::special_name(nodes[neighbor.index()].get().clone())
-                    .map(ToString::to_string)
-                    .unwrap_or_else(|| format!("{:?}", nodes[neighbor.index()].get()));
-                println!("<<< {}", name);
-            }
+        // A temporary can have several nodes: it gets one for each definition and live range.
+        let temps: BTreeSet<Temp> = nodes.iter().map(|node| *node.get()).collect();
+        let mut edges = BTreeSet::new();
+        for node in nodes {
             for &neighbor in node.successors() {
-                let name = X86_64::special_name(nodes[neighbor.index()].get().clone())
-                    .map(ToString::to_string)
-                    .unwrap_or_else(|| format!("{:?}", nodes[neighbor.index()].get()));
-                println!(">>> {}", name);
+                let (temp1, temp2) = (*node.get(), *nodes[neighbor.index()].get());
+                if temp1 != temp2 {
+                    edges.insert((temp1.min(temp2), temp1.max(temp2)));
+                }
+            }
+        }
+
+        let mut dot = format!("graph \"{}\" {{\n", name);
+        for temp in &temps {
+            if precolored.contains_key(temp) {
+                dot.push_str(&format!(
+                    "    {} [style=filled, fillcolor=lightblue];\n",
+                    temp.to_string::<F>()
+                ));
+            } else {
+                dot.push_str(&format!("    {};\n", temp.to_string::<F>()));
+            }
+        }
+        for &(temp1, temp2) in &edges {
+            dot.push_str(&format!(
+                "    {} -- {};\n",
+                temp1.to_string::<F>(),
+                temp2.to_string::<F>()
+            ));
+        }
+        for &(destination, source) in &self.worklist_moves {
+            if destination != source {
+                dot.push_str(&format!(
+                    "    {} -- {} [style=dashed];\n",
+                    destination.to_string::<F>(),
+                    source.to_string::<F>()
+                ));
             }
         }
+        dot.push_str("}\n");
+        dot
     }
 }
 
+/// A set of temporaries numbered densely by `Liveness`, one bit per temporary.
+#[derive(Clone, PartialEq)]
+struct BitSet {
+    words: Vec<u64>,
+}
+
+impl BitSet {
+    fn new(size: usize) -> Self {
+        Self {
+            words: vec![0; size.div_ceil(64)],
+        }
+    }
+
+    fn insert(&mut self, index: usize) {
+        self.words[index / 64] |= 1 << (index % 64);
+    }
+
+    fn union_with(&mut self, other: &BitSet) {
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+
+    /// `self = uses ∪ (self - defines)`
+    fn transfer(&mut self, uses: &BitSet, defines: &BitSet) {
+        for ((word, uses), defines) in self.words.iter_mut().zip(&uses.words).zip(&defines.words) {
+            *word = uses | (*word & !defines);
+        }
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = usize> + 'a {
+        self.words.iter().enumerate().flat_map(|(index, &word)| {
+            let mut word = word;
+            iter::from_fn(move || {
+                if word == 0 {
+                    return None;
+                }
+                let bit = word.trailing_zeros() as usize;
+                word &= word - 1;
+                Some(index * 64 + bit)
+            })
+        })
+    }
+}
+
+/// The live-in and live-out sets of the nodes of the flow graph.
+///
 /// ```text
 /// in[n] = use[n] ∪ (out[n] - def[n])
 /// out[n] = ∀s ∈ succ[n]: ∪in[s]
 /// ```
-pub fn interference_graph(graph: FlowGraph) -> InterferenceGraph {
-    let mut live_in: HashMap<usize, HashSet<Temp>> = HashMap::new();
-    let mut live_out: HashMap<usize, HashSet<Temp>> = HashMap::new();
+///
+/// The equations are solved on the basic blocks of the graph (the chains of nodes with a single
+/// successor and a single predecessor), then the sets of the nodes are computed once, backward in
+/// each block. The blocks are visited in postorder, so that the successors of a block are usually
+/// computed before it, and a block is visited again only when the live-in set of one of its
+/// successors changes.
+pub struct Liveness {
+    /// The temporaries, by their index in the bit sets.
+    temps: Vec<Temp>,
+    live_in: Vec<BitSet>,
+    live_out: Vec<BitSet>,
+}
 
-    let mut worklist_moves = BTreeSet::new();
+impl Liveness {
+    pub fn new(graph: &FlowGraph) -> Self {
+        let nodes = graph.nodes();
+        let mut temps = vec![];
+        let mut indices = HashMap::new();
+        for node in nodes {
+            for &temp in node.uses.iter().chain(&node.defines) {
+                indices.entry(temp).or_insert_with(|| {
+                    temps.push(temp);
+                    temps.len() - 1
+                });
+            }
+        }
+        let to_set = |temps: &HashSet<Temp>| {
+            let mut set = BitSet::new(indices.len());
+            for temp in temps {
+                set.insert(indices[temp]);
+            }
+            set
+        };
+        let uses: Vec<_> = nodes.iter().map(|node| to_set(&node.uses)).collect();
+        let defines: Vec<_> = nodes.iter().map(|node| to_set(&node.defines)).collect();
 
-    let mut new_live_in = HashMap::new();
-    let mut new_live_out = HashMap::new();
+        // A block starts at the entry, at a join and after a branch.
+        let is_start = |index: usize| match nodes[index].predecessors() {
+            [predecessor] => index == 0 || nodes[predecessor.index()].successors().len() != 1,
+            _ => true,
+        };
+        let mut blocks = vec![];
+        let mut block_of = vec![0; nodes.len()];
+        for start in (0..nodes.len()).filter(|&index| is_start(index)) {
+            let mut block = vec![start];
+            let mut current = start;
+            while let [successor] = nodes[current].successors() {
+                if is_start(successor.index()) {
+                    break;
+                }
+                current = successor.index();
+                block.push(current);
+            }
+            for &node in &block {
+                block_of[node] = blocks.len();
+            }
+            blocks.push(block);
+        }
+        let block_successors: Vec<Vec<usize>> = blocks
+            .iter()
+            .map(|block| {
+                let last = *block.last().expect("node in block");
+                nodes[last]
+                    .successors()
+                    .iter()
+                    .map(|successor| block_of[successor.index()])
+                    .collect()
+            })
+            .collect();
+        let mut block_predecessors = vec![vec![]; blocks.len()];
+        for (block, successors) in block_successors.iter().enumerate() {
+            for &successor in successors {
+                block_predecessors[successor].push(block);
+            }
+        }
 
-    loop {
-        for (index, node) in graph.nodes().iter().enumerate() {
-            new_live_in.insert(index, live_in.get(&index).cloned().unwrap_or_default());
-            new_live_out.insert(index, live_out.get(&index).cloned().unwrap_or_default());
+        // use[b] and def[b] compose the transfer functions of the nodes, from the last one.
+        let empty = BitSet::new(indices.len());
+        let mut block_uses = vec![empty.clone(); blocks.len()];
+        let mut block_defines = vec![empty.clone(); blocks.len()];
+        for (index, block) in blocks.iter().enumerate() {
+            for &node in block.iter().rev() {
+                block_uses[index].transfer(&uses[node], &defines[node]);
+                block_defines[index].union_with(&defines[node]);
+            }
+        }
 
-            let mut set = node.uses.clone();
-            let out = live_out.entry(index).or_insert_with(|| HashSet::new());
-            set.extend(out.difference(&node.defines));
-            live_in.insert(index, set);
+        let mut block_in = vec![empty.clone(); blocks.len()];
+        let mut block_out = vec![empty.clone(); blocks.len()];
+        let mut worklist: VecDeque<usize> = postorder(&block_successors).into_iter().collect();
+        let mut queued = vec![false; blocks.len()];
+        for &block in &worklist {
+            queued[block] = true;
+        }
+        while let Some(block) = worklist.pop_front() {
+            queued[block] = false;
+            let mut out = empty.clone();
+            for &successor in &block_successors[block] {
+                out.union_with(&block_in[successor]);
+            }
+            let mut live_in = out.clone();
+            live_in.transfer(&block_uses[block], &block_defines[block]);
+            block_out[block] = out;
+            if live_in != block_in[block] {
+                block_in[block] = live_in;
+                for &predecessor in &block_predecessors[block] {
+                    if !queued[predecessor] {
+                        queued[predecessor] = true;
+                        worklist.push_back(predecessor);
+                    }
+                }
+            }
+        }
 
-            let mut set = HashSet::new();
-            for &successor in node.successors() {
-                let in_set = live_in
-                    .entry(successor.index())
-                    .or_insert_with(|| HashSet::new());
-                set.extend(in_set.clone());
+        let mut live_in = vec![empty.clone(); nodes.len()];
+        let mut live_out = vec![empty; nodes.len()];
+        for (index, block) in blocks.iter().enumerate() {
+            let mut live = block_out[index].clone();
+            for &node in block.iter().rev() {
+                live_out[node] = live.clone();
+                live.transfer(&uses[node], &defines[node]);
+                live_in[node] = live.clone();
             }
-            live_out.insert(index, set);
         }
 
-        if new_live_in == live_in && new_live_out == live_out {
-            break;
+        Self {
+            temps,
+            live_in,
+            live_out,
         }
     }
 
+    pub fn live_in<'a>(&'a self, node: usize) -> impl Iterator<Item = Temp> + 'a {
+        self.live_in[node]
+            .iter()
+            .map(move |index| self.temps[index])
+    }
+
+    pub fn live_out<'a>(&'a self, node: usize) -> impl Iterator<Item = Temp> + 'a {
+        self.live_out[node]
+            .iter()
+            .map(move |index| self.temps[index])
+    }
+}
+
+/// The blocks reachable from the entry, each one after all its successors except on the back
+/// edges.
+fn postorder(successors: &[Vec<usize>]) -> Vec<usize> {
+    let mut order = vec![];
+    if successors.is_empty() {
+        return order;
+    }
+    let mut visited = vec![false; successors.len()];
+    // The blocks being visited, with the index of their next successor.
+    let mut stack = vec![(0, 0)];
+    visited[0] = true;
+    while let Some(&mut (block, ref mut next)) = stack.last_mut() {
+        match successors[block].get(*next) {
+            Some(&successor) => {
+                *next += 1;
+                if !visited[successor] {
+                    visited[successor] = true;
+                    stack.push((successor, 0));
+                }
+            }
+            None => {
+                order.push(block);
+                stack.pop();
+            }
+        }
+    }
+    order
+}
+
+pub fn interference_graph(graph: FlowGraph) -> InterferenceGraph {
+    let liveness = Liveness::new(&graph);
+    let mut worklist_moves = BTreeSet::new();
+
     let mut interference_graph = Graph::new();
     let mut temp_nodes = HashMap::new();
     let mut move_list = HashMap::new();
+    let mut setjmp_live = HashSet::new();
 
     for (index, node) in graph.nodes().iter().enumerate() {
+        if node.is_setjmp {
+            setjmp_live.extend(liveness.live_out(index));
+        }
         for define in &node.defines {
             let define_node = interference_graph.insert(define.clone());
             temp_nodes.insert(define.clone(), define_node);
-            for temp in &live_out[&index] {
-                let temp_node = interference_graph.insert(temp.clone());
-                temp_nodes.insert(temp.clone(), temp_node);
+            for temp in liveness.live_out(index) {
+                let temp_node = interference_graph.insert(temp);
+                temp_nodes.insert(temp, temp_node);
                 interference_graph.link(define_node, temp_node);
             }
         }
@@ -127,6 +352,237 @@ pub fn interference_graph(graph: FlowGraph) -> InterferenceGraph {
         graph: interference_graph,
         _temp_nodes: temp_nodes,
         move_list,
+        setjmp_live,
         worklist_moves,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    use super::Liveness;
+    use asm::Instruction;
+    use canon::{basic_blocks, linearize, trace_schedule};
+    use env::Env;
+    use escape::find_escapes;
+    use flow::{instructions_to_graph, FlowGraph};
+    use frame::x86_64::X86_64;
+    use frame::{Fragment, Frame};
+    use lexer::Lexer;
+    use parser::Parser;
+    use semant::SemanticAnalyzer;
+    use symbol::{Strings, Symbols};
+    use temp::{Label, Temp};
+
+    /// The fixed point computed on hash sets for every node, iterated until nothing changes, like
+    /// before `Liveness`. The nodes are visited backward to converge faster.
+    fn reference_live_sets(graph: &FlowGraph) -> (Vec<HashSet<Temp>>, Vec<HashSet<Temp>>) {
+        let nodes = graph.nodes();
+        let mut live_in = vec![HashSet::new(); nodes.len()];
+        let mut live_out = vec![HashSet::new(); nodes.len()];
+        loop {
+            let mut changed = false;
+            for (index, node) in nodes.iter().enumerate().rev() {
+                let mut out = HashSet::new();
+                for successor in node.successors() {
+                    out.extend(live_in[successor.index()].iter().cloned());
+                }
+                let mut set = node.uses.clone();
+                set.extend(out.difference(&node.defines));
+                changed |= set != live_in[index] || out != live_out[index];
+                live_in[index] = set;
+                live_out[index] = out;
+            }
+            if !changed {
+                return (live_in, live_out);
+            }
+        }
+    }
+
+    /// A function made of nested loops with branches, using many temporaries.
+    fn generate_program(loops: usize, temp_count: usize) -> Vec<Instruction> {
+        let temps: Vec<_> = (0..temp_count).map(|_| Temp::new()).collect();
+        let mut seed = 42u64;
+        let mut random = |bound: usize| {
+            seed = seed
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (seed >> 33) as usize % bound
+        };
+        let label = |label: &Label| Instruction::Label {
+            assembly: format!("{}:", label),
+            label: label.clone(),
+        };
+        let mut instructions = vec![];
+        for _ in 0..loops {
+            let (head, skip, exit) = (Label::new(), Label::new(), Label::new());
+            instructions.push(label(&head));
+            for _ in 0..8 {
+                let (destination, source) = (temps[random(temp_count)], temps[random(temp_count)]);
+                instructions.push(Instruction::Operation {
+                    assembly: "add 'd0, 's0".to_string(),
+                    destination: vec![destination],
+                    source: vec![source, destination],
+                    jump: None,
+                });
+            }
+            instructions.push(Instruction::Operation {
+                assembly: "je 'j0".to_string(),
+                destination: vec![],
+                source: vec![temps[random(temp_count)]],
+                jump: Some(vec![skip.clone()]),
+            });
+            let (destination, source) = (temps[random(temp_count)], temps[random(temp_count)]);
+            instructions.push(Instruction::Move {
+                assembly: "mov 'd0, 's0".to_string(),
+                destination: vec![destination],
+                source: vec![source],
+            });
+            instructions.push(label(&skip));
+            instructions.push(Instruction::Operation {
+                assembly: "jl 'j0".to_string(),
+                destination: vec![],
+                source: vec![temps[random(temp_count)]],
+                jump: Some(vec![head]),
+            });
+            instructions.push(label(&exit));
+        }
+        instructions.push(Instruction::Operation {
+            assembly: "ret".to_string(),
+            destination: vec![],
+            source: temps[..8].to_vec(),
+            jump: None,
+        });
+        instructions
+    }
+
+    #[test]
+    fn test_liveness() {
+        let instructions = generate_program(100, 100);
+        let graph = instructions_to_graph::<X86_64>(&instructions);
+        let (reference_in, reference_out) = reference_live_sets(&graph);
+        let liveness = Liveness::new(&graph);
+        for index in 0..graph.nodes().len() {
+            let live_in: HashSet<_> = liveness.live_in(index).collect();
+            let live_out: HashSet<_> = liveness.live_out(index).collect();
+            assert_eq!(live_in, reference_in[index]);
+            assert_eq!(live_out, reference_out[index]);
+        }
+    }
+
+    /// The source of a Tiger function with `loops` loops, each with branches and an inner loop, over
+    /// `variable_count` variables.
+    fn generate_source(loops: usize, variable_count: usize) -> String {
+        let mut seed = 42u64;
+        let mut random = |bound: usize| {
+            seed = seed
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (seed >> 33) as usize % bound
+        };
+        let mut source = "let function big(n: int): int =\n    let".to_string();
+        for variable in 0..variable_count {
+            source.push_str(&format!(" var v{} := n + {}", variable, variable));
+        }
+        source.push_str("\n    in\n");
+        for index in 0..loops {
+            source.push_str(&format!("        for i{} := 0 to n do (\n", index));
+            for _ in 0..8 {
+                let (destination, left, right) = (
+                    random(variable_count),
+                    random(variable_count),
+                    random(variable_count),
+                );
+                source.push_str(&format!(
+                    "            if v{} > v{} then v{} := v{} - i{};\n",
+                    left, right, destination, left, index
+                ));
+            }
+            source.push_str(&format!(
+                "            while v{} < n do v{} := v{} + 1\n        );\n",
+                random(variable_count),
+                random(variable_count),
+                random(variable_count)
+            ));
+        }
+        source.push_str("        v0");
+        for variable in 1..variable_count {
+            source.push_str(&format!(" + v{}", variable));
+        }
+        source.push_str("\n    end\nin\n    printi(big(3))\nend\n");
+        source
+    }
+
+    /// Compare the time of the fixed point on hash sets with `Liveness` on the instructions of a
+    /// large generated program. Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_liveness() {
+        let source = generate_source(200, 100);
+        let strings = Rc::new(Strings::new());
+        let mut symbols = Symbols::new(Rc::clone(&strings));
+        let file_symbol = symbols.symbol("generated.tig");
+        let main_symbol = symbols.symbol("main");
+        let lexer = Lexer::new(source.as_bytes(), file_symbol);
+        let ast = Parser::new(lexer, &mut symbols).parse().expect("parse");
+        let escape_env = find_escapes(&ast, Rc::clone(&strings));
+        let mut env = Env::<X86_64>::new(&strings, escape_env);
+        let fragments = SemanticAnalyzer::new(&mut env, Rc::clone(&strings), false)
+            .analyze(main_symbol, ast)
+            .expect("semantic analysis");
+        let function = fragments
+            .into_iter()
+            .filter_map(|fragment| match fragment {
+                Fragment::Function { body, frame } => {
+                    let body = frame.borrow_mut().proc_entry_exit1(body);
+                    let (basic_blocks, done_label) = basic_blocks(linearize(body));
+                    Some(X86_64::select(&trace_schedule(basic_blocks, done_label)))
+                }
+                _ => None,
+            })
+            .max_by_key(Vec::len)
+            .expect("function");
+        let graph = instructions_to_graph::<X86_64>(&function);
+
+        let start = Instant::now();
+        let (reference_in, _) = reference_live_sets(&graph);
+        let reference_time = start.elapsed();
+        let start = Instant::now();
+        let liveness = Liveness::new(&graph);
+        let time = start.elapsed();
+        println!(
+            "{} instructions: {:?} with bit sets on blocks, {:?} with hash sets on instructions, {:.1}x faster",
+            function.len(),
+            time,
+            reference_time,
+            reference_time.as_secs_f64() / time.as_secs_f64()
+        );
+        let live_in: HashSet<_> = liveness.live_in(0).collect();
+        assert_eq!(live_in, reference_in[0]);
+    }
+
+    #[test]
+    fn test_long_function() {
+        // Building the graph used to recurse once per instruction.
+        let temp = Temp::new();
+        let instructions: Vec<_> = (0..200_000)
+            .map(|_| Instruction::Operation {
+                assembly: "inc 'd0".to_string(),
+                destination: vec![temp],
+                source: vec![temp],
+                jump: None,
+            })
+            .collect();
+        let graph = instructions_to_graph::<X86_64>(&instructions);
+        let liveness = Liveness::new(&graph);
+        assert_eq!(liveness.live_out(0).collect::<Vec<_>>(), vec![temp]);
+        let last = graph
+            .instruction_node(instructions.len() - 1)
+            .expect("node")
+            .index();
+        assert_eq!(liveness.live_out(last).count(), 0);
+    }
+}
@@ -1,11 +1,13 @@
 #![feature(box_patterns)]
 #![feature(map_first_last)]
 
+mod aarch64_gen;
 mod asm;
 mod asm_gen;
 mod ast;
 mod canon;
 mod color;
+mod dot;
 mod env;
 mod error;
 mod escape;
@@ -13,41 +15,56 @@ mod flow;
 mod frame;
 mod gen;
 mod graph;
+mod header;
 mod ir;
 mod lexer;
+mod linear_scan;
 mod liveness;
+mod opt;
 mod parser;
 mod position;
 mod reg_alloc;
+mod rv64_gen;
 mod semant;
 mod symbol;
 mod temp;
 mod terminal;
+mod tiling;
 mod token;
 mod types;
+mod wasm;
+mod wasm_gen;
 mod simplest_reg_alloc;
 
+use std::collections::HashSet;
 use std::env::args;
-use std::fs::{read_dir, File};
+use std::fs::{self, read_dir, File};
 use std::io::{self, BufReader, Write};
 use std::path::PathBuf;
 use std::process::Command;
 use std::rc::Rc;
 
-use asm_gen::Gen;
 use canon::{basic_blocks, linearize, trace_schedule};
 use env::Env;
 use error::Error;
 use escape::find_escapes;
+use flow::instructions_to_graph;
+use frame::aarch64::Aarch64;
+use frame::rv64::Rv64;
+use frame::wasm32::Wasm32;
 use frame::x86_64::X86_64;
 use frame::{Fragment, Frame};
 use lexer::Lexer;
+use linear_scan::LinearScan;
+use liveness::interference_graph;
+use opt::PassManager;
 use parser::Parser;
-use reg_alloc::alloc;
+use reg_alloc::{GraphColoring, RegisterAllocator};
 use semant::SemanticAnalyzer;
 use symbol::{Strings, Symbols};
+use temp::Label;
 use terminal::Terminal;
-use simplest_reg_alloc::simplest_allocate;
+use simplest_reg_alloc::SpillEverything;
 
 fn main() {
     let strings = Rc::new(Strings::new());
@@ -60,156 +77,463 @@ fn main() {
     }
 }
 
+/// The options of the command line used to compile a file.
+struct Options {
+    filename: String,
+    reg_alloc_strategy: String,
+    overflow_checks: bool,
+    omit_frame_pointer: bool,
+    maximal_munch: bool,
+    estimate_cycles: bool,
+    regalloc_stats: bool,
+    emit_interference: bool,
+    emit_cfg: bool,
+    compile_only: bool,
+}
+
 fn drive(strings: Rc<Strings>, symbols: &mut Symbols<()>) -> Result<(), Error> {
-    let mut args = args();
-    args.next();
     let mut reg_alloc_strategy = String::new();
-    if let Some(arg) = args.next() {
+    let mut linker_args = vec![];
+    let mut filename = None;
+    let mut target = "x86_64".to_string();
+    let mut overflow_checks = false;
+    let mut omit_frame_pointer = false;
+    let mut maximal_munch = false;
+    let mut estimate_cycles = false;
+    let mut regalloc_stats = false;
+    let mut emit_interference = false;
+    let mut emit_cfg = false;
+    let mut compile_only = false;
+    let mut optimization = opt::Options::default();
+    for arg in args().skip(1) {
         if arg == "-h" {
             println!("-simplealloc filename.tig");
             println!("  最简单的寄存器分配策略");
             println!("-coloralloc filename.tig");
             println!("  图着色寄存器分配策略");
+            println!("-linearscan filename.tig");
+            println!("  线性扫描寄存器分配策略");
+            println!("-maxmunch filename.tig");
+            println!("  用最大吞进选择指令，默认用动态规划求最优覆盖");
+            println!("--target=x86_64 --target=aarch64 --target=riscv64 filename.tig");
+            println!("  目标架构，默认为 x86_64；其他架构只生成汇编 filename.s，不汇编和链接");
+            println!("--target=wasm32 filename.tig");
+            println!("  生成 WebAssembly 模块 filename.wat 和 filename.wasm，用 node src/runtime.js 运行");
+            println!("--overflow-checks filename.tig");
+            println!("  运行时检查整数溢出，溢出时抛出 Overflow 异常");
+            println!("--omit-frame-pointer filename.tig");
+            println!("  尽可能通过 rsp 访问栈帧，不保存和设置 rbp");
+            println!("--estimate-cycles filename.tig");
+            println!("  按指令调度的延迟表打印每个函数执行一遍所需的周期数");
+            println!("--regalloc-stats filename.tig");
+            println!("  打印每个函数的寄存器分配统计：临时变量、分配轮数、合并和冻结的传送指令、溢出和栈帧大小");
+            println!("-O0 -O1 -O2 filename.tig");
+            println!("  优化级别，默认为 -O0");
+            println!("-f<pass> -fno-<pass> filename.tig");
+            println!("  启用或禁用某一遍优化");
+            println!("--emit=<pass> filename.tig");
+            println!("  打印某一遍优化之后的中间表示，canon 表示优化之前");
+            println!("--emit=interference filename.tig");
+            println!("  打印每个函数寄存器分配之前的冲突图（Graphviz DOT）");
+            println!("--emit=cfg filename.tig");
+            println!("  把每个函数划分基本块之后和指令选择之后的控制流图写入");
+            println!("  filename.<函数>.ir.dot 和 filename.<函数>.asm.dot（Graphviz DOT）");
+            println!("-c filename.tig");
+            println!("  只汇编成 filename.o，不链接，也不导出 main，供 C 程序链接导出的函数");
+            println!("-lname -Ldirectory file.o file.a");
+            println!("  传递给链接器的库、库目录和目标文件");
         } else if arg == "-simplealloc" {
             reg_alloc_strategy = "simple".to_string();
         } else if arg == "-coloralloc" {
             reg_alloc_strategy = "color".to_string();
+        } else if arg == "-linearscan" {
+            reg_alloc_strategy = "linear".to_string();
+        } else if arg == "-maxmunch" {
+            maximal_munch = true;
+        } else if let Some(name) = arg.strip_prefix("--target=") {
+            target = name.to_string();
+        } else if arg == "--overflow-checks" {
+            overflow_checks = true;
+        } else if arg == "--omit-frame-pointer" {
+            omit_frame_pointer = true;
+        } else if arg == "--estimate-cycles" {
+            estimate_cycles = true;
+        } else if arg == "--regalloc-stats" {
+            regalloc_stats = true;
+        } else if arg == "-c" {
+            compile_only = true;
+        } else if let Some(level) = arg.strip_prefix("-O") {
+            optimization.level = level
+                .parse()
+                .ok()
+                .filter(|&level| level <= opt::MAX_LEVEL)
+                .ok_or_else(|| Error::Msg(format!("Invalid optimization level `{}`", arg)))?;
+        } else if let Some(pass) = arg.strip_prefix("-fno-") {
+            optimization.disabled.push(pass.to_string());
+        } else if let Some(pass) = arg.strip_prefix("-f") {
+            optimization.enabled.push(pass.to_string());
+        } else if arg == "--emit=interference" {
+            emit_interference = true;
+        } else if arg == "--emit=cfg" {
+            emit_cfg = true;
+        } else if let Some(pass) = arg.strip_prefix("--emit=") {
+            optimization.emit = Some(pass.to_string());
+        } else if is_linker_arg(&arg) {
+            linker_args.push(arg);
+        } else {
+            filename = Some(arg);
+        }
+    }
+    if target != "x86_64" {
+        // These passes read the assembly of x86-64.
+        for pass in &["schedule", "peephole", "late-schedule"] {
+            optimization.disabled.push(pass.to_string());
         }
     }
-    if let Some(filename) = args.next() {
-        let file = BufReader::new(File::open(&filename)?);
-        let file_symbol = symbols.symbol(&filename);
-        let lexer = Lexer::new(file, file_symbol);
-        let main_symbol = symbols.symbol("main");
-        let mut parser = Parser::new(lexer, symbols);
-        let ast = parser.parse()?;
-        let escape_env = find_escapes(&ast, Rc::clone(&strings));
-        let mut env = Env::<X86_64>::new(&strings, escape_env);
-        {
-            let semantic_analyzer = SemanticAnalyzer::new(&mut env, Rc::clone(&strings));
-            let fragments = semantic_analyzer.analyze(main_symbol, ast)?;
-
-            let mut asm_output_path = PathBuf::from(&filename);
-            asm_output_path.set_extension("s");
-            let mut file = File::create(&asm_output_path)?;
-
-            writeln!(file, "global main\n")?;
-
-            for (function_name, _) in env::external_functions() {
-                writeln!(file, "extern {}", function_name)?;
+    if let Some(filename) = filename {
+        let options = Options {
+            filename,
+            reg_alloc_strategy,
+            overflow_checks,
+            omit_frame_pointer,
+            maximal_munch,
+            estimate_cycles,
+            regalloc_stats,
+            emit_interference,
+            emit_cfg,
+            compile_only,
+        };
+        match target.as_str() {
+            "x86_64" => {
+                let pass_manager = PassManager::<X86_64>::new(optimization)?;
+                compile(strings, symbols, &options, &pass_manager)?;
+                assemble_and_link(&options, linker_args)?;
+            }
+            "aarch64" => {
+                let pass_manager = PassManager::<Aarch64>::new(optimization)?;
+                compile(strings, symbols, &options, &pass_manager)?;
+            }
+            "riscv64" => {
+                let pass_manager = PassManager::<Rv64>::new(optimization)?;
+                compile(strings, symbols, &options, &pass_manager)?;
             }
-            writeln!(file, "")?;
+            "wasm32" => {
+                let pass_manager = PassManager::<Wasm32>::new(optimization)?;
+                compile_wasm(strings, symbols, &options, &pass_manager)?;
+            }
+            _ => {
+                return Err(Error::Msg(format!(
+                    "Unknown target `{}`, expected one of: x86_64, aarch64, riscv64, wasm32",
+                    target
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse and analyze the program, and optimize its fragments as a whole.
+fn analyze<F: Frame + PartialEq>(
+    strings: Rc<Strings>,
+    symbols: &mut Symbols<()>,
+    options: &Options,
+    pass_manager: &PassManager<F>,
+) -> Result<Vec<Fragment<F>>, Error> {
+    let filename = &options.filename;
+    let file = BufReader::new(File::open(filename)?);
+    let file_symbol = symbols.symbol(filename);
+    let lexer = Lexer::new(file, file_symbol);
+    let main_symbol = symbols.symbol("main");
+    let mut parser = Parser::new(lexer, symbols);
+    let ast = parser.parse()?;
+    let escape_env = find_escapes(&ast, Rc::clone(&strings));
+    let mut env = Env::<F>::new(&strings, escape_env);
+    let fragments = {
+        let semantic_analyzer =
+            SemanticAnalyzer::new(&mut env, Rc::clone(&strings), options.overflow_checks);
+        semantic_analyzer.analyze(main_symbol, ast)?
+    };
+    env.end_scope(); // TODO: move after the semantic analysis?
+    Ok(pass_manager.run_program(fragments))
+}
+
+/// The strings of the program, with their labels.
+fn string_literals<F: Frame>(fragments: &[Fragment<F>]) -> Vec<(&Label, &str)> {
+    fragments
+        .iter()
+        .filter_map(|fragment| match *fragment {
+            Fragment::Str(ref label, ref string) => Some((label, string.as_str())),
+            Fragment::Export { .. } | Fragment::Extern(_) | Fragment::Function { .. } => None,
+        })
+        .collect()
+}
 
-            writeln!(file, "section .data")?;
-            writeln!(file, "    align 2")?;
+/// Write the assembly of the program in `filename.s`.
+fn compile<F: Frame + PartialEq>(
+    strings: Rc<Strings>,
+    symbols: &mut Symbols<()>,
+    options: &Options,
+    pass_manager: &PassManager<F>,
+) -> Result<(), Error> {
+    let allocator: Box<dyn RegisterAllocator<F>> = match options.reg_alloc_strategy.as_str() {
+        "simple" => Box::new(SpillEverything),
+        "linear" => Box::new(LinearScan),
+        _ => Box::new(GraphColoring),
+    };
+    let filename = &options.filename;
+    let fragments = analyze(strings, symbols, options, pass_manager)?;
+    {
+        let mut asm_output_path = PathBuf::from(filename);
+        asm_output_path.set_extension("s");
+        let mut file = File::create(&asm_output_path)?;
 
-            for fragment in &fragments {
-                match fragment {
-                    Fragment::Function { .. } => (),
-                    Fragment::Str(label, string) => {
-                        writeln!(file, "    {}: db {}, 0", label, to_nasm(string))?;
-                    }
+        // The exported functions are declared in the C header `filename.h`.
+        let mut global_symbols = vec![];
+        if !options.compile_only {
+            global_symbols.push("main".to_string());
+        }
+        let mut prototypes = vec![];
+        for fragment in &fragments {
+            if let Fragment::Export {
+                ref name,
+                ref parameters,
+                ref result,
+            } = *fragment
+            {
+                global_symbols.push(name.to_string());
+                prototypes.push(header::prototype(name, parameters, result));
+            }
+        }
+        if !prototypes.is_empty() {
+            let mut header_path = PathBuf::from(filename);
+            header_path.set_extension("h");
+            let file_stem = header_path
+                .file_stem()
+                .expect("file stem")
+                .to_string_lossy();
+            fs::write(&header_path, header::header(&file_stem, &prototypes))?;
+        }
+
+        let mut external_symbols: Vec<String> = env::external_functions()
+            .keys()
+            .chain(env::external_exceptions().values())
+            .map(ToString::to_string)
+            .collect();
+        for fragment in &fragments {
+            if let Fragment::Extern(ref label) = *fragment {
+                let symbol = label.to_string();
+                if !external_symbols.contains(&symbol) {
+                    external_symbols.push(symbol);
                 }
             }
+        }
+
+        let strings = string_literals(&fragments);
+        write!(
+            file,
+            "{}",
+            F::prelude(&global_symbols, &external_symbols, &strings)
+        )?;
+
+        for fragment in fragments {
+            match fragment {
+                Fragment::Function { body, frame } => {
+                    let mut frame = frame.borrow_mut();
+                    let body = frame.proc_entry_exit1(body);
+
+                    let statements = linearize(body);
+                    let (basic_blocks, done_label) = basic_blocks(statements);
+                    if options.emit_cfg {
+                        let dot = dot::ir_graph::<F>(&frame.name(), &basic_blocks, &done_label);
+                        fs::write(dot_path(filename, &frame.name(), "ir"), dot)?;
+                    }
+                    let statements = trace_schedule(basic_blocks, done_label);
+                    let statements = pass_manager.run(&frame.name(), statements);
 
-            writeln!(file, "\nsection .text")?;
-
-            for fragment in fragments {
-                match fragment {
-                    Fragment::Function { body, frame } => {
-                        let mut frame = frame.borrow_mut();
-                        let body = frame.proc_entry_exit1(body);
-
-                        let statements = linearize(body);
-                        let (basic_blocks, done_label) = basic_blocks(statements);
-                        let statements = trace_schedule(basic_blocks, done_label);
-
-                        let mut generator = Gen::new();
-                        for statement in statements {
-                            generator.munch_statement(statement);
-                        }
-                        let instructions = generator.get_result();
-                        let instructions = frame.proc_entry_exit2(instructions);
-
-                        let instructions_;
-                        if reg_alloc_strategy == "color" {
-                            instructions_ = alloc::<X86_64>(instructions, &mut *frame);
-                        } else if reg_alloc_strategy == "simple" {
-                            instructions_ = simplest_allocate::<X86_64>(instructions, &mut frame);
+                    // The simplest allocator only has two scratch registers, so it needs the
+                    // instructions of maximal munch, which use at most two temporaries.
+                    let instructions =
+                        if options.maximal_munch || options.reg_alloc_strategy == "simple" {
+                            statements.into_iter().flat_map(F::codegen).collect()
                         } else {
-                            instructions_ = alloc::<X86_64>(instructions, &mut *frame);
-                        }
-
-                        let subroutine = frame.proc_entry_exit3(instructions_);
-                        writeln!(file, "    {}", subroutine.prolog)?;
-                        for instruction in subroutine.body {
-                            writeln!(file, "    {}", instruction.to_string::<X86_64>())?;
-                        }
-                        writeln!(file, "    {}", subroutine.epilog)?;
+                            F::select(&statements)
+                        };
+                    if options.emit_cfg {
+                        let dot = dot::instruction_graph::<F>(&frame.name(), &instructions);
+                        fs::write(dot_path(filename, &frame.name(), "asm"), dot)?;
                     }
-                    Fragment::Str(_, _) => (),
+                    // The scheduler must not move the definitions of the frame registers
+                    // that exit2 adds to the first instruction.
+                    let instructions = pass_manager.run_instructions(&frame.name(), instructions);
+                    let instructions = frame.proc_entry_exit2(instructions);
+
+                    if options.emit_interference {
+                        let graph = interference_graph(instructions_to_graph::<F>(&instructions));
+                        print!("{}", graph.to_dot::<F>(&frame.name().to_string()));
+                    }
+                    let (instructions, statistics) = allocator.allocate(instructions, &mut *frame);
+                    if options.regalloc_stats {
+                        println!(
+                            "# {} {} temps, {} iterations, {} coalesced moves, {} frozen moves, {} spills, {} frame bytes",
+                            frame.name(),
+                            statistics.temps,
+                            statistics.iterations,
+                            statistics.coalesced_moves,
+                            statistics.frozen_moves,
+                            statistics.spills,
+                            frame.locals_size()
+                        );
+                    }
+
+                    frame.set_omit_frame_pointer(options.omit_frame_pointer);
+                    let subroutine = frame.proc_entry_exit3(instructions);
+                    let body = pass_manager.run_assembly(&frame.name(), subroutine.body);
+                    if options.estimate_cycles {
+                        println!("# {} {} cycles", frame.name(), opt::estimate_cycles(&body));
+                    }
+                    writeln!(file, "    {}", subroutine.prolog)?;
+                    for instruction in body {
+                        writeln!(file, "    {}", instruction.to_string::<F>())?;
+                    }
+                    writeln!(file, "    {}", subroutine.epilog)?;
                 }
+                Fragment::Export { .. } | Fragment::Extern(_) | Fragment::Str(_, _) => (),
             }
+        }
+    }
+    Ok(())
+}
 
-            let status = Command::new("nasm")
-                .args(&[
-                    "-f",
-                    "elf64",
-                    asm_output_path.to_str().expect("asm output path"),
-                ])
-                .status();
-
-            if let Ok(return_code) = status {
-                if return_code.success() {
-                    let mut object_output_path = PathBuf::from(&filename);
-                    object_output_path.set_extension("o");
-                    let mut executable_output_path = PathBuf::from(&filename);
-                    executable_output_path.set_extension("");
-                    Command::new("ld")
-                        .args(&[
-                            "-dynamic-linker",
-                            "/usr/lib64/ld-linux-x86-64.so.2",
-                            "-o",
-                            executable_output_path
-                                .to_str()
-                                .expect("executable output path"),
-                            "/usr/lib/x86_64-linux-gnu/Scrt1.o",
-                            "/usr/lib/x86_64-linux-gnu/crti.o",
-                            &format!("-L{}", get_gcc_lib_dir()?),
-                            "-L/usr/lib64/",
-                            object_output_path.to_str().expect("object output path"),
-                            "target/debug/libruntime.a",
-                            "-lpthread",
-                            "-ldl",
-                            "--no-as-needed",
-                            "-lc",
-                            "-lgcc",
-                            "--as-needed",
-                            "-lgcc_s",
-                            "--no-as-needed",
-                            "/usr/lib/x86_64-linux-gnu/crtn.o",
-                        ])
-                        .status()
-                        .expect("link");
-                }
+/// Write the WebAssembly module of the program in `filename.wat` and `filename.wasm`.
+fn compile_wasm(
+    strings: Rc<Strings>,
+    symbols: &mut Symbols<()>,
+    options: &Options,
+    pass_manager: &PassManager<Wasm32>,
+) -> Result<(), Error> {
+    let filename = &options.filename;
+    let fragments = analyze(strings, symbols, options, pass_manager)?;
+
+    let mut exports = vec![];
+    if !options.compile_only {
+        exports.push("main".to_string());
+    }
+    let mut functions = HashSet::new();
+    for fragment in &fragments {
+        match *fragment {
+            Fragment::Export { ref name, .. } => exports.push(name.to_string()),
+            Fragment::Function { ref frame, .. } => {
+                functions.insert(frame.borrow().name());
             }
+            Fragment::Extern(_) | Fragment::Str(_, _) => (),
         }
-        env.end_scope(); // TODO: move after the semantic analysis?
     }
+    let mut generator = wasm_gen::Gen::new(&string_literals(&fragments), functions);
+
+    for fragment in fragments {
+        if let Fragment::Function { body, frame } = fragment {
+            let mut frame = frame.borrow_mut();
+            let body = frame.proc_entry_exit1(body);
+
+            let statements = linearize(body);
+            let (basic_blocks, done_label) = basic_blocks(statements);
+            if options.emit_cfg {
+                let dot = dot::ir_graph::<Wasm32>(&frame.name(), &basic_blocks, &done_label);
+                fs::write(dot_path(filename, &frame.name(), "ir"), dot)?;
+            }
+            let statements = trace_schedule(basic_blocks, done_label);
+            let statements = pass_manager.run(&frame.name(), statements);
+            generator.function(&frame, statements);
+        }
+    }
+
+    let module = generator.module(exports);
+    let mut path = PathBuf::from(filename);
+    path.set_extension("wat");
+    fs::write(&path, module.to_wat())?;
+    path.set_extension("wasm");
+    fs::write(&path, module.to_binary())?;
     Ok(())
 }
 
-fn to_nasm(string: &str) -> String {
-    let mut result = "'".to_string();
-    for char in string.chars() {
-        let string = match char {
-            '\n' | '\t' => format!("', {}, '", char as u32),
-            _ => char.to_string(),
-        };
-        result.push_str(&string);
+/// Assemble `filename.s` with nasm and link it with the runtime, unless only the object file is
+/// wanted.
+fn assemble_and_link(options: &Options, linker_args: Vec<String>) -> Result<(), Error> {
+    let filename = &options.filename;
+    let mut asm_output_path = PathBuf::from(filename);
+    asm_output_path.set_extension("s");
+    let status = Command::new("nasm")
+        .args(&[
+            "-f",
+            "elf64",
+            asm_output_path.to_str().expect("asm output path"),
+        ])
+        .status();
+
+    if let Ok(return_code) = status {
+        if return_code.success() && !options.compile_only {
+            let mut object_output_path = PathBuf::from(filename);
+            object_output_path.set_extension("o");
+            let mut executable_output_path = PathBuf::from(filename);
+            executable_output_path.set_extension("");
+            let mut link_args = vec![
+                "-dynamic-linker".to_string(),
+                "/usr/lib64/ld-linux-x86-64.so.2".to_string(),
+                "-o".to_string(),
+                executable_output_path
+                    .to_str()
+                    .expect("executable output path")
+                    .to_string(),
+                "/usr/lib/x86_64-linux-gnu/Scrt1.o".to_string(),
+                "/usr/lib/x86_64-linux-gnu/crti.o".to_string(),
+                format!("-L{}", get_gcc_lib_dir()?),
+                "-L/usr/lib64/".to_string(),
+                object_output_path
+                    .to_str()
+                    .expect("object output path")
+                    .to_string(),
+                "target/debug/libruntime.a".to_string(),
+            ];
+            // User objects and libraries come after the program so that they can resolve
+            // its external symbols.
+            link_args.extend(linker_args);
+            link_args.extend(
+                [
+                    "-lpthread",
+                    "-ldl",
+                    "--no-as-needed",
+                    "-lc",
+                    "-lgcc",
+                    "--as-needed",
+                    "-lgcc_s",
+                    "--no-as-needed",
+                    "/usr/lib/x86_64-linux-gnu/crtn.o",
+                ]
+                .iter()
+                .map(ToString::to_string),
+            );
+            Command::new("ld")
+                .args(&link_args)
+                .status()
+                .expect("link");
+        }
     }
-    result.push('\'');
-    result
+    Ok(())
+}
+
+/// `tests/queens.tig` gives `tests/queens.<function>.<level>.dot`.
+fn dot_path(filename: &str, function: &Label, level: &str) -> PathBuf {
+    let mut path = PathBuf::from(filename);
+    path.set_extension(format!("{}.{}.dot", function, level));
+    path
+}
+
+fn is_linker_arg(arg: &str) -> bool {
+    arg.starts_with("-l")
+        || arg.starts_with("-L")
+        || arg.ends_with(".o")
+        || arg.ends_with(".a")
+        || arg.ends_with(".so")
 }
 
 fn get_gcc_lib_dir() -> io::Result<String> {
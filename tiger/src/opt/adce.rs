@@ -0,0 +1,192 @@
+//! **激进的死代码删除**(ADCE)。普通的死代码删除假设每条语句都是活的，直到证明它是死的；
+//! 激进的死代码删除则相反，假设每条语句都是死的，直到证明它是活的：
+//! 1. 有副作用的语句（函数调用、存储、对机器寄存器的赋值）和跳转是活的；
+//! 2. 活语句使用的变量的定值是活的；
+//! 3. 若基本块`b`包含活语句，则`b`控制依赖的条件跳转是活的。`b`控制依赖于它的后必经结点
+//!    边界(postdominance frontier)中的结点；
+//! 4. 活的`φ`函数使它的前驱基本块变为活的。
+//!
+//! 不是活的条件跳转被替换为跳转到它的直接后必经结点的无条件跳转，这样可以删除没有作用的
+//! 循环和条件语句。
+
+use std::collections::{HashMap, HashSet};
+
+use super::dominators::DominatorTree;
+use super::ssa::SsaFunction;
+use super::{is_pure, statement_uses};
+use ir::{Exp, Statement};
+use temp::Temp;
+
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+enum Item {
+    Phi(usize, usize),
+    Statement(usize, usize),
+}
+
+struct Marker<'a> {
+    function: &'a SsaFunction,
+    postdominance_frontiers: Vec<Vec<usize>>,
+    definitions: HashMap<Temp, Item>,
+    live: HashSet<Item>,
+    live_blocks: Vec<bool>,
+    worklist: Vec<Item>,
+}
+
+impl<'a> Marker<'a> {
+    fn mark(&mut self, item: Item) {
+        if self.live.insert(item) {
+            self.worklist.push(item);
+        }
+    }
+
+    fn mark_block(&mut self, block_index: usize) {
+        if self.live_blocks[block_index] {
+            return;
+        }
+        self.live_blocks[block_index] = true;
+        for &frontier in &self.postdominance_frontiers[block_index].clone() {
+            // The virtual exit node has no statement.
+            if let Some(block) = self.function.blocks.get(frontier) {
+                if !block.statements.is_empty() {
+                    self.mark(Item::Statement(frontier, block.statements.len() - 1));
+                }
+            }
+        }
+    }
+
+    fn run(&mut self) {
+        while let Some(item) = self.worklist.pop() {
+            let mut uses = HashSet::new();
+            match item {
+                Item::Phi(block_index, phi_index) => {
+                    let block = &self.function.blocks[block_index];
+                    uses.extend(block.phis[phi_index].arguments.iter().cloned());
+                    self.mark_block(block_index);
+                    for &predecessor in &block.predecessors {
+                        self.mark_block(predecessor);
+                    }
+                }
+                Item::Statement(block_index, statement_index) => {
+                    let statement = &self.function.blocks[block_index].statements[statement_index];
+                    statement_uses(statement, &mut uses);
+                    self.mark_block(block_index);
+                }
+            }
+            for temp in uses {
+                if let Some(&definition) = self.definitions.get(&temp) {
+                    self.mark(definition);
+                }
+            }
+        }
+    }
+}
+
+pub fn eliminate(function: &mut SsaFunction) {
+    let block_count = function.blocks.len();
+
+    // The postdominators are the dominators of the reverse graph, with a virtual exit node after
+    // the blocks without successors.
+    let exit = block_count;
+    let mut reverse_successors = vec![vec![]; block_count + 1];
+    let mut reverse_predecessors = vec![vec![]; block_count + 1];
+    for (index, block) in function.blocks.iter().enumerate() {
+        reverse_successors[index] = block.predecessors.clone();
+        reverse_predecessors[index] = block.successors.clone();
+        if block.successors.is_empty() {
+            reverse_successors[exit].push(index);
+            reverse_predecessors[index].push(exit);
+        }
+    }
+    let postdominators = DominatorTree::new(exit, &reverse_successors, &reverse_predecessors);
+
+    // Where the conditional jumps that are not live go: their immediate postdominator, when it is
+    // a block without φ-function (no value would be defined for the new edge).
+    let bypass: Vec<Option<usize>> = (0..block_count)
+        .map(|index| {
+            postdominators
+                .idom(index)
+                .filter(|&target| target != exit && function.blocks[target].phis.is_empty())
+        })
+        .collect();
+
+    let mut definitions = HashMap::new();
+    for (block_index, block) in function.blocks.iter().enumerate() {
+        for (phi_index, phi) in block.phis.iter().enumerate() {
+            definitions.insert(phi.destination, Item::Phi(block_index, phi_index));
+        }
+        for (statement_index, statement) in block.statements.iter().enumerate() {
+            if let Statement::Move(Exp::Temp(temp), _) = *statement {
+                if function.is_variable(temp) {
+                    definitions.insert(temp, Item::Statement(block_index, statement_index));
+                }
+            }
+        }
+    }
+
+    let live = {
+        let mut marker = Marker {
+            function,
+            postdominance_frontiers: postdominators.frontiers(&reverse_predecessors),
+            definitions,
+            live: HashSet::new(),
+            live_blocks: vec![false; block_count],
+            worklist: vec![],
+        };
+        for (block_index, block) in function.blocks.iter().enumerate() {
+            for (statement_index, statement) in block.statements.iter().enumerate() {
+                let is_root = match *statement {
+                    Statement::Move(Exp::Temp(temp), ref source) => {
+                        !function.is_variable(temp) || !is_pure(source)
+                    }
+                    Statement::Move(_, _) | Statement::Jump(_, _) => true,
+                    Statement::Exp(ref exp) => !is_pure(exp),
+                    // A conditional jump must be kept when there is nowhere to go instead.
+                    Statement::CondJump { .. } => bypass[block_index].is_none(),
+                    Statement::Sequence(_, _) | Statement::Label(_) => true,
+                };
+                if is_root {
+                    marker.mark(Item::Statement(block_index, statement_index));
+                }
+            }
+        }
+        marker.run();
+        marker.live
+    };
+
+    let labels: Vec<_> = function
+        .blocks
+        .iter()
+        .map(|block| block.label.clone())
+        .collect();
+    let mut removed_edges = HashSet::new();
+    let mut added_edges = vec![];
+    for (block_index, block) in function.blocks.iter_mut().enumerate() {
+        let mut phi_index = 0;
+        block.phis.retain(|_| {
+            phi_index += 1;
+            live.contains(&Item::Phi(block_index, phi_index - 1))
+        });
+        let statements = ::std::mem::take(&mut block.statements);
+        for (statement_index, statement) in statements.into_iter().enumerate() {
+            if live.contains(&Item::Statement(block_index, statement_index)) {
+                block.statements.push(statement);
+            } else if let Statement::CondJump { .. } = statement {
+                let target = bypass[block_index].expect("bypass");
+                block.statements.push(Statement::Jump(
+                    Exp::Name(labels[target].clone()),
+                    vec![labels[target].clone()],
+                ));
+                for &successor in &block.successors {
+                    removed_edges.insert((block_index, successor));
+                }
+                added_edges.push((block_index, target));
+            }
+        }
+    }
+    function.retain_edges(|from, to| !removed_edges.contains(&(from, to)));
+    for (from, to) in added_edges {
+        function.blocks[from].successors.push(to);
+        function.blocks[to].predecessors.push(from);
+    }
+    function.remove_unreachable_blocks();
+}
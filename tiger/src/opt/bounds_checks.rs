@@ -0,0 +1,262 @@
+//! **数组边界检查删除**。`gen::array_subscript`生成的边界检查`cjump i <u mem[a - 8]`在已知
+//! `0 <= i < length(a)`时是多余的，可以替换为跳转到检查通过的分支。
+//!
+//! 数组的长度在数组是由`initArray(n, x)`创建的、`n`是常量时已知。下标的范围在它是常量或者
+//! 循环的归纳变量时已知。对于基本归纳变量`i`：
+//! 1. 第一次迭代时`i`等于它的初值`i0`；
+//! 2. 每条回边上`i`增加一个正的常量`c`，并且回边被一个条件跳转的一条出边所支配，这个条件
+//!    跳转测试`i`的族中的变量`j = i + d`的上界：`j < u`；
+//!
+//! 于是可以用归纳法证明在循环中`i0 <= i <= max(i0, u - 1 - d + c)`（计算都不会溢出时）。
+//! `for`循环正是这种形式。
+
+use std::collections::{HashMap, HashSet};
+
+use super::dominators::DominatorTree;
+use super::loops::{find_loops, induction_variables, InductionVariable, Loop};
+use super::ssa::{jump, SsaFunction};
+use canon::negate_condition;
+use frame::Frame;
+use ir::{BinOp, Exp, RelationalOp, Statement};
+use temp::{Label, Temp};
+
+struct Ranges<'a> {
+    function: &'a SsaFunction,
+    definitions: HashMap<Temp, &'a Exp>,
+    tree: DominatorTree,
+    ranges: HashMap<Temp, (i64, i64)>,
+}
+
+impl<'a> Ranges<'a> {
+    fn new(function: &'a SsaFunction, loops: &[Loop]) -> Self {
+        let mut definitions = HashMap::new();
+        for block in &function.blocks {
+            for statement in &block.statements {
+                if let Statement::Move(Exp::Temp(temp), ref source) = *statement {
+                    if function.is_variable(temp) {
+                        definitions.insert(temp, source);
+                    }
+                }
+            }
+        }
+        let mut ranges = Ranges {
+            function,
+            definitions,
+            tree: function.dominator_tree(),
+            ranges: HashMap::new(),
+        };
+        for natural_loop in loops {
+            for variable in induction_variables(function, natural_loop) {
+                ranges.add_induction_variable(natural_loop, &variable);
+            }
+        }
+        ranges
+    }
+
+    /// The value of `temp`, following the copies.
+    fn definition(&self, temp: Temp) -> Option<&'a Exp> {
+        let mut temp = temp;
+        // Bound the walk in case of undefined temporaries.
+        for _ in 0..self.definitions.len() {
+            match self.definitions.get(&temp) {
+                Some(&&Exp::Temp(source)) => temp = source,
+                Some(&exp) => return Some(exp),
+                None => return None,
+            }
+        }
+        None
+    }
+
+    fn constant(&self, temp: Temp) -> Option<i64> {
+        match self.definition(temp) {
+            Some(&Exp::Const(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The length of the array, if it was created with a constant length.
+    fn array_length(&self, array: Temp) -> Option<i64> {
+        match self.definition(array) {
+            Some(&Exp::Call(box Exp::Name(ref function), ref arguments))
+                if *function == Label::with_name("initArray") =>
+            {
+                match arguments.first() {
+                    Some(&Exp::Const(length)) => Some(length),
+                    Some(&Exp::Temp(temp)) => self.constant(temp),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn range(&self, exp: &Exp) -> Option<(i64, i64)> {
+        match *exp {
+            Exp::Const(value) => Some((value, value)),
+            Exp::Temp(temp) => self
+                .ranges
+                .get(&temp)
+                .cloned()
+                .or_else(|| self.constant(temp).map(|value| (value, value))),
+            _ => None,
+        }
+    }
+
+    /// The upper bound of the induction variable when control reaches `block`, from a conditional
+    /// jump testing a member of its family on the way from the header.
+    fn upper_bound(
+        &self,
+        natural_loop: &Loop,
+        variable: &InductionVariable,
+        block: usize,
+    ) -> Option<i64> {
+        let mut bound: Option<i64> = None;
+        let mut node = block;
+        while node != natural_loop.header {
+            let predecessors = &self.function.blocks[node].predecessors;
+            if let [predecessor] = predecessors[..] {
+                let member_bound = self.function.blocks[predecessor]
+                    .statements
+                    .last()
+                    .and_then(|jump| {
+                        edge_upper_bound(variable, jump, &self.function.blocks[node].label)
+                    });
+                if let Some(member_bound) = member_bound {
+                    bound = Some(bound.map_or(member_bound, |bound| bound.min(member_bound)));
+                }
+            }
+            node = self.tree.idom(node)?;
+        }
+        bound
+    }
+
+    fn add_induction_variable(&mut self, natural_loop: &Loop, variable: &InductionVariable) {
+        let low = match self.constant(variable.initial) {
+            Some(low) => low,
+            None => return,
+        };
+        let mut high = low;
+        for &(predecessor, increment) in &variable.increments {
+            if increment <= 0 {
+                return;
+            }
+            let next = self
+                .upper_bound(natural_loop, variable, predecessor)
+                .and_then(|bound| bound.checked_add(increment));
+            match next {
+                Some(next) => high = high.max(next),
+                None => return,
+            }
+        }
+        let mut ranges = vec![];
+        for (&member, &offset) in &variable.family {
+            match (low.checked_add(offset), high.checked_add(offset)) {
+                (Some(member_low), Some(member_high)) => {
+                    ranges.push((member, (member_low, member_high)))
+                }
+                // The members could wrap around.
+                _ => return,
+            }
+        }
+        self.ranges.extend(ranges);
+    }
+}
+
+/// The upper bound of the induction variable on the edge of `jump` going to `label`, if the
+/// jump compares a member of the family with a constant.
+fn edge_upper_bound(variable: &InductionVariable, jump: &Statement, label: &Label) -> Option<i64> {
+    match *jump {
+        Statement::CondJump {
+            ref op,
+            ref left,
+            ref right,
+            ref true_label,
+            ref false_label,
+        } if true_label != false_label => {
+            let op = if true_label == label {
+                op.clone()
+            } else {
+                negate_condition(op.clone())
+            };
+            let (temp, op, constant) = match (left, right) {
+                (&Exp::Temp(temp), &Exp::Const(constant)) => (temp, op, constant),
+                (&Exp::Const(constant), &Exp::Temp(temp)) => (temp, commute(op), constant),
+                _ => return None,
+            };
+            let offset = variable.family.get(&temp)?;
+            member_upper_bound(op, constant).and_then(|bound| bound.checked_sub(*offset))
+        }
+        _ => None,
+    }
+}
+
+/// The upper bound of `x` when `x op constant` is true.
+fn member_upper_bound(op: RelationalOp, constant: i64) -> Option<i64> {
+    match op {
+        RelationalOp::LesserThan => constant.checked_sub(1),
+        RelationalOp::LesserOrEqual | RelationalOp::Equal => Some(constant),
+        _ => None,
+    }
+}
+
+/// The operator such that `b op' a` is `a op b`.
+fn commute(op: RelationalOp) -> RelationalOp {
+    match op {
+        RelationalOp::LesserThan => RelationalOp::GreaterThan,
+        RelationalOp::LesserOrEqual => RelationalOp::GreaterOrEqual,
+        RelationalOp::GreaterThan => RelationalOp::LesserThan,
+        RelationalOp::GreaterOrEqual => RelationalOp::LesserOrEqual,
+        RelationalOp::UnsignedLesserThan => RelationalOp::UnsignedGreaterThan,
+        RelationalOp::UnsignedLesserOrEqual => RelationalOp::UnsignedGreaterOrEqual,
+        RelationalOp::UnsignedGreaterThan => RelationalOp::UnsignedLesserThan,
+        RelationalOp::UnsignedGreaterOrEqual => RelationalOp::UnsignedLesserOrEqual,
+        op @ RelationalOp::Equal | op @ RelationalOp::NotEqual => op,
+    }
+}
+
+pub fn eliminate<F: Frame>(function: &mut SsaFunction) {
+    let loops = find_loops(function);
+    let mut removed_edges = HashSet::new();
+    let mut jumps = vec![];
+    {
+        let ranges = Ranges::new(function, &loops);
+        for (index, block) in function.blocks.iter().enumerate() {
+            if let Some(&Statement::CondJump {
+                op: RelationalOp::UnsignedLesserThan,
+                ref left,
+                right:
+                    Exp::Mem(box Exp::BinOp {
+                        op: BinOp::Plus,
+                        left: box Exp::Temp(array),
+                        right: box Exp::Const(offset),
+                    }),
+                ref true_label,
+                ref false_label,
+            }) = block.statements.last()
+            {
+                if offset != -F::WORD_SIZE || true_label == false_label {
+                    continue;
+                }
+                let in_bounds = match (ranges.range(left), ranges.array_length(array)) {
+                    (Some((low, high)), Some(length)) => low >= 0 && high < length,
+                    _ => false,
+                };
+                if in_bounds {
+                    for &successor in &block.successors {
+                        if function.blocks[successor].label == *false_label {
+                            removed_edges.insert((index, successor));
+                        }
+                    }
+                    jumps.push((index, true_label.clone()));
+                }
+            }
+        }
+    }
+    for (index, label) in jumps {
+        let block = &mut function.blocks[index];
+        block.statements.pop();
+        block.statements.push(jump(label));
+    }
+    function.retain_edges(|from, to| !removed_edges.contains(&(from, to)));
+    function.remove_unreachable_blocks();
+}
@@ -0,0 +1,78 @@
+//! **控制流图**。基本块以标号开始，以跳转结束或者顺序执行到下一个基本块。规范化之后的语句
+//! 列表中，第一条语句是入口基本块的标号，条件跳转后面总是紧跟着它的假标号。基本块记录它在
+//! 语句列表中的区间，以便各遍在原地改写语句。
+
+use std::collections::HashMap;
+
+use ir::Statement;
+
+pub struct Block {
+    /// Index of the first statement of the block.
+    pub start: usize,
+    /// Index after the last statement of the block.
+    pub end: usize,
+    pub successors: Vec<usize>,
+    pub predecessors: Vec<usize>,
+}
+
+pub struct ControlFlowGraph {
+    pub blocks: Vec<Block>,
+}
+
+impl ControlFlowGraph {
+    pub fn new(statements: &[Statement]) -> Self {
+        let mut blocks: Vec<Block> = vec![];
+        let mut labels = HashMap::new();
+        for (index, statement) in statements.iter().enumerate() {
+            let starts_block = match *statement {
+                Statement::Label(ref label) => {
+                    labels.insert(label.clone(), blocks.len());
+                    true
+                }
+                _ => blocks.is_empty(),
+            };
+            if starts_block {
+                if let Some(block) = blocks.last_mut() {
+                    block.end = index;
+                }
+                blocks.push(Block {
+                    start: index,
+                    end: index,
+                    successors: vec![],
+                    predecessors: vec![],
+                });
+            }
+        }
+        if let Some(block) = blocks.last_mut() {
+            block.end = statements.len();
+        }
+
+        let block_count = blocks.len();
+        for index in 0..block_count {
+            let successors = match statements[blocks[index].end - 1] {
+                Statement::Jump(_, ref targets) => targets
+                    .iter()
+                    .filter_map(|label| labels.get(label).cloned())
+                    .collect(),
+                Statement::CondJump {
+                    ref true_label,
+                    ref false_label,
+                    ..
+                } => labels
+                    .get(true_label)
+                    .into_iter()
+                    .chain(labels.get(false_label))
+                    .cloned()
+                    .collect(),
+                _ if index + 1 < block_count => vec![index + 1],
+                _ => vec![],
+            };
+            for &successor in &successors {
+                blocks[successor].predecessors.push(index);
+            }
+            blocks[index].successors = successors;
+        }
+
+        Self { blocks }
+    }
+}
@@ -0,0 +1,72 @@
+//! **公共子表达式删除**。若语句`n: v <- x ⊕ y`中的`x ⊕ y`在`n`可用，则不需要重新计算它：
+//! 对于每一个到达`n`的计算`s: t <- x ⊕ y`，把`s`改写为
+//! ```text
+//! w <- x ⊕ y
+//! t <- w
+//! ```
+//! 并把`n`改写为`v <- w`。同一个表达式的所有改写使用同一个新临时变量`w`。
+
+use std::collections::HashMap;
+
+use super::cfg::ControlFlowGraph;
+use super::dataflow::{
+    computed_expression, solve, statement_facts, volatile_registers, AvailableExpressions,
+    ReachingExpressions,
+};
+use frame::Frame;
+use ir::{Exp, Statement};
+use temp::Temp;
+
+pub fn eliminate<F: Frame>(statements: Vec<Statement>) -> Vec<Statement> {
+    let graph = ControlFlowGraph::new(&statements);
+    let available = AvailableExpressions::new::<F>(&statements);
+    let available_solution = solve(&available, &graph, &statements);
+    let available_facts = statement_facts(&available, &graph, &statements, &available_solution);
+    let reaching = ReachingExpressions::new::<F>(&statements);
+    let reaching_solution = solve(&reaching, &graph, &statements);
+    let reaching_facts = statement_facts(&reaching, &graph, &statements, &reaching_solution);
+
+    let volatile_registers = volatile_registers::<F>();
+    let mut temps: HashMap<Exp, Temp> = HashMap::new();
+    let mut reused = HashMap::new();
+    let mut computations = HashMap::new();
+    for (index, statement) in statements.iter().enumerate() {
+        if let Some(exp) = computed_expression(statement, &volatile_registers) {
+            if !available_facts[index].contains(exp) {
+                continue;
+            }
+            let sources: Vec<_> = reaching_facts[index]
+                .iter()
+                .cloned()
+                .filter(|&source| reaching.expression(source) == Some(exp))
+                .collect();
+            // Can only happen in an unreachable block.
+            if sources.is_empty() {
+                continue;
+            }
+            let temp = *temps.entry(exp.clone()).or_insert_with(Temp::new);
+            reused.insert(index, temp);
+            for source in sources {
+                computations.insert(source, temp);
+            }
+        }
+    }
+
+    let mut result = vec![];
+    for (index, statement) in statements.into_iter().enumerate() {
+        match statement {
+            Statement::Move(destination, source) => {
+                if let Some(&temp) = reused.get(&index) {
+                    result.push(Statement::Move(destination, Exp::Temp(temp)));
+                } else if let Some(&temp) = computations.get(&index) {
+                    result.push(Statement::Move(Exp::Temp(temp), source));
+                    result.push(Statement::Move(destination, Exp::Temp(temp)));
+                } else {
+                    result.push(Statement::Move(destination, source));
+                }
+            }
+            statement => result.push(statement),
+        }
+    }
+    result
+}
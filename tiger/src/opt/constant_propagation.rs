@@ -0,0 +1,66 @@
+//! **常量传播**。若临时变量`t`在语句`n`的所有到达定值都是`t <- c`，并且常量`c`相同，则可以把
+//! `n`中对`t`的使用替换为`c`。替换之后再做常量折叠并删除变得不可达的基本块，这可能产生新的
+//! 常量定值，所以重复直到没有变化。
+
+use std::collections::{HashMap, HashSet};
+
+use super::cfg::ControlFlowGraph;
+use super::dataflow::{solve, statement_facts, ReachingDefinitions};
+use super::fold::fold;
+use super::unreachable;
+use super::{replace_uses, statement_uses};
+use frame::Frame;
+use ir::{Exp, Statement};
+
+pub fn propagate<F: Frame>(mut statements: Vec<Statement>) -> Vec<Statement> {
+    let machine_registers = F::temp_map();
+    loop {
+        let graph = ControlFlowGraph::new(&statements);
+        let analysis = ReachingDefinitions::new(&statements);
+        let solution = solve(&analysis, &graph, &statements);
+        let facts = statement_facts(&analysis, &graph, &statements, &solution);
+
+        let constants: HashMap<usize, i64> = statements
+            .iter()
+            .enumerate()
+            .filter_map(|(index, statement)| match *statement {
+                Statement::Move(Exp::Temp(_), Exp::Const(value)) => Some((index, value)),
+                _ => None,
+            })
+            .collect();
+
+        let mut changed = false;
+        for (index, reaching) in facts.iter().enumerate() {
+            let mut uses = HashSet::new();
+            statement_uses(&statements[index], &mut uses);
+            let mut replacements = HashMap::new();
+            for temp in uses {
+                if machine_registers.contains_key(&temp) {
+                    continue;
+                }
+                let mut values = analysis
+                    .definitions(temp)
+                    .iter()
+                    .filter(|definition| reaching.contains(definition))
+                    .map(|definition| constants.get(definition));
+                if let Some(Some(&value)) = values.next() {
+                    if values.all(|other| other == Some(&value)) {
+                        replacements.insert(temp, Exp::Const(value));
+                    }
+                }
+            }
+            if !replacements.is_empty() {
+                let statement = statements[index].clone();
+                statements[index] = replace_uses(statement, &replacements);
+                changed = true;
+            }
+        }
+
+        // Folding can turn conditional jumps into jumps: remove the blocks that become
+        // unreachable so that their definitions do not reach the rest of the function.
+        statements = unreachable::eliminate(fold(statements));
+        if !changed {
+            return statements;
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! **复写传播**。若复写`t <- s`在语句`n`可用，则可以把`n`中对`t`的使用替换为`s`。之后`t <- s`
+//! 常常变成死代码。
+
+use std::collections::{HashMap, HashSet};
+
+use super::cfg::ControlFlowGraph;
+use super::dataflow::{solve, statement_facts, AvailableCopies};
+use super::{replace_uses, statement_uses};
+use frame::Frame;
+use ir::{Exp, Statement};
+
+pub fn propagate<F: Frame>(mut statements: Vec<Statement>) -> Vec<Statement> {
+    loop {
+        let graph = ControlFlowGraph::new(&statements);
+        let analysis = AvailableCopies::new::<F>(&statements);
+        let solution = solve(&analysis, &graph, &statements);
+        let facts = statement_facts(&analysis, &graph, &statements, &solution);
+
+        let mut changed = false;
+        for (index, copies) in facts.iter().enumerate() {
+            let mut uses = HashSet::new();
+            statement_uses(&statements[index], &mut uses);
+            let replacements: HashMap<_, _> = copies
+                .iter()
+                .filter(|&&(destination, _)| uses.contains(&destination))
+                .map(|&(destination, source)| (destination, Exp::Temp(source)))
+                .collect();
+            if !replacements.is_empty() {
+                let statement = statements[index].clone();
+                statements[index] = replace_uses(statement, &replacements);
+                changed = true;
+            }
+        }
+
+        // Remove the copies of a temporary to itself created by the propagation.
+        statements.retain(|statement| match *statement {
+            Statement::Move(Exp::Temp(destination), Exp::Temp(source)) => destination != source,
+            _ => true,
+        });
+        if !changed {
+            return statements;
+        }
+    }
+}
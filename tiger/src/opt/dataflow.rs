@@ -0,0 +1,510 @@
+//! **数据流分析**。数据流分析在控制流图上求出每个程序点的事实（fact），例如哪些定值可以到达
+//! 该点。一个分析由以下部分组成：
+//! 1. 事实构成的格（lattice）以及它的交汇运算（meet），例如并集或交集；
+//! 2. 传递函数（transfer function），描述一条语句如何改变事实；
+//! 3. 方向：前向分析从入口出发沿着边传播事实，后向分析从出口出发逆着边传播事实。
+//!
+//! 求解器使用工作表（worklist）算法：一个基本块的输入事实改变时，重新计算它的输出事实，若输出
+//! 事实也改变了，则把它的后继（后向分析中是前驱）加入工作表，直到不动点。
+//!
+//! ```text
+//! 前向：in[b] = ∧ out[p], p ∈ pred[b]    out[b] = f_b(in[b])
+//! 后向：out[b] = ∧ in[s], s ∈ succ[b]    in[b] = f_b(out[b])
+//! ```
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use super::cfg::ControlFlowGraph;
+use super::{exp_uses, is_pure, statement_uses};
+use frame::Frame;
+use ir::{Exp, Statement};
+use temp::Temp;
+
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+pub trait Analysis {
+    type Fact: Clone + PartialEq;
+
+    const DIRECTION: Direction;
+
+    /// Fact at the entry of the function for a forward analysis, at its exits for a backward one.
+    fn boundary(&self) -> Self::Fact;
+    /// Top of the lattice: the identity of meet and the initial fact of the blocks.
+    fn top(&self) -> Self::Fact;
+    fn meet(&self, fact: &mut Self::Fact, other: &Self::Fact);
+    /// Apply the effect of the statement at `index` to the fact.
+    fn transfer(&self, index: usize, statement: &Statement, fact: &mut Self::Fact);
+}
+
+pub struct Solution<Fact> {
+    pub block_in: Vec<Fact>,
+    pub block_out: Vec<Fact>,
+}
+
+pub fn solve<A: Analysis>(
+    analysis: &A,
+    graph: &ControlFlowGraph,
+    statements: &[Statement],
+) -> Solution<A::Fact> {
+    let block_count = graph.blocks.len();
+    let mut block_in = vec![analysis.top(); block_count];
+    let mut block_out = vec![analysis.top(); block_count];
+    let mut worklist: VecDeque<usize> = match A::DIRECTION {
+        Direction::Forward => (0..block_count).collect(),
+        Direction::Backward => (0..block_count).rev().collect(),
+    };
+    let mut queued = vec![true; block_count];
+
+    while let Some(index) = worklist.pop_front() {
+        queued[index] = false;
+        let block = &graph.blocks[index];
+        let next = match A::DIRECTION {
+            Direction::Forward => {
+                let mut fact = if index == 0 {
+                    analysis.boundary()
+                } else {
+                    analysis.top()
+                };
+                for &predecessor in &block.predecessors {
+                    analysis.meet(&mut fact, &block_out[predecessor]);
+                }
+                block_in[index] = fact.clone();
+                let block_statements = &statements[block.start..block.end];
+                for (statement_index, statement) in (block.start..).zip(block_statements) {
+                    analysis.transfer(statement_index, statement, &mut fact);
+                }
+                if fact == block_out[index] {
+                    continue;
+                }
+                block_out[index] = fact;
+                &block.successors
+            }
+            Direction::Backward => {
+                let mut fact = if block.successors.is_empty() {
+                    analysis.boundary()
+                } else {
+                    analysis.top()
+                };
+                for &successor in &block.successors {
+                    analysis.meet(&mut fact, &block_in[successor]);
+                }
+                block_out[index] = fact.clone();
+                for statement_index in (block.start..block.end).rev() {
+                    analysis.transfer(statement_index, &statements[statement_index], &mut fact);
+                }
+                if fact == block_in[index] {
+                    continue;
+                }
+                block_in[index] = fact;
+                &block.predecessors
+            }
+        };
+        for &block_index in next {
+            if !queued[block_index] {
+                queued[block_index] = true;
+                worklist.push_back(block_index);
+            }
+        }
+    }
+
+    Solution {
+        block_in,
+        block_out,
+    }
+}
+
+/// Fact holding before each statement for a forward analysis, after each statement for a backward
+/// one.
+pub fn statement_facts<A: Analysis>(
+    analysis: &A,
+    graph: &ControlFlowGraph,
+    statements: &[Statement],
+    solution: &Solution<A::Fact>,
+) -> Vec<A::Fact> {
+    let mut facts = vec![analysis.top(); statements.len()];
+    for (index, block) in graph.blocks.iter().enumerate() {
+        match A::DIRECTION {
+            Direction::Forward => {
+                let mut fact = solution.block_in[index].clone();
+                for statement_index in block.start..block.end {
+                    facts[statement_index] = fact.clone();
+                    analysis.transfer(statement_index, &statements[statement_index], &mut fact);
+                }
+            }
+            Direction::Backward => {
+                let mut fact = solution.block_out[index].clone();
+                for statement_index in (block.start..block.end).rev() {
+                    facts[statement_index] = fact.clone();
+                    analysis.transfer(statement_index, &statements[statement_index], &mut fact);
+                }
+            }
+        }
+    }
+    facts
+}
+
+/// Temporary assigned by the statement.
+pub fn defined_temp(statement: &Statement) -> Option<Temp> {
+    match *statement {
+        Statement::Move(Exp::Temp(temp), _) => Some(temp),
+        _ => None,
+    }
+}
+
+fn writes_memory(statement: &Statement) -> bool {
+    match *statement {
+        Statement::Move(Exp::Mem(_), _) => true,
+        _ => contains_call(statement),
+    }
+}
+
+fn contains_call(statement: &Statement) -> bool {
+    match *statement {
+        Statement::Move(ref destination, ref source) => {
+            exp_contains_call(destination) || exp_contains_call(source)
+        }
+        Statement::Exp(ref exp) | Statement::Jump(ref exp, _) => exp_contains_call(exp),
+        Statement::CondJump {
+            ref left,
+            ref right,
+            ..
+        } => exp_contains_call(left) || exp_contains_call(right),
+        Statement::Sequence(ref first, ref second) => contains_call(first) || contains_call(second),
+        Statement::Label(_) => false,
+    }
+}
+
+fn exp_contains_call(exp: &Exp) -> bool {
+    match *exp {
+        Exp::Const(_) | Exp::Error | Exp::Name(_) | Exp::Temp(_) => false,
+        Exp::BinOp {
+            ref left,
+            ref right,
+            ..
+        } => exp_contains_call(left) || exp_contains_call(right),
+        Exp::Mem(ref exp) => exp_contains_call(exp),
+        Exp::Call(_, _) => true,
+        Exp::ExpSequence(ref statement, ref exp) => {
+            contains_call(statement) || exp_contains_call(exp)
+        }
+    }
+}
+
+fn reads_memory(exp: &Exp) -> bool {
+    match *exp {
+        Exp::Mem(_) => true,
+        Exp::BinOp {
+            ref left,
+            ref right,
+            ..
+        } => reads_memory(left) || reads_memory(right),
+        _ => false,
+    }
+}
+
+/// Registers that can change without an explicit assignment, for instance during a call.
+/// The frame pointer is constant during the body of a function.
+pub fn volatile_registers<F: Frame>() -> HashSet<Temp> {
+    F::temp_map()
+        .keys()
+        .cloned()
+        .filter(|&temp| temp != F::fp())
+        .collect()
+}
+
+/// Expression computed by a statement `t <- e` that could be reused instead of being computed
+/// again.
+pub fn computed_expression<'a>(
+    statement: &'a Statement,
+    volatile_registers: &HashSet<Temp>,
+) -> Option<&'a Exp> {
+    if let Statement::Move(Exp::Temp(temp), ref exp) = *statement {
+        match *exp {
+            Exp::BinOp { .. } | Exp::Mem(_) if is_pure(exp) => {
+                let mut uses = HashSet::new();
+                exp_uses(exp, &mut uses);
+                if !uses.contains(&temp) && uses.is_disjoint(volatile_registers) {
+                    return Some(exp);
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Whether the statement changes the value of the expression.
+fn kills(statement: &Statement, exp: &Exp) -> bool {
+    if let Some(temp) = defined_temp(statement) {
+        let mut uses = HashSet::new();
+        exp_uses(exp, &mut uses);
+        if uses.contains(&temp) {
+            return true;
+        }
+    }
+    reads_memory(exp) && writes_memory(statement)
+}
+
+/// **到达定值**。变量`t`的定值`d`到达语句`n`是指存在一条从`d`到`n`的路径，并且此路径上没有
+/// `t`的其他定值。事实是定值语句下标的集合。
+/// ```text
+/// in[n] = ∪ out[p], p ∈ pred[n]
+/// out[n] = gen[n] ∪ (in[n] - kill[n])
+/// ```
+pub struct ReachingDefinitions {
+    definitions: HashMap<Temp, Vec<usize>>,
+}
+
+impl ReachingDefinitions {
+    pub fn new(statements: &[Statement]) -> Self {
+        let mut definitions = HashMap::new();
+        for (index, statement) in statements.iter().enumerate() {
+            if let Some(temp) = defined_temp(statement) {
+                definitions.entry(temp).or_insert_with(Vec::new).push(index);
+            }
+        }
+        Self { definitions }
+    }
+
+    pub fn definitions(&self, temp: Temp) -> &[usize] {
+        self.definitions
+            .get(&temp)
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+    }
+}
+
+impl Analysis for ReachingDefinitions {
+    type Fact = HashSet<usize>;
+
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self) -> Self::Fact {
+        HashSet::new()
+    }
+
+    fn top(&self) -> Self::Fact {
+        HashSet::new()
+    }
+
+    fn meet(&self, fact: &mut Self::Fact, other: &Self::Fact) {
+        fact.extend(other);
+    }
+
+    fn transfer(&self, index: usize, statement: &Statement, fact: &mut Self::Fact) {
+        if let Some(temp) = defined_temp(statement) {
+            for definition in self.definitions(temp) {
+                fact.remove(definition);
+            }
+            fact.insert(index);
+        }
+    }
+}
+
+/// **可用表达式**。表达式`x ⊕ y`在语句`n`可用是指从入口到`n`的每一条路径上都计算了`x ⊕ y`，
+/// 并且在最后一次计算之后没有`x`和`y`的定值。访存表达式还会被存储和函数调用杀死。
+/// ```text
+/// in[n] = ∩ out[p], p ∈ pred[n]
+/// out[n] = gen[n] ∪ (in[n] - kill[n])
+/// ```
+pub struct AvailableExpressions {
+    expressions: HashSet<Exp>,
+    volatile_registers: HashSet<Temp>,
+}
+
+impl AvailableExpressions {
+    pub fn new<F: Frame>(statements: &[Statement]) -> Self {
+        let volatile_registers = volatile_registers::<F>();
+        let expressions = statements
+            .iter()
+            .filter_map(|statement| computed_expression(statement, &volatile_registers))
+            .cloned()
+            .collect();
+        Self {
+            expressions,
+            volatile_registers,
+        }
+    }
+}
+
+impl Analysis for AvailableExpressions {
+    type Fact = HashSet<Exp>;
+
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self) -> Self::Fact {
+        HashSet::new()
+    }
+
+    fn top(&self) -> Self::Fact {
+        self.expressions.clone()
+    }
+
+    fn meet(&self, fact: &mut Self::Fact, other: &Self::Fact) {
+        fact.retain(|exp| other.contains(exp));
+    }
+
+    fn transfer(&self, _index: usize, statement: &Statement, fact: &mut Self::Fact) {
+        if let Some(exp) = computed_expression(statement, &self.volatile_registers) {
+            fact.insert(exp.clone());
+        }
+        fact.retain(|exp| !kills(statement, exp));
+    }
+}
+
+/// **到达表达式**。语句`s: t <- x ⊕ y`到达语句`n`是指存在一条从`s`到`n`的路径，此路径上没有
+/// 再次计算`x ⊕ y`，也没有`x`和`y`的定值。事实是计算表达式的语句下标的集合。
+/// ```text
+/// in[n] = ∪ out[p], p ∈ pred[n]
+/// out[n] = gen[n] ∪ (in[n] - kill[n])
+/// ```
+pub struct ReachingExpressions {
+    expressions: HashMap<usize, Exp>,
+    volatile_registers: HashSet<Temp>,
+}
+
+impl ReachingExpressions {
+    pub fn new<F: Frame>(statements: &[Statement]) -> Self {
+        let volatile_registers = volatile_registers::<F>();
+        let expressions = statements
+            .iter()
+            .enumerate()
+            .filter_map(|(index, statement)| {
+                computed_expression(statement, &volatile_registers).map(|exp| (index, exp.clone()))
+            })
+            .collect();
+        Self {
+            expressions,
+            volatile_registers,
+        }
+    }
+
+    pub fn expression(&self, index: usize) -> Option<&Exp> {
+        self.expressions.get(&index)
+    }
+}
+
+impl Analysis for ReachingExpressions {
+    type Fact = HashSet<usize>;
+
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self) -> Self::Fact {
+        HashSet::new()
+    }
+
+    fn top(&self) -> Self::Fact {
+        HashSet::new()
+    }
+
+    fn meet(&self, fact: &mut Self::Fact, other: &Self::Fact) {
+        fact.extend(other);
+    }
+
+    fn transfer(&self, index: usize, statement: &Statement, fact: &mut Self::Fact) {
+        let expressions = &self.expressions;
+        if let Some(exp) = computed_expression(statement, &self.volatile_registers) {
+            fact.retain(|other| expressions[other] != *exp);
+            fact.insert(index);
+        }
+        fact.retain(|other| !kills(statement, &expressions[other]));
+    }
+}
+
+/// **可用复写**。复写`t <- s`在语句`n`可用是指从入口到`n`的每一条路径上都执行了`t <- s`，
+/// 并且在最后一次执行之后没有`t`和`s`的定值。只考虑不是机器寄存器的临时变量。
+/// ```text
+/// in[n] = ∩ out[p], p ∈ pred[n]
+/// out[n] = gen[n] ∪ (in[n] - kill[n])
+/// ```
+pub struct AvailableCopies {
+    copies: HashSet<(Temp, Temp)>,
+}
+
+impl AvailableCopies {
+    pub fn new<F: Frame>(statements: &[Statement]) -> Self {
+        let machine_registers = F::temp_map();
+        let copies = statements
+            .iter()
+            .filter_map(copy)
+            .filter(|&(destination, source)| {
+                !machine_registers.contains_key(&destination)
+                    && !machine_registers.contains_key(&source)
+            })
+            .collect();
+        Self { copies }
+    }
+}
+
+fn copy(statement: &Statement) -> Option<(Temp, Temp)> {
+    match *statement {
+        Statement::Move(Exp::Temp(destination), Exp::Temp(source)) if destination != source => {
+            Some((destination, source))
+        }
+        _ => None,
+    }
+}
+
+impl Analysis for AvailableCopies {
+    type Fact = HashSet<(Temp, Temp)>;
+
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self) -> Self::Fact {
+        HashSet::new()
+    }
+
+    fn top(&self) -> Self::Fact {
+        self.copies.clone()
+    }
+
+    fn meet(&self, fact: &mut Self::Fact, other: &Self::Fact) {
+        fact.retain(|copy| other.contains(copy));
+    }
+
+    fn transfer(&self, _index: usize, statement: &Statement, fact: &mut Self::Fact) {
+        if let Some(temp) = defined_temp(statement) {
+            fact.retain(|&(destination, source)| destination != temp && source != temp);
+        }
+        if let Some(copy) = copy(statement) {
+            if self.copies.contains(&copy) {
+                fact.insert(copy);
+            }
+        }
+    }
+}
+
+/// **活跃变量**。和`liveness.rs`中对指令的分析相同，但作用于中间表示的语句。
+/// ```text
+/// out[n] = ∪ in[s], s ∈ succ[n]
+/// in[n] = use[n] ∪ (out[n] - def[n])
+/// ```
+pub struct LiveTemps;
+
+impl Analysis for LiveTemps {
+    type Fact = HashSet<Temp>;
+
+    const DIRECTION: Direction = Direction::Backward;
+
+    fn boundary(&self) -> Self::Fact {
+        HashSet::new()
+    }
+
+    fn top(&self) -> Self::Fact {
+        HashSet::new()
+    }
+
+    fn meet(&self, fact: &mut Self::Fact, other: &Self::Fact) {
+        fact.extend(other);
+    }
+
+    fn transfer(&self, _index: usize, statement: &Statement, fact: &mut Self::Fact) {
+        if let Some(temp) = defined_temp(statement) {
+            fact.remove(&temp);
+        }
+        statement_uses(statement, fact);
+    }
+}
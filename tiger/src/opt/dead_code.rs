@@ -0,0 +1,43 @@
+//! **死代码删除**。若一条语句把一个纯表达式赋给一个在该语句之后不活跃的临时变量，则删除这条
+//! 语句；若表达式有副作用（函数调用），则只保留表达式。删除一条语句可能使另一个临时变量
+//! 不再活跃，所以重复直到没有变化。机器寄存器（返回值、参数等）被认为总是活跃的。
+
+use super::cfg::ControlFlowGraph;
+use super::dataflow::{solve, statement_facts, LiveTemps};
+use super::is_pure;
+use frame::Frame;
+use ir::{Exp, Statement};
+
+pub fn eliminate<F: Frame>(mut statements: Vec<Statement>) -> Vec<Statement> {
+    let machine_registers = F::temp_map();
+    loop {
+        let graph = ControlFlowGraph::new(&statements);
+        let solution = solve(&LiveTemps, &graph, &statements);
+        let live_out = statement_facts(&LiveTemps, &graph, &statements, &solution);
+        let mut changed = false;
+        statements = statements
+            .into_iter()
+            .zip(live_out)
+            .filter_map(|(statement, live)| match statement {
+                Statement::Move(Exp::Temp(temp), source)
+                    if !machine_registers.contains_key(&temp) && !live.contains(&temp) =>
+                {
+                    changed = true;
+                    if is_pure(&source) {
+                        None
+                    } else {
+                        Some(Statement::Exp(source))
+                    }
+                }
+                Statement::Exp(ref exp) if is_pure(exp) => {
+                    changed = true;
+                    None
+                }
+                statement => Some(statement),
+            })
+            .collect();
+        if !changed {
+            return statements;
+        }
+    }
+}
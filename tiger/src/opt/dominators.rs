@@ -0,0 +1,180 @@
+//! **必经结点**。若从入口到结点`n`的每一条有向路径都经过结点`d`，则称`d`是`n`的必经结点
+//! (dominator)。`n`的直接必经结点(idom)是`n`的严格必经结点中离`n`最近的那一个；除入口以外的
+//! 每个可达结点都有唯一的直接必经结点，它们构成必经结点树。
+//! 结点`x`的必经结点边界(dominance frontier)是所有满足下面条件的结点`w`的集合：`x`是`w`
+//! 的某个前驱的必经结点，但不是`w`的严格必经结点。
+//!
+//! 这里使用 Cooper、Harvey 和 Kennedy 的迭代算法，按逆后序处理结点。在反向的图上计算，就得到
+//! 后必经结点(postdominator)。
+
+pub struct DominatorTree {
+    idom: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+    /// Position of the reachable nodes in reverse postorder.
+    order: Vec<Option<usize>>,
+}
+
+impl DominatorTree {
+    pub fn new(entry: usize, successors: &[Vec<usize>], predecessors: &[Vec<usize>]) -> Self {
+        let node_count = successors.len();
+        let postorder = postorder(entry, successors);
+        let mut order = vec![None; node_count];
+        for (index, &node) in postorder.iter().rev().enumerate() {
+            order[node] = Some(index);
+        }
+
+        let mut idom = vec![None; node_count];
+        idom[entry] = Some(entry);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &node in postorder.iter().rev() {
+                if node == entry {
+                    continue;
+                }
+                let mut new_idom = None;
+                for &predecessor in &predecessors[node] {
+                    if idom[predecessor].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => predecessor,
+                        Some(other) => intersect(&idom, &order, predecessor, other),
+                    });
+                }
+                if new_idom.is_some() && idom[node] != new_idom {
+                    idom[node] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idom[entry] = None;
+
+        let mut children = vec![vec![]; node_count];
+        for (node, &parent) in idom.iter().enumerate() {
+            if let Some(parent) = parent {
+                children[parent].push(node);
+            }
+        }
+
+        Self {
+            idom,
+            children,
+            order,
+        }
+    }
+
+    pub fn idom(&self, node: usize) -> Option<usize> {
+        self.idom[node]
+    }
+
+    pub fn children(&self, node: usize) -> &[usize] {
+        &self.children[node]
+    }
+
+    pub fn is_reachable(&self, node: usize) -> bool {
+        self.order[node].is_some()
+    }
+
+    pub fn dominates(&self, dominator: usize, node: usize) -> bool {
+        if !self.is_reachable(node) {
+            return false;
+        }
+        let mut current = node;
+        loop {
+            if current == dominator {
+                return true;
+            }
+            match self.idom[current] {
+                Some(parent) => current = parent,
+                None => return false,
+            }
+        }
+    }
+
+    pub fn frontiers(&self, predecessors: &[Vec<usize>]) -> Vec<Vec<usize>> {
+        let mut frontiers = vec![vec![]; predecessors.len()];
+        for (node, node_predecessors) in predecessors.iter().enumerate() {
+            if !self.is_reachable(node) || node_predecessors.len() < 2 {
+                continue;
+            }
+            for &predecessor in node_predecessors {
+                let mut runner = predecessor;
+                while self.is_reachable(runner) && Some(runner) != self.idom[node] {
+                    if !frontiers[runner].contains(&node) {
+                        frontiers[runner].push(node);
+                    }
+                    match self.idom[runner] {
+                        Some(parent) => runner = parent,
+                        None => break,
+                    }
+                }
+            }
+        }
+        frontiers
+    }
+}
+
+fn intersect(
+    idom: &[Option<usize>],
+    order: &[Option<usize>],
+    mut node1: usize,
+    mut node2: usize,
+) -> usize {
+    while node1 != node2 {
+        while order[node1] > order[node2] {
+            node1 = idom[node1].expect("idom");
+        }
+        while order[node2] > order[node1] {
+            node2 = idom[node2].expect("idom");
+        }
+    }
+    node1
+}
+
+fn postorder(entry: usize, successors: &[Vec<usize>]) -> Vec<usize> {
+    let mut visited = vec![false; successors.len()];
+    let mut result = vec![];
+    let mut stack = vec![(entry, 0)];
+    visited[entry] = true;
+    while let Some(&mut (node, ref mut next)) = stack.last_mut() {
+        if let Some(&successor) = successors[node].get(*next) {
+            *next += 1;
+            if !visited[successor] {
+                visited[successor] = true;
+                stack.push((successor, 0));
+            }
+        } else {
+            result.push(node);
+            stack.pop();
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DominatorTree;
+
+    #[test]
+    fn test_dominators() {
+        // 0 -> 1 -> 2 -> 4
+        //      1 -> 3 -> 4 -> 1
+        let successors = vec![vec![1], vec![2, 3], vec![4], vec![4], vec![1]];
+        let predecessors = vec![vec![], vec![0, 4], vec![1], vec![1], vec![2, 3]];
+        let tree = DominatorTree::new(0, &successors, &predecessors);
+        assert_eq!(tree.idom(0), None);
+        assert_eq!(tree.idom(1), Some(0));
+        assert_eq!(tree.idom(2), Some(1));
+        assert_eq!(tree.idom(3), Some(1));
+        assert_eq!(tree.idom(4), Some(1));
+        assert!(tree.dominates(1, 4));
+        assert!(!tree.dominates(2, 4));
+
+        let frontiers = tree.frontiers(&predecessors);
+        assert_eq!(frontiers[2], vec![4]);
+        assert_eq!(frontiers[3], vec![4]);
+        assert_eq!(frontiers[4], vec![1]);
+        assert_eq!(frontiers[1], vec![1]);
+    }
+}
@@ -0,0 +1,179 @@
+//! **常量折叠**。运算数都是常量的表达式在编译时求值；两个运算数都是常量的条件跳转变为无条件跳转。
+//! 求值使用和目标机器相同的语义：算术运算回绕，移位数取低6位。
+
+use ir::{BinOp, Exp, RelationalOp, Statement};
+
+pub fn fold(statements: Vec<Statement>) -> Vec<Statement> {
+    statements.into_iter().map(fold_statement).collect()
+}
+
+fn fold_statement(statement: Statement) -> Statement {
+    match statement {
+        Statement::Move(destination, source) => {
+            Statement::Move(fold_exp(destination), fold_exp(source))
+        }
+        Statement::Exp(exp) => Statement::Exp(fold_exp(exp)),
+        Statement::Jump(exp, labels) => Statement::Jump(fold_exp(exp), labels),
+        Statement::CondJump {
+            op,
+            left,
+            right,
+            true_label,
+            false_label,
+        } => {
+            let left = fold_exp(left);
+            let right = fold_exp(right);
+            if let (&Exp::Const(left), &Exp::Const(right)) = (&left, &right) {
+                let label = if compare(&op, left, right) {
+                    true_label
+                } else {
+                    false_label
+                };
+                return Statement::Jump(Exp::Name(label.clone()), vec![label]);
+            }
+            Statement::CondJump {
+                op,
+                left,
+                right,
+                true_label,
+                false_label,
+            }
+        }
+        Statement::Sequence(first, second) => Statement::Sequence(
+            Box::new(fold_statement(*first)),
+            Box::new(fold_statement(*second)),
+        ),
+        Statement::Label(label) => Statement::Label(label),
+    }
+}
+
+fn fold_exp(exp: Exp) -> Exp {
+    match exp {
+        Exp::BinOp { op, left, right } => {
+            let left = fold_exp(*left);
+            let right = fold_exp(*right);
+            if let (&Exp::Const(left), &Exp::Const(right)) = (&left, &right) {
+                if let Some(value) = evaluate(&op, left, right) {
+                    return Exp::Const(value);
+                }
+            }
+            Exp::BinOp {
+                op,
+                left: Box::new(left),
+                right: Box::new(right),
+            }
+        }
+        Exp::Mem(exp) => Exp::Mem(Box::new(fold_exp(*exp))),
+        Exp::Call(function, arguments) => Exp::Call(
+            Box::new(fold_exp(*function)),
+            arguments.into_iter().map(fold_exp).collect(),
+        ),
+        Exp::ExpSequence(statement, exp) => Exp::ExpSequence(
+            Box::new(fold_statement(*statement)),
+            Box::new(fold_exp(*exp)),
+        ),
+        exp @ Exp::Const(_) | exp @ Exp::Error | exp @ Exp::Name(_) | exp @ Exp::Temp(_) => exp,
+    }
+}
+
+/// Returns None for a division by zero, which must be left to the runtime.
+pub fn evaluate(op: &BinOp, left: i64, right: i64) -> Option<i64> {
+    let value = match *op {
+        BinOp::Plus => left.wrapping_add(right),
+        BinOp::Minus => left.wrapping_sub(right),
+        BinOp::Mul => left.wrapping_mul(right),
+        BinOp::Div => {
+            if right == 0 {
+                return None;
+            }
+            left.wrapping_div(right)
+        }
+        BinOp::And => left & right,
+        BinOp::Or => left | right,
+        BinOp::ShiftLeft => left.wrapping_shl(right as u32),
+        BinOp::ShiftRight => (left as u64).wrapping_shr(right as u32) as i64,
+        BinOp::ArithmeticShiftRight => left.wrapping_shr(right as u32),
+        BinOp::Xor => left ^ right,
+    };
+    Some(value)
+}
+
+pub fn compare(op: &RelationalOp, left: i64, right: i64) -> bool {
+    match *op {
+        RelationalOp::Equal => left == right,
+        RelationalOp::NotEqual => left != right,
+        RelationalOp::LesserThan => left < right,
+        RelationalOp::GreaterThan => left > right,
+        RelationalOp::LesserOrEqual => left <= right,
+        RelationalOp::GreaterOrEqual => left >= right,
+        RelationalOp::UnsignedLesserThan => (left as u64) < right as u64,
+        RelationalOp::UnsignedLesserOrEqual => left as u64 <= right as u64,
+        RelationalOp::UnsignedGreaterThan => left as u64 > right as u64,
+        RelationalOp::UnsignedGreaterOrEqual => left as u64 >= right as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fold;
+    use ir::{BinOp, Exp, RelationalOp, Statement};
+    use temp::{Label, Temp};
+
+    fn binop(op: BinOp, left: Exp, right: Exp) -> Exp {
+        Exp::BinOp {
+            op,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    #[test]
+    fn test_fold() {
+        let temp = Temp::new();
+        let statements = vec![
+            Statement::Move(
+                Exp::Temp(temp),
+                binop(
+                    BinOp::Mul,
+                    binop(BinOp::Plus, Exp::Const(1), Exp::Const(2)),
+                    Exp::Const(4),
+                ),
+            ),
+            Statement::Move(
+                Exp::Temp(temp),
+                binop(BinOp::ShiftRight, Exp::Const(-1), Exp::Const(60)),
+            ),
+            Statement::Move(
+                Exp::Temp(temp),
+                binop(BinOp::Div, Exp::Const(1), Exp::Const(0)),
+            ),
+        ];
+        let expected = vec![
+            Statement::Move(Exp::Temp(temp), Exp::Const(12)),
+            Statement::Move(Exp::Temp(temp), Exp::Const(15)),
+            Statement::Move(
+                Exp::Temp(temp),
+                binop(BinOp::Div, Exp::Const(1), Exp::Const(0)),
+            ),
+        ];
+        assert_eq!(fold(statements), expected);
+    }
+
+    #[test]
+    fn test_fold_cond_jump() {
+        let true_label = Label::new();
+        let false_label = Label::new();
+        let statements = vec![Statement::CondJump {
+            op: RelationalOp::UnsignedLesserThan,
+            left: Exp::Const(-1),
+            right: Exp::Const(1),
+            true_label: true_label.clone(),
+            false_label: false_label.clone(),
+        }];
+        let expected = vec![Statement::Jump(
+            Exp::Name(false_label.clone()),
+            vec![false_label],
+        )];
+        assert_eq!(fold(statements), expected);
+    }
+}
@@ -0,0 +1,265 @@
+//! **归纳变量强度削弱**(strength reduction)。对于循环中的基本归纳变量`i`（每次迭代加上常量
+//! `c`）和它的族中的变量`j = i + d`，表达式`a + j * m`（`a`是循环不变量，`m`是常量）的值
+//! 每次迭代增加`c * m`。为它引入一个新的归纳变量`s`：
+//!
+//! ```text
+//! 前置结点：s0 <- a + d * m + i0 * m
+//! 首结点：  s <- φ(s0, s1)
+//! 回边：    s1 <- s + c * m
+//! ```
+//!
+//! 族中的变量与循环不变量的和（例如内层循环中的下标`i + j`）也一样处理。然后把表达式替换为`s`。这样数组下标`mem[array + (i << 3)]`中的乘法（或移位）和加法变为
+//! 每次迭代一次指针加法，原来的归纳变量如果只用于循环测试，它的其他计算可以被死代码删除。
+
+use std::collections::{HashMap, HashSet};
+
+use super::loops::{find_loops, induction_variables, InductionVariable, Loop};
+use super::ssa::{Phi, SsaFunction};
+use ir::{BinOp, Exp, Statement};
+use temp::Temp;
+
+/// `base + constant + (variable + invariant) * scale`, where `variable` is the basic induction
+/// variable.
+#[derive(Clone, Eq, Hash, PartialEq)]
+struct Linear {
+    variable: usize,
+    invariant: Option<Temp>,
+    base: Option<Temp>,
+    constant: i64,
+    scale: i64,
+}
+
+struct Reducer<'a> {
+    function: &'a SsaFunction,
+    variables: &'a [InductionVariable],
+    /// The temporaries defined in the loop.
+    definitions: &'a HashSet<Temp>,
+    /// The new induction variable of each expression, in order of creation.
+    reductions: Vec<(Linear, Temp)>,
+}
+
+impl<'a> Reducer<'a> {
+    /// The induction variable, invariant and offset of a member of a family or of its sum with an
+    /// invariant.
+    fn member(&self, temp: Temp) -> Option<(usize, Option<Temp>, i64)> {
+        self.variables
+            .iter()
+            .enumerate()
+            .filter_map(|(index, variable)| match variable.sums.get(&temp) {
+                Some(&(member, invariant)) => {
+                    Some((index, Some(invariant), variable.family[&member]))
+                }
+                None => variable
+                    .family
+                    .get(&temp)
+                    .map(|&offset| (index, None, offset)),
+            })
+            .next()
+    }
+
+    fn linear(&self, exp: &Exp) -> Option<Linear> {
+        let scaled = |temp: Temp, scale: i64| {
+            self.member(temp)
+                .map(|(variable, invariant, offset)| Linear {
+                    variable,
+                    invariant,
+                    base: None,
+                    constant: offset.wrapping_mul(scale),
+                    scale,
+                })
+        };
+        match *exp {
+            Exp::BinOp {
+                op: BinOp::Mul,
+                left: box Exp::Temp(temp),
+                right: box Exp::Const(scale),
+            }
+            | Exp::BinOp {
+                op: BinOp::Mul,
+                left: box Exp::Const(scale),
+                right: box Exp::Temp(temp),
+            } => scaled(temp, scale),
+            Exp::BinOp {
+                op: BinOp::ShiftLeft,
+                left: box Exp::Temp(temp),
+                right: box Exp::Const(shift),
+            } if (0..64).contains(&shift) => scaled(temp, 1i64.wrapping_shl(shift as u32)),
+            Exp::BinOp {
+                op: BinOp::Plus,
+                ref left,
+                ref right,
+            } => self.add(left, right).or_else(|| self.add(right, left)),
+            Exp::BinOp {
+                op: BinOp::Minus,
+                ref left,
+                right: box Exp::Const(constant),
+            } => self.linear(left).map(|linear| Linear {
+                constant: linear.constant.wrapping_sub(constant),
+                ..linear
+            }),
+            _ => None,
+        }
+    }
+
+    fn add(&self, exp: &Exp, addend: &Exp) -> Option<Linear> {
+        let linear = self.linear(exp)?;
+        match *addend {
+            Exp::Const(constant) => Some(Linear {
+                constant: linear.constant.wrapping_add(constant),
+                ..linear
+            }),
+            Exp::Temp(temp)
+                if linear.base.is_none()
+                    && self.function.is_variable(temp)
+                    && !self.definitions.contains(&temp) =>
+            {
+                Some(Linear {
+                    base: Some(temp),
+                    ..linear
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn reduce(&mut self, exp: Exp) -> Exp {
+        if let Some(linear) = self.linear(&exp) {
+            if let Some(&(_, temp)) = self.reductions.iter().find(|(other, _)| *other == linear) {
+                return Exp::Temp(temp);
+            }
+            let temp = Temp::new();
+            self.reductions.push((linear, temp));
+            return Exp::Temp(temp);
+        }
+        match exp {
+            Exp::BinOp { op, left, right } => Exp::BinOp {
+                op,
+                left: Box::new(self.reduce(*left)),
+                right: Box::new(self.reduce(*right)),
+            },
+            Exp::Mem(exp) => Exp::Mem(Box::new(self.reduce(*exp))),
+            Exp::Call(function, arguments) => Exp::Call(
+                function,
+                arguments
+                    .into_iter()
+                    .map(|argument| self.reduce(argument))
+                    .collect(),
+            ),
+            exp => exp,
+        }
+    }
+
+    fn reduce_statement(&mut self, statement: Statement) -> Statement {
+        match statement {
+            Statement::Move(Exp::Temp(temp), source) => {
+                Statement::Move(Exp::Temp(temp), self.reduce(source))
+            }
+            Statement::Move(destination, source) => {
+                Statement::Move(self.reduce(destination), self.reduce(source))
+            }
+            Statement::Exp(exp) => Statement::Exp(self.reduce(exp)),
+            Statement::CondJump {
+                op,
+                left,
+                right,
+                true_label,
+                false_label,
+            } => Statement::CondJump {
+                op,
+                left: self.reduce(left),
+                right: self.reduce(right),
+                true_label,
+                false_label,
+            },
+            statement => statement,
+        }
+    }
+}
+
+fn binop(op: BinOp, left: Exp, right: Exp) -> Exp {
+    Exp::BinOp {
+        op,
+        left: Box::new(left),
+        right: Box::new(right),
+    }
+}
+
+fn reduce_loop(function: &mut SsaFunction, natural_loop: &Loop) {
+    let variables = induction_variables(function, natural_loop);
+    if variables.is_empty() {
+        return;
+    }
+    let blocks = natural_loop.sorted_blocks();
+    let definitions = natural_loop.definitions(function);
+    let mut loop_statements: Vec<_> = blocks
+        .iter()
+        .map(|&index| ::std::mem::take(&mut function.blocks[index].statements))
+        .collect();
+    let reductions = {
+        let mut reducer = Reducer {
+            function,
+            variables: &variables,
+            definitions: &definitions,
+            reductions: vec![],
+        };
+        for statements in &mut loop_statements {
+            *statements = ::std::mem::take(statements)
+                .into_iter()
+                .map(|statement| reducer.reduce_statement(statement))
+                .collect();
+        }
+        reducer.reductions
+    };
+    for (&index, statements) in blocks.iter().zip(loop_statements) {
+        function.blocks[index].statements = statements;
+    }
+
+    for (linear, temp) in reductions {
+        let variable = &variables[linear.variable];
+        let mut initial_value = Exp::Temp(variable.initial);
+        if let Some(invariant) = linear.invariant {
+            initial_value = binop(BinOp::Plus, initial_value, Exp::Temp(invariant));
+        }
+        initial_value = binop(BinOp::Mul, initial_value, Exp::Const(linear.scale));
+        if let Some(base) = linear.base {
+            initial_value = binop(BinOp::Plus, Exp::Temp(base), initial_value);
+        }
+        if linear.constant != 0 {
+            initial_value = binop(BinOp::Plus, initial_value, Exp::Const(linear.constant));
+        }
+        let initial = Temp::new();
+        function.blocks[natural_loop.preheader]
+            .push_before_jump(Statement::Move(Exp::Temp(initial), initial_value));
+
+        let mut next_values = HashMap::new();
+        for &(predecessor, increment) in &variable.increments {
+            let next = Temp::new();
+            function.blocks[predecessor].push_before_jump(Statement::Move(
+                Exp::Temp(next),
+                binop(
+                    BinOp::Plus,
+                    Exp::Temp(temp),
+                    Exp::Const(increment.wrapping_mul(linear.scale)),
+                ),
+            ));
+            next_values.insert(predecessor, next);
+        }
+
+        let header = &mut function.blocks[natural_loop.header];
+        let arguments = header
+            .predecessors
+            .iter()
+            .map(|predecessor| next_values.get(predecessor).cloned().unwrap_or(initial))
+            .collect();
+        header.phis.push(Phi {
+            destination: temp,
+            arguments,
+        });
+    }
+}
+
+pub fn reduce(function: &mut SsaFunction) {
+    for natural_loop in find_loops(function) {
+        reduce_loop(function, &natural_loop);
+    }
+}
@@ -0,0 +1,398 @@
+//! **内联**(inlining)。调用小函数的代价（静态链的计算、`proc_entry_exit1`中被调用者保存寄存器的
+//! 保存和恢复）可能比函数体本身还大。内联作用于`Fragment::Function`的函数体，在规范化之前
+//! 执行，所以后面的各遍可以优化内联后的代码。
+//!
+//! 调用`f(a1, ..., an, sl)`被替换为：
+//!
+//! ```text
+//! eseq(p1 <- a1; ...; pn <- an; psl <- sl, body)
+//! ```
+//!
+//! 其中`body`是`f`的函数体的副本：参数（包括静态链）的位置被替换为新的临时变量`pi`，其他临时
+//! 变量和函数体中定义的标号也被替换为新的。函数体中除了参数之外不能使用帧指针：逃逸的变量
+//! 在`f`的栈帧中，嵌套函数需要`f`的帧指针作为静态链，这样的函数不被内联。包含尾调用的函数
+//! 会释放`f`的栈帧，也不被内联。
+//!
+//! 函数体的结点数不超过`MAX_SIZE`的函数，或者不调用程序中其他函数的叶函数(leaf function)的
+//! 结点数不超过`MAX_LEAF_SIZE`时被内联。内联后的函数体中的调用也会被内联，嵌套的深度不超过
+//! `MAX_DEPTH`，递归函数在自身中最多被展开`MAX_RECURSION`次。
+
+use std::collections::{HashMap, HashSet};
+
+use frame::{Fragment, Frame};
+use ir::{Exp, Statement};
+use temp::{Label, Temp};
+
+const MAX_SIZE: usize = 80;
+const MAX_LEAF_SIZE: usize = 200;
+const MAX_DEPTH: usize = 4;
+const MAX_RECURSION: usize = 1;
+
+struct Callee {
+    /// The location of each parameter in the frame of the callee, the static link last.
+    parameters: Vec<Exp>,
+    body: Exp,
+    /// The labels defined in the body.
+    labels: Vec<Label>,
+    size: usize,
+    is_leaf: bool,
+}
+
+impl Callee {
+    fn new<F: Frame>(body: &Statement, frame: &F, functions: &HashSet<Label>) -> Option<Self> {
+        let body = match *body {
+            Statement::Move(Exp::Temp(temp), ref body) if temp == F::return_value() => body,
+            _ => return None,
+        };
+        let parameters: Vec<_> = frame
+            .formals()
+            .iter()
+            .map(|formal| frame.exp(formal.clone(), Exp::Temp(F::fp())))
+            .collect();
+        let mut scan = Scan {
+            parameters: &parameters,
+            functions,
+            machine_registers: F::temp_map(),
+            labels: vec![],
+            size: 0,
+            is_leaf: true,
+            uses_frame: false,
+        };
+        scan.exp(body);
+        if scan.uses_frame {
+            return None;
+        }
+        Some(Callee {
+            labels: scan.labels,
+            size: scan.size,
+            is_leaf: scan.is_leaf,
+            parameters,
+            body: body.clone(),
+        })
+    }
+
+    fn is_inlinable(&self) -> bool {
+        self.size <= MAX_SIZE || (self.is_leaf && self.size <= MAX_LEAF_SIZE)
+    }
+}
+
+struct Scan<'a> {
+    parameters: &'a [Exp],
+    functions: &'a HashSet<Label>,
+    machine_registers: HashMap<Temp, &'static str>,
+    labels: Vec<Label>,
+    size: usize,
+    is_leaf: bool,
+    /// Whether the body needs the frame of the callee: it uses a machine register, like the frame
+    /// pointer elsewhere than in the location of a parameter, or makes a tail call.
+    uses_frame: bool,
+}
+
+impl<'a> Scan<'a> {
+    fn exp(&mut self, exp: &Exp) {
+        self.size += 1;
+        if self.parameters.contains(exp) {
+            return;
+        }
+        match *exp {
+            Exp::Const(_) | Exp::Error | Exp::Name(_) => (),
+            Exp::Temp(temp) => {
+                if self.machine_registers.contains_key(&temp) {
+                    self.uses_frame = true;
+                }
+            }
+            Exp::BinOp {
+                ref left,
+                ref right,
+                ..
+            } => {
+                self.exp(left);
+                self.exp(right);
+            }
+            Exp::Mem(ref exp) => self.exp(exp),
+            Exp::Call(ref function, ref arguments) => {
+                if let Exp::Name(ref label) = **function {
+                    if self.functions.contains(label) {
+                        self.is_leaf = false;
+                    }
+                }
+                self.exp(function);
+                for argument in arguments {
+                    self.exp(argument);
+                }
+            }
+            Exp::ExpSequence(ref statement, ref exp) => {
+                self.statement(statement);
+                self.exp(exp);
+            }
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        self.size += 1;
+        match *statement {
+            Statement::Move(ref destination, ref source) => {
+                self.exp(destination);
+                self.exp(source);
+            }
+            // A tail call leaves the frame of the callee.
+            Statement::Jump(_, ref labels) if labels.is_empty() => self.uses_frame = true,
+            Statement::Exp(ref exp) | Statement::Jump(ref exp, _) => self.exp(exp),
+            Statement::CondJump {
+                ref left,
+                ref right,
+                ..
+            } => {
+                self.exp(left);
+                self.exp(right);
+            }
+            Statement::Sequence(ref first, ref second) => {
+                self.statement(first);
+                self.statement(second);
+            }
+            Statement::Label(ref label) => self.labels.push(label.clone()),
+        }
+    }
+}
+
+/// Copy of the body of a callee with new temporaries and labels.
+struct Renamer {
+    /// The temporaries replacing the locations of the parameters.
+    parameters: HashMap<Exp, Temp>,
+    temps: HashMap<Temp, Temp>,
+    labels: HashMap<Label, Label>,
+}
+
+impl Renamer {
+    fn temp(&mut self, temp: Temp) -> Temp {
+        *self.temps.entry(temp).or_insert_with(Temp::new)
+    }
+
+    fn label(&self, label: &Label) -> Label {
+        self.labels
+            .get(label)
+            .cloned()
+            .unwrap_or_else(|| label.clone())
+    }
+
+    fn exp(&mut self, exp: &Exp) -> Exp {
+        if let Some(&temp) = self.parameters.get(exp) {
+            return Exp::Temp(temp);
+        }
+        match *exp {
+            Exp::Const(_) | Exp::Error => exp.clone(),
+            Exp::Name(ref label) => Exp::Name(self.label(label)),
+            Exp::Temp(temp) => Exp::Temp(self.temp(temp)),
+            Exp::BinOp {
+                ref op,
+                ref left,
+                ref right,
+            } => Exp::BinOp {
+                op: op.clone(),
+                left: Box::new(self.exp(left)),
+                right: Box::new(self.exp(right)),
+            },
+            Exp::Mem(ref exp) => Exp::Mem(Box::new(self.exp(exp))),
+            Exp::Call(ref function, ref arguments) => Exp::Call(
+                Box::new(self.exp(function)),
+                arguments
+                    .iter()
+                    .map(|argument| self.exp(argument))
+                    .collect(),
+            ),
+            Exp::ExpSequence(ref statement, ref exp) => {
+                Exp::ExpSequence(Box::new(self.statement(statement)), Box::new(self.exp(exp)))
+            }
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Statement {
+        match *statement {
+            Statement::Move(ref destination, ref source) => {
+                Statement::Move(self.exp(destination), self.exp(source))
+            }
+            Statement::Exp(ref exp) => Statement::Exp(self.exp(exp)),
+            Statement::Jump(ref exp, ref labels) => Statement::Jump(
+                self.exp(exp),
+                labels.iter().map(|label| self.label(label)).collect(),
+            ),
+            Statement::CondJump {
+                ref op,
+                ref left,
+                ref right,
+                ref true_label,
+                ref false_label,
+            } => Statement::CondJump {
+                op: op.clone(),
+                left: self.exp(left),
+                right: self.exp(right),
+                true_label: self.label(true_label),
+                false_label: self.label(false_label),
+            },
+            Statement::Sequence(ref first, ref second) => Statement::Sequence(
+                Box::new(self.statement(first)),
+                Box::new(self.statement(second)),
+            ),
+            Statement::Label(ref label) => Statement::Label(self.label(label)),
+        }
+    }
+}
+
+struct Inliner<'a> {
+    callees: &'a HashMap<Label, Callee>,
+    /// The function being transformed, followed by the callees being inlined in it.
+    stack: Vec<Label>,
+}
+
+impl<'a> Inliner<'a> {
+    fn callee(&self, label: &Label, arguments: &[Exp]) -> Option<&'a Callee> {
+        let callees = self.callees;
+        let callee = callees.get(label)?;
+        let recursion = self
+            .stack
+            .iter()
+            .filter(|&function| function == label)
+            .count();
+        if self.stack.len() <= MAX_DEPTH
+            && recursion <= MAX_RECURSION
+            && callee.parameters.len() == arguments.len()
+        {
+            Some(callee)
+        } else {
+            None
+        }
+    }
+
+    fn expand(&mut self, label: Label, callee: &Callee, arguments: Vec<Exp>) -> Exp {
+        let mut renamer = Renamer {
+            parameters: HashMap::new(),
+            temps: HashMap::new(),
+            labels: callee
+                .labels
+                .iter()
+                .map(|label| (label.clone(), Label::new()))
+                .collect(),
+        };
+        let mut statements = vec![];
+        for (location, argument) in callee.parameters.iter().zip(arguments) {
+            let temp = Temp::new();
+            renamer.parameters.insert(location.clone(), temp);
+            statements.push(Statement::Move(Exp::Temp(temp), argument));
+        }
+        let body = renamer.exp(&callee.body);
+        self.stack.push(label);
+        let body = self.exp(body);
+        self.stack.pop();
+        let mut statements = statements.into_iter();
+        let first = statements.next().expect("static link");
+        let statement = statements.fold(first, |sequence, statement| {
+            Statement::Sequence(Box::new(sequence), Box::new(statement))
+        });
+        Exp::ExpSequence(Box::new(statement), Box::new(body))
+    }
+
+    fn exp(&mut self, exp: Exp) -> Exp {
+        match exp {
+            Exp::BinOp { op, left, right } => Exp::BinOp {
+                op,
+                left: Box::new(self.exp(*left)),
+                right: Box::new(self.exp(*right)),
+            },
+            Exp::Mem(exp) => Exp::Mem(Box::new(self.exp(*exp))),
+            Exp::Call(function, arguments) => {
+                let arguments: Vec<_> = arguments
+                    .into_iter()
+                    .map(|argument| self.exp(argument))
+                    .collect();
+                let callee = match *function {
+                    Exp::Name(ref label) => self
+                        .callee(label, &arguments)
+                        .map(|callee| (label.clone(), callee)),
+                    _ => None,
+                };
+                match callee {
+                    Some((label, callee)) => self.expand(label, callee, arguments),
+                    None => Exp::Call(function, arguments),
+                }
+            }
+            Exp::ExpSequence(statement, exp) => {
+                let statement = self.statement(*statement);
+                Exp::ExpSequence(Box::new(statement), Box::new(self.exp(*exp)))
+            }
+            exp @ Exp::Const(_) | exp @ Exp::Error | exp @ Exp::Name(_) | exp @ Exp::Temp(_) => exp,
+        }
+    }
+
+    fn statement(&mut self, statement: Statement) -> Statement {
+        match statement {
+            Statement::Move(destination, source) => {
+                let destination = self.exp(destination);
+                Statement::Move(destination, self.exp(source))
+            }
+            Statement::Exp(exp) => Statement::Exp(self.exp(exp)),
+            // The arguments of a tail call are already in temporaries.
+            statement @ Statement::Jump(Exp::Call(_, _), _) => statement,
+            Statement::Jump(exp, labels) => Statement::Jump(self.exp(exp), labels),
+            Statement::CondJump {
+                op,
+                left,
+                right,
+                true_label,
+                false_label,
+            } => {
+                let left = self.exp(left);
+                Statement::CondJump {
+                    op,
+                    left,
+                    right: self.exp(right),
+                    true_label,
+                    false_label,
+                }
+            }
+            Statement::Sequence(first, second) => {
+                let first = self.statement(*first);
+                Statement::Sequence(Box::new(first), Box::new(self.statement(*second)))
+            }
+            statement @ Statement::Label(_) => statement,
+        }
+    }
+}
+
+pub fn inline<F: Frame>(fragments: Vec<Fragment<F>>) -> Vec<Fragment<F>> {
+    let functions: HashSet<_> = fragments
+        .iter()
+        .filter_map(|fragment| match *fragment {
+            Fragment::Function { ref frame, .. } => Some(frame.borrow().name()),
+            Fragment::Export { .. } | Fragment::Extern(_) | Fragment::Str(_, _) => None,
+        })
+        .collect();
+    let mut callees = HashMap::new();
+    for fragment in &fragments {
+        if let Fragment::Function {
+            ref body,
+            ref frame,
+        } = *fragment
+        {
+            let frame = frame.borrow();
+            if let Some(callee) = Callee::new(body, &*frame, &functions) {
+                if callee.is_inlinable() {
+                    callees.insert(frame.name(), callee);
+                }
+            }
+        }
+    }
+    fragments
+        .into_iter()
+        .map(|fragment| match fragment {
+            Fragment::Function { body, frame } => {
+                let mut inliner = Inliner {
+                    callees: &callees,
+                    stack: vec![frame.borrow().name()],
+                };
+                let body = inliner.statement(body);
+                Fragment::Function { body, frame }
+            }
+            fragment => fragment,
+        })
+        .collect()
+}
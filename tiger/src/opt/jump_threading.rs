@@ -0,0 +1,217 @@
+//! **跳转穿越**(jump threading)。条件表达式的值先被保存到一个临时变量中，再由另一个条件
+//! 跳转测试，例如`while`循环的条件：
+//!
+//! ```text
+//! l1: t <- 1; jump l3
+//! l2: t <- 0
+//! l3: cjump t <> 1, done, body
+//! ```
+//!
+//! 若基本块末尾`t`的值是已知的常量，并且它跳转到只包含一条测试`t`的条件跳转的基本块，则可以
+//! 直接跳转到条件跳转的目标。这样循环的出口测试直接作用于循环变量，便于循环优化。
+
+use std::collections::HashMap;
+
+use super::fold::compare;
+use super::{is_pure, unreachable};
+use ir::{Exp, RelationalOp, Statement};
+use temp::{Label, Temp};
+
+struct Test {
+    temp: Temp,
+    op: RelationalOp,
+    constant: i64,
+    true_label: Label,
+    false_label: Label,
+}
+
+impl Test {
+    fn new(block: &[Statement]) -> Option<Self> {
+        let mut statements = block.iter().skip(1).filter(|statement| match **statement {
+            Statement::Exp(ref exp) => !is_pure(exp),
+            _ => true,
+        });
+        let test = match statements.next() {
+            Some(&Statement::CondJump {
+                ref op,
+                left: Exp::Temp(temp),
+                right: Exp::Const(constant),
+                ref true_label,
+                ref false_label,
+            }) => Test {
+                temp,
+                op: op.clone(),
+                constant,
+                true_label: true_label.clone(),
+                false_label: false_label.clone(),
+            },
+            _ => return None,
+        };
+        match statements.next() {
+            None => Some(test),
+            Some(_) => None,
+        }
+    }
+}
+
+/// The value of `temp` at the end of `block`, before its jump, if it is a known constant.
+fn value_at_end(block: &[Statement], temp: Temp) -> Option<i64> {
+    let statements = match block.split_last() {
+        Some((&Statement::Jump(_, _), statements)) => statements,
+        _ => block,
+    };
+    for statement in statements.iter().rev() {
+        match *statement {
+            Statement::Move(Exp::Temp(destination), ref source) if destination == temp => {
+                return match *source {
+                    Exp::Const(value) => Some(value),
+                    _ => None,
+                };
+            }
+            // A call can modify the machine registers.
+            Statement::Move(_, ref exp) | Statement::Exp(ref exp) if !is_pure(exp) => return None,
+            Statement::Move(_, _) | Statement::Exp(_) | Statement::Label(_) => (),
+            _ => return None,
+        }
+    }
+    None
+}
+
+pub fn thread(statements: Vec<Statement>) -> Vec<Statement> {
+    let mut blocks: Vec<Vec<Statement>> = vec![];
+    for statement in statements {
+        match statement {
+            Statement::Label(_) => blocks.push(vec![statement]),
+            statement if blocks.is_empty() => blocks.push(vec![statement]),
+            statement => blocks.last_mut().expect("block").push(statement),
+        }
+    }
+
+    let mut tests = HashMap::new();
+    let mut labels = vec![];
+    for block in &blocks {
+        match block.first() {
+            Some(Statement::Label(label)) => {
+                if let Some(test) = Test::new(block) {
+                    tests.insert(label.clone(), test);
+                }
+                labels.push(Some(label.clone()));
+            }
+            _ => labels.push(None),
+        }
+    }
+
+    for (index, block) in blocks.iter_mut().enumerate() {
+        // Follow the chains of tests, at most once through each of them in case of an infinite
+        // loop.
+        for _ in 0..tests.len() {
+            let target = match block.last() {
+                Some(&Statement::Jump(Exp::Name(ref label), _)) => Some(label.clone()),
+                Some(&Statement::Jump(_, _)) | Some(&Statement::CondJump { .. }) => None,
+                _ => labels.get(index + 1).cloned().and_then(|label| label),
+            };
+            let test = match target.and_then(|label| tests.get(&label)) {
+                Some(test) => test,
+                None => break,
+            };
+            let value = match value_at_end(block, test.temp) {
+                Some(value) => value,
+                None => break,
+            };
+            let label = if compare(&test.op, value, test.constant) {
+                test.true_label.clone()
+            } else {
+                test.false_label.clone()
+            };
+            if let Some(&Statement::Jump(_, _)) = block.last() {
+                block.pop();
+            }
+            block.push(Statement::Jump(Exp::Name(label.clone()), vec![label]));
+        }
+    }
+
+    unreachable::eliminate(blocks.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::thread;
+    use ir::{BinOp, Exp, RelationalOp, Statement};
+    use temp::{Label, Temp};
+
+    fn jump(label: &Label) -> Statement {
+        Statement::Jump(Exp::Name(label.clone()), vec![label.clone()])
+    }
+
+    fn cond_jump(op: RelationalOp, left: Exp, right: Exp, labels: (&Label, &Label)) -> Statement {
+        Statement::CondJump {
+            op,
+            left,
+            right,
+            true_label: labels.0.clone(),
+            false_label: labels.1.clone(),
+        }
+    }
+
+    #[test]
+    fn test_thread() {
+        // while i < 10 do i := i + 1
+        let i = Temp::new();
+        let test = Temp::new();
+        let increment = Exp::BinOp {
+            op: BinOp::Plus,
+            left: Box::new(Exp::Temp(i)),
+            right: Box::new(Exp::Const(1)),
+        };
+        let labels: Vec<_> = (0..7).map(|_| Label::new()).collect();
+        let statements = vec![
+            Statement::Label(labels[0].clone()),
+            cond_jump(
+                RelationalOp::GreaterOrEqual,
+                Exp::Temp(i),
+                Exp::Const(10),
+                (&labels[2], &labels[1]),
+            ),
+            Statement::Label(labels[1].clone()),
+            Statement::Move(Exp::Temp(test), Exp::Const(1)),
+            jump(&labels[3]),
+            Statement::Label(labels[2].clone()),
+            Statement::Move(Exp::Temp(test), Exp::Const(0)),
+            Statement::Label(labels[3].clone()),
+            Statement::Exp(Exp::Const(0)),
+            cond_jump(
+                RelationalOp::NotEqual,
+                Exp::Temp(test),
+                Exp::Const(1),
+                (&labels[5], &labels[4]),
+            ),
+            Statement::Label(labels[4].clone()),
+            Statement::Move(Exp::Temp(i), increment.clone()),
+            jump(&labels[0]),
+            Statement::Label(labels[5].clone()),
+            jump(&labels[6]),
+            Statement::Label(labels[6].clone()),
+        ];
+        let expected = vec![
+            Statement::Label(labels[0].clone()),
+            cond_jump(
+                RelationalOp::GreaterOrEqual,
+                Exp::Temp(i),
+                Exp::Const(10),
+                (&labels[2], &labels[1]),
+            ),
+            Statement::Label(labels[1].clone()),
+            Statement::Move(Exp::Temp(test), Exp::Const(1)),
+            jump(&labels[4]),
+            Statement::Label(labels[2].clone()),
+            Statement::Move(Exp::Temp(test), Exp::Const(0)),
+            jump(&labels[5]),
+            Statement::Label(labels[4].clone()),
+            Statement::Move(Exp::Temp(i), increment.clone()),
+            jump(&labels[0]),
+            Statement::Label(labels[5].clone()),
+            Statement::Label(labels[6].clone()),
+        ];
+        assert_eq!(thread(statements), expected);
+    }
+}
@@ -0,0 +1,169 @@
+//! **循环不变量外提**(loop-invariant code motion)。若表达式的所有运算数都在循环外定值（在 SSA
+//! 形式中，即不在循环中定值），则它在每次迭代中的值都相同，可以在前置结点中只计算一次：
+//! 1. 右边是不变表达式的`t <- a op b`和`t <- a`被整个移到前置结点中。在 SSA 形式中`t`只有这一个定值，
+//!    而前置结点是循环中所有结点的必经结点，所以这总是安全的；
+//! 2. 其余语句中的不变子表达式（例如`gen::array_subscript`中的地址计算）被替换为一个在前置
+//!    结点中计算的新临时变量。
+//!
+//! 被外提的表达式即使在循环一次都不执行时也会被计算，所以只外提不会出错的运算：不包含内存
+//! 读取（可能是空指针或越界的地址）和除法（可能除以零）。从内层循环开始处理，外提到内层循环
+//! 前置结点的语句在处理外层循环时可以被继续外提。
+
+use std::collections::{HashMap, HashSet};
+
+use super::loops::{find_loops, Loop};
+use super::ssa::SsaFunction;
+use super::{exp_uses, is_pure};
+use ir::{BinOp, Exp, Statement};
+use temp::Temp;
+
+struct Hoister<'a> {
+    function: &'a SsaFunction,
+    definitions: HashSet<Temp>,
+    /// The invariant subexpressions already computed in the preheader.
+    expressions: HashMap<Exp, Temp>,
+    hoisted: Vec<Statement>,
+}
+
+impl<'a> Hoister<'a> {
+    fn is_invariant(&self, exp: &Exp) -> bool {
+        let mut uses = HashSet::new();
+        exp_uses(exp, &mut uses);
+        uses.iter()
+            .all(|&temp| self.function.is_variable(temp) && !self.definitions.contains(&temp))
+    }
+
+    fn hoist_subexpressions(&mut self, exp: Exp) -> Exp {
+        match exp {
+            Exp::BinOp { .. } if can_hoist(&exp) && self.is_invariant(&exp) => {
+                if let Some(&temp) = self.expressions.get(&exp) {
+                    return Exp::Temp(temp);
+                }
+                let temp = Temp::new();
+                self.expressions.insert(exp.clone(), temp);
+                self.hoisted.push(Statement::Move(Exp::Temp(temp), exp));
+                Exp::Temp(temp)
+            }
+            Exp::BinOp { op, left, right } => Exp::BinOp {
+                op,
+                left: Box::new(self.hoist_subexpressions(*left)),
+                right: Box::new(self.hoist_subexpressions(*right)),
+            },
+            Exp::Mem(exp) => Exp::Mem(Box::new(self.hoist_subexpressions(*exp))),
+            Exp::Call(function, arguments) => Exp::Call(
+                function,
+                arguments
+                    .into_iter()
+                    .map(|argument| self.hoist_subexpressions(argument))
+                    .collect(),
+            ),
+            exp => exp,
+        }
+    }
+
+    /// Returns the statement to keep in the loop, if any.
+    fn hoist(&mut self, statement: Statement) -> Option<Statement> {
+        match statement {
+            Statement::Move(Exp::Temp(temp), source) => {
+                // Moving the constants out of the loop would only make their live range longer.
+                let is_computation = matches!(source, Exp::BinOp { .. } | Exp::Temp(_));
+                if is_computation
+                    && self.function.is_variable(temp)
+                    && can_hoist(&source)
+                    && self.is_invariant(&source)
+                {
+                    self.definitions.remove(&temp);
+                    self.hoisted.push(Statement::Move(Exp::Temp(temp), source));
+                    return None;
+                }
+                let source = match source {
+                    Exp::BinOp { op, left, right } => Exp::BinOp {
+                        op,
+                        left: Box::new(self.hoist_subexpressions(*left)),
+                        right: Box::new(self.hoist_subexpressions(*right)),
+                    },
+                    source => self.hoist_subexpressions(source),
+                };
+                Some(Statement::Move(Exp::Temp(temp), source))
+            }
+            Statement::Move(destination, source) => Some(Statement::Move(
+                self.hoist_subexpressions(destination),
+                self.hoist_subexpressions(source),
+            )),
+            Statement::Exp(exp) => Some(Statement::Exp(self.hoist_subexpressions(exp))),
+            Statement::CondJump {
+                op,
+                left,
+                right,
+                true_label,
+                false_label,
+            } => Some(Statement::CondJump {
+                op,
+                left: self.hoist_subexpressions(left),
+                right: self.hoist_subexpressions(right),
+                true_label,
+                false_label,
+            }),
+            statement => Some(statement),
+        }
+    }
+}
+
+/// Whether the expression can be evaluated before the loop: it must not have side effects nor
+/// trap.
+fn can_hoist(exp: &Exp) -> bool {
+    match *exp {
+        Exp::Const(_) | Exp::Name(_) | Exp::Temp(_) => true,
+        Exp::BinOp { op: BinOp::Div, .. } => false,
+        Exp::BinOp {
+            ref left,
+            ref right,
+            ..
+        } => is_pure(exp) && can_hoist(left) && can_hoist(right),
+        Exp::Error | Exp::Mem(_) | Exp::Call(_, _) | Exp::ExpSequence(_, _) => false,
+    }
+}
+
+fn hoist_loop(function: &mut SsaFunction, natural_loop: &Loop) {
+    let blocks = natural_loop.sorted_blocks();
+    let definitions = natural_loop.definitions(function);
+    let mut loop_statements: Vec<_> = blocks
+        .iter()
+        .map(|&index| ::std::mem::take(&mut function.blocks[index].statements))
+        .collect();
+    let hoisted = {
+        let mut hoister = Hoister {
+            function,
+            definitions,
+            expressions: HashMap::new(),
+            hoisted: vec![],
+        };
+        // Hoisting a definition can make the statements using it invariant.
+        loop {
+            let count = hoister.hoisted.len();
+            for statements in &mut loop_statements {
+                *statements = ::std::mem::take(statements)
+                    .into_iter()
+                    .filter_map(|statement| hoister.hoist(statement))
+                    .collect();
+            }
+            if hoister.hoisted.len() == count {
+                break;
+            }
+        }
+        hoister.hoisted
+    };
+    for (&index, statements) in blocks.iter().zip(loop_statements) {
+        function.blocks[index].statements = statements;
+    }
+    let preheader = &mut function.blocks[natural_loop.preheader];
+    for statement in hoisted {
+        preheader.push_before_jump(statement);
+    }
+}
+
+pub fn hoist(function: &mut SsaFunction) {
+    for natural_loop in find_loops(function) {
+        hoist_loop(function, &natural_loop);
+    }
+}
@@ -0,0 +1,335 @@
+//! **循环**。若边`n -> h`的`h`是`n`的必经结点，则它是一条回边(back edge)；回边的自然循环
+//! (natural loop)由`h`（首结点，header）和不经过`h`就能到达`n`的所有结点组成。首结点相同的
+//! 自然循环被合并为一个循环。
+//!
+//! 循环优化把语句移到循环之前执行，所以每个循环需要一个前置结点(preheader)：它是首结点在
+//! 循环外的唯一前驱，并且首结点是它唯一的后继。缺少前置结点时插入一个新的基本块，首结点的
+//! `φ`函数中来自循环外的参数移到前置结点中。
+//!
+//! 在 SSA 形式中，基本归纳变量(basic induction variable)是首结点的`φ`函数`i`，每条回边上的
+//! 参数都是`i`加上一个常量。归纳变量`i`的族(family)是由`i`通过加上或减去常量得到的变量。
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::ssa::{jump, Block, Phi, SsaFunction};
+use ir::{BinOp, Exp, Statement};
+use temp::{Label, Temp};
+
+pub struct Loop {
+    pub header: usize,
+    pub preheader: usize,
+    /// The blocks of the loop, including the header and the blocks of the nested loops.
+    pub blocks: HashSet<usize>,
+}
+
+impl Loop {
+    /// The blocks of the loop in layout order.
+    pub fn sorted_blocks(&self) -> Vec<usize> {
+        let mut blocks: Vec<_> = self.blocks.iter().cloned().collect();
+        blocks.sort();
+        blocks
+    }
+
+    /// The temporaries defined in the loop. The others are invariant in the loop.
+    pub fn definitions(&self, function: &SsaFunction) -> HashSet<Temp> {
+        let mut definitions = HashSet::new();
+        for &index in &self.blocks {
+            let block = &function.blocks[index];
+            definitions.extend(block.phis.iter().map(|phi| phi.destination));
+            for statement in &block.statements {
+                if let Statement::Move(Exp::Temp(temp), _) = *statement {
+                    definitions.insert(temp);
+                }
+            }
+        }
+        definitions
+    }
+}
+
+/// Find the loops of the function, inserting the missing preheaders. The nested loops come before
+/// the loops containing them.
+pub fn find_loops(function: &mut SsaFunction) -> Vec<Loop> {
+    loop {
+        let loops = natural_loops(function);
+        let missing = loops
+            .iter()
+            .find(|&&(header, ref blocks)| preheader(function, header, blocks).is_none());
+        match missing {
+            Some(&(header, ref blocks)) => insert_preheader(function, header, blocks),
+            None => {
+                return loops
+                    .into_iter()
+                    .map(|(header, blocks)| Loop {
+                        header,
+                        preheader: preheader(function, header, &blocks).expect("preheader"),
+                        blocks,
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+fn natural_loops(function: &SsaFunction) -> Vec<(usize, HashSet<usize>)> {
+    let tree = function.dominator_tree();
+    let mut loops: BTreeMap<usize, HashSet<usize>> = BTreeMap::new();
+    for (index, block) in function.blocks.iter().enumerate() {
+        for &header in &block.successors {
+            if !tree.dominates(header, index) {
+                continue;
+            }
+            let blocks = loops.entry(header).or_default();
+            blocks.insert(header);
+            let mut stack = vec![index];
+            while let Some(node) = stack.pop() {
+                if blocks.insert(node) {
+                    stack.extend(
+                        function.blocks[node]
+                            .predecessors
+                            .iter()
+                            .filter(|&&predecessor| tree.is_reachable(predecessor)),
+                    );
+                }
+            }
+        }
+    }
+    let mut loops: Vec<_> = loops.into_iter().collect();
+    loops.sort_by_key(|(_, blocks)| blocks.len());
+    loops
+}
+
+fn preheader(function: &SsaFunction, header: usize, blocks: &HashSet<usize>) -> Option<usize> {
+    let outside: Vec<_> = function.blocks[header]
+        .predecessors
+        .iter()
+        .filter(|predecessor| !blocks.contains(predecessor))
+        .collect();
+    match outside[..] {
+        [&predecessor] if function.blocks[predecessor].successors == [header] => Some(predecessor),
+        _ => None,
+    }
+}
+
+fn insert_preheader(function: &mut SsaFunction, header: usize, blocks: &HashSet<usize>) {
+    let label = Label::new();
+    let header_label = function.blocks[header].label.clone();
+
+    // The preheader goes just before the header, unless the block there is in the loop and
+    // falls through to the header. It then goes before the last block and jumps to the header.
+    let previous = &function.blocks[header - 1];
+    let falls_through = !matches!(previous.statements.last(), Some(&Statement::Jump(_, _)));
+    let (position, statements) = if falls_through && blocks.contains(&(header - 1)) {
+        (function.blocks.len() - 1, vec![jump(header_label.clone())])
+    } else {
+        (header, vec![])
+    };
+
+    let mut outside = vec![];
+    let mut phis = vec![];
+    {
+        let header_block = &mut function.blocks[header];
+        let mut position = 0;
+        while position < header_block.predecessors.len() {
+            let predecessor = header_block.predecessors[position];
+            if blocks.contains(&predecessor) {
+                position += 1;
+                continue;
+            }
+            outside.push((
+                predecessor,
+                header_block
+                    .phis
+                    .iter_mut()
+                    .map(|phi| phi.arguments.remove(position))
+                    .collect::<Vec<_>>(),
+            ));
+            header_block.predecessors.remove(position);
+        }
+        for (index, phi) in header_block.phis.iter_mut().enumerate() {
+            let arguments: Vec<_> = outside
+                .iter()
+                .map(|(_, arguments)| arguments[index])
+                .collect();
+            if arguments.len() == 1 {
+                phi.arguments.push(arguments[0]);
+            } else {
+                let destination = Temp::new();
+                phis.push(Phi {
+                    destination,
+                    arguments,
+                });
+                phi.arguments.push(destination);
+            }
+        }
+    }
+
+    let predecessors: Vec<_> = outside
+        .iter()
+        .map(|&(predecessor, _)| predecessor)
+        .collect();
+    function.insert_block(
+        position,
+        Block {
+            label: label.clone(),
+            phis,
+            statements,
+            predecessors: predecessors.clone(),
+            successors: vec![header],
+        },
+    );
+    let header = if position <= header {
+        header + 1
+    } else {
+        header
+    };
+    function.blocks[header].predecessors.push(position);
+    for predecessor in predecessors {
+        let predecessor = if position <= predecessor {
+            predecessor + 1
+        } else {
+            predecessor
+        };
+        let block = &mut function.blocks[predecessor];
+        for successor in &mut block.successors {
+            if *successor == header {
+                *successor = position;
+            }
+        }
+        let replace = |target: &mut Label| {
+            if *target == header_label {
+                *target = label.clone();
+            }
+        };
+        match block.statements.last_mut() {
+            Some(&mut Statement::Jump(ref mut exp, ref mut targets)) => {
+                if let Exp::Name(ref mut target) = *exp {
+                    replace(target);
+                }
+                targets.iter_mut().for_each(replace);
+            }
+            Some(&mut Statement::CondJump {
+                ref mut true_label,
+                ref mut false_label,
+                ..
+            }) => {
+                replace(true_label);
+                replace(false_label);
+            }
+            _ => (),
+        }
+    }
+}
+
+pub struct InductionVariable {
+    /// The value of the variable in the first iteration.
+    pub initial: Temp,
+    /// The variables of the family, with their offset: `member = temp + offset`.
+    pub family: HashMap<Temp, i64>,
+    /// The variables that are the sum of a member of the family and a loop invariant, like the
+    /// subscript `i + j` in an inner loop on `i`.
+    pub sums: HashMap<Temp, (Temp, Temp)>,
+    /// The increment on each back edge, by predecessor of the header.
+    pub increments: Vec<(usize, i64)>,
+}
+
+pub fn induction_variables(function: &SsaFunction, natural_loop: &Loop) -> Vec<InductionVariable> {
+    let header = &function.blocks[natural_loop.header];
+    let blocks = natural_loop.sorted_blocks();
+    let definitions = natural_loop.definitions(function);
+    let is_invariant = |temp| function.is_variable(temp) && !definitions.contains(&temp);
+    let mut variables = vec![];
+    for phi in &header.phis {
+        let mut family = HashMap::new();
+        family.insert(phi.destination, 0);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &index in &blocks {
+                for statement in &function.blocks[index].statements {
+                    if let Statement::Move(Exp::Temp(temp), ref source) = *statement {
+                        if family.contains_key(&temp) || !function.is_variable(temp) {
+                            continue;
+                        }
+                        if let Some(offset) = offset(source, &family) {
+                            family.insert(temp, offset);
+                            changed = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut sums = HashMap::new();
+        for &index in &blocks {
+            for statement in &function.blocks[index].statements {
+                if let Statement::Move(
+                    Exp::Temp(temp),
+                    Exp::BinOp {
+                        op: BinOp::Plus,
+                        left: box Exp::Temp(left),
+                        right: box Exp::Temp(right),
+                    },
+                ) = *statement
+                {
+                    if family.contains_key(&left) && is_invariant(right) {
+                        sums.insert(temp, (left, right));
+                    } else if family.contains_key(&right) && is_invariant(left) {
+                        sums.insert(temp, (right, left));
+                    }
+                }
+            }
+        }
+
+        let mut initial = None;
+        let mut increments = vec![];
+        for (&argument, &predecessor) in phi.arguments.iter().zip(&header.predecessors) {
+            if predecessor == natural_loop.preheader {
+                initial = Some(argument);
+            } else {
+                match family.get(&argument) {
+                    Some(&increment) => increments.push((predecessor, increment)),
+                    None => break,
+                }
+            }
+        }
+        if increments.len() + 1 == header.predecessors.len() {
+            if let Some(initial) = initial {
+                variables.push(InductionVariable {
+                    initial,
+                    family,
+                    sums,
+                    increments,
+                });
+            }
+        }
+    }
+    variables
+}
+
+/// The offset of `exp` from the induction variable, if it is a member of the family plus a
+/// constant.
+fn offset(exp: &Exp, family: &HashMap<Temp, i64>) -> Option<i64> {
+    match *exp {
+        Exp::Temp(temp) => family.get(&temp).cloned(),
+        Exp::BinOp {
+            op: BinOp::Plus,
+            left: box Exp::Temp(temp),
+            right: box Exp::Const(constant),
+        }
+        | Exp::BinOp {
+            op: BinOp::Plus,
+            left: box Exp::Const(constant),
+            right: box Exp::Temp(temp),
+        } => family
+            .get(&temp)
+            .map(|offset| offset.wrapping_add(constant)),
+        Exp::BinOp {
+            op: BinOp::Minus,
+            left: box Exp::Temp(temp),
+            right: box Exp::Const(constant),
+        } => family
+            .get(&temp)
+            .map(|offset| offset.wrapping_sub(constant)),
+        _ => None,
+    }
+}
@@ -0,0 +1,505 @@
+//! **优化**。优化作用于规范化之后（`trace_schedule`之后）的中间表示：每一遍（pass）接收一个
+//! 函数的语句列表，返回变换后的语句列表。作用于整个程序的遍（内联）则在规范化之前接收所有的
+//! 片段，作用于指令的遍则在指令选择之后接收一个函数的指令：指令调度在寄存器分配之前，窥孔优化
+//! 在寄存器分配之后。各遍的顺序是固定的；`-O`级别决定执行哪些遍，`-f<pass>`和`-fno-<pass>`
+//! 可以单独启用或禁用某一遍，`--emit=<pass>`在该遍之后打印中间表示。
+
+mod adce;
+mod bounds_checks;
+mod cfg;
+mod common_subexpression;
+mod constant_propagation;
+mod copy_propagation;
+mod dataflow;
+mod dead_code;
+mod dominators;
+mod fold;
+mod induction_variables;
+mod inline;
+mod jump_threading;
+mod loop_invariant;
+mod loops;
+mod peephole;
+mod sccp;
+mod schedule;
+mod simplify;
+mod ssa;
+mod unreachable;
+
+use std::collections::{HashMap, HashSet};
+
+use self::ssa::SsaFunction;
+use asm::Instruction;
+use frame::{Fragment, Frame};
+use ir::{Exp, Statement};
+use temp::{Label, Temp};
+
+pub use self::cfg::ControlFlowGraph;
+pub use self::dataflow::{solve, LiveTemps};
+pub use self::dominators::DominatorTree;
+pub use self::schedule::estimate_cycles;
+
+/// Name accepted by --emit to print the IR before any optimization.
+const CANON: &str = "canon";
+
+pub struct Pass<F: Frame> {
+    pub name: &'static str,
+    /// Lowest -O level at which the pass is enabled.
+    pub level: u32,
+    pub transform: Transform<F>,
+}
+
+pub enum Transform<F: Frame> {
+    /// Passes on the whole program run before the canonicalization of the functions.
+    Program(fn(Vec<Fragment<F>>) -> Vec<Fragment<F>>),
+    Ir(fn(Vec<Statement>) -> Vec<Statement>),
+    /// Consecutive SSA passes share the same conversion to SSA form.
+    Ssa(fn(&mut SsaFunction)),
+    /// Passes on the instructions of a function run before the register allocation.
+    Instructions(fn(Vec<Instruction>) -> Vec<Instruction>),
+    /// Passes on the instructions of a function run after the register allocation.
+    Assembly(fn(Vec<Instruction>) -> Vec<Instruction>),
+}
+
+enum Form {
+    Ir(Vec<Statement>),
+    Ssa(SsaFunction),
+}
+
+impl Form {
+    fn into_ir(self) -> Vec<Statement> {
+        match self {
+            Form::Ir(statements) => statements,
+            Form::Ssa(function) => function.into_statements(),
+        }
+    }
+}
+
+pub fn passes<F: Frame>() -> Vec<Pass<F>> {
+    vec![
+        Pass {
+            name: "inline",
+            level: 2,
+            transform: Transform::Program(inline::inline::<F>),
+        },
+        Pass {
+            name: "constant-folding",
+            level: 1,
+            transform: Transform::Ir(fold::fold),
+        },
+        Pass {
+            name: "algebraic-simplification",
+            level: 1,
+            transform: Transform::Ir(simplify::simplify),
+        },
+        Pass {
+            name: "jump-threading",
+            level: 2,
+            transform: Transform::Ir(jump_threading::thread),
+        },
+        Pass {
+            name: "sccp",
+            level: 2,
+            transform: Transform::Ssa(sccp::propagate),
+        },
+        Pass {
+            name: "bounds-checks",
+            level: 2,
+            transform: Transform::Ssa(bounds_checks::eliminate::<F>),
+        },
+        Pass {
+            name: "loop-invariant",
+            level: 2,
+            transform: Transform::Ssa(loop_invariant::hoist),
+        },
+        Pass {
+            name: "induction-variables",
+            level: 2,
+            transform: Transform::Ssa(induction_variables::reduce),
+        },
+        Pass {
+            name: "adce",
+            level: 2,
+            transform: Transform::Ssa(adce::eliminate),
+        },
+        Pass {
+            name: "constant-propagation",
+            level: 2,
+            transform: Transform::Ir(constant_propagation::propagate::<F>),
+        },
+        Pass {
+            name: "common-subexpression",
+            level: 2,
+            transform: Transform::Ir(common_subexpression::eliminate::<F>),
+        },
+        Pass {
+            name: "copy-propagation",
+            level: 2,
+            transform: Transform::Ir(copy_propagation::propagate::<F>),
+        },
+        Pass {
+            name: "dead-code",
+            level: 2,
+            transform: Transform::Ir(dead_code::eliminate::<F>),
+        },
+        Pass {
+            name: "unreachable-blocks",
+            level: 1,
+            transform: Transform::Ir(unreachable::eliminate),
+        },
+        Pass {
+            name: "schedule",
+            level: 2,
+            transform: Transform::Instructions(schedule::schedule),
+        },
+        Pass {
+            name: "peephole",
+            level: 1,
+            transform: Transform::Assembly(peephole::optimize::<F>),
+        },
+        Pass {
+            name: "late-schedule",
+            // Only enabled with -flate-schedule.
+            level: MAX_LEVEL + 1,
+            transform: Transform::Assembly(schedule::schedule),
+        },
+    ]
+}
+
+pub const MAX_LEVEL: u32 = 2;
+
+#[derive(Default)]
+pub struct Options {
+    pub level: u32,
+    /// Passes enabled with -f<name>.
+    pub enabled: Vec<String>,
+    /// Passes disabled with -fno-<name>.
+    pub disabled: Vec<String>,
+    /// Pass after which the IR is printed.
+    pub emit: Option<String>,
+}
+
+pub struct PassManager<F: Frame> {
+    passes: Vec<(Pass<F>, bool)>,
+    emit: Option<String>,
+}
+
+impl<F: Frame> PassManager<F> {
+    pub fn new(options: Options) -> Result<Self, String> {
+        let passes = passes::<F>();
+        let known = |name: &String| name == CANON || passes.iter().any(|pass| pass.name == name);
+        let names = options
+            .enabled
+            .iter()
+            .chain(&options.disabled)
+            .chain(&options.emit);
+        for name in names {
+            if !known(name) {
+                let names: Vec<_> = passes.iter().map(|pass| pass.name).collect();
+                return Err(format!(
+                    "Unknown optimization pass `{}`, expected one of: {}, {}",
+                    name,
+                    CANON,
+                    names.join(", ")
+                ));
+            }
+        }
+        let passes = passes
+            .into_iter()
+            .map(|pass| {
+                let name = pass.name.to_string();
+                let enabled = !options.disabled.contains(&name)
+                    && (options.level >= pass.level || options.enabled.contains(&name));
+                (pass, enabled)
+            })
+            .collect();
+        Ok(Self {
+            passes,
+            emit: options.emit,
+        })
+    }
+
+    /// Run the passes on the whole program.
+    pub fn run_program(&self, fragments: Vec<Fragment<F>>) -> Vec<Fragment<F>> {
+        let mut fragments = fragments;
+        for &(ref pass, enabled) in &self.passes {
+            let transform = match pass.transform {
+                Transform::Program(transform) => transform,
+                Transform::Ir(_)
+                | Transform::Ssa(_)
+                | Transform::Instructions(_)
+                | Transform::Assembly(_) => continue,
+            };
+            if enabled {
+                fragments = transform(fragments);
+            }
+            if self.emit.as_deref() == Some(pass.name) {
+                for fragment in &fragments {
+                    if let Fragment::Function {
+                        ref body,
+                        ref frame,
+                    } = *fragment
+                    {
+                        println!("# {} after {}", frame.borrow().name(), pass.name);
+                        println!("    {}", body.to_string::<F>());
+                    }
+                }
+            }
+        }
+        fragments
+    }
+
+    pub fn run(&self, function: &Label, statements: Vec<Statement>) -> Vec<Statement> {
+        let mut form = Form::Ir(statements);
+        self.emit(CANON, function, &form);
+        for &(ref pass, enabled) in &self.passes {
+            match pass.transform {
+                Transform::Program(_) | Transform::Instructions(_) | Transform::Assembly(_) => {
+                    continue
+                }
+                Transform::Ir(_) | Transform::Ssa(_) => (),
+            }
+            if enabled {
+                form = match (&pass.transform, form) {
+                    (&Transform::Program(_), form)
+                    | (&Transform::Instructions(_), form)
+                    | (&Transform::Assembly(_), form) => form,
+                    (&Transform::Ir(transform), form) => Form::Ir(transform(form.into_ir())),
+                    (&Transform::Ssa(transform), Form::Ssa(mut ssa_function)) => {
+                        transform(&mut ssa_function);
+                        verify(function, pass.name, &ssa_function);
+                        Form::Ssa(ssa_function)
+                    }
+                    (&Transform::Ssa(transform), Form::Ir(statements)) => {
+                        let mut ssa_function = SsaFunction::new::<F>(statements);
+                        verify(function, "SSA construction", &ssa_function);
+                        transform(&mut ssa_function);
+                        verify(function, pass.name, &ssa_function);
+                        Form::Ssa(ssa_function)
+                    }
+                };
+            }
+            // Disabled passes can still be emitted: the IR is then the same as after the
+            // previous pass.
+            self.emit(pass.name, function, &form);
+        }
+        form.into_ir()
+    }
+
+    /// Run the passes on the instructions of a function, before its registers are allocated.
+    pub fn run_instructions(
+        &self,
+        function: &Label,
+        instructions: Vec<Instruction>,
+    ) -> Vec<Instruction> {
+        self.run_on_instructions(function, instructions, |transform| match *transform {
+            Transform::Instructions(transform) => Some(transform),
+            Transform::Program(_)
+            | Transform::Ir(_)
+            | Transform::Ssa(_)
+            | Transform::Assembly(_) => None,
+        })
+    }
+
+    /// Run the passes on the instructions of a function, once its registers are allocated.
+    pub fn run_assembly(
+        &self,
+        function: &Label,
+        instructions: Vec<Instruction>,
+    ) -> Vec<Instruction> {
+        self.run_on_instructions(function, instructions, |transform| match *transform {
+            Transform::Assembly(transform) => Some(transform),
+            Transform::Program(_)
+            | Transform::Ir(_)
+            | Transform::Ssa(_)
+            | Transform::Instructions(_) => None,
+        })
+    }
+
+    fn run_on_instructions<S>(
+        &self,
+        function: &Label,
+        instructions: Vec<Instruction>,
+        select: S,
+    ) -> Vec<Instruction>
+    where
+        S: Fn(&Transform<F>) -> Option<fn(Vec<Instruction>) -> Vec<Instruction>>,
+    {
+        let mut instructions = instructions;
+        for &(ref pass, enabled) in &self.passes {
+            let transform = match select(&pass.transform) {
+                Some(transform) => transform,
+                None => continue,
+            };
+            if enabled {
+                instructions = transform(instructions);
+            }
+            if self.emit.as_deref() == Some(pass.name) {
+                println!("# {} after {}", function, pass.name);
+                for instruction in &instructions {
+                    match *instruction {
+                        Instruction::Label { .. } => println!("{}", instruction.to_string::<F>()),
+                        _ => println!("    {}", instruction.to_string::<F>()),
+                    }
+                }
+            }
+        }
+        instructions
+    }
+
+    fn emit(&self, pass: &str, function: &Label, form: &Form) {
+        if self.emit.as_deref() == Some(pass) {
+            match *form {
+                Form::Ir(ref statements) => {
+                    println!("# {} after {}", function, pass);
+                    for statement in statements {
+                        match *statement {
+                            Statement::Label(_) => println!("{}", statement.to_string::<F>()),
+                            _ => println!("    {}", statement.to_string::<F>()),
+                        }
+                    }
+                }
+                Form::Ssa(ref ssa_function) => {
+                    println!("# {} after {} (SSA)", function, pass);
+                    print!("{}", ssa_function.to_string::<F>());
+                }
+            }
+        }
+    }
+}
+
+/// The SSA form is checked in debug builds.
+fn verify(function: &Label, pass: &str, ssa_function: &SsaFunction) {
+    if cfg!(debug_assertions) {
+        if let Err(error) = ssa_function.verify() {
+            panic!("Invalid SSA form in {} after {}: {}", function, pass, error);
+        }
+    }
+}
+
+/// Whether the expression can be removed or duplicated without changing the behavior of the
+/// program.
+pub fn is_pure(exp: &Exp) -> bool {
+    match *exp {
+        Exp::Const(_) | Exp::Error | Exp::Name(_) | Exp::Temp(_) => true,
+        Exp::BinOp {
+            ref left,
+            ref right,
+            ..
+        } => is_pure(left) && is_pure(right),
+        Exp::Mem(ref exp) => is_pure(exp),
+        Exp::Call(_, _) | Exp::ExpSequence(_, _) => false,
+    }
+}
+
+/// Add the temporaries read by the statement to `temps`.
+pub fn statement_uses(statement: &Statement, temps: &mut HashSet<Temp>) {
+    match *statement {
+        Statement::Move(Exp::Temp(_), ref source) => exp_uses(source, temps),
+        Statement::Move(ref destination, ref source) => {
+            exp_uses(destination, temps);
+            exp_uses(source, temps);
+        }
+        Statement::Exp(ref exp) | Statement::Jump(ref exp, _) => exp_uses(exp, temps),
+        Statement::CondJump {
+            ref left,
+            ref right,
+            ..
+        } => {
+            exp_uses(left, temps);
+            exp_uses(right, temps);
+        }
+        Statement::Sequence(ref first, ref second) => {
+            statement_uses(first, temps);
+            statement_uses(second, temps);
+        }
+        Statement::Label(_) => (),
+    }
+}
+
+pub fn exp_uses(exp: &Exp, temps: &mut HashSet<Temp>) {
+    match *exp {
+        Exp::Const(_) | Exp::Error | Exp::Name(_) => (),
+        Exp::Temp(temp) => {
+            temps.insert(temp);
+        }
+        Exp::BinOp {
+            ref left,
+            ref right,
+            ..
+        } => {
+            exp_uses(left, temps);
+            exp_uses(right, temps);
+        }
+        Exp::Mem(ref exp) => exp_uses(exp, temps),
+        Exp::Call(ref function, ref arguments) => {
+            exp_uses(function, temps);
+            for argument in arguments {
+                exp_uses(argument, temps);
+            }
+        }
+        Exp::ExpSequence(ref statement, ref exp) => {
+            statement_uses(statement, temps);
+            exp_uses(exp, temps);
+        }
+    }
+}
+
+/// Replace the temporaries read by the statement.
+pub fn replace_uses(statement: Statement, replacements: &HashMap<Temp, Exp>) -> Statement {
+    match statement {
+        Statement::Move(Exp::Temp(temp), source) => {
+            Statement::Move(Exp::Temp(temp), replace_exp_uses(source, replacements))
+        }
+        Statement::Move(destination, source) => Statement::Move(
+            replace_exp_uses(destination, replacements),
+            replace_exp_uses(source, replacements),
+        ),
+        Statement::Exp(exp) => Statement::Exp(replace_exp_uses(exp, replacements)),
+        Statement::Jump(exp, labels) => {
+            Statement::Jump(replace_exp_uses(exp, replacements), labels)
+        }
+        Statement::CondJump {
+            op,
+            left,
+            right,
+            true_label,
+            false_label,
+        } => Statement::CondJump {
+            op,
+            left: replace_exp_uses(left, replacements),
+            right: replace_exp_uses(right, replacements),
+            true_label,
+            false_label,
+        },
+        Statement::Sequence(first, second) => Statement::Sequence(
+            Box::new(replace_uses(*first, replacements)),
+            Box::new(replace_uses(*second, replacements)),
+        ),
+        Statement::Label(label) => Statement::Label(label),
+    }
+}
+
+fn replace_exp_uses(exp: Exp, replacements: &HashMap<Temp, Exp>) -> Exp {
+    match exp {
+        Exp::Temp(temp) => replacements.get(&temp).cloned().unwrap_or(Exp::Temp(temp)),
+        Exp::BinOp { op, left, right } => Exp::BinOp {
+            op,
+            left: Box::new(replace_exp_uses(*left, replacements)),
+            right: Box::new(replace_exp_uses(*right, replacements)),
+        },
+        Exp::Mem(exp) => Exp::Mem(Box::new(replace_exp_uses(*exp, replacements))),
+        Exp::Call(function, arguments) => Exp::Call(
+            Box::new(replace_exp_uses(*function, replacements)),
+            arguments
+                .into_iter()
+                .map(|argument| replace_exp_uses(argument, replacements))
+                .collect(),
+        ),
+        Exp::ExpSequence(statement, exp) => Exp::ExpSequence(
+            Box::new(replace_uses(*statement, replacements)),
+            Box::new(replace_exp_uses(*exp, replacements)),
+        ),
+        exp @ Exp::Const(_) | exp @ Exp::Error | exp @ Exp::Name(_) => exp,
+    }
+}
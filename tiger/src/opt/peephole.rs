@@ -0,0 +1,487 @@
+//! **窥孔优化**(peephole optimization)。寄存器分配之后，在最终的指令序列上用一个小窗口查找
+//! 可以替换为更短序列的相邻指令，例如寄存器到自身的传送、跳转到紧接着的标号、溢出产生的
+//! 先存储后读取同一地址。
+//!
+//! 规则用汇编文本描述：模式中的`$name`匹配一个运算数，同名的变量必须匹配相同的文本，替换中的
+//! 变量被替换为匹配到的文本：
+//!
+//! ```text
+//! mov $t, [$m]
+//! mov $r, $t       =>  mov $r, [$m]     当 $t 在之后不再被使用
+//! ```
+//!
+//! 规则的条件可以查询运算数的种类以及寄存器在窗口之后是否活跃。活跃性在每一遍替换之前计算；
+//! 这些规则不改变窗口之外的活跃性，所以同一遍中的替换可以共享它。重复替换直到没有规则可用。
+
+use std::collections::{HashMap, HashSet};
+
+use asm::Instruction;
+use frame::Frame;
+use temp::{Label, Temp};
+
+enum Token {
+    Text(String),
+    Variable(String),
+}
+
+struct Pattern {
+    tokens: Vec<Token>,
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> Self {
+        let mut tokens = vec![];
+        let mut rest = pattern;
+        while let Some(position) = rest.find('$') {
+            if position > 0 {
+                tokens.push(Token::Text(rest[..position].to_string()));
+            }
+            rest = &rest[position..];
+            let name_length = rest[1..]
+                .find(|char: char| !char.is_ascii_alphanumeric())
+                .unwrap_or(rest.len() - 1);
+            tokens.push(Token::Variable(rest[1..1 + name_length].to_string()));
+            rest = &rest[1 + name_length..];
+        }
+        if !rest.is_empty() {
+            tokens.push(Token::Text(rest.to_string()));
+        }
+        Pattern { tokens }
+    }
+
+    fn matches(&self, text: &str, bindings: &mut HashMap<String, String>) -> bool {
+        match_tokens(&self.tokens, text, bindings)
+    }
+
+    fn instantiate(&self, bindings: &HashMap<String, String>) -> String {
+        self.tokens
+            .iter()
+            .map(|token| match *token {
+                Token::Text(ref text) => text.as_str(),
+                Token::Variable(ref name) => bindings[name].as_str(),
+            })
+            .collect()
+    }
+}
+
+/// Match the tokens with the text, backtracking on the length of the text matched by the
+/// variables. A variable matches a non-empty operand, without comma.
+fn match_tokens(tokens: &[Token], text: &str, bindings: &mut HashMap<String, String>) -> bool {
+    let (token, tokens) = match tokens.split_first() {
+        Some(split) => split,
+        None => return text.is_empty(),
+    };
+    match *token {
+        Token::Text(ref prefix) => {
+            text.starts_with(prefix.as_str())
+                && match_tokens(tokens, &text[prefix.len()..], bindings)
+        }
+        Token::Variable(ref name) => {
+            if let Some(value) = bindings.get(name).cloned() {
+                return text.starts_with(value.as_str())
+                    && match_tokens(tokens, &text[value.len()..], bindings);
+            }
+            let operand_end = text.find(',').unwrap_or(text.len());
+            for (end, _) in text[..operand_end]
+                .char_indices()
+                .skip(1)
+                .chain(Some((operand_end, ' ')))
+                .filter(|&(end, _)| end > 0)
+            {
+                bindings.insert(name.clone(), text[..end].to_string());
+                if match_tokens(tokens, &text[end..], bindings) {
+                    return true;
+                }
+            }
+            bindings.remove(name);
+            false
+        }
+    }
+}
+
+/// A successful match of a rule, given to its condition.
+pub struct Match<'a> {
+    bindings: HashMap<String, String>,
+    registers: &'a HashMap<&'static str, Temp>,
+    /// The registers live after the window.
+    live_out: &'a HashSet<Temp>,
+}
+
+impl<'a> Match<'a> {
+    fn register(&self, variable: &str) -> Option<Temp> {
+        self.registers
+            .get(self.bindings[variable].as_str())
+            .cloned()
+    }
+
+    pub fn is_register(&self, variable: &str) -> bool {
+        self.register(variable).is_some()
+    }
+
+    pub fn is_memory(&self, variable: &str) -> bool {
+        self.bindings[variable].starts_with('[')
+    }
+
+    /// Whether the operand is a constant that fits in the 32-bit immediate of an instruction.
+    pub fn is_immediate(&self, variable: &str) -> bool {
+        self.bindings[variable]
+            .parse::<i64>()
+            .map(|value| value >= i64::from(i32::MIN) && value <= i64::from(i32::MAX))
+            .unwrap_or(false)
+    }
+
+    /// Whether the register is not used after the window.
+    pub fn is_dead(&self, variable: &str) -> bool {
+        self.register(variable)
+            .is_some_and(|register| !self.live_out.contains(&register))
+    }
+
+    /// Whether the operand `variable` contains the register `register`, for instance in its
+    /// address.
+    pub fn mentions(&self, variable: &str, register: &str) -> bool {
+        let register = &self.bindings[register];
+        self.bindings[variable]
+            .split(|char: char| !char.is_ascii_alphanumeric())
+            .any(|word| word == register)
+    }
+}
+
+pub struct Rule {
+    patterns: Vec<Pattern>,
+    replacement: Vec<Pattern>,
+    condition: Option<fn(&Match) -> bool>,
+}
+
+impl Rule {
+    pub fn new(patterns: &[&str], replacement: &[&str]) -> Self {
+        Rule {
+            patterns: patterns
+                .iter()
+                .map(|pattern| Pattern::new(pattern))
+                .collect(),
+            replacement: replacement
+                .iter()
+                .map(|pattern| Pattern::new(pattern))
+                .collect(),
+            condition: None,
+        }
+    }
+
+    pub fn when(mut self, condition: fn(&Match) -> bool) -> Self {
+        self.condition = Some(condition);
+        self
+    }
+}
+
+pub fn rules() -> Vec<Rule> {
+    vec![
+        // Move of a register to itself.
+        Rule::new(&["mov $a, $a"], &[]),
+        // Jump to the next instruction.
+        Rule::new(&["jmp $l", "$l:"], &["$l:"]),
+        // Reload of a spilled value.
+        Rule::new(&["mov [$m], $r", "mov $r, [$m]"], &["mov [$m], $r"]),
+        Rule::new(
+            &["mov [$m], $r", "mov $s, [$m]"],
+            &["mov [$m], $r", "mov $s, $r"],
+        )
+        .when(|m| m.is_register("r") && m.is_register("s")),
+        // Store of the value that was just loaded.
+        Rule::new(&["mov $r, [$m]", "mov [$m], $r"], &["mov $r, [$m]"])
+            .when(|m| !m.mentions("m", "r")),
+        // Load or copy through a temporary register.
+        Rule::new(&["mov $t, $s", "mov $r, $t"], &["mov $r, $s"]).when(|m| {
+            m.is_register("t")
+                && m.is_dead("t")
+                && !m.mentions("r", "t")
+                && (!m.is_memory("r") || m.is_register("s"))
+        }),
+        // Constant materialized in a register only to be compared.
+        Rule::new(&["mov $r, $c", "cmp $x, $r"], &["cmp $x, $c"]).when(|m| {
+            m.is_register("r") && m.is_immediate("c") && m.is_dead("r") && !m.mentions("x", "r")
+        }),
+    ]
+}
+
+pub fn optimize<F: Frame>(instructions: Vec<Instruction>) -> Vec<Instruction> {
+    let rules = rules();
+    let registers: HashMap<_, _> = F::temp_map()
+        .into_iter()
+        .map(|(temp, name)| (name, temp))
+        .collect();
+    let mut instructions = instructions;
+    loop {
+        let (new_instructions, changed) = rewrite::<F>(instructions, &rules, &registers);
+        instructions = new_instructions;
+        if !changed {
+            return instructions;
+        }
+    }
+}
+
+fn rewrite<F: Frame>(
+    instructions: Vec<Instruction>,
+    rules: &[Rule],
+    registers: &HashMap<&'static str, Temp>,
+) -> (Vec<Instruction>, bool) {
+    let live_out = live_out(&instructions);
+    let texts: Vec<_> = instructions
+        .iter()
+        .map(|instruction| instruction.to_string::<F>())
+        .collect();
+    let mut result = vec![];
+    let mut changed = false;
+    let mut index = 0;
+    'instructions: while index < instructions.len() {
+        for rule in rules {
+            let end = index + rule.patterns.len();
+            if end > instructions.len() {
+                continue;
+            }
+            let mut bindings = HashMap::new();
+            let matches = rule
+                .patterns
+                .iter()
+                .zip(&texts[index..end])
+                .all(|(pattern, text)| pattern.matches(text, &mut bindings));
+            if !matches {
+                continue;
+            }
+            let rule_match = Match {
+                bindings,
+                registers,
+                live_out: &live_out[end - 1],
+            };
+            if let Some(condition) = rule.condition {
+                if !condition(&rule_match) {
+                    continue;
+                }
+            }
+            for pattern in &rule.replacement {
+                let text = pattern.instantiate(&rule_match.bindings);
+                // The instructions kept by the rule, like the labels, are reused as they are.
+                match texts[index..end]
+                    .iter()
+                    .position(|matched| *matched == text)
+                {
+                    Some(position) => result.push(instructions[index + position].clone()),
+                    None => result.push(new_instruction(text, registers)),
+                }
+            }
+            changed = true;
+            index = end;
+            continue 'instructions;
+        }
+        result.push(instructions[index].clone());
+        index += 1;
+    }
+    (result, changed)
+}
+
+/// Create an instruction of a replacement, with the registers it defines and uses.
+fn new_instruction(assembly: String, registers: &HashMap<&'static str, Temp>) -> Instruction {
+    let registers_of = |operand: &str| -> Vec<Temp> {
+        operand
+            .split(|char: char| !char.is_ascii_alphanumeric())
+            .filter_map(|word| registers.get(word).cloned())
+            .collect()
+    };
+    let mut destination = vec![];
+    let mut source = vec![];
+    {
+        let mut operands = assembly
+            .split_once(' ')
+            .map_or("", |(_, operands)| operands)
+            .split(", ");
+        let first = operands.next().unwrap_or("");
+        if assembly.starts_with("mov ") && !first.starts_with('[') {
+            destination.extend(registers_of(first));
+        } else {
+            source.extend(registers_of(first));
+        }
+        for operand in operands {
+            source.extend(registers_of(operand));
+        }
+    }
+    Instruction::Operation {
+        assembly,
+        destination,
+        source,
+        jump: None,
+    }
+}
+
+/// The registers live after each instruction.
+fn live_out(instructions: &[Instruction]) -> Vec<HashSet<Temp>> {
+    let labels: HashMap<Label, usize> = instructions
+        .iter()
+        .enumerate()
+        .filter_map(|(index, instruction)| match *instruction {
+            Instruction::Label { ref label, .. } => Some((label.clone(), index)),
+            _ => None,
+        })
+        .collect();
+    let successors: Vec<Vec<usize>> = instructions
+        .iter()
+        .enumerate()
+        .map(|(index, instruction)| {
+            let mut successors = vec![];
+            let falls_through = match *instruction {
+                Instruction::Operation {
+                    ref assembly,
+                    jump: Some(ref jump),
+                    ..
+                } => {
+                    successors.extend(jump.iter().map(|label| labels[label]));
+                    !assembly.starts_with("jmp ") && !jump.is_empty()
+                }
+                _ => true,
+            };
+            if falls_through && index + 1 < instructions.len() {
+                successors.push(index + 1);
+            }
+            successors
+        })
+        .collect();
+
+    let mut live_in = vec![HashSet::new(); instructions.len()];
+    let mut live_out = vec![HashSet::new(); instructions.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for index in (0..instructions.len()).rev() {
+            let out: HashSet<Temp> = successors[index]
+                .iter()
+                .flat_map(|&successor| live_in[successor].iter().cloned())
+                .collect();
+            let mut in_ = out.clone();
+            match instructions[index] {
+                Instruction::Label { .. } => (),
+                Instruction::Move {
+                    ref destination,
+                    ref source,
+                    ..
+                } => {
+                    for temp in destination {
+                        in_.remove(temp);
+                    }
+                    in_.extend(source.iter().cloned());
+                }
+                Instruction::Operation {
+                    ref assembly,
+                    ref destination,
+                    ref source,
+                    ..
+                } => {
+                    // A two-address instruction also reads the register it modifies, even if it
+                    // is only listed as a destination.
+                    if assembly.starts_with("mov ")
+                        || assembly.starts_with("lea ")
+                        || assembly.starts_with("call ")
+                    {
+                        for temp in destination {
+                            in_.remove(temp);
+                        }
+                    } else {
+                        in_.extend(destination.iter().cloned());
+                    }
+                    in_.extend(source.iter().cloned());
+                }
+            }
+            if in_ != live_in[index] || out != live_out[index] {
+                live_in[index] = in_;
+                live_out[index] = out;
+                changed = true;
+            }
+        }
+    }
+    live_out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::optimize;
+    use asm::Instruction;
+    use frame::x86_64::{RAX, RBP, RBX, RCX, RDI, RSP, X86_64};
+    use temp::{Label, Temp};
+
+    fn operation(assembly: &str, destination: Vec<Temp>, source: Vec<Temp>) -> Instruction {
+        Instruction::Operation {
+            assembly: assembly.to_string(),
+            destination,
+            source,
+            jump: None,
+        }
+    }
+
+    fn texts(instructions: &[Instruction]) -> Vec<String> {
+        instructions
+            .iter()
+            .map(|instruction| instruction.to_string::<X86_64>())
+            .collect()
+    }
+
+    #[test]
+    fn test_optimize() {
+        let label = Label::new();
+        let sink = Instruction::Operation {
+            assembly: String::new(),
+            destination: vec![],
+            source: vec![RAX, RSP, RBP],
+            jump: Some(vec![]),
+        };
+        let instructions = vec![
+            operation("mov 'd0, 's0", vec![RBX], vec![RBX]),
+            operation("mov ['s0 + -8], 's1", vec![], vec![RBP, RDI]),
+            operation("mov 'd0, ['s0 + -8]", vec![RDI], vec![RBP]),
+            operation("mov 'd0, ['s0 + -16]", vec![RCX], vec![RBP]),
+            operation("mov 'd0, 's0", vec![RAX], vec![RCX]),
+            operation("mov 'd0, 1", vec![RCX], vec![]),
+            Instruction::Operation {
+                assembly: "cmp 's0, 's1".to_string(),
+                destination: vec![],
+                source: vec![RAX, RCX],
+                jump: None,
+            },
+            Instruction::Operation {
+                assembly: format!("jmp {}", label),
+                destination: vec![],
+                source: vec![],
+                jump: Some(vec![label.clone()]),
+            },
+            Instruction::Label {
+                assembly: format!("{}:", label),
+                label: label.clone(),
+            },
+            operation("add 'd0, 's0", vec![RAX], vec![RDI, RAX]),
+            sink,
+        ];
+        let expected = vec![
+            "mov [rbp + -8], rdi".to_string(),
+            "mov rax, [rbp + -16]".to_string(),
+            "cmp rax, 1".to_string(),
+            format!("{}:", label),
+            "add rax, rdi".to_string(),
+            String::new(),
+        ];
+        assert_eq!(texts(&optimize::<X86_64>(instructions)), expected);
+    }
+
+    #[test]
+    fn test_live_register() {
+        // rcx is still used after the comparison: the constant stays in it.
+        let instructions = vec![
+            operation("mov 'd0, 1", vec![RCX], vec![]),
+            operation("cmp 's0, 's1", vec![], vec![RAX, RCX]),
+            operation("mov 'd0, 's0", vec![RAX], vec![RCX]),
+            Instruction::Operation {
+                assembly: String::new(),
+                destination: vec![],
+                source: vec![RAX],
+                jump: Some(vec![]),
+            },
+        ];
+        let expected = texts(&instructions);
+        assert_eq!(texts(&optimize::<X86_64>(instructions)), expected);
+    }
+}
@@ -0,0 +1,278 @@
+//! **稀疏条件常量传播**(SCCP)。每个临时变量的值属于一个格：⊤（还没有发现定值可执行）、
+//! 一个常量`c`、或者⊥（可能有多个值）。一开始只有入口是可执行的，所有变量都是⊤；
+//! 1. 一个基本块第一次变为可执行时，计算它的所有语句；
+//! 2. 条件跳转的条件是常量时，只有一条出边变为可执行；
+//! 3. `φ`函数只对可执行的入边取交汇；
+//! 4. 一个变量的值降低时，重新计算它所有可执行的使用。
+//!
+//! 之后把常量变量的使用替换为常量，并删除不可执行的边和基本块。和分别做常量传播和不可达代码删除
+//! 相比，它还能发现只在不可执行的路径上不是常量的变量。
+
+use std::collections::{HashMap, HashSet};
+
+use super::fold::{compare, evaluate, fold};
+use super::ssa::SsaFunction;
+use super::{is_pure, replace_uses, statement_uses};
+use ir::{Exp, Statement};
+use temp::Temp;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Value {
+    Top,
+    Const(i64),
+    Bottom,
+}
+
+fn meet(value1: Value, value2: Value) -> Value {
+    match (value1, value2) {
+        (Value::Top, value) | (value, Value::Top) => value,
+        (Value::Const(const1), Value::Const(const2)) if const1 == const2 => value1,
+        _ => Value::Bottom,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Site {
+    Phi(usize, usize),
+    Statement(usize, usize),
+}
+
+struct Propagation<'a> {
+    function: &'a SsaFunction,
+    values: HashMap<Temp, Value>,
+    executable_blocks: Vec<bool>,
+    executable_edges: HashSet<(usize, usize)>,
+    flow_worklist: Vec<(usize, usize)>,
+    ssa_worklist: Vec<Temp>,
+}
+
+impl<'a> Propagation<'a> {
+    fn value(&self, temp: Temp) -> Value {
+        // Temporaries without definition (machine registers, undefined variables) can hold any
+        // value.
+        self.values.get(&temp).cloned().unwrap_or(Value::Bottom)
+    }
+
+    fn lower(&mut self, temp: Temp, value: Value) {
+        if let Some(old_value) = self.values.get(&temp).cloned() {
+            let new_value = meet(old_value, value);
+            if new_value != old_value {
+                self.values.insert(temp, new_value);
+                self.ssa_worklist.push(temp);
+            }
+        }
+    }
+
+    fn evaluate(&self, exp: &Exp) -> Value {
+        match *exp {
+            Exp::Const(value) => Value::Const(value),
+            Exp::Temp(temp) => self.value(temp),
+            Exp::BinOp {
+                ref op,
+                ref left,
+                ref right,
+            } => match (self.evaluate(left), self.evaluate(right)) {
+                (Value::Const(left), Value::Const(right)) => evaluate(op, left, right)
+                    .map(Value::Const)
+                    .unwrap_or(Value::Bottom),
+                (Value::Bottom, _) | (_, Value::Bottom) => Value::Bottom,
+                _ => Value::Top,
+            },
+            _ => Value::Bottom,
+        }
+    }
+
+    fn visit(&mut self, site: Site) {
+        match site {
+            Site::Phi(block_index, phi_index) => {
+                let block = &self.function.blocks[block_index];
+                let phi = &block.phis[phi_index];
+                let mut value = Value::Top;
+                for (&argument, &predecessor) in phi.arguments.iter().zip(&block.predecessors) {
+                    if self.executable_edges.contains(&(predecessor, block_index)) {
+                        value = meet(value, self.value(argument));
+                    }
+                }
+                self.lower(phi.destination, value);
+            }
+            Site::Statement(block_index, statement_index) => {
+                let block = &self.function.blocks[block_index];
+                match block.statements[statement_index] {
+                    Statement::Move(Exp::Temp(temp), ref source) => {
+                        let value = self.evaluate(source);
+                        self.lower(temp, value);
+                    }
+                    Statement::CondJump {
+                        ref op,
+                        ref left,
+                        ref right,
+                        ref true_label,
+                        ref false_label,
+                    } => {
+                        let blocks = &self.function.blocks;
+                        let successors = &block.successors;
+                        match (self.evaluate(left), self.evaluate(right)) {
+                            (Value::Const(left), Value::Const(right)) => {
+                                let label = if compare(op, left, right) {
+                                    true_label
+                                } else {
+                                    false_label
+                                };
+                                for &successor in successors {
+                                    if blocks[successor].label == *label {
+                                        self.flow_worklist.push((block_index, successor));
+                                    }
+                                }
+                            }
+                            (Value::Top, _) | (_, Value::Top) => (),
+                            _ => {
+                                for &successor in successors {
+                                    self.flow_worklist.push((block_index, successor));
+                                }
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    fn visit_block(&mut self, block_index: usize) {
+        let block = &self.function.blocks[block_index];
+        for phi_index in 0..block.phis.len() {
+            self.visit(Site::Phi(block_index, phi_index));
+        }
+        if self.executable_blocks[block_index] {
+            return;
+        }
+        self.executable_blocks[block_index] = true;
+        for statement_index in 0..block.statements.len() {
+            self.visit(Site::Statement(block_index, statement_index));
+        }
+        match block.statements.last() {
+            Some(&Statement::CondJump { .. }) => (),
+            _ => {
+                for &successor in &block.successors {
+                    self.flow_worklist.push((block_index, successor));
+                }
+            }
+        }
+    }
+}
+
+pub fn propagate(function: &mut SsaFunction) {
+    let (values, executable_edges) = {
+        let mut uses: HashMap<Temp, Vec<Site>> = HashMap::new();
+        let mut values = HashMap::new();
+        for (block_index, block) in function.blocks.iter().enumerate() {
+            for (phi_index, phi) in block.phis.iter().enumerate() {
+                values.insert(phi.destination, Value::Top);
+                for &argument in &phi.arguments {
+                    uses.entry(argument)
+                        .or_default()
+                        .push(Site::Phi(block_index, phi_index));
+                }
+            }
+            for (statement_index, statement) in block.statements.iter().enumerate() {
+                if let Statement::Move(Exp::Temp(temp), _) = *statement {
+                    if function.is_variable(temp) {
+                        values.insert(temp, Value::Top);
+                    }
+                }
+                let mut temps = HashSet::new();
+                statement_uses(statement, &mut temps);
+                for temp in temps {
+                    uses.entry(temp)
+                        .or_default()
+                        .push(Site::Statement(block_index, statement_index));
+                }
+            }
+        }
+
+        let mut propagation = Propagation {
+            function,
+            values,
+            executable_blocks: vec![false; function.blocks.len()],
+            executable_edges: HashSet::new(),
+            flow_worklist: vec![],
+            ssa_worklist: vec![],
+        };
+        propagation.visit_block(0);
+        loop {
+            if let Some(edge) = propagation.flow_worklist.pop() {
+                if propagation.executable_edges.insert(edge) {
+                    propagation.visit_block(edge.1);
+                }
+            } else if let Some(temp) = propagation.ssa_worklist.pop() {
+                for &site in uses.get(&temp).map(Vec::as_slice).unwrap_or(&[]) {
+                    let block_index = match site {
+                        Site::Phi(block_index, _) | Site::Statement(block_index, _) => block_index,
+                    };
+                    if propagation.executable_blocks[block_index] {
+                        propagation.visit(site);
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+        (propagation.values, propagation.executable_edges)
+    };
+
+    let constants: HashMap<Temp, Exp> = values
+        .into_iter()
+        .filter_map(|(temp, value)| match value {
+            Value::Const(value) => Some((temp, Exp::Const(value))),
+            _ => None,
+        })
+        .collect();
+
+    // φ arguments are temporaries: the constants they use still need a definition.
+    let phi_arguments: HashSet<Temp> = function
+        .blocks
+        .iter()
+        .flat_map(|block| &block.phis)
+        .filter(|phi| !constants.contains_key(&phi.destination))
+        .flat_map(|phi| phi.arguments.iter().cloned())
+        .collect();
+
+    for block in &mut function.blocks {
+        let mut definitions = vec![];
+        block
+            .phis
+            .retain(|phi| match constants.get(&phi.destination) {
+                Some(constant) => {
+                    if phi_arguments.contains(&phi.destination) {
+                        definitions.push(Statement::Move(
+                            Exp::Temp(phi.destination),
+                            constant.clone(),
+                        ));
+                    }
+                    false
+                }
+                None => true,
+            });
+        let statements = ::std::mem::take(&mut block.statements);
+        definitions.extend(
+            statements
+                .into_iter()
+                .filter_map(|statement| match statement {
+                    Statement::Move(Exp::Temp(temp), ref source)
+                        if constants.contains_key(&temp) && is_pure(source) =>
+                    {
+                        if phi_arguments.contains(&temp) {
+                            Some(Statement::Move(Exp::Temp(temp), constants[&temp].clone()))
+                        } else {
+                            None
+                        }
+                    }
+                    statement => Some(replace_uses(statement, &constants)),
+                }),
+        );
+        // The conditional jumps on constants become jumps to the only executable successor.
+        block.statements = fold(definitions);
+    }
+    function.retain_edges(|from, to| executable_edges.contains(&(from, to)));
+    function.remove_unreachable_blocks();
+}
@@ -0,0 +1,330 @@
+//! **指令调度**（第20章）。指令选择按树的遍历顺序产生指令，没有考虑指令的延迟：一条载入指令
+//! 之后紧接着使用其结果的指令会让流水线停顿。调度在每个区域（基本块中不含标号、跳转、调用和
+//! 比较的最长指令序列）内按依赖关系重新排列指令。
+//!
+//! 依赖图的边来自指令的`destination`和`source`：写后读的边带有前一条指令的延迟，读后写和
+//! 写后写的边只约束顺序。内存按一个整体处理：存储和之前的载入、存储之间都有边，载入只依赖之前
+//! 的存储。列表调度按周期逐条发射指令：在操作数已经就绪的指令中，选择到区域结尾的关键路径最长
+//! 的那条；若都未就绪，则等待最早就绪的指令。
+//!
+//! 调度默认在寄存器分配之前进行，此时临时变量还没有被重用，依赖最少；`-flate-schedule`在
+//! 寄存器分配之后再调度一次最终的指令。
+
+use std::collections::HashMap;
+
+use asm::Instruction;
+use temp::Temp;
+
+/// Latency of the instruction in cycles, by instruction class.
+fn latency(instruction: &Instruction) -> u64 {
+    let assembly = assembly(instruction);
+    match mnemonic(assembly) {
+        "idiv" => 25,
+        "imul" => 3,
+        _ if reads_memory(assembly) => 4,
+        _ => 1,
+    }
+}
+
+fn assembly(instruction: &Instruction) -> &str {
+    match *instruction {
+        Instruction::Label { ref assembly, .. }
+        | Instruction::Move { ref assembly, .. }
+        | Instruction::Operation { ref assembly, .. } => assembly,
+    }
+}
+
+fn mnemonic(assembly: &str) -> &str {
+    assembly.split(' ').next().unwrap_or("")
+}
+
+fn is_memory_operand(operand: &str) -> bool {
+    operand.starts_with('[') || operand.starts_with("qword [")
+}
+
+fn first_operand(assembly: &str) -> &str {
+    assembly
+        .split_once(' ')
+        .map_or("", |(_, operands)| operands.trim_start())
+}
+
+fn writes_memory(assembly: &str) -> bool {
+    match mnemonic(assembly) {
+        "push" => true,
+        "cmp" => false,
+        _ => is_memory_operand(first_operand(assembly)),
+    }
+}
+
+fn reads_memory(assembly: &str) -> bool {
+    match mnemonic(assembly) {
+        "pop" => true,
+        "lea" => false,
+        // A store only writes its first operand.
+        "mov" if writes_memory(assembly) => false,
+        _ => assembly.contains('['),
+    }
+}
+
+/// Instructions which stay in place: they end the regions which are scheduled.
+fn is_barrier(instruction: &Instruction) -> bool {
+    match *instruction {
+        Instruction::Label { .. } => true,
+        Instruction::Move { .. } => false,
+        Instruction::Operation {
+            ref assembly,
+            ref source,
+            ref destination,
+            ref jump,
+        } => {
+            // The flags set by a comparison are read by the next jump.
+            jump.is_some()
+                || mnemonic(assembly) == "call"
+                || mnemonic(assembly) == "cmp"
+                // The instructions without operands (like the epilogue of a tail call) have
+                // effects which are not described.
+                || (source.is_empty() && destination.is_empty())
+        }
+    }
+}
+
+fn temps(instruction: &Instruction) -> (&[Temp], &[Temp]) {
+    match *instruction {
+        Instruction::Label { .. } => (&[], &[]),
+        Instruction::Move {
+            ref source,
+            ref destination,
+            ..
+        }
+        | Instruction::Operation {
+            ref source,
+            ref destination,
+            ..
+        } => (source, destination),
+    }
+}
+
+/// Dependency graph of a region: the edges go from an instruction to the later instructions
+/// which depend on it, with the number of cycles to wait between them.
+struct Graph {
+    successors: Vec<Vec<(usize, u64)>>,
+    predecessor_counts: Vec<usize>,
+}
+
+impl Graph {
+    fn new(region: &[Instruction]) -> Self {
+        let mut graph = Graph {
+            successors: vec![vec![]; region.len()],
+            predecessor_counts: vec![0; region.len()],
+        };
+        let mut definitions = HashMap::new();
+        let mut uses: HashMap<Temp, Vec<usize>> = HashMap::new();
+        let mut last_store = None;
+        let mut loads = vec![];
+        for (index, instruction) in region.iter().enumerate() {
+            let (source, destination) = temps(instruction);
+            for temp in source {
+                if let Some(&definition) = definitions.get(temp) {
+                    graph.add_edge(definition, index, latency(&region[definition]));
+                }
+                uses.entry(*temp).or_default().push(index);
+            }
+            for temp in destination {
+                if let Some(&definition) = definitions.get(temp) {
+                    graph.add_edge(definition, index, 1);
+                }
+                for &user in uses.remove(temp).iter().flatten() {
+                    if user != index {
+                        graph.add_edge(user, index, 0);
+                    }
+                }
+                definitions.insert(*temp, index);
+            }
+
+            let assembly = assembly(instruction);
+            if reads_memory(assembly) {
+                if let Some(store) = last_store {
+                    graph.add_edge(store, index, 1);
+                }
+                loads.push(index);
+            }
+            if writes_memory(assembly) {
+                if let Some(store) = last_store {
+                    graph.add_edge(store, index, 1);
+                }
+                for load in loads.drain(..) {
+                    if load != index {
+                        graph.add_edge(load, index, 0);
+                    }
+                }
+                last_store = Some(index);
+            }
+        }
+        graph
+    }
+
+    fn add_edge(&mut self, from: usize, to: usize, latency: u64) {
+        self.successors[from].push((to, latency));
+        self.predecessor_counts[to] += 1;
+    }
+
+    /// Length in cycles of the longest path from each instruction to the end of the region.
+    fn critical_paths(&self, region: &[Instruction]) -> Vec<u64> {
+        let mut lengths = vec![0; region.len()];
+        // The edges go forward, so the successors are computed first.
+        for index in (0..region.len()).rev() {
+            lengths[index] = self.successors[index]
+                .iter()
+                .map(|&(successor, latency)| latency + lengths[successor])
+                .fold(latency(&region[index]), u64::max);
+        }
+        lengths
+    }
+}
+
+fn schedule_region(region: Vec<Instruction>, result: &mut Vec<Instruction>) {
+    let mut graph = Graph::new(&region);
+    let priorities = graph.critical_paths(&region);
+    let mut earliest_cycles = vec![0; region.len()];
+    let mut ready: Vec<usize> = (0..region.len())
+        .filter(|&index| graph.predecessor_counts[index] == 0)
+        .collect();
+    let mut order = vec![];
+    let mut cycle = 0;
+    while !ready.is_empty() {
+        // Issue the instruction which can start the soonest, preferring the longest critical
+        // path, then the original order.
+        let (position, &index) = ready
+            .iter()
+            .enumerate()
+            .min_by_key(|&(_, &index)| {
+                (
+                    u64::max(cycle, earliest_cycles[index]),
+                    u64::MAX - priorities[index],
+                    index,
+                )
+            })
+            .expect("ready instruction");
+        ready.swap_remove(position);
+        let issue_cycle = u64::max(cycle, earliest_cycles[index]);
+        cycle = issue_cycle + 1;
+        for &(successor, latency) in &graph.successors[index] {
+            earliest_cycles[successor] =
+                u64::max(earliest_cycles[successor], issue_cycle + latency);
+            graph.predecessor_counts[successor] -= 1;
+            if graph.predecessor_counts[successor] == 0 {
+                ready.push(successor);
+            }
+        }
+        order.push(index);
+    }
+
+    let mut region: Vec<_> = region.into_iter().map(Some).collect();
+    for index in order {
+        result.push(region[index].take().expect("instruction scheduled once"));
+    }
+}
+
+pub fn schedule(instructions: Vec<Instruction>) -> Vec<Instruction> {
+    let mut result = vec![];
+    let mut region = vec![];
+    for instruction in instructions {
+        if is_barrier(&instruction) {
+            schedule_region(region, &mut result);
+            region = vec![];
+            result.push(instruction);
+        } else {
+            region.push(instruction);
+        }
+    }
+    schedule_region(region, &mut result);
+    result
+}
+
+/// Estimate the number of cycles to run the instructions once, in order, on a processor issuing
+/// one instruction per cycle and waiting for the operands with the latencies of the scheduler.
+pub fn estimate_cycles(instructions: &[Instruction]) -> u64 {
+    let mut ready_cycles: HashMap<Temp, u64> = HashMap::new();
+    let mut cycle = 0;
+    for instruction in instructions {
+        if let Instruction::Label { .. } = *instruction {
+            continue;
+        }
+        let (source, destination) = temps(instruction);
+        let issue_cycle = source
+            .iter()
+            .filter_map(|temp| ready_cycles.get(temp))
+            .fold(cycle, |cycle, &ready_cycle| u64::max(cycle, ready_cycle));
+        for temp in destination {
+            ready_cycles.insert(*temp, issue_cycle + latency(instruction));
+        }
+        cycle = issue_cycle + 1;
+    }
+    ready_cycles
+        .values()
+        .fold(cycle, |cycle, &ready_cycle| u64::max(cycle, ready_cycle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{estimate_cycles, schedule};
+    use asm::Instruction;
+    use frame::x86_64::X86_64;
+    use temp::Temp;
+
+    fn operation(assembly: &str, destination: Vec<Temp>, source: Vec<Temp>) -> Instruction {
+        Instruction::Operation {
+            assembly: assembly.to_string(),
+            destination,
+            source,
+            jump: None,
+        }
+    }
+
+    fn texts(instructions: &[Instruction]) -> Vec<String> {
+        instructions
+            .iter()
+            .map(|instruction| instruction.to_string::<X86_64>())
+            .collect()
+    }
+
+    #[test]
+    fn test_schedule() {
+        let (array, index, value, sum, other) = (
+            Temp::new(),
+            Temp::new(),
+            Temp::new(),
+            Temp::new(),
+            Temp::new(),
+        );
+        // The independent additions are moved between the load and the use of its result.
+        let instructions = vec![
+            operation("add 'd0, 1", vec![index], vec![index]),
+            operation("mov 'd0, ['s0 + 's1*8]", vec![value], vec![array, index]),
+            operation("add 'd0, 's0", vec![sum], vec![value, sum]),
+            operation("add 'd0, 2", vec![other], vec![other]),
+            operation("add 'd0, 3", vec![other], vec![other]),
+        ];
+        let original = texts(&instructions);
+        let cycles = estimate_cycles(&instructions);
+        let scheduled = schedule(instructions);
+        let expected: Vec<_> = [0, 1, 3, 4, 2]
+            .iter()
+            .map(|&index| original[index].clone())
+            .collect();
+        assert_eq!(texts(&scheduled), expected);
+        assert_eq!(cycles, 8);
+        assert_eq!(estimate_cycles(&scheduled), 6);
+
+        // The second load would hide the latency of the first one, but it cannot move above the
+        // store.
+        let instructions = vec![
+            operation("mov 'd0, ['s0]", vec![value], vec![array]),
+            operation("mov ['s0 + 8], 's1", vec![], vec![array, index]),
+            operation("mov 'd0, ['s0 + 16]", vec![other], vec![array]),
+            operation("add 'd0, 's0", vec![sum], vec![value, sum]),
+        ];
+        let original = texts(&instructions);
+        assert_eq!(texts(&schedule(instructions)), original);
+    }
+}
@@ -0,0 +1,150 @@
+//! **代数化简**。利用运算的恒等式化简表达式，例如`x + 0 = x`、`x * 2^k = x << k`，并合并
+//! `(x + c1) + c2`这样的常量。交换运算的常量运算数被移到右边，以便指令选择使用立即数。
+
+use super::is_pure;
+use ir::{BinOp, Exp, Statement};
+
+pub fn simplify(statements: Vec<Statement>) -> Vec<Statement> {
+    statements.into_iter().map(simplify_statement).collect()
+}
+
+fn simplify_statement(statement: Statement) -> Statement {
+    match statement {
+        Statement::Move(destination, source) => {
+            Statement::Move(simplify_exp(destination), simplify_exp(source))
+        }
+        Statement::Exp(exp) => Statement::Exp(simplify_exp(exp)),
+        Statement::Jump(exp, labels) => Statement::Jump(simplify_exp(exp), labels),
+        Statement::CondJump {
+            op,
+            left,
+            right,
+            true_label,
+            false_label,
+        } => Statement::CondJump {
+            op,
+            left: simplify_exp(left),
+            right: simplify_exp(right),
+            true_label,
+            false_label,
+        },
+        Statement::Sequence(first, second) => Statement::Sequence(
+            Box::new(simplify_statement(*first)),
+            Box::new(simplify_statement(*second)),
+        ),
+        Statement::Label(label) => Statement::Label(label),
+    }
+}
+
+fn simplify_exp(exp: Exp) -> Exp {
+    match exp {
+        Exp::BinOp { op, left, right } => {
+            simplify_binop(op, simplify_exp(*left), simplify_exp(*right))
+        }
+        Exp::Mem(exp) => Exp::Mem(Box::new(simplify_exp(*exp))),
+        Exp::Call(function, arguments) => Exp::Call(
+            Box::new(simplify_exp(*function)),
+            arguments.into_iter().map(simplify_exp).collect(),
+        ),
+        Exp::ExpSequence(statement, exp) => Exp::ExpSequence(
+            Box::new(simplify_statement(*statement)),
+            Box::new(simplify_exp(*exp)),
+        ),
+        exp @ Exp::Const(_) | exp @ Exp::Error | exp @ Exp::Name(_) | exp @ Exp::Temp(_) => exp,
+    }
+}
+
+fn is_commutative(op: &BinOp) -> bool {
+    match *op {
+        BinOp::Plus | BinOp::Mul | BinOp::And | BinOp::Or | BinOp::Xor => true,
+        BinOp::Minus
+        | BinOp::Div
+        | BinOp::ShiftLeft
+        | BinOp::ShiftRight
+        | BinOp::ArithmeticShiftRight => false,
+    }
+}
+
+fn binop(op: BinOp, left: Exp, right: Exp) -> Exp {
+    Exp::BinOp {
+        op,
+        left: Box::new(left),
+        right: Box::new(right),
+    }
+}
+
+fn simplify_binop(op: BinOp, left: Exp, right: Exp) -> Exp {
+    let (left, right) = match (left, right) {
+        (left @ Exp::Const(_), right) if is_commutative(&op) => (right, left),
+        operands => operands,
+    };
+    match (op, left, right) {
+        (BinOp::Plus, left, Exp::Const(0))
+        | (BinOp::Minus, left, Exp::Const(0))
+        | (BinOp::Mul, left, Exp::Const(1))
+        | (BinOp::Div, left, Exp::Const(1))
+        | (BinOp::And, left, Exp::Const(-1))
+        | (BinOp::Or, left, Exp::Const(0))
+        | (BinOp::Xor, left, Exp::Const(0))
+        | (BinOp::ShiftLeft, left, Exp::Const(0))
+        | (BinOp::ShiftRight, left, Exp::Const(0))
+        | (BinOp::ArithmeticShiftRight, left, Exp::Const(0)) => left,
+        (BinOp::Mul, ref left, Exp::Const(0)) | (BinOp::And, ref left, Exp::Const(0))
+            if is_pure(left) =>
+        {
+            Exp::Const(0)
+        }
+        (BinOp::Or, ref left, Exp::Const(-1)) if is_pure(left) => Exp::Const(-1),
+        (BinOp::Minus, ref left, ref right) | (BinOp::Xor, ref left, ref right)
+            if left == right && is_pure(left) =>
+        {
+            Exp::Const(0)
+        }
+        (BinOp::Mul, left, Exp::Const(num)) if num > 0 && (num as u64).is_power_of_two() => binop(
+            BinOp::ShiftLeft,
+            left,
+            Exp::Const(num.trailing_zeros() as i64),
+        ),
+        // Reassociate the constants: (x + c1) + c2 = x + (c1 + c2).
+        (
+            BinOp::Plus,
+            Exp::BinOp {
+                op: BinOp::Plus,
+                left: inner,
+                right: box Exp::Const(constant1),
+            },
+            Exp::Const(constant2),
+        ) => simplify_binop(
+            BinOp::Plus,
+            *inner,
+            Exp::Const(constant1.wrapping_add(constant2)),
+        ),
+        (
+            BinOp::Minus,
+            Exp::BinOp {
+                op: BinOp::Plus,
+                left: inner,
+                right: box Exp::Const(constant1),
+            },
+            Exp::Const(constant2),
+        ) => simplify_binop(
+            BinOp::Plus,
+            *inner,
+            Exp::Const(constant1.wrapping_sub(constant2)),
+        ),
+        (
+            BinOp::Mul,
+            Exp::BinOp {
+                op: BinOp::Mul,
+                left: inner,
+                right: box Exp::Const(constant1),
+            },
+            Exp::Const(constant2),
+        ) => simplify_binop(
+            BinOp::Mul,
+            *inner,
+            Exp::Const(constant1.wrapping_mul(constant2)),
+        ),
+        (op, left, right) => binop(op, left, right),
+    }
+}
@@ -0,0 +1,641 @@
+//! **静态单赋值形式**。在静态单赋值形式(SSA)中，每个变量在程序正文中只有一个定值。在控制流
+//! 汇合的地方插入`φ`函数：`a3 <- φ(a1, a2)`表示若控制从第一个前驱到达则`a3`取`a1`的值，
+//! 从第二个前驱到达则取`a2`的值。
+//!
+//! 转换为 SSA 形式分为两步：
+//! 1. 插入`φ`函数：若基本块`x`包含`a`的定值，则`x`的必经结点边界中的每个结点`z`都需要一个
+//!    `a`的`φ`函数（`φ`函数本身也是定值，所以迭代进行）。只在`a`入口活跃的结点插入（剪枝的
+//!    SSA）。
+//! 2. 重命名：沿必经结点树深度优先遍历，每个定值产生一个新的临时变量，每个使用替换为离它最近
+//!    的定值。
+//!
+//! 只有普通的临时变量被转换，机器寄存器（参数、返回值等）可能被函数调用隐式地改变，保持原样。
+//!
+//! 转换回普通的中间表示时，`φ`函数被替换为前驱末尾的传送指令；若前驱有多个后继（关键边），
+//! 则在这条边上插入一个新的基本块。
+
+use std::collections::{HashMap, HashSet};
+
+use super::cfg::ControlFlowGraph;
+use super::dataflow::{defined_temp, solve, LiveTemps};
+use super::dominators::DominatorTree;
+use super::{replace_uses, statement_uses, unreachable};
+use canon::negate_condition;
+use frame::Frame;
+use ir::{Exp, Statement};
+use temp::{Label, Temp};
+
+pub struct Phi {
+    pub destination: Temp,
+    /// The argument at index `i` is the value coming from the predecessor at index `i`.
+    pub arguments: Vec<Temp>,
+}
+
+pub struct Block {
+    pub label: Label,
+    pub phis: Vec<Phi>,
+    /// Statements after the label, the last one being the jump, if any.
+    pub statements: Vec<Statement>,
+    pub predecessors: Vec<usize>,
+    pub successors: Vec<usize>,
+}
+
+impl Block {
+    /// Add a statement at the end of the block, before its jump.
+    pub fn push_before_jump(&mut self, statement: Statement) {
+        let position = match self.statements.last() {
+            Some(&Statement::Jump(_, _)) | Some(&Statement::CondJump { .. }) => {
+                self.statements.len() - 1
+            }
+            _ => self.statements.len(),
+        };
+        self.statements.insert(position, statement);
+    }
+}
+
+/// A function in SSA form. The blocks are kept in their layout order: the first one is the entry
+/// and the last one is the `done` label followed by the epilog.
+pub struct SsaFunction {
+    pub blocks: Vec<Block>,
+    machine_registers: HashSet<Temp>,
+}
+
+impl SsaFunction {
+    pub fn new<F: Frame>(statements: Vec<Statement>) -> Self {
+        let mut statements = unreachable::eliminate(statements);
+        let starts_with_label = matches!(statements.first(), Some(&Statement::Label(_)));
+        if !starts_with_label
+            || !ControlFlowGraph::new(&statements).blocks[0]
+                .predecessors
+                .is_empty()
+        {
+            // The entry block must not have predecessors so that no φ-function is needed there.
+            statements.insert(0, Statement::Label(Label::new()));
+        }
+
+        let graph = ControlFlowGraph::new(&statements);
+        let live_in = solve(&LiveTemps, &graph, &statements).block_in;
+        let mut blocks: Vec<Block> = graph
+            .blocks
+            .iter()
+            .map(|block| {
+                let label = match statements[block.start] {
+                    Statement::Label(ref label) => label.clone(),
+                    _ => unreachable!("block without label"),
+                };
+                Block {
+                    label,
+                    phis: vec![],
+                    statements: statements[block.start + 1..block.end].to_vec(),
+                    predecessors: block.predecessors.clone(),
+                    successors: block.successors.clone(),
+                }
+            })
+            .collect();
+
+        let mut function = Self {
+            blocks: vec![],
+            machine_registers: F::temp_map().keys().cloned().collect(),
+        };
+        let tree = DominatorTree::new(0, &successors(&blocks), &predecessors(&blocks));
+        let originals = function.insert_phis(&mut blocks, &tree, &live_in);
+        function.blocks = blocks;
+        let mut stacks = HashMap::new();
+        function.rename(0, &tree, &originals, &mut stacks);
+        function
+    }
+
+    pub fn is_variable(&self, temp: Temp) -> bool {
+        !self.machine_registers.contains(&temp)
+    }
+
+    /// Returns the original temporary of each φ-function.
+    fn insert_phis(
+        &self,
+        blocks: &mut [Block],
+        tree: &DominatorTree,
+        live_in: &[HashSet<Temp>],
+    ) -> Vec<Vec<Temp>> {
+        let mut definition_sites: HashMap<Temp, Vec<usize>> = HashMap::new();
+        for (index, block) in blocks.iter().enumerate() {
+            for statement in &block.statements {
+                if let Some(temp) = defined_temp(statement) {
+                    if self.is_variable(temp) {
+                        let sites = definition_sites.entry(temp).or_default();
+                        if sites.last() != Some(&index) {
+                            sites.push(index);
+                        }
+                    }
+                }
+            }
+        }
+
+        let frontiers = tree.frontiers(&predecessors(blocks));
+        let mut originals = vec![vec![]; blocks.len()];
+        let mut temps: Vec<_> = definition_sites.keys().cloned().collect();
+        temps.sort();
+        for temp in temps {
+            let mut worklist = definition_sites[&temp].clone();
+            let mut has_phi = HashSet::new();
+            while let Some(node) = worklist.pop() {
+                for &frontier in &frontiers[node] {
+                    if has_phi.contains(&frontier) || !live_in[frontier].contains(&temp) {
+                        continue;
+                    }
+                    has_phi.insert(frontier);
+                    let block = &mut blocks[frontier];
+                    block.phis.push(Phi {
+                        destination: temp,
+                        arguments: vec![temp; block.predecessors.len()],
+                    });
+                    originals[frontier].push(temp);
+                    worklist.push(frontier);
+                }
+            }
+        }
+        originals
+    }
+
+    fn rename(
+        &mut self,
+        node: usize,
+        tree: &DominatorTree,
+        originals: &[Vec<Temp>],
+        stacks: &mut HashMap<Temp, Vec<Temp>>,
+    ) {
+        let mut defined = vec![];
+
+        for (phi, &original) in self.blocks[node].phis.iter_mut().zip(&originals[node]) {
+            let temp = Temp::new();
+            stacks.entry(original).or_default().push(temp);
+            defined.push(original);
+            phi.destination = temp;
+        }
+
+        let statements = ::std::mem::take(&mut self.blocks[node].statements);
+        let mut renamed = vec![];
+        for statement in statements {
+            let mut uses = HashSet::new();
+            statement_uses(&statement, &mut uses);
+            let replacements: HashMap<_, _> = uses
+                .into_iter()
+                .filter_map(|temp| {
+                    stacks
+                        .get(&temp)
+                        .and_then(|stack| stack.last())
+                        .map(|&current| (temp, Exp::Temp(current)))
+                })
+                .collect();
+            let statement = replace_uses(statement, &replacements);
+            let statement = match statement {
+                Statement::Move(Exp::Temp(original), source) if self.is_variable(original) => {
+                    let temp = Temp::new();
+                    stacks.entry(original).or_default().push(temp);
+                    defined.push(original);
+                    Statement::Move(Exp::Temp(temp), source)
+                }
+                statement => statement,
+            };
+            renamed.push(statement);
+        }
+        self.blocks[node].statements = renamed;
+
+        let successors = self.blocks[node].successors.clone();
+        for successor in successors {
+            let block = &mut self.blocks[successor];
+            for (position, &predecessor) in block.predecessors.iter().enumerate() {
+                if predecessor != node {
+                    continue;
+                }
+                for (phi, original) in block.phis.iter_mut().zip(&originals[successor]) {
+                    phi.arguments[position] = stacks
+                        .get(original)
+                        .and_then(|stack| stack.last())
+                        .cloned()
+                        .unwrap_or(*original);
+                }
+            }
+        }
+
+        for &child in tree.children(node) {
+            self.rename(child, tree, originals, stacks);
+        }
+
+        for original in defined {
+            stacks.get_mut(&original).map(Vec::pop);
+        }
+    }
+
+    pub fn dominator_tree(&self) -> DominatorTree {
+        DominatorTree::new(0, &successors(&self.blocks), &predecessors(&self.blocks))
+    }
+
+    /// Remove the edges for which `keep` returns false, along with the corresponding φ-function
+    /// arguments. The jumps must be updated separately.
+    pub fn retain_edges<P: Fn(usize, usize) -> bool>(&mut self, keep: P) {
+        for index in 0..self.blocks.len() {
+            let block = &mut self.blocks[index];
+            block.successors.retain(|&successor| keep(index, successor));
+            let mut position = 0;
+            while position < block.predecessors.len() {
+                if keep(block.predecessors[position], index) {
+                    position += 1;
+                } else {
+                    block.predecessors.remove(position);
+                    for phi in &mut block.phis {
+                        phi.arguments.remove(position);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Insert a block at `position` in the layout. The indices of the blocks after it, including
+    /// those in the predecessors and successors of the new block, are shifted.
+    pub fn insert_block(&mut self, position: usize, mut block: Block) {
+        let shift = |index: &mut usize| {
+            if *index >= position {
+                *index += 1;
+            }
+        };
+        for block in self.blocks.iter_mut().chain(Some(&mut block)) {
+            block.predecessors.iter_mut().for_each(shift);
+            block.successors.iter_mut().for_each(shift);
+        }
+        self.blocks.insert(position, block);
+    }
+
+    /// Remove the blocks that cannot be reached from the entry. The last block is always kept.
+    pub fn remove_unreachable_blocks(&mut self) {
+        let tree = self.dominator_tree();
+        let last = self.blocks.len() - 1;
+        let keep: Vec<bool> = (0..self.blocks.len())
+            .map(|index| index == last || tree.is_reachable(index))
+            .collect();
+        self.retain_edges(|from, to| keep[from] && keep[to]);
+        let mut new_indices = vec![None; self.blocks.len()];
+        let mut count = 0;
+        for index in 0..self.blocks.len() {
+            if keep[index] {
+                new_indices[index] = Some(count);
+                count += 1;
+            }
+        }
+        let blocks = ::std::mem::take(&mut self.blocks);
+        for (index, mut block) in blocks.into_iter().enumerate() {
+            if keep[index] {
+                for successor in &mut block.successors {
+                    *successor = new_indices[*successor].expect("kept block");
+                }
+                for predecessor in &mut block.predecessors {
+                    *predecessor = new_indices[*predecessor].expect("kept block");
+                }
+                self.blocks.push(block);
+            }
+        }
+    }
+
+    /// Convert back to normal form by replacing the φ-functions with moves.
+    pub fn into_statements(self) -> Vec<Statement> {
+        let mut copies: HashMap<(usize, usize), Vec<(Temp, Temp)>> = HashMap::new();
+        for (index, block) in self.blocks.iter().enumerate() {
+            for (position, &predecessor) in block.predecessors.iter().enumerate() {
+                let edge_copies = copies.entry((predecessor, index)).or_default();
+                for phi in &block.phis {
+                    let argument = phi.arguments[position];
+                    if phi.destination != argument
+                        && !edge_copies.contains(&(phi.destination, argument))
+                    {
+                        edge_copies.push((phi.destination, argument));
+                    }
+                }
+            }
+        }
+
+        let labels: Vec<_> = self
+            .blocks
+            .iter()
+            .map(|block| block.label.clone())
+            .collect();
+        let mut result = vec![];
+        for (index, mut block) in self.blocks.into_iter().enumerate() {
+            result.push(Statement::Label(block.label));
+            let mut edge_copies = |successor: usize| {
+                copies
+                    .remove(&(index, successor))
+                    .map(sequentialize)
+                    .unwrap_or_default()
+            };
+            match block.statements.pop() {
+                Some(Statement::CondJump {
+                    ref true_label,
+                    ref false_label,
+                    ..
+                }) if true_label == false_label => {
+                    result.extend(block.statements);
+                    for &successor in &block.successors {
+                        result.extend(edge_copies(successor));
+                    }
+                    result.push(jump(true_label.clone()));
+                }
+                Some(Statement::CondJump {
+                    op,
+                    left,
+                    right,
+                    true_label,
+                    false_label,
+                }) => {
+                    let true_copies = block
+                        .successors
+                        .iter()
+                        .find(|&&successor| labels[successor] == true_label)
+                        .map(|&successor| edge_copies(successor))
+                        .unwrap_or_default();
+                    let false_copies = block
+                        .successors
+                        .iter()
+                        .find(|&&successor| labels[successor] == false_label)
+                        .map(|&successor| edge_copies(successor))
+                        .unwrap_or_default();
+                    result.extend(block.statements);
+                    // The moves of a critical edge go in a new block. The false label must still
+                    // follow the conditional jump.
+                    match (true_copies.is_empty(), false_copies.is_empty()) {
+                        (true, true) => result.push(Statement::CondJump {
+                            op,
+                            left,
+                            right,
+                            true_label,
+                            false_label,
+                        }),
+                        (false, true) => {
+                            let edge_label = Label::new();
+                            result.push(Statement::CondJump {
+                                op: negate_condition(op),
+                                left,
+                                right,
+                                true_label: false_label,
+                                false_label: edge_label.clone(),
+                            });
+                            result.push(Statement::Label(edge_label));
+                            result.extend(true_copies);
+                            result.push(jump(true_label));
+                        }
+                        (true_empty, false) => {
+                            let true_edge_label = Label::new();
+                            let false_edge_label = Label::new();
+                            result.push(Statement::CondJump {
+                                op,
+                                left,
+                                right,
+                                true_label: if true_empty {
+                                    true_label.clone()
+                                } else {
+                                    true_edge_label.clone()
+                                },
+                                false_label: false_edge_label.clone(),
+                            });
+                            result.push(Statement::Label(false_edge_label));
+                            result.extend(false_copies);
+                            result.push(jump(false_label));
+                            if !true_empty {
+                                result.push(Statement::Label(true_edge_label));
+                                result.extend(true_copies);
+                                result.push(jump(true_label));
+                            }
+                        }
+                    }
+                }
+                Some(jump_statement @ Statement::Jump(_, _)) => {
+                    result.extend(block.statements);
+                    for &successor in &block.successors {
+                        result.extend(edge_copies(successor));
+                    }
+                    result.push(jump_statement);
+                }
+                last => {
+                    result.extend(block.statements);
+                    result.extend(last);
+                    for &successor in &block.successors {
+                        result.extend(edge_copies(successor));
+                    }
+                }
+            }
+        }
+        unreachable::eliminate(result)
+    }
+
+    /// Check the invariants of the SSA form: every temporary is defined once, the definition of a
+    /// temporary dominates its uses and the φ-functions have one argument per predecessor.
+    pub fn verify(&self) -> Result<(), String> {
+        let labels: HashMap<_, _> = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(index, block)| (block.label.clone(), index))
+            .collect();
+        // Definition site: block, and position in the block (φ-functions come before the
+        // statements).
+        let mut definitions = HashMap::new();
+        for (index, block) in self.blocks.iter().enumerate() {
+            let phi_definitions = block.phis.iter().map(|phi| phi.destination);
+            let statement_definitions = block.statements.iter().map(defined_temp);
+            let defined = phi_definitions.map(Some).chain(statement_definitions);
+            for (position, temp) in defined.enumerate() {
+                if let Some(temp) = temp {
+                    if !self.is_variable(temp) {
+                        continue;
+                    }
+                    if definitions.insert(temp, (index, position)).is_some() {
+                        return Err(format!("t{} is defined more than once", temp.num));
+                    }
+                }
+            }
+            for phi in &block.phis {
+                if phi.arguments.len() != block.predecessors.len() {
+                    return Err(format!(
+                        "the φ-function of t{} in {} has {} arguments for {} predecessors",
+                        phi.destination.num,
+                        block.label,
+                        phi.arguments.len(),
+                        block.predecessors.len()
+                    ));
+                }
+            }
+            for (position, statement) in block.statements.iter().enumerate() {
+                let is_last = position + 1 == block.statements.len();
+                match *statement {
+                    Statement::Label(_) => {
+                        return Err(format!("label inside of block {}", block.label))
+                    }
+                    Statement::Jump(_, _) | Statement::CondJump { .. } if !is_last => {
+                        return Err(format!("jump in the middle of block {}", block.label))
+                    }
+                    _ => (),
+                }
+            }
+            let mut successors: Vec<usize> = match block.statements.last() {
+                Some(Statement::Jump(_, targets)) => targets
+                    .iter()
+                    .filter_map(|label| labels.get(label).cloned())
+                    .collect(),
+                Some(Statement::CondJump {
+                    true_label,
+                    false_label,
+                    ..
+                }) => {
+                    if index + 1 >= self.blocks.len()
+                        || self.blocks[index + 1].label != *false_label
+                    {
+                        return Err(format!(
+                            "the false label of the jump in {} does not follow it",
+                            block.label
+                        ));
+                    }
+                    labels
+                        .get(true_label)
+                        .into_iter()
+                        .chain(labels.get(false_label))
+                        .cloned()
+                        .collect()
+                }
+                _ if index + 1 < self.blocks.len() => vec![index + 1],
+                _ => vec![],
+            };
+            let mut expected = block.successors.clone();
+            successors.sort();
+            expected.sort();
+            if successors != expected {
+                return Err(format!("wrong successors for {}", block.label));
+            }
+            for &successor in &block.successors {
+                let forward = block.successors.iter().filter(|&&other| other == successor);
+                let backward = self.blocks[successor]
+                    .predecessors
+                    .iter()
+                    .filter(|&&other| other == index);
+                if forward.count() != backward.count() {
+                    return Err(format!(
+                        "{} is not a predecessor of {}",
+                        block.label, self.blocks[successor].label
+                    ));
+                }
+            }
+        }
+
+        let tree = self.dominator_tree();
+        let check = |temp: Temp, block: usize, position: usize| -> Result<(), String> {
+            if let Some(&(definition_block, definition_position)) = definitions.get(&temp) {
+                let dominates = if definition_block == block {
+                    definition_position < position
+                } else {
+                    tree.dominates(definition_block, block)
+                };
+                if !dominates {
+                    return Err(format!(
+                        "the definition of t{} does not dominate its use in {}",
+                        temp.num, self.blocks[block].label
+                    ));
+                }
+            }
+            Ok(())
+        };
+        for (index, block) in self.blocks.iter().enumerate() {
+            if !tree.is_reachable(index) {
+                continue;
+            }
+            for phi in &block.phis {
+                for (&argument, &predecessor) in phi.arguments.iter().zip(&block.predecessors) {
+                    if tree.is_reachable(predecessor) {
+                        // The argument is used at the end of the predecessor.
+                        check(argument, predecessor, usize::MAX)?;
+                    }
+                }
+            }
+            for (position, statement) in block.statements.iter().enumerate() {
+                let mut uses = HashSet::new();
+                statement_uses(statement, &mut uses);
+                for temp in uses {
+                    check(temp, index, block.phis.len() + position)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_string<F: Frame>(&self) -> String {
+        let mut result = String::new();
+        for block in &self.blocks {
+            let predecessors: Vec<_> = block
+                .predecessors
+                .iter()
+                .map(|&predecessor| self.blocks[predecessor].label.to_string())
+                .collect();
+            result.push_str(&format!("{}:", block.label));
+            if !predecessors.is_empty() {
+                result.push_str(&format!(" # predecessors: {}", predecessors.join(", ")));
+            }
+            result.push('\n');
+            for phi in &block.phis {
+                let arguments: Vec<_> = phi
+                    .arguments
+                    .iter()
+                    .zip(&predecessors)
+                    .map(|(argument, predecessor)| {
+                        format!("{}: {}", predecessor, argument.to_string::<F>())
+                    })
+                    .collect();
+                result.push_str(&format!(
+                    "    {} <- φ({})\n",
+                    phi.destination.to_string::<F>(),
+                    arguments.join(", ")
+                ));
+            }
+            for statement in &block.statements {
+                result.push_str(&format!("    {}\n", statement.to_string::<F>()));
+            }
+        }
+        result
+    }
+}
+
+fn successors(blocks: &[Block]) -> Vec<Vec<usize>> {
+    blocks
+        .iter()
+        .map(|block| block.successors.clone())
+        .collect()
+}
+
+fn predecessors(blocks: &[Block]) -> Vec<Vec<usize>> {
+    blocks
+        .iter()
+        .map(|block| block.predecessors.clone())
+        .collect()
+}
+
+pub fn jump(label: Label) -> Statement {
+    Statement::Jump(Exp::Name(label.clone()), vec![label])
+}
+
+/// The moves of the φ-functions of an edge happen in parallel: when a destination is also a
+/// source, go through new temporaries.
+fn sequentialize(copies: Vec<(Temp, Temp)>) -> Vec<Statement> {
+    let overlap = copies
+        .iter()
+        .any(|&(destination, _)| copies.iter().any(|&(_, source)| source == destination));
+    if !overlap {
+        return copies
+            .into_iter()
+            .map(|(destination, source)| Statement::Move(Exp::Temp(destination), Exp::Temp(source)))
+            .collect();
+    }
+    let temps: Vec<_> = copies.iter().map(|_| Temp::new()).collect();
+    let mut result = vec![];
+    for (&(_, source), &temp) in copies.iter().zip(&temps) {
+        result.push(Statement::Move(Exp::Temp(temp), Exp::Temp(source)));
+    }
+    for (&(destination, _), &temp) in copies.iter().zip(&temps) {
+        result.push(Statement::Move(Exp::Temp(destination), Exp::Temp(temp)));
+    }
+    result
+}
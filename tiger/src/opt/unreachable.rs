@@ -0,0 +1,83 @@
+//! **不可达基本块删除**。从函数的入口出发，沿着跳转和顺序执行（fall-through）找出所有可达的
+//! 基本块，删除其余的基本块。最后一个基本块（`done`标号）总是保留，因为尾声（epilog）紧跟
+//! 在它后面。跳转到紧接着的标号的`jump`也被删除。
+
+use std::collections::HashMap;
+
+use ir::{Exp, Statement};
+
+pub fn eliminate(statements: Vec<Statement>) -> Vec<Statement> {
+    let mut blocks: Vec<Vec<Statement>> = vec![];
+    for statement in statements {
+        match statement {
+            Statement::Label(_) => blocks.push(vec![statement]),
+            statement if blocks.is_empty() => blocks.push(vec![statement]),
+            statement => {
+                let block = blocks.last_mut().expect("block");
+                // The statements after a jump can never be executed.
+                if !block.last().map(is_jump).unwrap_or(false) {
+                    block.push(statement);
+                }
+            }
+        }
+    }
+
+    let mut indices = HashMap::new();
+    for (index, block) in blocks.iter().enumerate() {
+        if let Some(Statement::Label(label)) = block.first() {
+            indices.insert(label.clone(), index);
+        }
+    }
+
+    let mut reachable = vec![false; blocks.len()];
+    let mut stack = vec![0];
+    if !blocks.is_empty() {
+        stack.push(blocks.len() - 1);
+    }
+    while let Some(index) = stack.pop() {
+        if index >= blocks.len() || reachable[index] {
+            continue;
+        }
+        reachable[index] = true;
+        match blocks[index].last() {
+            Some(Statement::Jump(_, labels)) => {
+                stack.extend(labels.iter().filter_map(|label| indices.get(label)));
+            }
+            Some(Statement::CondJump {
+                true_label,
+                false_label,
+                ..
+            }) => {
+                stack.extend(indices.get(true_label));
+                stack.extend(indices.get(false_label));
+            }
+            _ => stack.push(index + 1),
+        }
+    }
+
+    let statements: Vec<_> = blocks
+        .into_iter()
+        .zip(reachable)
+        .filter(|&(_, reachable)| reachable)
+        .flat_map(|(block, _)| block)
+        .collect();
+
+    let mut result = vec![];
+    let mut statements = statements.into_iter().peekable();
+    while let Some(statement) = statements.next() {
+        if let Statement::Jump(Exp::Name(ref label), _) = statement {
+            if statements.peek() == Some(&Statement::Label(label.clone())) {
+                continue;
+            }
+        }
+        result.push(statement);
+    }
+    result
+}
+
+fn is_jump(statement: &Statement) -> bool {
+    matches!(
+        *statement,
+        Statement::Jump(_, _) | Statement::CondJump { .. }
+    )
+}
@@ -1,10 +1,12 @@
 /*
  * Operator precedence:
- * -
- * * /
+ * - not
+ * * / %
  * + -
+ * << >> >>>
  * = <> > < >= <=
  * &
+ * ^
  * |
  */
 
@@ -13,9 +15,10 @@ use std::result;
 
 use ast::Declaration::VariableDeclaration;
 use ast::{
-    dummy_var_expr, Declaration, DeclarationWithPos, Expr, ExprWithPos, Field, FieldWithPos,
-    FuncDeclaration, FuncDeclarationWithPos, Operator, RecordField, RecordFieldWithPos, Ty,
-    TyWithPos, TypeDec, TypeDecWithPos, Var, VarWithPos,
+    dummy_var_expr, Declaration, DeclarationWithPos, Expr, ExprWithPos, ExternFuncDeclaration,
+    Field, FieldWithPos, FuncDeclaration, FuncDeclarationWithPos, Handler, HandlerWithPos,
+    Operator, RecordField, RecordFieldWithPos, Ty, TyWithPos, TypeDec, TypeDecWithPos,
+    UnaryOperator, Var, VarWithPos,
 };
 use error::Error;
 use error::Error::UnexpectedToken;
@@ -177,17 +180,57 @@ impl<'a, R: Read> Parser<'a, R> {
 
     fn dec(&mut self) -> Result<DeclarationWithPos> {
         match self.peek()?.token {
-            Function => self.fun_decs(),
+            Exception => self.exception_dec(),
+            Extern => self.extern_dec(),
+            Export | Function => self.fun_decs(),
             Type => self.ty_decs(),
             Var => self.var_dec(),
-            _ => Err(self.unexpected_token("function, type or var")?),
+            _ => Err(self.unexpected_token("exception, export, extern, function, type or var")?),
         }
     }
 
+    fn exception_dec(&mut self) -> Result<DeclarationWithPos> {
+        let pos = eat!(self, Exception);
+        let exception_name;
+        eat!(self, Ident, exception_name);
+        let name = self.symbols.symbol(&exception_name);
+        Ok(WithPos::new(Declaration::Exception { name }, pos))
+    }
+
     fn expr(&mut self) -> Result<ExprWithPos> {
         self.logical_or_expr()
     }
 
+    fn extern_dec(&mut self) -> Result<DeclarationWithPos> {
+        let pos = eat!(self, Extern);
+        eat!(self, Function);
+        let func_name;
+        eat!(self, Ident, func_name);
+        let name = self.symbols.symbol(&func_name);
+        eat!(self, OpenParen);
+        let params = fields!(self, CloseParen);
+        eat!(self, CloseParen);
+        let result = self.optional_type()?;
+        // The C symbol defaults to the name of the function.
+        let symbol = if let Equal = self.peek()?.token {
+            eat!(self, Equal);
+            let symbol;
+            eat!(self, Str, symbol);
+            symbol
+        } else {
+            func_name
+        };
+        Ok(WithPos::new(
+            Declaration::ExternFunction(ExternFuncDeclaration {
+                name,
+                params,
+                result,
+                symbol,
+            }),
+            pos,
+        ))
+    }
+
     fn field_dec(&mut self) -> Result<FieldWithPos> {
         let field_name;
         let pos = eat!(self, Ident, field_name);
@@ -319,13 +362,19 @@ impl<'a, R: Read> Parser<'a, R> {
         let func = self.fun_dec()?;
         let pos = func.pos;
         let mut functions = vec![func];
-        while let Function = self.peek()?.token {
+        while let Export | Function = self.peek()?.token {
             functions.push(self.fun_dec()?);
         }
         Ok(WithPos::new(Declaration::Function(functions), pos))
     }
 
     fn fun_dec(&mut self) -> Result<FuncDeclarationWithPos> {
+        let exported = if let Export = self.peek()?.token {
+            eat!(self, Export);
+            true
+        } else {
+            false
+        };
         let pos = eat!(self, Function);
         let func_name;
         eat!(self, Ident, func_name);
@@ -339,6 +388,7 @@ impl<'a, R: Read> Parser<'a, R> {
         Ok(WithPos::new(
             FuncDeclaration {
                 body,
+                exported,
                 name,
                 params,
                 result,
@@ -347,6 +397,16 @@ impl<'a, R: Read> Parser<'a, R> {
         ))
     }
 
+    fn handler(&mut self) -> Result<HandlerWithPos> {
+        let pos = eat!(self, Handle);
+        let exception_name;
+        let exception_pos = eat!(self, Ident, exception_name);
+        let exception = WithPos::new(self.symbols.symbol(&exception_name), exception_pos);
+        eat!(self, Arrow);
+        let body = self.expr()?;
+        Ok(WithPos::new(Handler { body, exception }, pos))
+    }
+
     fn if_then_else(&mut self) -> Result<ExprWithPos> {
         let pos = eat!(self, If);
         let test = Box::new(self.expr()?);
@@ -377,11 +437,17 @@ impl<'a, R: Read> Parser<'a, R> {
         let mut declarations = vec![self.dec()?];
         loop {
             match self.peek()?.token {
-                Function | Type | Var => declarations.push(self.dec()?),
+                Exception | Export | Extern | Function | Type | Var => {
+                    declarations.push(self.dec()?)
+                }
                 _ => break,
             }
         }
-        eat!(self, In, "function, in, type, var".to_string());
+        eat!(
+            self,
+            In,
+            "exception, export, extern, function, in, type, var".to_string()
+        );
         let expr = self.expr()?;
         let mut exprs = vec![expr];
         while let Semicolon = self.peek()?.token {
@@ -418,10 +484,10 @@ impl<'a, R: Read> Parser<'a, R> {
     }
 
     fn logical_or_expr(&mut self) -> Result<ExprWithPos> {
-        let mut expr = self.logical_and_expr()?;
+        let mut expr = self.logical_xor_expr()?;
         while let Ok(&Pipe) = self.peek_token() {
             let oper_pos = eat!(self, Pipe);
-            let right = Box::new(self.logical_and_expr()?);
+            let right = Box::new(self.logical_xor_expr()?);
             let pos = expr.pos.grow(right.pos);
             expr = WithPos::new(
                 Expr::Oper {
@@ -435,6 +501,24 @@ impl<'a, R: Read> Parser<'a, R> {
         Ok(expr)
     }
 
+    fn logical_xor_expr(&mut self) -> Result<ExprWithPos> {
+        let mut expr = self.logical_and_expr()?;
+        while let Ok(&Caret) = self.peek_token() {
+            let oper_pos = eat!(self, Caret);
+            let right = Box::new(self.logical_and_expr()?);
+            let pos = expr.pos.grow(right.pos);
+            expr = WithPos::new(
+                Expr::Oper {
+                    left: Box::new(expr),
+                    oper: WithPos::new(Operator::Xor, oper_pos),
+                    right,
+                },
+                pos,
+            );
+        }
+        Ok(expr)
+    }
+
     fn lvalue(&mut self, var: VarWithPos) -> Result<VarWithPos> {
         match self.peek()?.token {
             OpenSquare => self.subscript(var),
@@ -485,6 +569,7 @@ impl<'a, R: Read> Parser<'a, R> {
         let mut expr = self.unary_expr()?;
         loop {
             let oper = match self.peek_token() {
+                Ok(&Percent) => WithPos::new(Operator::Modulo, eat!(self, Percent)),
                 Ok(&Slash) => WithPos::new(Operator::Divide, eat!(self, Slash)),
                 Ok(&Star) => WithPos::new(Operator::Times, eat!(self, Star)),
                 _ => break,
@@ -530,14 +615,28 @@ impl<'a, R: Read> Parser<'a, R> {
             Let => self.let_expr(),
             Nil => self.nil(),
             OpenParen => self.seq_exp(),
+            Raise => self.raise(),
             Str(_) => self.string_lit(),
+            Try => self.try_handle(),
             While => self.while_loop(),
             _ => Err(self.unexpected_token(
-                "break, for, if, identifier, integer literal, let, nil, (, string literal, while",
+                "break, for, if, identifier, integer literal, let, nil, (, raise, string literal, \
+                 try, while",
             )?),
         }
     }
 
+    fn raise(&mut self) -> Result<ExprWithPos> {
+        let pos = eat!(self, Raise);
+        let exception_name;
+        let exception_pos = eat!(self, Ident, exception_name);
+        let exception = WithPos::new(self.symbols.symbol(&exception_name), exception_pos);
+        Ok(WithPos::new(
+            Expr::Raise { exception },
+            pos.grow(exception_pos),
+        ))
+    }
+
     fn rec_create(&mut self, typ: SymbolWithPos, pos: Pos) -> Result<ExprWithPos> {
         eat!(self, OpenCurly);
         let field = self.field_create()?;
@@ -560,7 +659,7 @@ impl<'a, R: Read> Parser<'a, R> {
     }
 
     fn relational_expr(&mut self) -> Result<ExprWithPos> {
-        let mut expr = self.additive_expr()?;
+        let mut expr = self.shift_expr()?;
         loop {
             let oper = match self.peek_token() {
                 Ok(&Equal) => WithPos::new(Operator::Equal, eat!(self, Equal)),
@@ -571,7 +670,7 @@ impl<'a, R: Read> Parser<'a, R> {
                 Ok(&NotEqual) => WithPos::new(Operator::Neq, eat!(self, NotEqual)),
                 _ => break,
             };
-            let right = Box::new(self.additive_expr()?);
+            let right = Box::new(self.shift_expr()?);
             let pos = expr.pos.grow(right.pos);
             expr = WithPos::new(
                 Expr::Oper {
@@ -597,6 +696,32 @@ impl<'a, R: Read> Parser<'a, R> {
         Ok(WithPos::new(Expr::Sequence(exprs), pos))
     }
 
+    fn shift_expr(&mut self) -> Result<ExprWithPos> {
+        let mut expr = self.additive_expr()?;
+        loop {
+            let oper = match self.peek_token() {
+                Ok(&ShiftLeft) => WithPos::new(Operator::ShiftLeft, eat!(self, ShiftLeft)),
+                Ok(&ShiftRight) => WithPos::new(Operator::ShiftRight, eat!(self, ShiftRight)),
+                Ok(&UnsignedShiftRight) => WithPos::new(
+                    Operator::UnsignedShiftRight,
+                    eat!(self, UnsignedShiftRight),
+                ),
+                _ => break,
+            };
+            let right = Box::new(self.additive_expr()?);
+            let pos = expr.pos.grow(right.pos);
+            expr = WithPos::new(
+                Expr::Oper {
+                    left: Box::new(expr),
+                    oper,
+                    right,
+                },
+                pos,
+            );
+        }
+        Ok(expr)
+    }
+
     fn string_lit(&mut self) -> Result<ExprWithPos> {
         let value;
         let pos = eat!(self, Str, value);
@@ -618,6 +743,20 @@ impl<'a, R: Read> Parser<'a, R> {
         self.lvalue(var)
     }
 
+    fn try_handle(&mut self) -> Result<ExprWithPos> {
+        let pos = eat!(self, Try);
+        let body = Box::new(self.expr()?);
+        let mut handlers = vec![self.handler()?];
+        while let Handle = self.peek()?.token {
+            handlers.push(self.handler()?);
+        }
+        let end_pos = eat!(self, End);
+        Ok(WithPos::new(
+            Expr::Try { body, handlers },
+            pos.grow(end_pos),
+        ))
+    }
+
     fn ty(&mut self) -> Result<TyWithPos> {
         match self.peek()?.token {
             Array => self.arr_ty(),
@@ -664,22 +803,14 @@ impl<'a, R: Read> Parser<'a, R> {
     }
 
     fn unary_expr(&mut self) -> Result<ExprWithPos> {
-        match self.peek()?.token {
-            Minus => {
-                let pos = eat!(self, Minus);
-                let expr = self.unary_expr()?;
-                let pos = pos.grow(expr.pos);
-                Ok(WithPos::new(
-                    Expr::Oper {
-                        left: Box::new(WithPos::new(Expr::Int { value: 0 }, pos)),
-                        oper: WithPos::new(Operator::Minus, pos),
-                        right: Box::new(expr),
-                    },
-                    pos,
-                ))
-            }
-            _ => self.primary_expr(),
-        }
+        let oper = match self.peek()?.token {
+            Minus => WithPos::new(UnaryOperator::Neg, eat!(self, Minus)),
+            Not => WithPos::new(UnaryOperator::Not, eat!(self, Not)),
+            _ => return self.primary_expr(),
+        };
+        let expr = Box::new(self.unary_expr()?);
+        let pos = oper.pos.grow(expr.pos);
+        Ok(WithPos::new(Expr::Unary { expr, oper }, pos))
     }
 
     fn var_dec(&mut self) -> Result<DeclarationWithPos> {
@@ -1,9 +1,8 @@
 use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 use asm::Instruction;
-use asm_gen::Gen;
 use color::color;
-use flow::instructions_to_graph;
+use flow::{instructions_to_graph, FlowGraph};
 use frame::Frame;
 use ir::{Exp, Statement};
 use liveness::interference_graph;
@@ -11,10 +10,85 @@ use temp::Temp;
 
 pub type Allocation = BTreeMap<Temp, Temp>; // Map temporaries to temporaries pre-assigned to machine registers.
 
-pub fn alloc<F: Frame>(instructions: Vec<Instruction>, frame: &mut F) -> Vec<Instruction> {
+/// What the register allocation of a function did.
+#[derive(Default)]
+pub struct Statistics {
+    /// Number of temporaries to allocate, without the machine registers.
+    pub temps: usize,
+    /// Number of allocation rounds: the program is rewritten and allocated again after spilling.
+    pub iterations: usize,
+    /// Number of moves removed by giving the same register to their operands, in the last round.
+    pub coalesced_moves: usize,
+    /// Number of moves given up for coalescing to simplify the graph, in the last round.
+    pub frozen_moves: usize,
+    /// Number of temporaries spilled to the frame.
+    pub spills: usize,
+}
+
+/// 指令中除机器寄存器以外的临时变量的个数。
+pub fn temp_count<F: Frame>(instructions: &[Instruction]) -> usize {
+    let precolored = F::temp_map();
+    let mut temps: BTreeSet<&Temp> = BTreeSet::new();
+    for instruction in instructions {
+        match *instruction {
+            Instruction::Label { .. } => (),
+            Instruction::Move {
+                ref destination,
+                ref source,
+                ..
+            }
+            | Instruction::Operation {
+                ref destination,
+                ref source,
+                ..
+            } => temps.extend(
+                destination
+                    .iter()
+                    .chain(source)
+                    .filter(|temp| !precolored.contains_key(temp)),
+            ),
+        }
+    }
+    temps.len()
+}
+
+/// A strategy to allocate the registers of a function, selected on the command line.
+pub trait RegisterAllocator<F: Frame> {
+    /// Replace the temporaries of the instructions by machine registers, spilling to the frame the
+    /// temporaries which do not fit.
+    fn allocate(
+        &self,
+        instructions: Vec<Instruction>,
+        frame: &mut F,
+    ) -> (Vec<Instruction>, Statistics);
+}
+
+/// Graph coloring with iterated register coalescing.
+pub struct GraphColoring;
+
+impl<F: Frame> RegisterAllocator<F> for GraphColoring {
+    fn allocate(
+        &self,
+        instructions: Vec<Instruction>,
+        frame: &mut F,
+    ) -> (Vec<Instruction>, Statistics) {
+        let mut statistics = Statistics {
+            temps: temp_count::<F>(&instructions),
+            ..Statistics::default()
+        };
+        let instructions = alloc(instructions, frame, &mut statistics);
+        (instructions, statistics)
+    }
+}
+
+fn alloc<F: Frame>(
+    instructions: Vec<Instruction>,
+    frame: &mut F,
+    statistics: &mut Statistics,
+) -> Vec<Instruction> {
     // temp_map是提前着好色的临时变量，例如`t1`着色为`RBP`。
     let precolored = F::temp_map();
-    // 将从`t17`开始的临时变量，添加到initial数组中，准备着色。
+    // 将机器寄存器以外的临时变量，添加到initial数组中，准备着色。
     let mut initial = vec![];
     for instruction in &instructions {
         match instruction {
@@ -43,43 +117,69 @@ pub fn alloc<F: Frame>(instructions: Vec<Instruction>, frame: &mut F) -> Vec<Ins
         }
     }
 
-    allocate(instructions, initial, frame)
+    allocate(instructions, initial, BTreeSet::new(), frame, statistics)
 }
 
 fn allocate<F: Frame>(
     instructions: Vec<Instruction>,
     initial: Vec<Temp>,
+    unspillable: BTreeSet<Temp>,
     frame: &mut F,
+    statistics: &mut Statistics,
 ) -> Vec<Instruction> {
     // 使用伪指令序列构建控制流图。
-    let flow_graph = instructions_to_graph(&instructions);
+    let flow_graph = instructions_to_graph::<F>(&instructions);
+    // 根据控制流图中的循环计算溢出代价。
+    let spill_costs = spill_costs(&flow_graph, &unspillable);
     // 根据控制流图计算出冲突图。
     let interference_graph = interference_graph(flow_graph);
     // 为`initial`数组中的临时变量着色。
-    let (allocation, spills, colored_nodes, coalesced_nodes) =
-        color::<F>(interference_graph, initial);
+    let coloring = color::<F>(interference_graph, initial, spill_costs);
+    statistics.iterations += 1;
+    statistics.coalesced_moves = coloring.coalesced_moves;
+    statistics.frozen_moves = coloring.frozen_moves;
     // 如果没有需要溢出的临时变量
-    if spills.is_empty() {
-        replace_allocation(instructions, allocation)
+    if coloring.spill_slots.is_empty() {
+        replace_allocation::<F>(instructions, coloring.allocation)
     }
     // 如果有需要溢出的临时变量，重写程序，产生一些新的指令。
     // 临时变量的溢出是指寄存器不够用，所以需要将临时变量保存到内存中，也就是栈帧中。
     // 保存操作由一系列IR指令表示。
     else {
-        let (instructions, new_temps) = rewrite_program(instructions, spills, frame);
-        let initial: Vec<_> = colored_nodes
+        statistics.spills += coloring.spill_slots.iter().map(Vec::len).sum::<usize>();
+        let (instructions, new_temps) = rewrite_program(instructions, coloring.spill_slots, frame);
+        let initial: Vec<_> = coloring
+            .colored_nodes
             .union(&new_temps)
             .cloned()
             .collect::<BTreeSet<_>>()
-            .union(&coalesced_nodes)
+            .union(&coloring.coalesced_nodes)
             .cloned()
             .collect();
-        allocate(instructions, initial, frame)
+        // 重写之后的溢出临时变量和新的临时变量的活跃范围只有一两条指令，再溢出它们也不能减少冲突。
+        let unspillable = unspillable.union(&new_temps).cloned().collect();
+        allocate(instructions, initial, unspillable, frame, statistics)
+    }
+}
+
+/// 溢出代价：临时变量的每一次定值和使用按所在循环的嵌套深度乘以`10^depth`，再求和。
+/// `unspillable`中的临时变量的代价为无穷大。
+fn spill_costs(flow_graph: &FlowGraph, unspillable: &BTreeSet<Temp>) -> HashMap<Temp, f64> {
+    let mut costs = HashMap::new();
+    for (node, depth) in flow_graph.nodes().iter().zip(flow_graph.loop_depths()) {
+        let weight = 10_f64.powi(depth as i32);
+        for temp in node.defines.iter().chain(&node.uses) {
+            *costs.entry(*temp).or_insert(0.0) += weight;
+        }
+    }
+    for temp in unspillable {
+        costs.insert(*temp, f64::INFINITY);
     }
+    costs
 }
 
 /// 将伪指令中的临时变量替换为分配好的机器寄存器。
-fn replace_allocation(
+pub fn replace_allocation<F: Frame>(
     mut instructions: Vec<Instruction>,
     allocation: Allocation,
 ) -> Vec<Instruction> {
@@ -117,33 +217,77 @@ fn replace_allocation(
             ref destination,
             ref source,
             ..
-        } => !(assembly == "mov 'd0, 's0" && destination[0] == source[0]),
+        } => !(assembly == F::MOVE && destination[0] == source[0]),
         _ => true,
     });
 
     instructions
 }
 
+/// 只有一个定值，并且这个定值不读取内存、只依赖帧指针的溢出临时变量：常量、标号和`fp + k`。
+/// 它们在每次使用之前重新计算（rematerialization），而不是存入栈帧再载入。
+fn rematerializable<F: Frame>(
+    instructions: &[Instruction],
+    spills: &[Temp],
+) -> HashMap<Temp, Instruction> {
+    let mut definitions: HashMap<Temp, Vec<&Instruction>> = HashMap::new();
+    for instruction in instructions {
+        match *instruction {
+            Instruction::Move {
+                ref destination, ..
+            }
+            | Instruction::Operation {
+                ref destination, ..
+            } => {
+                for temp in destination {
+                    if spills.contains(temp) {
+                        definitions.entry(*temp).or_default().push(instruction);
+                    }
+                }
+            }
+            Instruction::Label { .. } => (),
+        }
+    }
+    definitions
+        .into_iter()
+        .filter_map(|(temp, definitions)| match definitions[..] {
+            [definition] if F::is_cheap(definition, temp) => Some((temp, definition.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
 fn rewrite_program<F: Frame>(
     instructions: Vec<Instruction>,
-    spills: Vec<Temp>,
+    spill_slots: Vec<Vec<Temp>>,
     frame: &mut F,
 ) -> (Vec<Instruction>, BTreeSet<Temp>) {
+    let spills: Vec<Temp> = spill_slots.iter().flatten().cloned().collect();
+    let rematerialized = rematerializable::<F>(&instructions, &spills);
     // key: 需要溢出的临时变量。
     // value: 溢出操作的IR指令。
     let mut memory = HashMap::new();
-    let mut new_temps = BTreeSet::new();
-    // 遍历需要溢出的临时变量。
-    for spill in &spills {
+    let mut new_temps: BTreeSet<Temp> = spills.iter().cloned().collect();
+    // 遍历栈帧中的位置：同一个位置中的溢出临时变量互不冲突，可以共用。
+    for slot in spill_slots {
+        let slot: Vec<_> = slot
+            .into_iter()
+            .filter(|spill| !rematerialized.contains_key(spill))
+            .collect();
+        if slot.is_empty() {
+            continue;
+        }
         // 溢出的临时变量的逃逸的。
         // 计算在栈帧中相对于帧指针的偏移量。
         let local = frame.alloc_local(true);
         // 生成IR指令。
         let exp = frame.exp(local, Exp::Temp(F::fp()));
-        memory.insert(spill, exp);
-        new_temps.insert(*spill);
+        for spill in slot {
+            memory.insert(spill, exp.clone());
+        }
     }
-    let mut gen = Gen::new();
+    let precolored = F::temp_map();
+    let mut result = vec![];
 
     // 遍历所有伪指令。
     for instruction in instructions {
@@ -158,50 +302,76 @@ fn rewrite_program<F: Frame>(
                 ref source,
                 ..
             } => {
+                // 重新计算的临时变量的定值被删除。
+                if destination
+                    .iter()
+                    .any(|destination| rematerialized.contains_key(destination))
+                {
+                    continue;
+                }
                 // mov r17, r18
                 // 如果r17和r18都是需要溢出的寄存器，那么方法是：
                 // mov r19 [fp + -8]
                 // mov r18 r19
                 // mov r17 r18
-                // mov [fp + -16] r18
+                // mov [fp + -16] r17
                 //
                 // r1 <- r2 + r1 (add r1 r2)
                 // 如果r1和r2都需要溢出，那么方法是：
                 // mov r3 [fp + -8]
                 // mov r2 r3
+                // mov r4 [fp + -16]
+                // mov r1 r4
                 // r1 <- r2 + r1
                 // mov [fp + -16] r1
+                let mut loaded = vec![];
+                for spill in source.iter().filter(|source| spills.contains(source)) {
+                    if loaded.contains(spill) {
+                        continue;
+                    }
+                    loaded.push(*spill);
+                    if let Some(definition) = rematerialized.get(spill) {
+                        result.push(definition.clone());
+                    } else {
+                        // 从溢出变量在内存中的位置载入spill临时变量。
+                        let load =
+                            F::codegen(Statement::Move(Exp::Temp(*spill), memory[spill].clone()));
+                        // 载入指令定值的其他临时变量是新产生的临时变量。
+                        for instruction in &load {
+                            if let Instruction::Move {
+                                ref destination, ..
+                            }
+                            | Instruction::Operation {
+                                ref destination, ..
+                            } = *instruction
+                            {
+                                new_temps.extend(
+                                    destination
+                                        .iter()
+                                        .filter(|temp| !precolored.contains_key(temp)),
+                                );
+                            }
+                        }
+                        result.extend(load);
+                    }
+                }
                 // 取出目标寄存器列表中第一个需要溢出的寄存器。
-                if let Some(spill) = destination
+                let spill = destination
                     .iter()
                     .find(|destination| spills.contains(destination))
-                {
-                    // 取出source临时变量列表中的第一个需要溢出的临时变量。
-                    if let Some(spill) = source.iter().find(|source| spills.contains(source)) {
-                        // IR语句的结果保存在temp临时变量中。
-                        // IR语句的作用是计算溢出变量在内存中的位置
-                        let temp = gen.munch_expression(memory[spill].clone());
-                        // 将temp中的值拷贝到spill临时变量中。
-                        gen.munch_statement(Statement::Move(Exp::Temp(*spill), Exp::Temp(temp)));
-                        // temp是新产生的临时变量。
-                        new_temps.insert(temp);
-                    }
-                    let spill = spill.clone();
-                    gen.emit(instruction);
+                    .cloned();
+                result.push(instruction);
+                if let Some(spill) = spill {
                     // 将目标spill临时变量中的值写入内存中。
-                    gen.munch_statement(Statement::Move(memory[&spill].clone(), Exp::Temp(spill)));
-                } else if let Some(spill) = source.iter().find(|source| spills.contains(source)) {
-                    let temp = gen.munch_expression(memory[spill].clone());
-                    gen.munch_statement(Statement::Move(Exp::Temp(*spill), Exp::Temp(temp)));
-                    new_temps.insert(temp);
-                    gen.emit(instruction);
-                } else {
-                    gen.emit(instruction);
+                    result.extend(F::codegen(Statement::Move(
+                        memory[&spill].clone(),
+                        Exp::Temp(spill),
+                    )));
                 }
             }
-            Instruction::Label { .. } => gen.emit(instruction),
+            Instruction::Label { .. } => result.push(instruction),
         }
     }
 
-    (gen.get_result(), new_temps)
+    (result, new_temps)
 }
@@ -0,0 +1,238 @@
+/*
+ * Runtime of the programs compiled to WebAssembly, which provides the functions of lib.rs to the
+ * module as imports.
+ * Compile with:
+cargo run -- --target=wasm32 tests/hello.tig
+ * Run with:
+node src/runtime.js tests/hello.wasm
+ */
+
+'use strict';
+
+const fs = require('fs');
+const {Worker, isMainThread, workerData} = require('worker_threads');
+
+const PAGE_SIZE = 65536;
+
+// The stack of V8 is much smaller than the one of a native program by default.
+const STACK_SIZE_MB = 64;
+
+class Exit {
+    constructor(code) {
+        this.code = code;
+    }
+}
+
+function run(path) {
+    // Compiling the module validates it.
+    const module = new WebAssembly.Module(fs.readFileSync(path));
+    const decoder = new TextDecoder();
+    const encoder = new TextEncoder();
+    let instance = null;
+    let heap = 0;
+    let stdin = null;
+    let stdinPosition = 0;
+
+    const memory = () => instance.exports.memory;
+    const bytes = () => new Uint8Array(memory().buffer);
+
+    function malloc(size) {
+        const address = heap;
+        heap = Math.ceil((heap + size) / 8) * 8;
+        const missing = heap - memory().buffer.byteLength;
+        if (missing > 0) {
+            memory().grow(Math.ceil(missing / PAGE_SIZE));
+        }
+        return address;
+    }
+
+    /*
+     * Strings.
+     * A string is a sequence of Unicode characters, stored as NUL-terminated UTF-8. Sizes, indices
+     * and the values of ord and chr count characters (code points), not bytes.
+     */
+
+    function stringBytes(string) {
+        const memoryBytes = bytes();
+        const start = Number(string);
+        let end = start;
+        while (memoryBytes[end] !== 0) {
+            end++;
+        }
+        return memoryBytes.subarray(start, end);
+    }
+
+    const toString = (string) => decoder.decode(stringBytes(string));
+
+    function toTigerString(string) {
+        const encoded = encoder.encode(string);
+        const address = malloc(encoded.length + 1);
+        const memoryBytes = bytes();
+        memoryBytes.set(encoded, address);
+        memoryBytes[address + encoded.length] = 0;
+        return BigInt(address);
+    }
+
+    /*
+     * Exceptions.
+     * An exception is identified by the address of its name. A handler is identified by a number,
+     * which is the value of the WebAssembly exception thrown to it.
+     */
+
+    const handlers = [];
+    let handlerCount = 0;
+    let exception = 0n;
+    let raisePosition = null;
+
+    function runtimeException(name) {
+        return instance.exports[`exception${name}`].value;
+    }
+
+    // Throw to the innermost handler. A null file means the exception is re-raised by a handler
+    // that does not handle it, so the position of the original raise is kept.
+    function raiseException(raised, file, line, column) {
+        exception = raised;
+        if (file !== 0n) {
+            raisePosition = {file, line, column};
+        }
+        if (handlers.length === 0) {
+            const name = toString(raised);
+            if (raisePosition === null) {
+                fs.writeSync(2, `Uncaught exception ${name}\n`);
+            } else {
+                const {file, line, column} = raisePosition;
+                fs.writeSync(2, `${toString(file)}:${line}:${column}: uncaught exception ${name}\n`);
+            }
+            throw new Exit(1);
+        }
+        const handler = BigInt(handlers.pop());
+        throw new WebAssembly.Exception(instance.exports.exception, [handler]);
+    }
+
+    // Every function returns an i64, 0 when it has no result.
+    const imports = {
+        // The code point of the first character, or -1 for the empty string.
+        ord(string) {
+            const char = toString(string).codePointAt(0);
+            return char === undefined ? -1n : BigInt(char);
+        },
+        chr(num) {
+            if (num <= 0n || num > 0x10FFFFn || (num >= 0xD800n && num <= 0xDFFFn)) {
+                raiseException(runtimeException('InvalidCharacter'), 0n, 0n, 0n);
+            }
+            return toTigerString(String.fromCodePoint(Number(num)));
+        },
+        // Read one character from the standard input, or return the empty string at the end of
+        // the input.
+        getchar() {
+            if (stdin === null) {
+                try {
+                    stdin = fs.readFileSync(0);
+                } catch (error) {
+                    stdin = Buffer.alloc(0);
+                }
+            }
+            if (stdinPosition >= stdin.length) {
+                return toTigerString('');
+            }
+            const firstByte = stdin[stdinPosition];
+            let length = 1;
+            if (firstByte >= 0xC0 && firstByte <= 0xDF) {
+                length = 2;
+            } else if (firstByte >= 0xE0 && firstByte <= 0xEF) {
+                length = 3;
+            } else if (firstByte >= 0xF0 && firstByte <= 0xF7) {
+                length = 4;
+            }
+            const end = Math.min(stdinPosition + length, stdin.length);
+            const char = decoder.decode(stdin.subarray(stdinPosition, end));
+            stdinPosition = end;
+            return toTigerString(char);
+        },
+        concat(string1, string2) {
+            return toTigerString(toString(string1) + toString(string2));
+        },
+        stringEqual(string1, string2) {
+            return Buffer.compare(stringBytes(string1), stringBytes(string2)) === 0 ? 1n : 0n;
+        },
+        malloc(size) {
+            return BigInt(malloc(Number(size)));
+        },
+        initArray(length, initValue) {
+            // The length is stored before the first element for the bounds checks.
+            const address = malloc((Number(length) + 1) * 8);
+            const words = new BigInt64Array(memory().buffer, address, Number(length) + 1);
+            words.fill(initValue);
+            words[0] = length;
+            return BigInt(address + 8);
+        },
+        pushHandler() {
+            handlerCount++;
+            handlers.push(handlerCount);
+            return BigInt(handlerCount);
+        },
+        popHandler() {
+            handlers.pop();
+            return 0n;
+        },
+        currentException() {
+            return exception;
+        },
+        raiseException,
+        print(string) {
+            fs.writeSync(1, stringBytes(string));
+            return 0n;
+        },
+        printi(num) {
+            fs.writeSync(1, `${num}\n`);
+            return 0n;
+        },
+        flush() {
+            return 0n;
+        },
+        size(string) {
+            return BigInt(Array.from(toString(string)).length);
+        },
+        // The `length` characters starting at the character `first`.
+        substring(string, first, length) {
+            const chars = Array.from(toString(string));
+            if (first < 0n || length < 0n || first + length > BigInt(chars.length)) {
+                raiseException(runtimeException('IndexOutOfBounds'), 0n, 0n, 0n);
+            }
+            return toTigerString(chars.slice(Number(first), Number(first + length)).join(''));
+        },
+        exit(code) {
+            throw new Exit(Number(code));
+        },
+    };
+
+    instance = new WebAssembly.Instance(module, {env: imports});
+    heap = Number(instance.exports.heap.value);
+    try {
+        // The static link of main is not used.
+        instance.exports.main(0n);
+    } catch (error) {
+        if (!(error instanceof Exit)) {
+            throw error;
+        }
+        // Only stop the worker, whose exit code becomes the one of the process.
+        process.exit(error.code);
+    }
+}
+
+// The program runs in a worker, which can have a bigger stack.
+if (isMainThread) {
+    if (process.argv.length !== 3) {
+        fs.writeSync(2, 'Usage: node src/runtime.js filename.wasm\n');
+        process.exit(2);
+    }
+    const worker = new Worker(__filename, {
+        workerData: process.argv[2],
+        resourceLimits: {stackSizeMb: STACK_SIZE_MB},
+    });
+    worker.on('exit', (code) => {
+        process.exitCode = code;
+    });
+} else {
+    run(workerData);
+}
@@ -0,0 +1,368 @@
+//! RISC-V RV64 的**指令选择**：用最大吞进(maximal munch)覆盖规范化之后的IR树，生成GNU语法的
+//! 指令。RV64的指令集很规则：算术指令有三个运算数，只有加载和存储访问内存，条件跳转直接比较两个
+//! 寄存器，没有条件码。
+//!
+//! 立即数是12位有符号数(`[-2048, 2048)`)，其他常量和地址用汇编器的伪指令`li`和`lla`构造。超出
+//! 范围的偏移量先在t6中计算：它不参与寄存器分配，所以寄存器分配器生成的溢出代码也不需要新的
+//! 临时变量。
+
+use asm::Instruction;
+use frame::rv64::{Rv64, A0, SP, T6};
+use frame::Frame;
+use ir::{BinOp, Exp, RelationalOp, Statement};
+use temp::Temp;
+
+pub struct Gen {
+    instructions: Vec<Instruction>,
+}
+
+impl Gen {
+    pub fn new() -> Self {
+        Self {
+            instructions: vec![],
+        }
+    }
+
+    pub fn emit(&mut self, instruction: Instruction) {
+        self.instructions.push(instruction);
+    }
+
+    fn operation(&mut self, assembly: String, destination: Vec<Temp>, source: Vec<Temp>) {
+        self.emit(Instruction::Operation {
+            assembly,
+            destination,
+            source,
+            jump: None,
+        });
+    }
+
+    /// Move the arguments of a call to the argument registers, and store the other ones at the
+    /// bottom of the stack.
+    fn munch_args(&mut self, arguments: Vec<Exp>) -> Vec<Temp> {
+        let temps: Vec<_> = arguments
+            .into_iter()
+            .map(|argument| self.munch_expression(argument))
+            .collect();
+
+        let stack_arguments_size = Rv64::stack_arguments_size(temps.len());
+        if stack_arguments_size != 0 {
+            self.operation(
+                format!("addi 'd0, 's0, -{}", stack_arguments_size),
+                vec![SP],
+                vec![SP],
+            );
+        }
+        let arg_registers = Rv64::arg_registers();
+        for (index, &temp) in temps.iter().enumerate().skip(arg_registers.len()) {
+            let offset = (index - arg_registers.len()) as i64 * Rv64::WORD_SIZE;
+            self.operation(format!("sd 's0, {}('s1)", offset), vec![], vec![temp, SP]);
+        }
+
+        let mut registers = vec![];
+        for (&temp, register) in temps.iter().zip(arg_registers) {
+            self.emit(Instruction::Move {
+                assembly: "mv 'd0, 's0".to_string(),
+                source: vec![temp],
+                destination: vec![register],
+            });
+            registers.push(register);
+        }
+        registers
+    }
+
+    /// The operand of a load or a store, with its source registers numbered from 0.
+    fn munch_address(&mut self, address: Exp) -> (String, Vec<Temp>) {
+        match address {
+            Exp::BinOp {
+                op: BinOp::Plus,
+                left: base,
+                right: box Exp::Const(offset),
+            }
+            | Exp::BinOp {
+                op: BinOp::Plus,
+                left: box Exp::Const(offset),
+                right: base,
+            } => {
+                let base = self.munch_expression(*base);
+                if is_immediate(offset) {
+                    (format!("{}('s0)", offset), vec![base])
+                } else {
+                    self.operation(format!("li 'd0, {}", offset), vec![T6], vec![]);
+                    self.operation("add 'd0, 's0, 's1".to_string(), vec![T6], vec![base, T6]);
+                    ("0('s0)".to_string(), vec![T6])
+                }
+            }
+            address => ("0('s0)".to_string(), vec![self.munch_expression(address)]),
+        }
+    }
+
+    fn call(&mut self, temp: Temp, assembly: String, source: Vec<Temp>, destination: Vec<Temp>) {
+        self.operation(assembly, destination, source);
+        self.emit(Instruction::Move {
+            assembly: "mv 'd0, 's0".to_string(),
+            source: vec![A0],
+            destination: vec![temp],
+        });
+    }
+
+    fn release_stack_arguments(&mut self, argument_count: usize) {
+        let stack_arguments_size = Rv64::stack_arguments_size(argument_count);
+        if stack_arguments_size != 0 {
+            self.operation(
+                format!("addi 'd0, 's0, {}", stack_arguments_size),
+                vec![SP],
+                vec![SP],
+            );
+        }
+    }
+
+    pub fn munch_expression(&mut self, expr: Exp) -> Temp {
+        let temp = Temp::new();
+        match expr {
+            // Error cases:
+            Exp::Error | Exp::ExpSequence(_, _) => unreachable!(),
+
+            Exp::Temp(temp) => return temp,
+            Exp::Const(num) => self.operation(format!("li 'd0, {}", num), vec![temp], vec![]),
+            Exp::Name(label) => {
+                self.operation(format!("lla 'd0, {}", label), vec![temp], vec![]);
+            }
+            Exp::Mem(address) => {
+                let (address, source) = self.munch_address(*address);
+                self.operation(format!("ld 'd0, {}", address), vec![temp], source);
+            }
+            Exp::BinOp {
+                op: op @ BinOp::Plus,
+                left: expr,
+                right: box Exp::Const(num),
+            }
+            | Exp::BinOp {
+                op: op @ BinOp::Plus,
+                left: box Exp::Const(num),
+                right: expr,
+            }
+            | Exp::BinOp {
+                op: op @ BinOp::And,
+                left: expr,
+                right: box Exp::Const(num),
+            }
+            | Exp::BinOp {
+                op: op @ BinOp::Or,
+                left: expr,
+                right: box Exp::Const(num),
+            }
+            | Exp::BinOp {
+                op: op @ BinOp::Xor,
+                left: expr,
+                right: box Exp::Const(num),
+            } if is_immediate(num) => {
+                let source = self.munch_expression(*expr);
+                self.operation(
+                    format!("{}i 'd0, 's0, {}", opcode(&op), num),
+                    vec![temp],
+                    vec![source],
+                );
+            }
+            // x - 8 is x + -8.
+            Exp::BinOp {
+                op: BinOp::Minus,
+                left: expr,
+                right: box Exp::Const(num),
+            } if num.checked_neg().is_some_and(is_immediate) => {
+                let source = self.munch_expression(*expr);
+                self.operation(format!("addi 'd0, 's0, {}", -num), vec![temp], vec![source]);
+            }
+            Exp::BinOp {
+                op: BinOp::Minus,
+                left: box Exp::Const(0),
+                right: expr,
+            } => {
+                let source = self.munch_expression(*expr);
+                self.operation("neg 'd0, 's0".to_string(), vec![temp], vec![source]);
+            }
+            Exp::BinOp {
+                op: op @ BinOp::ShiftLeft,
+                left: expr,
+                right: box Exp::Const(num),
+            }
+            | Exp::BinOp {
+                op: op @ BinOp::ShiftRight,
+                left: expr,
+                right: box Exp::Const(num),
+            }
+            | Exp::BinOp {
+                op: op @ BinOp::ArithmeticShiftRight,
+                left: expr,
+                right: box Exp::Const(num),
+            } if (0..64).contains(&num) => {
+                let source = self.munch_expression(*expr);
+                self.operation(
+                    format!("{}i 'd0, 's0, {}", opcode(&op), num),
+                    vec![temp],
+                    vec![source],
+                );
+            }
+            Exp::BinOp { op, left, right } => {
+                let left = self.munch_expression(*left);
+                let right = self.munch_expression(*right);
+                self.operation(
+                    format!("{} 'd0, 's0, 's1", opcode(&op)),
+                    vec![temp],
+                    vec![left, right],
+                );
+            }
+            Exp::Call(box Exp::Name(label), arguments) => {
+                let argument_count = arguments.len();
+                let source = self.munch_args(arguments);
+                let assembly = format!("call {}", label);
+                self.call(temp, assembly, source, Rv64::calldefs_of(&label));
+                self.release_stack_arguments(argument_count);
+            }
+            Exp::Call(function, arguments) => {
+                let argument_count = arguments.len();
+                let mut source = vec![self.munch_expression(*function)];
+                source.extend(self.munch_args(arguments));
+                self.call(temp, "jalr 's0".to_string(), source, Rv64::calldefs());
+                self.release_stack_arguments(argument_count);
+            }
+        }
+
+        temp
+    }
+
+    pub fn munch_statement(&mut self, statement: Statement) {
+        match statement {
+            Statement::Sequence(statement1, statement2) => {
+                self.munch_statement(*statement1);
+                self.munch_statement(*statement2);
+            }
+            Statement::Move(Exp::Mem(address), source) => {
+                let source = self.munch_expression(source);
+                let (address, mut sources) = self.munch_address(*address);
+                let assembly = format!("sd 's{}, {}", sources.len(), address);
+                sources.push(source);
+                self.operation(assembly, vec![], sources);
+            }
+            // The loads and the constants go directly to the destination: the spill code of the
+            // register allocators must not need other temporaries.
+            Statement::Move(Exp::Temp(temp), Exp::Mem(address)) => {
+                let (address, source) = self.munch_address(*address);
+                self.operation(format!("ld 'd0, {}", address), vec![temp], source);
+            }
+            Statement::Move(Exp::Temp(temp), Exp::Const(num)) => {
+                self.operation(format!("li 'd0, {}", num), vec![temp], vec![]);
+            }
+            Statement::Move(Exp::Temp(temp), source) => {
+                let source = self.munch_expression(source);
+                self.emit(Instruction::Move {
+                    assembly: "mv 'd0, 's0".to_string(),
+                    source: vec![source],
+                    destination: vec![temp],
+                });
+            }
+            Statement::Label(label) => {
+                self.emit(Instruction::Label {
+                    assembly: format!("{}:", label),
+                    label,
+                });
+            }
+            Statement::Exp(Exp::Const(_)) => (), // Nop statement.
+            Statement::Exp(exp) => {
+                self.munch_expression(exp);
+            }
+            Statement::Jump(exp, labels) => match exp {
+                // Tail call: the arguments passed on the stack are already in place, and
+                // proc_entry_exit3 releases the frame before the jump.
+                Exp::Call(box Exp::Name(label), arguments) => {
+                    let source = self.munch_args(arguments);
+                    self.emit(Instruction::Operation {
+                        assembly: format!("tail {}", label),
+                        source,
+                        destination: vec![],
+                        jump: Some(vec![]),
+                    });
+                }
+                Exp::Name(label) => {
+                    self.emit(Instruction::Operation {
+                        assembly: format!("j {}", label),
+                        source: vec![],
+                        destination: vec![],
+                        jump: Some(labels),
+                    });
+                }
+                _ => panic!("Unexpected jump expression: {:?}", exp),
+            },
+            Statement::CondJump {
+                op,
+                left,
+                right,
+                false_label,
+                true_label,
+            } => {
+                let left = self.munch_expression(left);
+                let branch = match op {
+                    RelationalOp::Equal => "beq",
+                    RelationalOp::NotEqual => "bne",
+                    RelationalOp::LesserThan => "blt",
+                    RelationalOp::GreaterThan => "bgt",
+                    RelationalOp::LesserOrEqual => "ble",
+                    RelationalOp::GreaterOrEqual => "bge",
+                    RelationalOp::UnsignedLesserThan => "bltu",
+                    RelationalOp::UnsignedLesserOrEqual => "bleu",
+                    RelationalOp::UnsignedGreaterThan => "bgtu",
+                    RelationalOp::UnsignedGreaterOrEqual => "bgeu",
+                };
+                // The register x0 is always zero.
+                let (assembly, source) = match right {
+                    Exp::Const(0) => (format!("{} 's0, zero, {}", branch, true_label), vec![left]),
+                    right => {
+                        let right = self.munch_expression(right);
+                        (
+                            format!("{} 's0, 's1, {}", branch, true_label),
+                            vec![left, right],
+                        )
+                    }
+                };
+                self.emit(Instruction::Operation {
+                    assembly,
+                    source,
+                    destination: vec![],
+                    jump: Some(vec![false_label, true_label]),
+                });
+            }
+
+            // Error cases:
+            Statement::Move(Exp::Const(_), _)
+            | Statement::Move(Exp::Error, _)
+            | Statement::Move(Exp::Name(_), _)
+            | Statement::Move(Exp::BinOp { .. }, _)
+            | Statement::Move(Exp::Call(_, _), _)
+            | Statement::Move(Exp::ExpSequence(_, _), _) => unreachable!("{:#?}", statement),
+        }
+    }
+
+    pub fn get_result(self) -> Vec<Instruction> {
+        self.instructions
+    }
+}
+
+fn opcode(op: &BinOp) -> &'static str {
+    match *op {
+        BinOp::Plus => "add",
+        BinOp::Minus => "sub",
+        BinOp::Mul => "mul",
+        BinOp::Div => "div",
+        BinOp::And => "and",
+        BinOp::Or => "or",
+        BinOp::Xor => "xor",
+        BinOp::ShiftLeft => "sll",
+        BinOp::ShiftRight => "srl",
+        BinOp::ArithmeticShiftRight => "sra",
+    }
+}
+
+/// The immediates of the I-type and S-type instructions: `addi`, `andi`, the offsets of `ld` and
+/// `sd`...
+fn is_immediate(num: i64) -> bool {
+    (-0x800..0x800).contains(&num)
+}
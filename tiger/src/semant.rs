@@ -1,19 +1,21 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::mem;
 use std::rc::Rc;
 
 use self::AddError::*;
 use ast::{
-    Declaration, DeclarationWithPos, Expr, ExprWithPos, FieldWithPos, FuncDeclaration, Operator,
-    RecordFieldWithPos, Ty, TyWithPos, TypeDec, TypeDecWithPos, Var, VarWithPos,
+    Declaration, DeclarationWithPos, Expr, ExprWithPos, ExternFuncDeclaration, FieldWithPos,
+    FuncDeclaration, Operator, RecordFieldWithPos, Ty, TyWithPos, TypeDec, TypeDecWithPos,
+    UnaryOperator, Var, VarWithPos,
 };
 use env::{Entry, Env};
 use error::{Error, Result};
 use frame::{Fragment, Frame};
 use gen;
 use gen::{
-    array_subscript, binary_oper, field_access, function_call, goto, if_expression, num,
-    record_create, relational_oper, simple_var, string_equality, unit, var_dec, var_decs,
-    while_loop, Gen, Level,
+    array_subscript, binary_oper, checked_arithmetic, checked_division, field_access,
+    function_call, goto, if_expression, num, record_create, relational_oper, simple_var,
+    string_equality, try_expression, unit, var_dec, var_decs, while_loop, Gen, Level,
 };
 use ir::{Exp, Statement};
 use position::{Pos, WithPos};
@@ -41,19 +43,32 @@ const EXP_TYPE_ERROR: ExpTy = ExpTy {
 pub struct SemanticAnalyzer<'a, F: Clone + Frame + 'a> {
     env: &'a mut Env<F>,
     errors: Vec<Error>,
+    // Whether the function being translated is exported, or nested in an exported function.
+    exported: bool,
+    file_names: HashMap<Symbol, Exp>,
     gen: Gen<F>,
+    // Number of exception handlers to pop when breaking out of the current loop.
+    handlers_in_loop: usize,
     in_loop: bool,
+    overflow_checks: bool,
     strings: Rc<Strings>,
+    // Whether the value of the expression being translated is returned by the function.
+    tail_position: bool,
 }
 
 impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
-    pub fn new(env: &'a mut Env<F>, strings: Rc<Strings>) -> Self {
+    pub fn new(env: &'a mut Env<F>, strings: Rc<Strings>, overflow_checks: bool) -> Self {
         SemanticAnalyzer {
             env,
             errors: vec![],
+            exported: false,
+            file_names: HashMap::new(),
             gen: Gen::new(),
+            handlers_in_loop: 0,
             in_loop: false,
+            overflow_checks,
             strings,
+            tail_position: false,
         }
     }
 
@@ -74,6 +89,7 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
                 Declaration::Function(vec![WithPos::new(
                     FuncDeclaration {
                         body,
+                        exported: false,
                         name: main_symbol,
                         params: vec![],
                         result,
@@ -117,6 +133,7 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
         oper: Operator,
         left: &ExprWithPos,
         right: &ExprWithPos,
+        pos: Pos,
         level: &Level<F>,
         done_label: Option<Label>,
     ) -> ExpTy {
@@ -126,10 +143,25 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
         let right_pos = right.pos;
         let right = self.trans_exp(right, level, done_label);
         self.check_int(&right, right_pos);
-        ExpTy {
-            exp: binary_oper(oper, left.exp, right.exp),
-            ty: Type::Int,
-        }
+        let overflow = if self.overflow_checks {
+            Some(self.raise_exception(Label::with_name("exceptionOverflow"), pos))
+        } else {
+            None
+        };
+        let exp = match (oper, overflow) {
+            (Operator::Divide, overflow) | (Operator::Modulo, overflow) => {
+                let division_by_zero =
+                    self.raise_exception(Label::with_name("exceptionDivisionByZero"), pos);
+                checked_division(oper, left.exp, right.exp, division_by_zero, overflow)
+            }
+            (Operator::Plus, Some(overflow))
+            | (Operator::Minus, Some(overflow))
+            | (Operator::Times, Some(overflow)) => {
+                checked_arithmetic(oper, left.exp, right.exp, overflow)
+            }
+            _ => binary_oper(oper, left.exp, right.exp),
+        };
+        ExpTy { exp, ty: Type::Int }
     }
 
     fn check_duplicate_types(&mut self, types: &[TypeDecWithPos]) {
@@ -150,7 +182,7 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
     }
 
     fn check_int(&mut self, expr: &ExpTy, pos: Pos) {
-        if expr.ty != Type::Int && expr.ty != Type::Error {
+        if expr.ty != Type::Int && expr.ty != Type::Bottom && expr.ty != Type::Error {
             return self.add_error(
                 Error::Type {
                     expected: Type::Int,
@@ -165,7 +197,12 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
     fn check_types(&mut self, expected: &Type, unexpected: &Type, pos: Pos) {
         let expected = self.actual_ty(expected);
         let unexpected = self.actual_ty(unexpected);
-        if expected != unexpected && expected != Type::Error && unexpected != Type::Error {
+        if expected != unexpected
+            && expected != Type::Bottom
+            && unexpected != Type::Bottom
+            && expected != Type::Error
+            && unexpected != Type::Error
+        {
             if let Type::Record(_, _, _) = expected {
                 if unexpected == Type::Nil {
                     return;
@@ -207,27 +244,74 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
         done_label: Option<Label>,
     ) -> Option<Statement> {
         match declaration.node {
+            Declaration::Exception { name } => {
+                let exception_name = self.env.var_name(name).to_string();
+                let label = self.gen.exception(exception_name);
+                self.env.enter_exception(name, label);
+                None
+            }
+            Declaration::ExternFunction(ExternFuncDeclaration {
+                name,
+                ref params,
+                ref result,
+                ref symbol,
+            }) => {
+                let result = if let Some(ref result) = *result {
+                    self.get_type(result, AddError)
+                } else {
+                    Type::Unit
+                };
+                let mut parameters = vec![];
+                let mut param_set = HashSet::new();
+                for param in params {
+                    parameters.push(self.get_type(&param.node.typ, AddError));
+                    if !param_set.insert(param.node.name) {
+                        self.duplicate_param(param);
+                    }
+                }
+                let label = Label::with_name(symbol);
+                self.gen.external_function(label.clone());
+                // External functions do not take a static link, so they live in the outermost level.
+                self.env.enter_var(
+                    name,
+                    Entry::Fun {
+                        exported: false,
+                        external: true,
+                        label,
+                        level: gen::outermost(),
+                        parameters,
+                        result,
+                    },
+                );
+                None
+            }
             Declaration::Function(ref declarations) => {
                 let mut levels = vec![];
                 for &WithPos {
                     node:
                         FuncDeclaration {
+                            exported,
                             name,
                             ref params,
                             ref result,
                             ..
                         },
-                    ..
+                    pos,
                 } in declarations
                 {
+                    if exported && !parent_level.is_main() {
+                        self.add_error(Error::ExportNotTopLevel { pos }, ());
+                    }
                     let formals = params
                         .iter()
-                        .map(|param| self.env.look_escape(param.node.name))
+                        .map(|param| self.env.look_escape(param.pos))
                         .collect();
+                    let label = self.function_label(name, exported);
                     let level = Level::new(
                         parent_level,
-                        Label::with_name(&self.strings.get(name).expect("string get")),
+                        label.clone(),
                         formals,
+                        self.env.look_escape(pos),
                     );
                     let result_type = if let Some(ref result) = *result {
                         self.get_type(result, AddError)
@@ -249,8 +333,9 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
                     self.env.enter_var(
                         name,
                         Entry::Fun {
+                            exported,
                             external: false,
-                            label: Label::with_name(&self.strings.get(name).expect("strings get")),
+                            label,
                             level,
                             parameters,
                             result: result_type.clone(),
@@ -264,8 +349,9 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
                             FuncDeclaration {
                                 ref params,
                                 ref body,
+                                exported,
+                                name,
                                 ref result,
-                                ..
                             },
                         ..
                     },
@@ -283,6 +369,11 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
                         parameters.push(self.get_type(&param.node.typ, DontAddError));
                         param_names.push(param.node.name);
                     }
+                    let c_parameters: Vec<_> = param_names
+                        .iter()
+                        .zip(&parameters)
+                        .map(|(&name, typ)| (self.env.var_name(name), self.actual_ty(typ)))
+                        .collect();
                     self.env.begin_scope();
                     for ((param, name), access) in parameters
                         .into_iter()
@@ -291,10 +382,24 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
                     {
                         self.env.enter_var(name, Entry::Var { access, typ: param });
                     }
+                    self.tail_position = true;
+                    let in_exported_function = self.exported;
+                    self.exported = in_exported_function || exported;
                     let exp = self.trans_exp(body, level, done_label.clone());
+                    self.exported = in_exported_function;
                     self.check_types(&result_type, &exp.ty, body.pos);
                     self.gen.proc_entry_exit(&level, exp.exp);
                     self.env.end_scope();
+                    if exported {
+                        let name = Label::with_name(&self.strings.get(name).expect("strings get"));
+                        let result_type = self.actual_ty(&result_type);
+                        self.gen.export_function(
+                            name,
+                            &level.current.borrow().name(),
+                            c_parameters,
+                            result_type,
+                        );
+                    }
                 }
                 None
             }
@@ -325,7 +430,7 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
                 ref typ,
                 ..
             } => {
-                let escape = self.env.look_escape(name);
+                let escape = self.env.look_escape(declaration.pos);
                 let access = gen::alloc_local(parent_level, escape);
                 let exp = self.trans_exp(init, parent_level, done_label);
                 if let Some(ref ident) = *typ {
@@ -358,6 +463,7 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
         level: &Level<F>,
         done_label: Option<Label>,
     ) -> ExpTy {
+        let tail_position = mem::replace(&mut self.tail_position, false);
         match expr.node {
             Expr::Array {
                 ref init,
@@ -403,8 +509,16 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
                     return self
                         .add_error(Error::BreakOutsideLoop { pos: expr.pos }, EXP_TYPE_ERROR);
                 }
+                let mut exp = goto(done_label.expect("break should be in while loop"));
+                // Uninstall the handlers of the try expressions the break jumps out of.
+                for _ in 0..self.handlers_in_loop {
+                    exp = Exp::ExpSequence(
+                        Box::new(Statement::Exp(F::external_call("popHandler", vec![]))),
+                        Box::new(exp),
+                    );
+                }
                 ExpTy {
-                    exp: goto(done_label.expect("break should be in while loop")),
+                    exp,
                     ty: Type::Unit,
                 }
             }
@@ -413,12 +527,28 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
                     // TODO: remove this clone.
                     return match entry {
                         Entry::Fun {
+                            exported,
                             external,
                             ref label,
                             ref parameters,
                             ref result,
                             level: ref current_level,
                         } => {
+                            // The static link of an exported function is null: it can only call the
+                            // functions of the main function which do not use it.
+                            if self.exported
+                                && !exported
+                                && current_level.parent().is_some_and(Level::is_main)
+                            {
+                                let ident = self.env.var_name(function);
+                                self.add_error(
+                                    Error::NotExported {
+                                        ident,
+                                        pos: expr.pos,
+                                    },
+                                    (),
+                                );
+                            }
                             let mut expr_args = vec![];
                             for (arg, param) in args.iter().zip(parameters) {
                                 let exp = self.trans_exp(arg, level, done_label.clone());
@@ -427,6 +557,8 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
                             }
                             let exp = if external {
                                 F::external_call(&label.to_name(), expr_args)
+                            } else if tail_position {
+                                self.gen.tail_call(label, expr_args, level, current_level)
                             } else {
                                 function_call(label, expr_args, level, current_level)
                             };
@@ -447,12 +579,19 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
             } => {
                 let test_expr = self.trans_exp(test, level, done_label.clone());
                 self.check_int(&test_expr, then.pos);
+                self.tail_position = tail_position;
                 let if_expr = self.trans_exp(then, level, done_label.clone());
                 let (else_expr, ty) = match *else_ {
                     Some(ref else_) => {
+                        self.tail_position = tail_position;
                         let else_expr = self.trans_exp(&else_, level, done_label);
                         self.check_types(&if_expr.ty, &else_expr.ty, else_.pos);
-                        (Some(else_expr), if_expr.ty)
+                        let ty = if if_expr.ty == Type::Bottom {
+                            else_expr.ty.clone()
+                        } else {
+                            if_expr.ty
+                        };
+                        (Some(else_expr), ty)
                     }
                     None => {
                         self.check_types(&Type::Unit, &if_expr.ty, then.pos);
@@ -478,7 +617,9 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
                 ref declarations,
             } => {
                 let old_in_loop = self.in_loop;
+                let old_handlers_in_loop = self.handlers_in_loop;
                 self.in_loop = false;
+                self.handlers_in_loop = 0;
                 self.env.begin_scope();
                 let mut vars = vec![];
                 for declaration in declarations {
@@ -488,6 +629,8 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
                     }
                 }
                 self.in_loop = old_in_loop;
+                self.handlers_in_loop = old_handlers_in_loop;
+                self.tail_position = tail_position;
                 let result = self.trans_exp(body, level, done_label);
                 self.env.end_scope();
                 ExpTy {
@@ -552,7 +695,52 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
                         ..
                     },
                 ref right,
-            } => self.check_binary_op(oper, left, right, level, done_label),
+            }
+            | Expr::Oper {
+                ref left,
+                oper:
+                    WithPos {
+                        node: oper @ Operator::Modulo,
+                        ..
+                    },
+                ref right,
+            }
+            | Expr::Oper {
+                ref left,
+                oper:
+                    WithPos {
+                        node: oper @ Operator::ShiftLeft,
+                        ..
+                    },
+                ref right,
+            }
+            | Expr::Oper {
+                ref left,
+                oper:
+                    WithPos {
+                        node: oper @ Operator::ShiftRight,
+                        ..
+                    },
+                ref right,
+            }
+            | Expr::Oper {
+                ref left,
+                oper:
+                    WithPos {
+                        node: oper @ Operator::UnsignedShiftRight,
+                        ..
+                    },
+                ref right,
+            }
+            | Expr::Oper {
+                ref left,
+                oper:
+                    WithPos {
+                        node: oper @ Operator::Xor,
+                        ..
+                    },
+                ref right,
+            } => self.check_binary_op(oper, left, right, expr.pos, level, done_label),
             Expr::Oper {
                 ref left,
                 oper:
@@ -618,6 +806,19 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
                 };
                 ExpTy { exp, ty: Type::Int }
             }
+            Expr::Raise { ref exception } => match self.env.look_exception(exception.node) {
+                Some(label) => {
+                    let label = label.clone();
+                    ExpTy {
+                        exp: Exp::ExpSequence(
+                            Box::new(Statement::Exp(self.raise_exception(label, expr.pos))),
+                            Box::new(unit()),
+                        ),
+                        ty: Type::Bottom,
+                    }
+                }
+                None => self.undefined_exception(exception),
+            },
             Expr::Record {
                 ref fields,
                 ref typ,
@@ -677,6 +878,7 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
                     for expr in exprs {
                         new_exprs.push(self.trans_exp(expr, level, done_label.clone()));
                     }
+                    self.tail_position = tail_position;
                     let last_expr = self.trans_exp(last_expr, level, done_label);
                     if new_exprs.is_empty() {
                         last_expr
@@ -701,15 +903,68 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
                 exp: self.gen.string_literal(value.clone()),
                 ty: Type::String,
             },
+            Expr::Try {
+                ref body,
+                ref handlers,
+            } => {
+                self.handlers_in_loop += 1;
+                let body_expr = self.trans_exp(body, level, done_label.clone());
+                self.handlers_in_loop -= 1;
+                let mut ty = body_expr.ty;
+                let mut handler_exprs = vec![];
+                for handler in handlers {
+                    let exception = &handler.node.exception;
+                    let label = match self.env.look_exception(exception.node) {
+                        Some(label) => label.clone(),
+                        None => {
+                            self.undefined_exception(exception);
+                            continue;
+                        }
+                    };
+                    let handler_expr =
+                        self.trans_exp(&handler.node.body, level, done_label.clone());
+                    self.check_types(&ty, &handler_expr.ty, handler.node.body.pos);
+                    if ty == Type::Bottom {
+                        ty = handler_expr.ty;
+                    }
+                    handler_exprs.push((label, handler_expr.exp));
+                }
+                ExpTy {
+                    exp: try_expression(body_expr.exp, handler_exprs, level),
+                    ty,
+                }
+            }
+            Expr::Unary {
+                expr: ref operand,
+                ref oper,
+            } => {
+                let operand_expr = self.trans_exp(operand, level, done_label);
+                self.check_int(&operand_expr, operand.pos);
+                let exp = match oper.node {
+                    UnaryOperator::Neg if self.overflow_checks => {
+                        let overflow =
+                            self.raise_exception(Label::with_name("exceptionOverflow"), expr.pos);
+                        checked_arithmetic(Operator::Minus, num(0), operand_expr.exp, overflow)
+                    }
+                    UnaryOperator::Neg => binary_oper(Operator::Minus, num(0), operand_expr.exp),
+                    UnaryOperator::Not => {
+                        relational_oper(Operator::Equal, operand_expr.exp, num(0), level)
+                    }
+                };
+                ExpTy { exp, ty: Type::Int }
+            }
             Expr::Variable(ref var) => self.trans_var(var, level, done_label),
             Expr::While { ref body, ref test } => {
                 let test_expr = self.trans_exp(test, level, done_label);
                 self.check_int(&test_expr, test.pos);
                 let old_in_loop = self.in_loop;
+                let old_handlers_in_loop = self.handlers_in_loop;
                 self.in_loop = true;
+                self.handlers_in_loop = 0;
                 let while_done_label = Label::new();
                 let result = self.trans_exp(body, level, Some(while_done_label.clone()));
                 self.in_loop = old_in_loop;
+                self.handlers_in_loop = old_handlers_in_loop;
                 ExpTy {
                     exp: while_loop(&while_done_label, test_expr.exp, result.exp),
                     ty: result.ty,
@@ -718,6 +973,28 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
         }
     }
 
+    /// Call the runtime to raise `exception`, reporting `pos` if nothing handles it.
+    fn raise_exception(&mut self, exception: Label, pos: Pos) -> Exp {
+        let (file, line, column) = if pos.is_dummy() {
+            (num(0), num(0), num(0))
+        } else {
+            let file = match self.file_names.get(&pos.file) {
+                Some(file) => file.clone(),
+                None => {
+                    let name = self.strings.get(pos.file).unwrap_or_default();
+                    let file = self.gen.string_literal(name);
+                    self.file_names.insert(pos.file, file.clone());
+                    file
+                }
+            };
+            (file, num(pos.line as i64), num(pos.column as i64))
+        };
+        F::external_call(
+            "raiseException",
+            vec![Exp::Name(exception), file, line, column],
+        )
+    }
+
     fn trans_ty(&mut self, symbol: Symbol, ty: &TyWithPos) -> Type {
         match ty.node {
             Ty::Array { ref ident } => {
@@ -752,8 +1029,12 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
                     Type::Record(record_type, ref fields, _) => {
                         for (index, &(name, ref typ)) in fields.iter().enumerate() {
                             if name == ident.node {
+                                let trap = self.raise_exception(
+                                    Label::with_name("exceptionNilAccess"),
+                                    this.pos,
+                                );
                                 return ExpTy {
-                                    exp: field_access::<F>(var.exp, index),
+                                    exp: field_access::<F>(var.exp, index, trap),
                                     ty: typ.clone(),
                                 };
                             }
@@ -772,6 +1053,16 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
                     ref typ,
                 }) = self.env.look_var(ident.node).cloned()
                 {
+                    if self.exported && access.0.is_main() {
+                        let ident = self.env.var_name(ident.node);
+                        return self.add_error(
+                            Error::CapturedByExport {
+                                ident,
+                                pos: var.pos,
+                            },
+                            EXP_TYPE_ERROR,
+                        );
+                    }
                     // TODO: remove this clone.
                     return ExpTy {
                         exp: simple_var(access.clone(), level),
@@ -785,10 +1076,16 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
                 let subscript_expr = self.trans_exp(expr, level, done_label);
                 self.check_int(&subscript_expr, expr.pos);
                 match var.ty {
-                    Type::Array(typ, _) => ExpTy {
-                        exp: array_subscript::<F>(var.exp, subscript_expr.exp),
-                        ty: self.actual_ty_var(&typ),
-                    },
+                    Type::Array(typ, _) => {
+                        let trap = self.raise_exception(
+                            Label::with_name("exceptionIndexOutOfBounds"),
+                            expr.pos,
+                        );
+                        ExpTy {
+                            exp: array_subscript::<F>(var.exp, subscript_expr.exp, trap),
+                            ty: self.actual_ty_var(&typ),
+                        }
+                    }
                     Type::Error => ExpTy {
                         exp: Exp::Error,
                         ty: Type::Error,
@@ -801,6 +1098,17 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
         }
     }
 
+    /// The function keeps its name, unless it is exported: the name is then the one of the wrapper
+    /// called from C.
+    fn function_label(&self, name: Symbol, exported: bool) -> Label {
+        let name = self.strings.get(name).expect("strings get");
+        if exported {
+            Label::with_name(&format!("{}.tiger", name))
+        } else {
+            Label::with_name(&name)
+        }
+    }
+
     fn duplicate_param(&mut self, param: &FieldWithPos) {
         let ident = self.env.var_name(param.node.name).to_string();
         self.add_error(
@@ -838,6 +1146,18 @@ impl<'a, F: Clone + Frame + PartialEq> SemanticAnalyzer<'a, F> {
         )
     }
 
+    fn undefined_exception(&mut self, symbol: &SymbolWithPos) -> ExpTy {
+        let ident = self.env.var_name(symbol.node).to_string();
+        self.add_error(
+            Error::Undefined {
+                ident,
+                item: "exception".to_string(),
+                pos: symbol.pos,
+            },
+            EXP_TYPE_ERROR,
+        )
+    }
+
     fn undefined_function(&mut self, ident: Symbol, pos: Pos) -> ExpTy {
         let ident = self.env.var_name(ident).to_string();
         self.add_error(
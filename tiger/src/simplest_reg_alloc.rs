@@ -5,12 +5,23 @@ use std::collections::HashMap;
 use frame::Frame;
 
 use asm::Instruction;
-use asm_gen::Gen;
 use temp::Temp;
 
 use ir::{Exp, Statement};
+use reg_alloc::{RegisterAllocator, Statistics};
 
-use frame::x86_64::{R10, R11};
+/// 将所有临时变量溢出的分配策略。
+pub struct SpillEverything;
+
+impl<F: Frame> RegisterAllocator<F> for SpillEverything {
+    fn allocate(
+        &self,
+        instructions: Vec<Instruction>,
+        frame: &mut F,
+    ) -> (Vec<Instruction>, Statistics) {
+        simplest_allocate(instructions, frame)
+    }
+}
 
 /// 非机器寄存器的临时变量全部溢出
 /// 
@@ -22,15 +33,17 @@ fn is_spilled<F: Frame>(temp: Temp) -> bool {
     return false;
 }
 
-pub fn simplest_allocate<F: Frame>(
+fn simplest_allocate<F: Frame>(
     mut instructions: Vec<Instruction>,
     frame: &mut F,
-) -> Vec<Instruction> {
+) -> (Vec<Instruction>, Statistics) {
     // K: 需要溢出的临时变量
     // V: 访问溢出到内存中的临时变量的IR语句 
     let mut memory = HashMap::new();
 
-    let mut gen = Gen::new();
+    let mut result = vec![];
+    // 溢出的源操作数载入这两个寄存器，目标操作数先写入第二个寄存器。
+    let [first, second] = F::scratch_registers();
 
     // 将所有需要溢出的临时变量保存到spills数组中
     let mut spills = vec![];
@@ -92,99 +105,108 @@ pub fn simplest_allocate<F: Frame>(
                 {
                     if source.len() > 1 && is_spilled::<F>(source[0]) && is_spilled::<F>(source[1]) {
                         let dst = dst.clone();
-                        gen.munch_statement(Statement::Move(
-                            Exp::Temp(R10),
+                        result.extend(F::codegen(Statement::Move(
+                            Exp::Temp(first),
                             memory[&source[0]].clone(),
-                        ));
-                        gen.munch_statement(Statement::Move(
-                            Exp::Temp(R11),
+                        )));
+                        result.extend(F::codegen(Statement::Move(
+                            Exp::Temp(second),
                             memory[&source[1]].clone(),
-                        ));
-                        *source = vec![R10, R11];
-                        *destination = vec![R11];
-                        gen.emit(instruction.clone());
-                        gen.munch_statement(Statement::Move(memory[&dst].clone(), Exp::Temp(R11)));
+                        )));
+                        *source = vec![first, second];
+                        *destination = vec![second];
+                        result.push(instruction.clone());
+                        result.extend(F::codegen(Statement::Move(memory[&dst].clone(), Exp::Temp(second))));
                     } else if source.len() > 1 && is_spilled::<F>(source[0]) && !is_spilled::<F>(source[1]) {
                         let dst = dst.clone();
-                        gen.munch_statement(Statement::Move(
-                            Exp::Temp(R10),
+                        result.extend(F::codegen(Statement::Move(
+                            Exp::Temp(first),
                             memory[&source[0]].clone(),
-                        ));
-                        *source = vec![R10, source[1]];
-                        *destination = vec![R11];
-                        gen.emit(instruction.clone());
-                        gen.munch_statement(Statement::Move(memory[&dst].clone(), Exp::Temp(R11)));
+                        )));
+                        *source = vec![first, source[1]];
+                        *destination = vec![second];
+                        result.push(instruction.clone());
+                        result.extend(F::codegen(Statement::Move(memory[&dst].clone(), Exp::Temp(second))));
                     } else if source.len() > 1 && !is_spilled::<F>(source[0]) && is_spilled::<F>(source[1]) {
                         let dst = dst.clone();
-                        gen.munch_statement(Statement::Move(
-                            Exp::Temp(R10),
+                        let register = if source[1] == dst { second } else { first };
+                        result.extend(F::codegen(Statement::Move(
+                            Exp::Temp(register),
                             memory[&source[1]].clone(),
-                        ));
-                        *source = vec![source[0], R10];
-                        *destination = vec![R11];
-                        gen.emit(instruction.clone());
-                        gen.munch_statement(Statement::Move(memory[&dst].clone(), Exp::Temp(R11)));
+                        )));
+                        *source = vec![source[0], register];
+                        *destination = vec![second];
+                        result.push(instruction.clone());
+                        result.extend(F::codegen(Statement::Move(memory[&dst].clone(), Exp::Temp(second))));
                     } else if source.len() == 1 && is_spilled::<F>(source[0]) {
                         let dst = dst.clone();
-                        gen.munch_statement(Statement::Move(
-                            Exp::Temp(R10),
+                        // 形如`sub 'd0, 1`的指令读取的是目标寄存器，所以目标临时变量要载入第二个寄存器。
+                        let register = if source[0] == dst { second } else { first };
+                        result.extend(F::codegen(Statement::Move(
+                            Exp::Temp(register),
                             memory[&source[0]].clone(),
-                        ));
-                        *source = vec![R10];
-                        *destination = vec![R11];
-                        gen.emit(instruction.clone());
-                        gen.munch_statement(Statement::Move(memory[&dst].clone(), Exp::Temp(R11)));
+                        )));
+                        *source = vec![register];
+                        *destination = vec![second];
+                        result.push(instruction.clone());
+                        result.extend(F::codegen(Statement::Move(memory[&dst].clone(), Exp::Temp(second))));
                     } else {
                         let dst = dst.clone();
-                        *destination = vec![R11];
-                        gen.emit(instruction.clone());
-                        gen.munch_statement(Statement::Move(memory[&dst].clone(), Exp::Temp(R11)));
+                        *destination = vec![second];
+                        result.push(instruction.clone());
+                        result.extend(F::codegen(Statement::Move(memory[&dst].clone(), Exp::Temp(second))));
                     }
                 } else if let Some(src) = source.iter().find(|source| spills.contains(source)) {
                     if source.len() > 1 && is_spilled::<F>(source[0]) && is_spilled::<F>(source[1])
                     {
-                        gen.munch_statement(Statement::Move(
-                            Exp::Temp(R10),
+                        result.extend(F::codegen(Statement::Move(
+                            Exp::Temp(first),
                             memory[&source[0]].clone(),
-                        ));
-                        gen.munch_statement(Statement::Move(
-                            Exp::Temp(R11),
+                        )));
+                        result.extend(F::codegen(Statement::Move(
+                            Exp::Temp(second),
                             memory[&source[1]].clone(),
-                        ));
-                        *source = vec![R10, R11];
-                        gen.emit(instruction.clone());
+                        )));
+                        *source = vec![first, second];
+                        result.push(instruction.clone());
                     } else if source.len() > 1
                         && !is_spilled::<F>(source[0])
                         && is_spilled::<F>(source[1])
                     {
-                        gen.munch_statement(Statement::Move(
-                            Exp::Temp(R11),
+                        result.extend(F::codegen(Statement::Move(
+                            Exp::Temp(second),
                             memory[&source[1]].clone(),
-                        ));
-                        *source = vec![source[0], R11];
-                        gen.emit(instruction.clone());
+                        )));
+                        *source = vec![source[0], second];
+                        result.push(instruction.clone());
                     } else if source.len() > 1
                         && is_spilled::<F>(source[0])
                         && !is_spilled::<F>(source[1])
                     {
-                        gen.munch_statement(Statement::Move(
-                            Exp::Temp(R10),
+                        result.extend(F::codegen(Statement::Move(
+                            Exp::Temp(first),
                             memory[&source[0]].clone(),
-                        ));
-                        *source = vec![R10, source[1]];
-                        gen.emit(instruction.clone());
+                        )));
+                        *source = vec![first, source[1]];
+                        result.push(instruction.clone());
                     } else {
-                        gen.munch_statement(Statement::Move(Exp::Temp(R10), memory[src].clone()));
-                        *source = vec![R10];
-                        gen.emit(instruction.clone());
+                        result.extend(F::codegen(Statement::Move(Exp::Temp(first), memory[src].clone())));
+                        *source = vec![first];
+                        result.push(instruction.clone());
                     }
                 } else {
-                    gen.emit(instruction.clone());
+                    result.push(instruction.clone());
                 }
             }
-            Instruction::Label { .. } => gen.emit(instruction.clone()),
+            Instruction::Label { .. } => result.push(instruction.clone()),
         }
     }
 
-    gen.get_result()
+    let statistics = Statistics {
+        temps: memory.len(),
+        iterations: 1,
+        spills: memory.len(),
+        ..Statistics::default()
+    };
+    (result, statistics)
 }
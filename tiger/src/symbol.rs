@@ -75,10 +75,6 @@ impl<T> Symbols<T> {
         self.table.get(&symbol).and_then(|vec| vec.last())
     }
 
-    pub fn look_mut(&mut self, symbol: Symbol) -> Option<&mut T> {
-        self.table.get_mut(&symbol).and_then(|vec| vec.last_mut())
-    }
-
     pub fn name(&self, symbol: Symbol) -> String {
         self.strings.strings.borrow()[&symbol].to_string()
     }
@@ -10,7 +10,8 @@ pub struct Temp {
 
 impl Temp {
     pub fn new() -> Self {
-        static mut COUNTER: u32 = 16;
+        // The first numbers are the machine registers of the targets.
+        static mut COUNTER: u32 = 64;
         unsafe {
             COUNTER += 1;
             Self { num: COUNTER }
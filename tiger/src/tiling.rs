@@ -0,0 +1,1182 @@
+//! 用**动态规划**求IR树的最优覆盖来选择指令(BURS)。
+//!
+//! 每个瓦片(tile)是一条规则：一个IR树模式、它归约成的非终结符、代价，以及发射指令的函数。
+//! 模式的叶子可以是非终结符，表示该子树先归约为这个非终结符。只有一个非终结符的模式是链规则，
+//! 例如`lea`把地址归约为寄存器。
+//!
+//! 选择分两步：
+//! 1. 自底向上标记：对每个节点，计算它归约成每个非终结符的最小代价以及对应的瓦片，代价是
+//!    瓦片本身的代价加上模式叶子的代价，再反复应用链规则直到代价不再减少；
+//! 2. 自顶向下归约：从根节点需要的非终结符开始，按标记的瓦片先归约模式的叶子，再发射瓦片的指令。
+//!
+//! 非终结符描述了x86-64指令的运算数：寄存器、32位立即数，以及寻址方式
+//! `[base + index*scale + displacement]`的各个部分，因此`a[i]`的读取只需一条指令，
+//! 而`x + 8`或`a + i*8`可以用`lea`计算。
+
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
+
+use asm::Instruction;
+use asm_gen::is_immediate;
+use frame::x86_64::X86_64;
+use frame::x86_64::{RAX, RCX, RDX, RSP};
+use frame::Frame;
+use ir::{BinOp, Exp, RelationalOp, Statement};
+use temp::{Label, Temp};
+
+#[derive(Clone, Copy, Debug)]
+enum Nonterminal {
+    Reg,
+    /// A constant that fits in the sign-extended 32-bit immediate of an instruction.
+    Imm,
+    /// A register scaled by 1, 2, 4 or 8.
+    Index,
+    /// An address without displacement.
+    BaseIndex,
+    Address,
+}
+
+const NONTERMINAL_COUNT: usize = 5;
+
+#[derive(Clone)]
+enum Pattern {
+    Nonterminal(Nonterminal),
+    Const(fn(i64) -> bool),
+    Temp,
+    Name,
+    /// A call, with its function and arguments in registers.
+    Call,
+    Mem(Box<Pattern>),
+    BinOp(BinOp, Box<Pattern>, Box<Pattern>),
+}
+
+use self::Nonterminal::{Address, BaseIndex, Imm, Index, Reg};
+
+fn nonterminal(nonterminal: Nonterminal) -> Pattern {
+    Pattern::Nonterminal(nonterminal)
+}
+
+fn mem(address: Pattern) -> Pattern {
+    Pattern::Mem(Box::new(address))
+}
+
+fn binop(op: BinOp, left: Pattern, right: Pattern) -> Pattern {
+    Pattern::BinOp(op, Box::new(left), Box::new(right))
+}
+
+/// The value of a subtree after its reduction.
+#[derive(Clone, Debug)]
+enum Operand {
+    Reg(Temp),
+    Imm(i64),
+    Name(Label),
+    Address(MemoryAddress),
+}
+
+impl Operand {
+    fn register(&self) -> Temp {
+        match *self {
+            Operand::Reg(temp) => temp,
+            _ => unreachable!("{:?} is not a register", self),
+        }
+    }
+
+    fn address(&self) -> MemoryAddress {
+        match *self {
+            Operand::Reg(temp) => MemoryAddress {
+                base: Some(temp),
+                ..MemoryAddress::default()
+            },
+            Operand::Imm(num) => MemoryAddress {
+                displacement: num,
+                ..MemoryAddress::default()
+            },
+            Operand::Address(ref address) => address.clone(),
+            Operand::Name(_) => unreachable!("{:?} is not an address", self),
+        }
+    }
+
+    /// The text of the operand, with its registers numbered from `first` among the sources.
+    fn to_assembly(&self, first: usize) -> (String, Vec<Temp>) {
+        match *self {
+            Operand::Reg(temp) => (format!("'s{}", first), vec![temp]),
+            Operand::Imm(num) => (num.to_string(), vec![]),
+            Operand::Name(ref label) => (label.to_string(), vec![]),
+            Operand::Address(ref address) => address.to_assembly(first),
+        }
+    }
+
+    /// Order the operands of a commutative tile: registers, then addresses, then immediates.
+    fn ordered(operands: Vec<Operand>) -> (Operand, Operand) {
+        let rank = |operand: &Operand| match *operand {
+            Operand::Reg(_) => 0,
+            Operand::Address(_) => 1,
+            Operand::Imm(_) | Operand::Name(_) => 2,
+        };
+        let mut operands = operands.into_iter();
+        let first = operands.next().expect("first operand");
+        let second = operands.next().expect("second operand");
+        if rank(&second) < rank(&first) {
+            (second, first)
+        } else {
+            (first, second)
+        }
+    }
+}
+
+/// The memory operand `[base + index*scale + displacement]`.
+#[derive(Clone, Debug, Default)]
+struct MemoryAddress {
+    base: Option<Temp>,
+    index: Option<(Temp, i64)>,
+    displacement: i64,
+}
+
+impl MemoryAddress {
+    fn to_assembly(&self, first: usize) -> (String, Vec<Temp>) {
+        let mut parts = vec![];
+        let mut source = vec![];
+        if let Some(base) = self.base {
+            parts.push(format!("'s{}", first + source.len()));
+            source.push(base);
+        }
+        if let Some((index, scale)) = self.index {
+            if scale == 1 {
+                parts.push(format!("'s{}", first + source.len()));
+            } else {
+                parts.push(format!("'s{}*{}", first + source.len(), scale));
+            }
+            source.push(index);
+        }
+        if self.displacement != 0 || parts.is_empty() {
+            parts.push(self.displacement.to_string());
+        }
+        (format!("[{}]", parts.join(" + ")), source)
+    }
+}
+
+#[derive(Clone)]
+struct Tile {
+    result: Nonterminal,
+    pattern: Pattern,
+    cost: u32,
+    emit: Emit,
+}
+
+/// Emit the instructions of a tile from the operands of the leaves of its pattern, in order.
+type Emit = fn(&mut Selector, Vec<Operand>) -> Operand;
+
+fn tile(result: Nonterminal, pattern: Pattern, cost: u32, emit: Emit) -> Tile {
+    Tile {
+        result,
+        pattern,
+        cost,
+        emit,
+    }
+}
+
+/// The tile and the same tile with the operands of its commutative operator swapped. Their emit
+/// functions order the operands with `Operand::ordered`.
+fn commutative(tile: Tile) -> Vec<Tile> {
+    let mut swapped = tile.clone();
+    if let Pattern::BinOp(_, ref mut left, ref mut right) = swapped.pattern {
+        mem::swap(left, right);
+    }
+    vec![tile, swapped]
+}
+
+fn any(_: i64) -> bool {
+    true
+}
+
+fn is_scale(num: i64) -> bool {
+    num == 1 || num == 2 || num == 4 || num == 8
+}
+
+fn is_scale_shift(num: i64) -> bool {
+    (0..=3).contains(&num)
+}
+
+fn is_shift_count(num: i64) -> bool {
+    (0..64).contains(&num)
+}
+
+/// Whether the constant can be subtracted as a displacement.
+fn is_negatable_immediate(num: i64) -> bool {
+    num != i64::MIN && is_immediate(-num)
+}
+
+fn tiles() -> Vec<Tile> {
+    let mut tiles = vec![
+        // Operands of the addressing modes.
+        tile(Imm, Pattern::Const(is_immediate), 0, |_, operands| {
+            operands[0].clone()
+        }),
+        tile(BaseIndex, nonterminal(Reg), 0, |_, operands| {
+            Operand::Address(operands[0].address())
+        }),
+        tile(Index, nonterminal(Reg), 0, |_, operands| {
+            Operand::Address(MemoryAddress {
+                index: Some((operands[0].register(), 1)),
+                ..MemoryAddress::default()
+            })
+        }),
+        tile(
+            Index,
+            binop(
+                BinOp::ShiftLeft,
+                nonterminal(Reg),
+                Pattern::Const(is_scale_shift),
+            ),
+            0,
+            |_, operands| match (&operands[0], &operands[1]) {
+                (&Operand::Reg(index), &Operand::Imm(shift)) => Operand::Address(MemoryAddress {
+                    index: Some((index, 1 << shift)),
+                    ..MemoryAddress::default()
+                }),
+                _ => unreachable!(),
+            },
+        ),
+        tile(BaseIndex, nonterminal(Index), 0, |_, operands| {
+            operands[0].clone()
+        }),
+        tile(Address, nonterminal(BaseIndex), 0, |_, operands| {
+            operands[0].clone()
+        }),
+        tile(Address, nonterminal(Imm), 0, |_, operands| {
+            Operand::Address(operands[0].address())
+        }),
+        tile(
+            Address,
+            binop(
+                BinOp::Minus,
+                nonterminal(BaseIndex),
+                Pattern::Const(is_negatable_immediate),
+            ),
+            0,
+            |_, operands| displace(&operands[0], &operands[1], -1),
+        ),
+        // Registers.
+        tile(Reg, Pattern::Temp, 0, |_, operands| operands[0].clone()),
+        tile(Reg, Pattern::Const(any), 1, |selector, operands| {
+            let (operand, _) = operands[0].to_assembly(0);
+            selector.move_to_new_temp(operand, vec![])
+        }),
+        tile(Reg, Pattern::Name, 1, |selector, operands| {
+            let (operand, _) = operands[0].to_assembly(0);
+            selector.move_to_new_temp(operand, vec![])
+        }),
+        tile(Reg, mem(nonterminal(Address)), 1, |selector, operands| {
+            let (operand, source) = operands[0].to_assembly(0);
+            selector.move_to_new_temp(operand, source)
+        }),
+        tile(Reg, nonterminal(Address), 1, |selector, operands| {
+            let temp = Temp::new();
+            selector.lea(temp, &operands[0]);
+            Operand::Reg(temp)
+        }),
+        tile(Reg, Pattern::Call, 1, |selector, operands| {
+            selector.call(operands)
+        }),
+        tile(
+            Reg,
+            binop(BinOp::Minus, nonterminal(Reg), nonterminal(Reg)),
+            2,
+            |selector, operands| selector.binary("sub", operands),
+        ),
+        tile(
+            Reg,
+            binop(BinOp::Minus, nonterminal(Reg), nonterminal(Imm)),
+            2,
+            |selector, operands| selector.binary("sub", operands),
+        ),
+        tile(
+            Reg,
+            binop(BinOp::Minus, nonterminal(Reg), mem(nonterminal(Address))),
+            2,
+            |selector, operands| selector.binary("sub", operands),
+        ),
+        tile(
+            Reg,
+            binop(BinOp::Mul, nonterminal(Reg), nonterminal(Reg)),
+            2,
+            |selector, operands| selector.binary("imul", operands),
+        ),
+        tile(
+            Reg,
+            binop(BinOp::Div, nonterminal(Reg), nonterminal(Reg)),
+            4,
+            |selector, operands| selector.divide(operands),
+        ),
+        tile(
+            Reg,
+            binop(BinOp::Div, nonterminal(Reg), mem(nonterminal(Address))),
+            4,
+            |selector, operands| selector.divide(operands),
+        ),
+    ];
+
+    tiles.extend(commutative(tile(
+        Index,
+        binop(BinOp::Mul, nonterminal(Reg), Pattern::Const(is_scale)),
+        0,
+        |_, operands| match Operand::ordered(operands) {
+            (Operand::Reg(index), Operand::Imm(scale)) => Operand::Address(MemoryAddress {
+                index: Some((index, scale)),
+                ..MemoryAddress::default()
+            }),
+            _ => unreachable!(),
+        },
+    )));
+    tiles.extend(commutative(tile(
+        BaseIndex,
+        binop(BinOp::Plus, nonterminal(Reg), nonterminal(Index)),
+        0,
+        |_, operands| match Operand::ordered(operands) {
+            (Operand::Reg(base), Operand::Address(address)) => Operand::Address(MemoryAddress {
+                base: Some(base),
+                ..address
+            }),
+            _ => unreachable!(),
+        },
+    )));
+    tiles.extend(commutative(tile(
+        Address,
+        binop(BinOp::Plus, nonterminal(BaseIndex), nonterminal(Imm)),
+        0,
+        |_, operands| {
+            let (address, displacement) = Operand::ordered(operands);
+            displace(&address, &displacement, 1)
+        },
+    )));
+    tiles.extend(commutative(tile(
+        Reg,
+        binop(BinOp::Plus, nonterminal(Reg), mem(nonterminal(Address))),
+        2,
+        |selector, operands| selector.binary("add", operands),
+    )));
+    tiles.extend(commutative(tile(
+        Reg,
+        binop(BinOp::Mul, nonterminal(Reg), nonterminal(Imm)),
+        1,
+        |selector, operands| {
+            let (left, right) = Operand::ordered(operands);
+            let temp = Temp::new();
+            let (operand, _) = right.to_assembly(0);
+            selector.emit(Instruction::Operation {
+                assembly: format!("imul 'd0, 's0, {}", operand),
+                source: vec![left.register()],
+                destination: vec![temp],
+                jump: None,
+            });
+            Operand::Reg(temp)
+        },
+    )));
+    tiles.extend(commutative(tile(
+        Reg,
+        binop(BinOp::Mul, nonterminal(Reg), mem(nonterminal(Address))),
+        2,
+        |selector, operands| selector.binary("imul", operands),
+    )));
+
+    tiles.extend(bitwise(BinOp::And, |selector, operands| {
+        selector.binary("and", operands)
+    }));
+    tiles.extend(bitwise(BinOp::Or, |selector, operands| {
+        selector.binary("or", operands)
+    }));
+    tiles.extend(bitwise(BinOp::Xor, |selector, operands| {
+        selector.binary("xor", operands)
+    }));
+    tiles.extend(shift(
+        BinOp::ShiftLeft,
+        |selector, operands| selector.binary("sal", operands),
+        |selector, operands| selector.shift("sal", operands),
+    ));
+    tiles.extend(shift(
+        BinOp::ArithmeticShiftRight,
+        |selector, operands| selector.binary("sar", operands),
+        |selector, operands| selector.shift("sar", operands),
+    ));
+    tiles.extend(shift(
+        BinOp::ShiftRight,
+        |selector, operands| selector.binary("shr", operands),
+        |selector, operands| selector.shift("shr", operands),
+    ));
+
+    tiles
+}
+
+fn bitwise(op: BinOp, emit: Emit) -> Vec<Tile> {
+    let mut tiles = vec![tile(
+        Reg,
+        binop(op.clone(), nonterminal(Reg), nonterminal(Reg)),
+        2,
+        emit,
+    )];
+    tiles.extend(commutative(tile(
+        Reg,
+        binop(op.clone(), nonterminal(Reg), nonterminal(Imm)),
+        2,
+        emit,
+    )));
+    tiles.extend(commutative(tile(
+        Reg,
+        binop(op, nonterminal(Reg), mem(nonterminal(Address))),
+        2,
+        emit,
+    )));
+    tiles
+}
+
+/// The shifts by a constant and, through `cl`, by a register.
+fn shift(op: BinOp, emit: Emit, emit_count: Emit) -> Vec<Tile> {
+    vec![
+        tile(
+            Reg,
+            binop(op.clone(), nonterminal(Reg), Pattern::Const(is_shift_count)),
+            2,
+            emit,
+        ),
+        tile(
+            Reg,
+            binop(op, nonterminal(Reg), nonterminal(Reg)),
+            3,
+            emit_count,
+        ),
+    ]
+}
+
+/// Add the constant, multiplied by `sign`, to the displacement of the address.
+fn displace(address: &Operand, displacement: &Operand, sign: i64) -> Operand {
+    match *displacement {
+        Operand::Imm(displacement) => Operand::Address(MemoryAddress {
+            displacement: sign * displacement,
+            ..address.address()
+        }),
+        _ => unreachable!(),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum StatementKind {
+    /// `Move(destination, source)`.
+    Move,
+    /// The comparison of a `CondJump`.
+    Compare,
+}
+
+/// A tile covering the root of a statement, with a pattern for each of its two expressions.
+struct StatementTile {
+    kind: StatementKind,
+    left: Pattern,
+    right: Pattern,
+    cost: u32,
+    emit: fn(&mut Selector, Vec<Operand>),
+}
+
+fn statement_tile(
+    kind: StatementKind,
+    left: Pattern,
+    right: Pattern,
+    cost: u32,
+    emit: fn(&mut Selector, Vec<Operand>),
+) -> StatementTile {
+    StatementTile {
+        kind,
+        left,
+        right,
+        cost,
+        emit,
+    }
+}
+
+fn statement_tiles() -> Vec<StatementTile> {
+    use self::StatementKind::{Compare, Move};
+    vec![
+        statement_tile(
+            Move,
+            Pattern::Temp,
+            nonterminal(Reg),
+            1,
+            |selector, operands| {
+                selector.emit(Instruction::Move {
+                    assembly: "mov 'd0, 's0".to_string(),
+                    source: vec![operands[1].register()],
+                    destination: vec![operands[0].register()],
+                });
+            },
+        ),
+        statement_tile(
+            Move,
+            Pattern::Temp,
+            Pattern::Const(any),
+            1,
+            Selector::move_to_temp,
+        ),
+        statement_tile(
+            Move,
+            Pattern::Temp,
+            mem(nonterminal(Address)),
+            1,
+            Selector::move_to_temp,
+        ),
+        statement_tile(
+            Move,
+            Pattern::Temp,
+            nonterminal(Address),
+            1,
+            |selector, operands| {
+                selector.lea(operands[0].register(), &operands[1]);
+            },
+        ),
+        statement_tile(
+            Move,
+            mem(nonterminal(Address)),
+            nonterminal(Reg),
+            1,
+            Selector::store,
+        ),
+        statement_tile(
+            Move,
+            mem(nonterminal(Address)),
+            nonterminal(Imm),
+            1,
+            Selector::store,
+        ),
+        statement_tile(
+            Compare,
+            nonterminal(Reg),
+            nonterminal(Reg),
+            1,
+            Selector::compare,
+        ),
+        statement_tile(
+            Compare,
+            nonterminal(Reg),
+            nonterminal(Imm),
+            1,
+            Selector::compare,
+        ),
+        statement_tile(
+            Compare,
+            nonterminal(Reg),
+            mem(nonterminal(Address)),
+            1,
+            Selector::compare,
+        ),
+        statement_tile(
+            Compare,
+            mem(nonterminal(Address)),
+            nonterminal(Reg),
+            1,
+            Selector::compare,
+        ),
+        statement_tile(
+            Compare,
+            mem(nonterminal(Address)),
+            nonterminal(Imm),
+            1,
+            Selector::compare,
+        ),
+    ]
+}
+
+/// The cheapest tile reducing a node to a nonterminal, with the cost of the whole subtree.
+#[derive(Clone, Copy)]
+struct Choice {
+    cost: u32,
+    tile: usize,
+}
+
+type Labels = [Option<Choice>; NONTERMINAL_COUNT];
+
+pub struct Selector {
+    instructions: Vec<Instruction>,
+    tiles: Rc<Vec<Tile>>,
+    statement_tiles: Rc<Vec<StatementTile>>,
+    /// The labels of the nodes of the current statement, by address.
+    labels: HashMap<*const Exp, Labels>,
+}
+
+/// Select the instructions of the statements.
+pub fn select(statements: &[Statement]) -> Vec<Instruction> {
+    let mut selector = Selector::new();
+    for statement in statements {
+        selector.select_statement(statement);
+    }
+    selector.instructions
+}
+
+impl Selector {
+    fn new() -> Self {
+        Self {
+            instructions: vec![],
+            tiles: Rc::new(tiles()),
+            statement_tiles: Rc::new(statement_tiles()),
+            labels: HashMap::new(),
+        }
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.instructions.push(instruction);
+    }
+
+    fn select_statement(&mut self, statement: &Statement) {
+        self.labels.clear();
+        match *statement {
+            Statement::Sequence(ref statement1, ref statement2) => {
+                self.select_statement(statement1);
+                self.select_statement(statement2);
+            }
+            Statement::Label(ref label) => {
+                self.emit(Instruction::Label {
+                    assembly: format!("{}:", label),
+                    label: label.clone(),
+                });
+            }
+            Statement::Exp(Exp::Const(_)) => (), // Nop statement.
+            Statement::Exp(ref exp) => {
+                self.label(exp);
+                self.reduce(exp, Reg);
+            }
+            Statement::Move(ref destination, ref source) => {
+                self.select_root(StatementKind::Move, destination, source);
+            }
+            // Tail call: the arguments passed on the stack are already in place, and
+            // proc_entry_exit3 releases the frame before the jump.
+            Statement::Jump(Exp::Call(box Exp::Name(ref label), ref arguments), _) => {
+                let arguments: Vec<_> = arguments
+                    .iter()
+                    .map(|argument| {
+                        self.label(argument);
+                        self.reduce(argument, Reg).register()
+                    })
+                    .collect();
+                let source = self.pass_arguments(arguments);
+                self.emit(Instruction::Operation {
+                    assembly: format!("jmp {}", label),
+                    source,
+                    destination: vec![],
+                    jump: Some(vec![]),
+                });
+            }
+            Statement::Jump(Exp::Name(ref label), ref labels) => {
+                self.emit(Instruction::Operation {
+                    assembly: format!("jmp {}", label),
+                    source: vec![],
+                    destination: vec![],
+                    jump: Some(labels.clone()),
+                });
+            }
+            Statement::Jump(ref exp, _) => panic!("Unexpected jump expression: {:?}", exp),
+            Statement::CondJump {
+                ref op,
+                ref left,
+                ref right,
+                ref false_label,
+                ref true_label,
+            } => {
+                self.select_root(StatementKind::Compare, left, right);
+                let opcode = match *op {
+                    RelationalOp::Equal => "je",
+                    RelationalOp::NotEqual => "jne",
+                    RelationalOp::LesserThan => "jl",
+                    RelationalOp::GreaterThan => "jg",
+                    RelationalOp::LesserOrEqual => "jle",
+                    RelationalOp::GreaterOrEqual => "jge",
+                    RelationalOp::UnsignedLesserThan => "jb",
+                    RelationalOp::UnsignedLesserOrEqual => "jbe",
+                    RelationalOp::UnsignedGreaterThan => "ja",
+                    RelationalOp::UnsignedGreaterOrEqual => "jae",
+                };
+                self.emit(Instruction::Operation {
+                    assembly: format!("{} {}", opcode, true_label),
+                    source: vec![],
+                    destination: vec![],
+                    jump: Some(vec![false_label.clone(), true_label.clone()]),
+                });
+            }
+        }
+    }
+
+    /// Label the expressions of the statement and emit its cheapest statement tile.
+    fn select_root(&mut self, kind: StatementKind, left: &Exp, right: &Exp) {
+        self.label(left);
+        self.label(right);
+        let tiles = Rc::clone(&self.statement_tiles);
+        let tile = tiles
+            .iter()
+            .filter(|tile| tile.kind == kind)
+            .filter_map(|tile| {
+                let cost = tile.cost
+                    + self.match_cost(&tile.left, left)?
+                    + self.match_cost(&tile.right, right)?;
+                Some((cost, tile))
+            })
+            .min_by_key(|&(cost, _)| cost)
+            .map(|(_, tile)| tile)
+            .unwrap_or_else(|| panic!("No tile covers {:?} and {:?}", left, right));
+        let mut operands = vec![];
+        self.operands(&tile.left, left, &mut operands);
+        self.operands(&tile.right, right, &mut operands);
+        (tile.emit)(self, operands);
+    }
+
+    /// Compute the labels of the expression and its subexpressions, bottom-up.
+    fn label(&mut self, exp: &Exp) {
+        match *exp {
+            Exp::Mem(ref address) => self.label(address),
+            Exp::BinOp {
+                ref left,
+                ref right,
+                ..
+            } => {
+                self.label(left);
+                self.label(right);
+            }
+            Exp::Call(ref function, ref arguments) => {
+                self.label(function);
+                for argument in arguments {
+                    self.label(argument);
+                }
+            }
+            Exp::Const(_) | Exp::Name(_) | Exp::Temp(_) | Exp::Error | Exp::ExpSequence(..) => (),
+        }
+
+        let mut labels: Labels = [None; NONTERMINAL_COUNT];
+        for (index, tile) in self.tiles.iter().enumerate() {
+            if let Pattern::Nonterminal(_) = tile.pattern {
+                continue;
+            }
+            if let Some(cost) = self.match_cost(&tile.pattern, exp) {
+                improve(&mut labels, tile.result, tile.cost + cost, index);
+            }
+        }
+        // Apply the chain rules until no cost decreases.
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (index, tile) in self.tiles.iter().enumerate() {
+                if let Pattern::Nonterminal(nonterminal) = tile.pattern {
+                    if let Some(choice) = labels[nonterminal as usize] {
+                        changed |=
+                            improve(&mut labels, tile.result, tile.cost + choice.cost, index);
+                    }
+                }
+            }
+        }
+        self.labels.insert(exp as *const Exp, labels);
+    }
+
+    fn cost(&self, exp: &Exp, nonterminal: Nonterminal) -> Option<u32> {
+        self.labels
+            .get(&(exp as *const Exp))
+            .and_then(|labels| labels[nonterminal as usize])
+            .map(|choice| choice.cost)
+    }
+
+    /// The cost of the leaves of the pattern if it matches the expression.
+    fn match_cost(&self, pattern: &Pattern, exp: &Exp) -> Option<u32> {
+        match (pattern, exp) {
+            (&Pattern::Nonterminal(nonterminal), _) => self.cost(exp, nonterminal),
+            (&Pattern::Const(predicate), &Exp::Const(num)) if predicate(num) => Some(0),
+            (&Pattern::Temp, &Exp::Temp(_)) | (&Pattern::Name, &Exp::Name(_)) => Some(0),
+            (&Pattern::Call, Exp::Call(function, arguments)) => {
+                let function_cost = match **function {
+                    Exp::Name(_) => 0,
+                    ref function => self.cost(function, Reg)?,
+                };
+                let arguments_cost: u32 = arguments
+                    .iter()
+                    .map(|argument| self.cost(argument, Reg))
+                    .sum::<Option<u32>>()?;
+                Some(function_cost + arguments_cost)
+            }
+            (Pattern::Mem(pattern), Exp::Mem(address)) => self.match_cost(pattern, address),
+            (
+                Pattern::BinOp(pattern_op, left_pattern, right_pattern),
+                Exp::BinOp { op, left, right },
+            ) if pattern_op == op => {
+                Some(self.match_cost(left_pattern, left)? + self.match_cost(right_pattern, right)?)
+            }
+            _ => None,
+        }
+    }
+
+    /// Emit the instructions reducing the expression to the nonterminal, top-down.
+    fn reduce(&mut self, exp: &Exp, nonterminal: Nonterminal) -> Operand {
+        let choice = self
+            .labels
+            .get(&(exp as *const Exp))
+            .and_then(|labels| labels[nonterminal as usize])
+            .unwrap_or_else(|| panic!("No tile covers {:?} as {:?}", exp, nonterminal));
+        let tiles = Rc::clone(&self.tiles);
+        let tile = &tiles[choice.tile];
+        let mut operands = vec![];
+        self.operands(&tile.pattern, exp, &mut operands);
+        (tile.emit)(self, operands)
+    }
+
+    /// Reduce the leaves of the pattern matching the expression.
+    fn operands(&mut self, pattern: &Pattern, exp: &Exp, operands: &mut Vec<Operand>) {
+        match (pattern, exp) {
+            (&Pattern::Nonterminal(nonterminal), _) => {
+                let operand = self.reduce(exp, nonterminal);
+                operands.push(operand);
+            }
+            (&Pattern::Const(_), &Exp::Const(num)) => operands.push(Operand::Imm(num)),
+            (&Pattern::Temp, &Exp::Temp(temp)) => operands.push(Operand::Reg(temp)),
+            (&Pattern::Name, Exp::Name(label)) => operands.push(Operand::Name(label.clone())),
+            (&Pattern::Call, Exp::Call(function, arguments)) => {
+                let function = match **function {
+                    Exp::Name(ref label) => Operand::Name(label.clone()),
+                    ref function => self.reduce(function, Reg),
+                };
+                operands.push(function);
+                for argument in arguments {
+                    let argument = self.reduce(argument, Reg);
+                    operands.push(argument);
+                }
+            }
+            (Pattern::Mem(pattern), Exp::Mem(address)) => self.operands(pattern, address, operands),
+            (Pattern::BinOp(_, left_pattern, right_pattern), Exp::BinOp { left, right, .. }) => {
+                self.operands(left_pattern, left, operands);
+                self.operands(right_pattern, right, operands);
+            }
+            _ => unreachable!("{:?} does not match its tile", exp),
+        }
+    }
+
+    /// `mov` of the operand, with its source registers, to a new temporary.
+    fn move_to_new_temp(&mut self, operand: String, source: Vec<Temp>) -> Operand {
+        let temp = Temp::new();
+        self.emit(Instruction::Move {
+            assembly: format!("mov 'd0, {}", operand),
+            source,
+            destination: vec![temp],
+        });
+        Operand::Reg(temp)
+    }
+
+    /// `mov` of a constant or a memory operand to the temporary of a `Move`.
+    fn move_to_temp(&mut self, operands: Vec<Operand>) {
+        let (operand, source) = operands[1].to_assembly(0);
+        self.emit(Instruction::Move {
+            assembly: format!("mov 'd0, {}", operand),
+            source,
+            destination: vec![operands[0].register()],
+        });
+    }
+
+    /// Load the address into the temporary, with a `mov` when it is a register.
+    fn lea(&mut self, temp: Temp, address: &Operand) {
+        let address = address.address();
+        if let MemoryAddress {
+            base: Some(base),
+            index: None,
+            displacement: 0,
+        } = address
+        {
+            self.emit(Instruction::Move {
+                assembly: "mov 'd0, 's0".to_string(),
+                source: vec![base],
+                destination: vec![temp],
+            });
+            return;
+        }
+        let (operand, source) = Operand::Address(address).to_assembly(0);
+        self.emit(Instruction::Operation {
+            assembly: format!("lea 'd0, {}", operand),
+            source,
+            destination: vec![temp],
+            jump: None,
+        });
+    }
+
+    fn store(&mut self, operands: Vec<Operand>) {
+        let (address, mut source) = operands[0].to_assembly(0);
+        let (operand, operand_source) = operands[1].to_assembly(source.len());
+        source.extend(operand_source);
+        let size = match operands[1] {
+            Operand::Imm(_) => "qword ",
+            _ => "",
+        };
+        self.emit(Instruction::Move {
+            assembly: format!("mov {}{}, {}", size, address, operand),
+            source,
+            destination: vec![],
+        });
+    }
+
+    fn compare(&mut self, operands: Vec<Operand>) {
+        let (left, mut source) = operands[0].to_assembly(0);
+        let (right, right_source) = operands[1].to_assembly(source.len());
+        source.extend(right_source);
+        let size = match (&operands[0], &operands[1]) {
+            (&Operand::Address(_), &Operand::Imm(_)) => "qword ",
+            _ => "",
+        };
+        self.emit(Instruction::Operation {
+            assembly: format!("cmp {}{}, {}", size, left, right),
+            source,
+            destination: vec![],
+            jump: None,
+        });
+    }
+
+    /// A two-address instruction on a copy of the register operand.
+    fn binary(&mut self, opcode: &str, operands: Vec<Operand>) -> Operand {
+        let (left, right) = Operand::ordered(operands);
+        let temp = Temp::new();
+        self.emit(Instruction::Move {
+            assembly: "mov 'd0, 's0".to_string(),
+            source: vec![left.register()],
+            destination: vec![temp],
+        });
+        let (operand, mut source) = right.to_assembly(0);
+        source.push(temp);
+        self.emit(Instruction::Operation {
+            assembly: format!("{} 'd0, {}", opcode, operand),
+            source,
+            destination: vec![temp],
+            jump: None,
+        });
+        Operand::Reg(temp)
+    }
+
+    /// A shift count which is not an immediate must be in cl.
+    fn shift(&mut self, opcode: &str, operands: Vec<Operand>) -> Operand {
+        let temp = Temp::new();
+        self.emit(Instruction::Move {
+            assembly: "mov 'd0, 's0".to_string(),
+            source: vec![operands[0].register()],
+            destination: vec![temp],
+        });
+        self.emit(Instruction::Move {
+            assembly: "mov 'd0, 's0".to_string(),
+            source: vec![operands[1].register()],
+            destination: vec![RCX],
+        });
+        self.emit(Instruction::Operation {
+            assembly: format!("{} 'd0, cl", opcode),
+            source: vec![RCX, temp],
+            destination: vec![temp],
+            jump: None,
+        });
+        Operand::Reg(temp)
+    }
+
+    fn divide(&mut self, operands: Vec<Operand>) -> Operand {
+        self.emit(Instruction::Move {
+            assembly: "mov 'd0, 's0".to_string(),
+            source: vec![operands[0].register()],
+            destination: vec![RAX],
+        });
+        // Sign-extend rax into rdx.
+        self.emit(Instruction::Operation {
+            assembly: "cqo".to_string(),
+            source: vec![RAX],
+            destination: vec![RDX],
+            jump: None,
+        });
+        let (divisor, mut source) = operands[1].to_assembly(0);
+        let size = match operands[1] {
+            Operand::Address(_) => "qword ",
+            _ => "",
+        };
+        source.extend(vec![RAX, RDX]);
+        self.emit(Instruction::Operation {
+            assembly: format!("idiv {}{}", size, divisor),
+            source,
+            destination: vec![RAX, RDX],
+            jump: None,
+        });
+        let temp = Temp::new();
+        self.emit(Instruction::Move {
+            assembly: "mov 'd0, 's0".to_string(),
+            source: vec![RAX],
+            destination: vec![temp],
+        });
+        Operand::Reg(temp)
+    }
+
+    fn call(&mut self, operands: Vec<Operand>) -> Operand {
+        let mut operands = operands.into_iter();
+        let function = operands.next().expect("function");
+        let arguments: Vec<_> = operands.map(|argument| argument.register()).collect();
+        let argument_count = arguments.len();
+        let mut source = vec![];
+        let (assembly, destination) = match function {
+            Operand::Name(label) => (format!("call {}", label), X86_64::calldefs_of(&label)),
+            function => {
+                source.push(function.register());
+                ("call 's0".to_string(), X86_64::calldefs())
+            }
+        };
+        source.extend(self.pass_arguments(arguments));
+        self.emit(Instruction::Operation {
+            assembly,
+            source,
+            destination,
+            jump: None,
+        });
+        let temp = Temp::new();
+        self.emit(Instruction::Move {
+            assembly: "mov 'd0, 's0".to_string(),
+            source: vec![RAX],
+            destination: vec![temp],
+        });
+        let stack_arguments_size = X86_64::stack_arguments_size(argument_count);
+        if stack_arguments_size != 0 {
+            self.emit(Instruction::Operation {
+                assembly: format!("add 'd0, {}", stack_arguments_size),
+                source: vec![RSP],
+                destination: vec![RSP],
+                jump: None,
+            });
+        }
+        Operand::Reg(temp)
+    }
+
+    /// Move the arguments to the argument registers and push the others, and return the argument
+    /// registers used.
+    fn pass_arguments(&mut self, arguments: Vec<Temp>) -> Vec<Temp> {
+        let registers: Vec<_> = X86_64::arg_registers()
+            .into_iter()
+            .zip(&arguments)
+            .map(|(register, &argument)| {
+                self.emit(Instruction::Move {
+                    assembly: "mov 'd0, 's0".to_string(),
+                    source: vec![argument],
+                    destination: vec![register],
+                });
+                register
+            })
+            .collect();
+        // The padding keeps the stack aligned on 16 bytes at the call.
+        let stack_arguments = &arguments[registers.len()..];
+        let padding = X86_64::stack_arguments_size(arguments.len())
+            - stack_arguments.len() as i64 * X86_64::WORD_SIZE;
+        if padding != 0 {
+            self.emit(Instruction::Operation {
+                assembly: format!("sub 'd0, {}", padding),
+                source: vec![RSP],
+                destination: vec![RSP],
+                jump: None,
+            });
+        }
+        // Arguments are pushed backwards.
+        for &argument in stack_arguments.iter().rev() {
+            self.emit(Instruction::Operation {
+                assembly: "push 's0".to_string(),
+                source: vec![argument, RSP],
+                destination: vec![RSP],
+                jump: None,
+            });
+        }
+        registers
+    }
+}
+
+/// Keep the choice if it is cheaper than the current one for the nonterminal.
+fn improve(labels: &mut Labels, nonterminal: Nonterminal, cost: u32, tile: usize) -> bool {
+    match labels[nonterminal as usize] {
+        Some(choice) if choice.cost <= cost => false,
+        _ => {
+            labels[nonterminal as usize] = Some(Choice { cost, tile });
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::select;
+    use asm::Instruction;
+    use ir::BinOp::{Mul, Plus};
+    use ir::{BinOp, Exp, RelationalOp, Statement};
+    use temp::{Label, Temp};
+
+    fn binop(op: BinOp, left: Exp, right: Exp) -> Exp {
+        Exp::BinOp {
+            op,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    fn mem(address: Exp) -> Exp {
+        Exp::Mem(Box::new(address))
+    }
+
+    fn assembly(statement: Statement) -> Vec<String> {
+        select(&[statement])
+            .into_iter()
+            .map(|instruction| match instruction {
+                Instruction::Label { assembly, .. }
+                | Instruction::Move { assembly, .. }
+                | Instruction::Operation { assembly, .. } => assembly,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_select() {
+        let temp = Exp::Temp(Temp::new());
+        let array = Exp::Temp(Temp::new());
+        let index = Exp::Temp(Temp::new());
+
+        let element = mem(binop(
+            Plus,
+            binop(
+                Plus,
+                array.clone(),
+                binop(Mul, index.clone(), Exp::Const(8)),
+            ),
+            Exp::Const(16),
+        ));
+        assert_eq!(
+            assembly(Statement::Move(temp.clone(), element)),
+            vec!["mov 'd0, ['s0 + 's1*8 + 16]"]
+        );
+
+        assert_eq!(
+            assembly(Statement::Move(
+                temp.clone(),
+                binop(Plus, Exp::Const(8), index.clone())
+            )),
+            vec!["lea 'd0, ['s0 + 8]"]
+        );
+
+        assert_eq!(
+            assembly(Statement::Move(
+                temp.clone(),
+                binop(Mul, index.clone(), Exp::Const(10))
+            )),
+            vec!["imul 'd0, 's0, 10", "mov 'd0, 's0"]
+        );
+
+        let label = Label::new();
+        assert_eq!(
+            assembly(Statement::CondJump {
+                op: RelationalOp::Equal,
+                left: mem(binop(Plus, array.clone(), Exp::Const(-8))),
+                right: Exp::Const(0),
+                true_label: label.clone(),
+                false_label: label.clone(),
+            }),
+            vec![
+                "cmp qword ['s0 + -8], 0".to_string(),
+                format!("je {}", label)
+            ]
+        );
+
+        // A constant which does not fit in 32 bits is moved to a register first.
+        assert_eq!(
+            assembly(Statement::Move(mem(array), Exp::Const(5_000_000_000))),
+            vec!["mov 'd0, 5000000000", "mov ['s0], 's1"]
+        );
+    }
+}
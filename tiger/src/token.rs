@@ -7,7 +7,9 @@ use position::Pos;
 pub enum Tok {
     Ampersand,
     Array,
+    Arrow,
     Break,
+    Caret,
     CloseCurly,
     CloseParen,
     CloseSquare,
@@ -19,10 +21,14 @@ pub enum Tok {
     Else,
     End,
     Equal,
+    Exception,
+    Export,
+    Extern,
     For,
     Function,
     Greater,
     GreaterOrEqual,
+    Handle,
     Ident(String),
     If,
     In,
@@ -32,20 +38,27 @@ pub enum Tok {
     Let,
     Minus,
     Nil,
+    Not,
     NotEqual,
     Of,
     OpenCurly,
     OpenParen,
     OpenSquare,
+    Percent,
     Pipe,
     Plus,
+    Raise,
     Semicolon,
+    ShiftLeft,
+    ShiftRight,
     Slash,
     Star,
     Str(String),
     Then,
     To,
+    Try,
     Type,
+    UnsignedShiftRight,
     Var,
     While,
 }
@@ -62,7 +75,9 @@ impl Display for Tok {
             let string = match *self {
                 Ampersand => "&",
                 Array => "array",
+                Arrow => "=>",
                 Break => "break",
+                Caret => "^",
                 CloseCurly => "}",
                 CloseParen => ")",
                 CloseSquare => "]",
@@ -74,10 +89,14 @@ impl Display for Tok {
                 Else => "else",
                 Equal => "=",
                 End => "end",
+                Exception => "exception",
+                Export => "export",
+                Extern => "extern",
                 For => "for",
                 Function => "function",
                 Greater => ">",
                 GreaterOrEqual => ">=",
+                Handle => "handle",
                 Ident(ref ident) => ident,
                 If => "if",
                 In => "in",
@@ -87,20 +106,27 @@ impl Display for Tok {
                 Let => "let",
                 Minus => "-",
                 Nil => "nil",
+                Not => "not",
                 NotEqual => "<>",
                 Of => "of",
                 OpenCurly => "{",
                 OpenParen => "(",
                 OpenSquare => "[",
+                Percent => "%",
                 Pipe => "|",
                 Plus => "+",
+                Raise => "raise",
                 Semicolon => ";",
+                ShiftLeft => "<<",
+                ShiftRight => ">>",
                 Slash => "/",
                 Star => "*",
                 Str(ref string) => return format!("{:?}", string),
                 Then => "then",
                 To => "to",
+                Try => "try",
                 Type => "type",
+                UnsignedShiftRight => ">>>",
                 Var => "var",
                 While => "while",
             };
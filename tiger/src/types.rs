@@ -10,6 +10,8 @@ pub enum Type {
     Nil,
     Unit,
     Name(SymbolWithPos, Option<Box<Type>>),
+    /// The type of `raise`, which has no value: it is accepted where any type is expected.
+    Bottom,
     Error,
 }
 
@@ -31,6 +33,7 @@ impl Type {
             Record(name, _, _) => format!("struct {}", symbols.name(name)),
             String => "string".to_string(),
             Unit => "()".to_string(),
+            Bottom => "bottom".to_string(),
             Error => "type error".to_string(),
         }
     }
@@ -0,0 +1,569 @@
+//! **WebAssembly 模块**：函数的指令，线性内存中的数据段，以及从宿主导入的运行时函数。同一个
+//! 模块写成文本格式(`.wat`)和二进制格式(`.wasm`)。
+//!
+//! 所有的值都是`i64`：整数、字符串和记录的地址都是64位的，和其他目标一样，只在访问内存之前用
+//! `i32.wrap_i64`转换成32位的地址。每个函数返回一个`i64`，没有返回值的函数返回0。运行时抛出的
+//! 异常是标签(tag)`$exception`的WebAssembly异常，它的值是处理器的编号（见`wasm_gen`）。
+//!
+//! 线性内存的开头是影子栈，向下增长，所以栈溢出时访问负地址而陷入(trap)，不会覆盖数据；之后是
+//! 字符串，然后是宿主分配的堆，从导出的全局变量`heap`开始。
+
+use std::collections::HashMap;
+
+use frame::wasm32::Wasm32;
+use temp::{Label, Temp};
+
+/// The size of the shadow stack, at the start of the linear memory, as the stack of a native
+/// program.
+pub const STACK_SIZE: i64 = 8 << 20;
+
+const PAGE_SIZE: i64 = 1 << 16;
+
+/// The global holding the stack pointer of the shadow stack.
+pub const STACK_POINTER: u32 = 0;
+
+#[derive(Clone, Debug)]
+pub enum Instruction {
+    Block,
+    Loop,
+    If,
+    Try,
+    /// Start of the handler of the exceptions of the runtime, with the exception on the stack.
+    Catch,
+    End,
+    Br(u32),
+    BrIf(u32),
+    BrTable(Vec<u32>, u32),
+    Call(Label),
+    ReturnCall(Label),
+    Rethrow(u32),
+    Drop,
+    Unreachable,
+    LocalGet(Temp),
+    LocalSet(Temp),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    I32WrapI64,
+    I64Const(i64),
+    /// Load from the address on the stack plus the offset.
+    I64Load(u32),
+    I64Store(u32),
+    Numeric(Numeric),
+}
+
+/// The arithmetic and comparison instructions on two `i64`.
+#[derive(Clone, Copy, Debug)]
+pub enum Numeric {
+    Eq,
+    Ne,
+    LtS,
+    LtU,
+    GtS,
+    GtU,
+    LeS,
+    LeU,
+    GeS,
+    GeU,
+    Add,
+    Sub,
+    Mul,
+    DivS,
+    And,
+    Or,
+    Xor,
+    Shl,
+    ShrS,
+    ShrU,
+}
+
+impl Numeric {
+    fn name(self) -> &'static str {
+        match self {
+            Numeric::Eq => "i64.eq",
+            Numeric::Ne => "i64.ne",
+            Numeric::LtS => "i64.lt_s",
+            Numeric::LtU => "i64.lt_u",
+            Numeric::GtS => "i64.gt_s",
+            Numeric::GtU => "i64.gt_u",
+            Numeric::LeS => "i64.le_s",
+            Numeric::LeU => "i64.le_u",
+            Numeric::GeS => "i64.ge_s",
+            Numeric::GeU => "i64.ge_u",
+            Numeric::Add => "i64.add",
+            Numeric::Sub => "i64.sub",
+            Numeric::Mul => "i64.mul",
+            Numeric::DivS => "i64.div_s",
+            Numeric::And => "i64.and",
+            Numeric::Or => "i64.or",
+            Numeric::Xor => "i64.xor",
+            Numeric::Shl => "i64.shl",
+            Numeric::ShrS => "i64.shr_s",
+            Numeric::ShrU => "i64.shr_u",
+        }
+    }
+
+    fn opcode(self) -> u8 {
+        match self {
+            Numeric::Eq => 0x51,
+            Numeric::Ne => 0x52,
+            Numeric::LtS => 0x53,
+            Numeric::LtU => 0x54,
+            Numeric::GtS => 0x55,
+            Numeric::GtU => 0x56,
+            Numeric::LeS => 0x57,
+            Numeric::LeU => 0x58,
+            Numeric::GeS => 0x59,
+            Numeric::GeU => 0x5A,
+            Numeric::Add => 0x7C,
+            Numeric::Sub => 0x7D,
+            Numeric::Mul => 0x7E,
+            Numeric::DivS => 0x7F,
+            Numeric::And => 0x83,
+            Numeric::Or => 0x84,
+            Numeric::Xor => 0x85,
+            Numeric::Shl => 0x86,
+            Numeric::ShrS => 0x87,
+            Numeric::ShrU => 0x88,
+        }
+    }
+}
+
+pub struct Function {
+    pub name: Label,
+    pub parameters: Vec<Temp>,
+    pub body: Vec<Instruction>,
+}
+
+impl Function {
+    /// The locals other than the parameters, in the order of their first use.
+    fn locals(&self) -> Vec<Temp> {
+        let mut locals = vec![];
+        for instruction in &self.body {
+            match *instruction {
+                Instruction::LocalGet(temp) | Instruction::LocalSet(temp)
+                    if !self.parameters.contains(&temp) && !locals.contains(&temp) =>
+                {
+                    locals.push(temp);
+                }
+                _ => (),
+            }
+        }
+        locals
+    }
+}
+
+pub struct Module {
+    /// The functions of the runtime and the external functions, with their number of parameters.
+    pub imports: Vec<(Label, usize)>,
+    pub functions: Vec<Function>,
+    pub exports: Vec<String>,
+    /// The addresses of the exceptions raised by the runtime, exported as globals.
+    pub exceptions: Vec<(Label, i64)>,
+    /// The content of the memory after the shadow stack.
+    pub data: Vec<u8>,
+}
+
+impl Module {
+    /// The start of the heap, after the data.
+    fn heap(&self) -> i64 {
+        (STACK_SIZE + self.data.len() as i64 + 7) & !7
+    }
+
+    fn global_names(&self) -> Vec<String> {
+        let mut names = vec!["sp".to_string(), "heap".to_string()];
+        names.extend(self.exceptions.iter().map(|(label, _)| label.to_string()));
+        names
+    }
+
+    /// The constant globals: the start of the heap and the addresses of the exceptions.
+    fn constants(&self) -> Vec<(String, i64)> {
+        let mut constants = vec![("heap".to_string(), self.heap())];
+        constants.extend(
+            self.exceptions
+                .iter()
+                .map(|&(ref label, address)| (label.to_string(), address)),
+        );
+        constants
+    }
+
+    pub fn to_wat(&self) -> String {
+        let globals = self.global_names();
+        let mut wat = "(module\n".to_string();
+        for &(ref name, parameter_count) in &self.imports {
+            wat.push_str(&format!(
+                "  (import \"env\" \"{}\" (func ${}{} (result i64)))\n",
+                name,
+                name,
+                " (param i64)".repeat(parameter_count)
+            ));
+        }
+        wat.push_str(&format!(
+            "  (memory (export \"memory\") {})\n",
+            self.memory_pages()
+        ));
+        wat.push_str("  (tag $exception (export \"exception\") (param i64))\n");
+        wat.push_str(&format!(
+            "  (global $sp (mut i64) (i64.const {}))\n",
+            STACK_SIZE
+        ));
+        for (name, value) in self.constants() {
+            wat.push_str(&format!(
+                "  (global ${} (export \"{}\") i64 (i64.const {}))\n",
+                name, name, value
+            ));
+        }
+        for function in &self.functions {
+            wat.push_str(&format!("  (func ${}", function.name));
+            let name = function.name.to_string();
+            if self.exports.contains(&name) {
+                wat.push_str(&format!(" (export \"{}\")", name));
+            }
+            for parameter in &function.parameters {
+                wat.push_str(&format!(
+                    " (param ${} i64)",
+                    parameter.to_string::<Wasm32>()
+                ));
+            }
+            wat.push_str(" (result i64)\n");
+            for local in function.locals() {
+                wat.push_str(&format!(
+                    "    (local ${} i64)\n",
+                    local.to_string::<Wasm32>()
+                ));
+            }
+            let mut depth = 2;
+            for instruction in &function.body {
+                if let Instruction::End | Instruction::Catch = *instruction {
+                    depth -= 1;
+                }
+                wat.push_str(&"  ".repeat(depth));
+                wat.push_str(&instruction_to_wat(instruction, &globals));
+                wat.push('\n');
+                match *instruction {
+                    Instruction::Block
+                    | Instruction::Loop
+                    | Instruction::If
+                    | Instruction::Try
+                    | Instruction::Catch => depth += 1,
+                    _ => (),
+                }
+            }
+            wat.push_str("  )\n");
+        }
+        wat.push_str(&format!(
+            "  (data (i32.const {}) \"{}\")\n",
+            STACK_SIZE,
+            to_wat_string(&self.data)
+        ));
+        wat.push_str(")\n");
+        wat
+    }
+
+    pub fn to_binary(&self) -> Vec<u8> {
+        // The type 0 is the one of the exception tag, the type n + 1 the one of the functions with
+        // n parameters.
+        let max_parameters = self
+            .imports
+            .iter()
+            .map(|&(_, parameter_count)| parameter_count)
+            .chain(
+                self.functions
+                    .iter()
+                    .map(|function| function.parameters.len()),
+            )
+            .max()
+            .unwrap_or(0);
+        let mut types = vec![vec![0x60, 1, 0x7E, 0]];
+        for parameter_count in 0..=max_parameters {
+            let mut function_type = vec![0x60];
+            unsigned(&mut function_type, parameter_count as u64);
+            function_type.extend(vec![0x7E; parameter_count]);
+            function_type.extend(&[1, 0x7E]);
+            types.push(function_type);
+        }
+
+        let mut function_indices = HashMap::new();
+        for (index, (name, _)) in self.imports.iter().enumerate() {
+            function_indices.insert(name.clone(), index as u32);
+        }
+        for (index, function) in self.functions.iter().enumerate() {
+            function_indices.insert(function.name.clone(), (self.imports.len() + index) as u32);
+        }
+
+        let mut binary = b"\0asm\x01\0\0\0".to_vec();
+        section(&mut binary, 1, &types);
+        let imports: Vec<_> = self
+            .imports
+            .iter()
+            .map(|&(ref name, parameter_count)| {
+                let mut import = vec![];
+                name_bytes(&mut import, "env");
+                name_bytes(&mut import, &name.to_string());
+                import.push(0);
+                unsigned(&mut import, parameter_count as u64 + 1);
+                import
+            })
+            .collect();
+        section(&mut binary, 2, &imports);
+        let functions: Vec<_> = self
+            .functions
+            .iter()
+            .map(|function| {
+                let mut type_index = vec![];
+                unsigned(&mut type_index, function.parameters.len() as u64 + 1);
+                type_index
+            })
+            .collect();
+        section(&mut binary, 3, &functions);
+        let mut memory = vec![0];
+        unsigned(&mut memory, self.memory_pages() as u64);
+        section(&mut binary, 5, &[memory]);
+        section(&mut binary, 13, &[vec![0, 0]]);
+        let mut globals = vec![global(true, STACK_SIZE)];
+        globals.extend(
+            self.constants()
+                .into_iter()
+                .map(|(_, value)| global(false, value)),
+        );
+        section(&mut binary, 6, &globals);
+
+        let mut exports = vec![];
+        exports.push(export("memory", 2, 0));
+        exports.push(export("exception", 4, 0));
+        for (index, (name, _)) in self.constants().into_iter().enumerate() {
+            exports.push(export(&name, 3, index as u32 + 1));
+        }
+        for function in &self.functions {
+            let name = function.name.to_string();
+            if self.exports.contains(&name) {
+                exports.push(export(&name, 0, function_indices[&function.name]));
+            }
+        }
+        section(&mut binary, 7, &exports);
+
+        let codes: Vec<_> = self
+            .functions
+            .iter()
+            .map(|function| {
+                let locals = function.locals();
+                let mut local_indices = HashMap::new();
+                for (index, &temp) in function.parameters.iter().chain(&locals).enumerate() {
+                    local_indices.insert(temp, index as u32);
+                }
+                let mut body = vec![];
+                if locals.is_empty() {
+                    body.push(0);
+                } else {
+                    body.push(1);
+                    unsigned(&mut body, locals.len() as u64);
+                    body.push(0x7E);
+                }
+                for instruction in &function.body {
+                    encode(&mut body, instruction, &function_indices, &local_indices);
+                }
+                body.push(0x0B);
+                let mut code = vec![];
+                unsigned(&mut code, body.len() as u64);
+                code.extend(body);
+                code
+            })
+            .collect();
+        section(&mut binary, 10, &codes);
+
+        let mut data = vec![0, 0x41];
+        signed(&mut data, STACK_SIZE);
+        data.push(0x0B);
+        unsigned(&mut data, self.data.len() as u64);
+        data.extend(&self.data);
+        section(&mut binary, 11, &[data]);
+        binary
+    }
+
+    /// The pages of the stack and the data, and a first page of heap.
+    fn memory_pages(&self) -> i64 {
+        self.heap() / PAGE_SIZE + 1
+    }
+}
+
+fn instruction_to_wat(instruction: &Instruction, globals: &[String]) -> String {
+    match *instruction {
+        Instruction::Block => "block".to_string(),
+        Instruction::Loop => "loop".to_string(),
+        Instruction::If => "if".to_string(),
+        Instruction::Try => "try".to_string(),
+        Instruction::Catch => "catch $exception".to_string(),
+        Instruction::End => "end".to_string(),
+        Instruction::Br(depth) => format!("br {}", depth),
+        Instruction::BrIf(depth) => format!("br_if {}", depth),
+        Instruction::BrTable(ref depths, default) => {
+            let depths: Vec<_> = depths.iter().map(ToString::to_string).collect();
+            format!("br_table {} {}", depths.join(" "), default)
+        }
+        Instruction::Call(ref function) => format!("call ${}", function),
+        Instruction::ReturnCall(ref function) => format!("return_call ${}", function),
+        Instruction::Rethrow(depth) => format!("rethrow {}", depth),
+        Instruction::Drop => "drop".to_string(),
+        Instruction::Unreachable => "unreachable".to_string(),
+        Instruction::LocalGet(temp) => format!("local.get ${}", temp.to_string::<Wasm32>()),
+        Instruction::LocalSet(temp) => format!("local.set ${}", temp.to_string::<Wasm32>()),
+        Instruction::GlobalGet(index) => format!("global.get ${}", globals[index as usize]),
+        Instruction::GlobalSet(index) => format!("global.set ${}", globals[index as usize]),
+        Instruction::I32WrapI64 => "i32.wrap_i64".to_string(),
+        Instruction::I64Const(value) => format!("i64.const {}", value),
+        Instruction::I64Load(0) => "i64.load".to_string(),
+        Instruction::I64Load(offset) => format!("i64.load offset={}", offset),
+        Instruction::I64Store(0) => "i64.store".to_string(),
+        Instruction::I64Store(offset) => format!("i64.store offset={}", offset),
+        Instruction::Numeric(numeric) => numeric.name().to_string(),
+    }
+}
+
+fn encode(
+    code: &mut Vec<u8>,
+    instruction: &Instruction,
+    functions: &HashMap<Label, u32>,
+    locals: &HashMap<Temp, u32>,
+) {
+    // The blocks have no parameters nor results.
+    const EMPTY: u8 = 0x40;
+    match *instruction {
+        Instruction::Block => code.extend(&[0x02, EMPTY]),
+        Instruction::Loop => code.extend(&[0x03, EMPTY]),
+        Instruction::If => code.extend(&[0x04, EMPTY]),
+        Instruction::Try => code.extend(&[0x06, EMPTY]),
+        Instruction::Catch => code.extend(&[0x07, 0]),
+        Instruction::End => code.push(0x0B),
+        Instruction::Br(depth) => {
+            code.push(0x0C);
+            unsigned(code, depth as u64);
+        }
+        Instruction::BrIf(depth) => {
+            code.push(0x0D);
+            unsigned(code, depth as u64);
+        }
+        Instruction::BrTable(ref depths, default) => {
+            code.push(0x0E);
+            unsigned(code, depths.len() as u64);
+            for &depth in depths {
+                unsigned(code, depth as u64);
+            }
+            unsigned(code, default as u64);
+        }
+        Instruction::Call(ref function) => {
+            code.push(0x10);
+            unsigned(code, functions[function] as u64);
+        }
+        Instruction::ReturnCall(ref function) => {
+            code.push(0x12);
+            unsigned(code, functions[function] as u64);
+        }
+        Instruction::Rethrow(depth) => {
+            code.push(0x09);
+            unsigned(code, depth as u64);
+        }
+        Instruction::Drop => code.push(0x1A),
+        Instruction::Unreachable => code.push(0x00),
+        Instruction::LocalGet(temp) => {
+            code.push(0x20);
+            unsigned(code, locals[&temp] as u64);
+        }
+        Instruction::LocalSet(temp) => {
+            code.push(0x21);
+            unsigned(code, locals[&temp] as u64);
+        }
+        Instruction::GlobalGet(index) => {
+            code.push(0x23);
+            unsigned(code, index as u64);
+        }
+        Instruction::GlobalSet(index) => {
+            code.push(0x24);
+            unsigned(code, index as u64);
+        }
+        Instruction::I32WrapI64 => code.push(0xA7),
+        Instruction::I64Const(value) => {
+            code.push(0x42);
+            signed(code, value);
+        }
+        // The alignment is 2^3 bytes.
+        Instruction::I64Load(offset) => {
+            code.extend(&[0x29, 3]);
+            unsigned(code, offset as u64);
+        }
+        Instruction::I64Store(offset) => {
+            code.extend(&[0x37, 3]);
+            unsigned(code, offset as u64);
+        }
+        Instruction::Numeric(numeric) => code.push(numeric.opcode()),
+    }
+}
+
+/// A section is its id, followed by the size and the number of its entries.
+fn section(binary: &mut Vec<u8>, id: u8, entries: &[Vec<u8>]) {
+    let mut content = vec![];
+    unsigned(&mut content, entries.len() as u64);
+    for entry in entries {
+        content.extend(entry);
+    }
+    binary.push(id);
+    unsigned(binary, content.len() as u64);
+    binary.extend(content);
+}
+
+fn global(mutable: bool, value: i64) -> Vec<u8> {
+    let mut global = vec![0x7E, mutable as u8, 0x42];
+    signed(&mut global, value);
+    global.push(0x0B);
+    global
+}
+
+fn export(name: &str, kind: u8, index: u32) -> Vec<u8> {
+    let mut export = vec![];
+    name_bytes(&mut export, name);
+    export.push(kind);
+    unsigned(&mut export, index as u64);
+    export
+}
+
+fn name_bytes(bytes: &mut Vec<u8>, name: &str) {
+    unsigned(bytes, name.len() as u64);
+    bytes.extend(name.as_bytes());
+}
+
+/// LEB128 encoding of an unsigned integer.
+fn unsigned(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// LEB128 encoding of a signed integer: the last byte has the sign in its bit 6.
+fn signed(bytes: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Printable ASCII characters are kept, the other bytes are written as hexadecimal escapes.
+fn to_wat_string(bytes: &[u8]) -> String {
+    let mut string = String::new();
+    for &byte in bytes {
+        if (b' '..=b'~').contains(&byte) && byte != b'"' && byte != b'\\' {
+            string.push(byte as char);
+        } else {
+            string.push_str(&format!("\\{:02x}", byte));
+        }
+    }
+    string
+}
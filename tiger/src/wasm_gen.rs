@@ -0,0 +1,507 @@
+//! WebAssembly 的**代码生成**：把轨迹排列(`canon::trace_schedule`)之后的规范IR直接翻译成栈式的
+//! WebAssembly指令。临时变量就是函数的局部变量，所以不需要指令选择和寄存器分配。
+//!
+//! WebAssembly只有结构化的控制流。基本块按轨迹的顺序排列，第k个基本块放在第k个嵌套的`block`的
+//! `end`之后：向前的跳转是跳出若干层`block`的`br`，条件跳转的假标号紧跟在后面，直接往下执行。
+//! 向后的跳转（循环）把目标基本块的编号写入一个局部变量，跳回包围所有基本块的`loop`的开头，由
+//! `br_table`分派到目标基本块。
+//!
+//! 异常处理器由`_setjmp`安装（见`gen::try_expression`）：`_setjmp`记录处理器的编号，返回0，之后的
+//! 语句开始一个新的基本块。这样的函数把所有基本块包在`try`中，`catch`把异常的值（处理器的编号）
+//! 和每个`_setjmp`记录的编号比较：相等时释放被调用函数的栈帧，让`_setjmp`的结果为1，并分派到它
+//! 之后的基本块；都不相等时重新抛出异常。局部变量在异常之后保持抛出时的值。
+
+use std::collections::{HashMap, HashSet};
+
+use env;
+use frame::wasm32::{Wasm32, FP};
+use frame::Frame;
+use ir::{BinOp, Exp, RelationalOp, Statement};
+use temp::{Label, Temp};
+use wasm::{Function, Instruction, Module, Numeric, STACK_POINTER, STACK_SIZE};
+
+pub struct Gen {
+    /// The addresses of the strings in the linear memory.
+    addresses: HashMap<Label, i64>,
+    data: Vec<u8>,
+    exceptions: Vec<(Label, i64)>,
+    /// The functions defined in the module: the other ones are imported from the host.
+    defined_functions: HashSet<Label>,
+    functions: Vec<Function>,
+    imports: Vec<(Label, usize)>,
+}
+
+impl Gen {
+    pub fn new(strings: &[(&Label, &str)], defined_functions: HashSet<Label>) -> Self {
+        let mut generator = Self {
+            addresses: HashMap::new(),
+            data: vec![],
+            exceptions: vec![],
+            defined_functions,
+            functions: vec![],
+            imports: vec![],
+        };
+        for &(label, string) in strings {
+            generator.add_string(label.clone(), string);
+        }
+        // The exceptions raised by the runtime are defined in the module, which exports them for
+        // the host.
+        let mut exceptions: Vec<_> = env::external_exceptions().into_iter().collect();
+        exceptions.sort_by_key(|&(_, label)| label);
+        for (name, label) in exceptions {
+            let label = Label::with_name(label);
+            let address = generator.add_string(label.clone(), name);
+            generator.exceptions.push((label, address));
+        }
+        generator
+    }
+
+    fn add_string(&mut self, label: Label, string: &str) -> i64 {
+        let address = STACK_SIZE + self.data.len() as i64;
+        self.data.extend(string.as_bytes());
+        self.data.push(0);
+        self.addresses.insert(label, address);
+        address
+    }
+
+    pub fn function(&mut self, frame: &Wasm32, statements: Vec<Statement>) {
+        let setjmp_result = Temp::new();
+        let mut setjmps = vec![];
+        let (blocks, labels) = split_blocks(statements, setjmp_result, &mut setjmps);
+        let has_backward_jump = blocks.iter().enumerate().any(|(index, block)| {
+            block
+                .iter()
+                .flat_map(jump_targets)
+                .any(|label| labels[label] <= index)
+        });
+        let mut generator = FunctionGen {
+            block_count: blocks.len(),
+            current_block: 0,
+            frame_size: frame.frame_size(),
+            has_try: !setjmps.is_empty(),
+            instructions: vec![],
+            labels,
+            module: self,
+            nesting: 0,
+            pc: Temp::new(),
+        };
+        let dispatch = has_backward_jump || generator.has_try;
+
+        // The frame pointer is the stack pointer at the entry.
+        generator.emit(Instruction::GlobalGet(STACK_POINTER));
+        generator.emit(Instruction::LocalSet(FP));
+        if generator.frame_size != 0 {
+            generator.reset_stack_pointer();
+        }
+        if dispatch {
+            generator.emit(Instruction::Loop);
+            if generator.has_try {
+                generator.emit(Instruction::Try);
+            }
+        }
+        let wrapped_blocks = if dispatch {
+            blocks.len()
+        } else {
+            blocks.len() - 1
+        };
+        for _ in 0..wrapped_blocks {
+            generator.emit(Instruction::Block);
+        }
+        if dispatch {
+            let pc = generator.pc;
+            generator.emit(Instruction::LocalGet(pc));
+            generator.emit(Instruction::I32WrapI64);
+            let depths = (0..blocks.len() as u32).collect();
+            generator.emit(Instruction::BrTable(depths, blocks.len() as u32 - 1));
+        }
+        for (index, block) in blocks.into_iter().enumerate() {
+            if dispatch || index > 0 {
+                generator.emit(Instruction::End);
+            }
+            generator.current_block = index;
+            for statement in block {
+                generator.statement(statement);
+            }
+        }
+        if generator.has_try {
+            generator.catch(&setjmps, setjmp_result);
+        }
+        if dispatch {
+            generator.emit(Instruction::End);
+        }
+        if generator.frame_size != 0 {
+            generator.emit(Instruction::LocalGet(FP));
+            generator.emit(Instruction::GlobalSet(STACK_POINTER));
+        }
+        generator.emit(Instruction::LocalGet(Wasm32::return_value()));
+
+        let body = generator.instructions;
+        self.functions.push(Function {
+            name: frame.name(),
+            parameters: frame.parameters(),
+            body,
+        });
+    }
+
+    pub fn module(self, exports: Vec<String>) -> Module {
+        Module {
+            imports: self.imports,
+            functions: self.functions,
+            exports,
+            exceptions: self.exceptions,
+            data: self.data,
+        }
+    }
+
+    fn import(&mut self, function: &Label, parameter_count: usize) {
+        if !self.imports.iter().any(|(name, _)| name == function) {
+            self.imports.push((function.clone(), parameter_count));
+        }
+    }
+}
+
+struct FunctionGen<'a> {
+    block_count: usize,
+    current_block: usize,
+    frame_size: i64,
+    has_try: bool,
+    instructions: Vec<Instruction>,
+    /// The index of the block of each label.
+    labels: HashMap<Label, usize>,
+    module: &'a mut Gen,
+    /// The number of `if` around the current instruction.
+    nesting: u32,
+    /// The index of the block where the `loop` dispatches.
+    pc: Temp,
+}
+
+impl<'a> FunctionGen<'a> {
+    fn emit(&mut self, instruction: Instruction) {
+        self.instructions.push(instruction);
+    }
+
+    /// Set the stack pointer at the bottom of the frame: this allocates the frame at the entry, and
+    /// releases the frames of the callees after an exception.
+    fn reset_stack_pointer(&mut self) {
+        self.emit(Instruction::LocalGet(FP));
+        self.emit(Instruction::I64Const(self.frame_size));
+        self.emit(Instruction::Numeric(Numeric::Sub));
+        self.emit(Instruction::GlobalSet(STACK_POINTER));
+    }
+
+    fn add_to_stack_pointer(&mut self, size: i64) {
+        self.emit(Instruction::GlobalGet(STACK_POINTER));
+        self.emit(Instruction::I64Const(size));
+        self.emit(Instruction::Numeric(Numeric::Add));
+        self.emit(Instruction::GlobalSet(STACK_POINTER));
+    }
+
+    /// The depth of the `loop` from the current instruction: the blocks after the current one are
+    /// still open.
+    fn loop_depth(&self) -> u32 {
+        (self.block_count - 1 - self.current_block) as u32 + self.has_try as u32 + self.nesting
+    }
+
+    /// The depth of the `block` whose end is the start of the block `target`, after the current
+    /// one.
+    fn block_depth(&self, target: usize) -> u32 {
+        (target - self.current_block - 1) as u32 + self.nesting
+    }
+
+    fn jump(&mut self, label: &Label) {
+        let target = self.labels[label];
+        if target == self.current_block + 1 {
+            // The next block follows.
+        } else if target > self.current_block {
+            let depth = self.block_depth(target);
+            self.emit(Instruction::Br(depth));
+        } else {
+            self.dispatch(target);
+        }
+    }
+
+    /// Jump to `label` when the condition on the stack is true.
+    fn jump_if(&mut self, label: &Label) {
+        let target = self.labels[label];
+        if target > self.current_block {
+            let depth = self.block_depth(target);
+            self.emit(Instruction::BrIf(depth));
+        } else {
+            self.emit(Instruction::If);
+            self.nesting += 1;
+            self.dispatch(target);
+            self.nesting -= 1;
+            self.emit(Instruction::End);
+        }
+    }
+
+    fn dispatch(&mut self, target: usize) {
+        let pc = self.pc;
+        self.emit(Instruction::I64Const(target as i64));
+        self.emit(Instruction::LocalSet(pc));
+        let depth = self.loop_depth();
+        self.emit(Instruction::Br(depth));
+    }
+
+    /// Resume after the `_setjmp` which recorded the handler of the exception, or propagate the
+    /// exception.
+    fn catch(&mut self, setjmps: &[(Temp, usize)], setjmp_result: Temp) {
+        let exception = Temp::new();
+        self.emit(Instruction::Catch);
+        self.emit(Instruction::LocalSet(exception));
+        for &(handler, block) in setjmps {
+            self.emit(Instruction::LocalGet(exception));
+            self.emit(Instruction::LocalGet(handler));
+            self.emit(Instruction::Numeric(Numeric::Eq));
+            self.emit(Instruction::If);
+            self.emit(Instruction::I64Const(1));
+            self.emit(Instruction::LocalSet(setjmp_result));
+            self.reset_stack_pointer();
+            let pc = self.pc;
+            self.emit(Instruction::I64Const(block as i64));
+            self.emit(Instruction::LocalSet(pc));
+            // Out of the if and the try.
+            self.emit(Instruction::Br(2));
+            self.emit(Instruction::End);
+        }
+        self.emit(Instruction::Rethrow(0));
+        self.emit(Instruction::End);
+    }
+
+    fn statement(&mut self, statement: Statement) {
+        match statement {
+            Statement::Move(Exp::Temp(temp), source) => {
+                self.exp(source);
+                self.emit(Instruction::LocalSet(temp));
+            }
+            Statement::Move(Exp::Mem(box address), source) => {
+                let offset = self.address(address);
+                self.exp(source);
+                self.emit(Instruction::I64Store(offset));
+            }
+            Statement::Move(destination, _) => {
+                panic!("Unexpected move destination {:?}", destination)
+            }
+            Statement::Exp(exp) => {
+                self.exp(exp);
+                self.emit(Instruction::Drop);
+            }
+            Statement::Jump(Exp::Name(label), _) => self.jump(&label),
+            // A tail call releases the frame: its arguments are all passed as parameters (see
+            // Frame::tail_call).
+            Statement::Jump(Exp::Call(box Exp::Name(function), arguments), _) => {
+                if !self.module.defined_functions.contains(&function) {
+                    self.module.import(&function, arguments.len());
+                }
+                for argument in arguments {
+                    self.exp(argument);
+                }
+                if self.frame_size != 0 {
+                    self.emit(Instruction::LocalGet(FP));
+                    self.emit(Instruction::GlobalSet(STACK_POINTER));
+                }
+                self.emit(Instruction::ReturnCall(function));
+            }
+            Statement::Jump(exp, _) => panic!("Unexpected jump to {:?}", exp),
+            Statement::CondJump {
+                op,
+                left,
+                right,
+                true_label,
+                false_label,
+            } => {
+                self.exp(left);
+                self.exp(right);
+                self.emit(Instruction::Numeric(relational_op(&op)));
+                self.jump_if(&true_label);
+                self.jump(&false_label);
+            }
+            Statement::Sequence(first, second) => {
+                self.statement(*first);
+                self.statement(*second);
+            }
+            Statement::Label(_) => unreachable!("labels start the blocks"),
+        }
+    }
+
+    fn exp(&mut self, exp: Exp) {
+        match exp {
+            Exp::Const(value) => self.emit(Instruction::I64Const(value)),
+            Exp::Error => self.emit(Instruction::Unreachable),
+            Exp::Name(label) => {
+                let address = *self
+                    .module
+                    .addresses
+                    .get(&label)
+                    .unwrap_or_else(|| panic!("{} is not the label of a string", label));
+                self.emit(Instruction::I64Const(address));
+            }
+            Exp::Temp(temp) => self.emit(Instruction::LocalGet(temp)),
+            Exp::BinOp { op, left, right } => {
+                self.exp(*left);
+                self.exp(*right);
+                self.emit(Instruction::Numeric(binary_op(&op)));
+            }
+            Exp::Mem(box address) => {
+                let offset = self.address(address);
+                self.emit(Instruction::I64Load(offset));
+            }
+            Exp::Call(box Exp::Name(function), arguments) => self.call(function, arguments),
+            Exp::Call(function, _) => panic!("Unexpected call of {:?}", function),
+            Exp::ExpSequence(statement, exp) => {
+                self.statement(*statement);
+                self.exp(*exp);
+            }
+        }
+    }
+
+    /// Push the 32-bit address of a memory access and return its constant offset.
+    fn address(&mut self, address: Exp) -> u32 {
+        match address {
+            Exp::BinOp {
+                op: BinOp::Plus,
+                left,
+                right: box Exp::Const(offset),
+            } if (0..=u32::MAX as i64).contains(&offset) => {
+                self.exp(*left);
+                self.emit(Instruction::I32WrapI64);
+                offset as u32
+            }
+            address => {
+                self.exp(address);
+                self.emit(Instruction::I32WrapI64);
+                0
+            }
+        }
+    }
+
+    /// The functions of the module take their first arguments as parameters and the other ones
+    /// above the stack pointer, like the stack arguments of the other targets. The imported
+    /// functions take all their arguments as parameters.
+    fn call(&mut self, function: Label, arguments: Vec<Exp>) {
+        if !self.module.defined_functions.contains(&function) {
+            self.module.import(&function, arguments.len());
+            for argument in arguments {
+                self.exp(argument);
+            }
+            self.emit(Instruction::Call(function));
+            return;
+        }
+
+        let stack_arguments_size = Wasm32::stack_arguments_size(arguments.len());
+        let arg_registers_len = Wasm32::arg_registers().len();
+        let mut arguments = arguments.into_iter();
+        let register_arguments: Vec<_> = arguments.by_ref().take(arg_registers_len).collect();
+        if stack_arguments_size != 0 {
+            self.add_to_stack_pointer(-stack_arguments_size);
+            for (index, argument) in arguments.enumerate() {
+                self.emit(Instruction::GlobalGet(STACK_POINTER));
+                self.emit(Instruction::I32WrapI64);
+                self.exp(argument);
+                let offset = Wasm32::WORD_SIZE * (index as i64 + 2);
+                self.emit(Instruction::I64Store(offset as u32));
+            }
+        }
+        for argument in register_arguments {
+            self.exp(argument);
+        }
+        self.emit(Instruction::Call(function));
+        if stack_arguments_size != 0 {
+            self.add_to_stack_pointer(stack_arguments_size);
+        }
+    }
+}
+
+/// Split the statements in blocks starting at the labels and after the calls of `_setjmp`, whose
+/// result is `setjmp_result`. Each `_setjmp` gives the local recording the handler and the block
+/// following it.
+fn split_blocks(
+    statements: Vec<Statement>,
+    setjmp_result: Temp,
+    setjmps: &mut Vec<(Temp, usize)>,
+) -> (Vec<Vec<Statement>>, HashMap<Label, usize>) {
+    let mut blocks = vec![vec![]];
+    let mut labels = HashMap::new();
+    for statement in statements {
+        let (destination, handler) = match statement {
+            Statement::Label(label) => {
+                if !blocks.last().is_none_or(Vec::is_empty) {
+                    blocks.push(vec![]);
+                }
+                labels.insert(label, blocks.len() - 1);
+                continue;
+            }
+            Statement::Move(destination, Exp::Call(box Exp::Name(ref function), ref arguments))
+                if is_setjmp(function) =>
+            {
+                (Some(destination), arguments[0].clone())
+            }
+            Statement::Exp(Exp::Call(box Exp::Name(ref function), ref arguments))
+                if is_setjmp(function) =>
+            {
+                (None, arguments[0].clone())
+            }
+            statement => {
+                blocks.last_mut().expect("block").push(statement);
+                continue;
+            }
+        };
+        let handler_temp = Temp::new();
+        let block = blocks.last_mut().expect("block");
+        block.push(Statement::Move(Exp::Temp(handler_temp), handler));
+        block.push(Statement::Move(Exp::Temp(setjmp_result), Exp::Const(0)));
+        let mut next_block = vec![];
+        if let Some(destination) = destination {
+            next_block.push(Statement::Move(destination, Exp::Temp(setjmp_result)));
+        }
+        blocks.push(next_block);
+        setjmps.push((handler_temp, blocks.len() - 1));
+    }
+    (blocks, labels)
+}
+
+fn is_setjmp(function: &Label) -> bool {
+    *function == Label::with_name("_setjmp")
+}
+
+fn jump_targets(statement: &Statement) -> Vec<&Label> {
+    match *statement {
+        Statement::Jump(Exp::Name(ref label), _) => vec![label],
+        Statement::CondJump {
+            ref true_label,
+            ref false_label,
+            ..
+        } => vec![true_label, false_label],
+        _ => vec![],
+    }
+}
+
+fn binary_op(op: &BinOp) -> Numeric {
+    match *op {
+        BinOp::Plus => Numeric::Add,
+        BinOp::Minus => Numeric::Sub,
+        BinOp::Mul => Numeric::Mul,
+        BinOp::Div => Numeric::DivS,
+        BinOp::And => Numeric::And,
+        BinOp::Or => Numeric::Or,
+        BinOp::ShiftLeft => Numeric::Shl,
+        BinOp::ShiftRight => Numeric::ShrU,
+        BinOp::ArithmeticShiftRight => Numeric::ShrS,
+        BinOp::Xor => Numeric::Xor,
+    }
+}
+
+fn relational_op(op: &RelationalOp) -> Numeric {
+    match *op {
+        RelationalOp::Equal => Numeric::Eq,
+        RelationalOp::NotEqual => Numeric::Ne,
+        RelationalOp::LesserThan => Numeric::LtS,
+        RelationalOp::GreaterThan => Numeric::GtS,
+        RelationalOp::LesserOrEqual => Numeric::LeS,
+        RelationalOp::GreaterOrEqual => Numeric::GeS,
+        RelationalOp::UnsignedLesserThan => Numeric::LtU,
+        RelationalOp::UnsignedLesserOrEqual => Numeric::LeU,
+        RelationalOp::UnsignedGreaterThan => Numeric::GtU,
+        RelationalOp::UnsignedGreaterOrEqual => Numeric::GeU,
+    }
+}
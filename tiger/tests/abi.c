@@ -0,0 +1,53 @@
+/* C functions called from abi.tig to check the System V AMD64 calling convention: the arguments
+ * after the sixth are on the stack, the stack is aligned on 16 bytes at the call and the
+ * caller-saved registers can be overwritten. */
+
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+/* After the return address and rbp are pushed, the frame is aligned on 16 bytes again. */
+#define CHECK_ALIGNMENT() check_alignment(__func__, __builtin_frame_address(0))
+
+static void check_alignment(const char *function, void *frame) {
+    if ((uintptr_t)frame % 16 != 0) {
+        fprintf(stderr, "%s: stack not aligned on 16 bytes\n", function);
+        abort();
+    }
+}
+
+static void clobber_caller_saved_registers(void) {
+    __asm__ volatile(
+        "mov $-1, %%rcx\n"
+        "mov $-1, %%rdx\n"
+        "mov $-1, %%rsi\n"
+        "mov $-1, %%rdi\n"
+        "mov $-1, %%r8\n"
+        "mov $-1, %%r9\n"
+        "mov $-1, %%r10\n"
+        "mov $-1, %%r11\n"
+        :
+        :
+        : "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11");
+}
+
+/* Each argument is weighted by its position, to check their order. */
+int64_t weigh7(int64_t a, int64_t b, int64_t c, int64_t d, int64_t e, int64_t f, int64_t g) {
+    CHECK_ALIGNMENT();
+    clobber_caller_saved_registers();
+    return a + 2 * b + 3 * c + 4 * d + 5 * e + 6 * f + 7 * g;
+}
+
+int64_t weigh8(int64_t a, int64_t b, int64_t c, int64_t d, int64_t e, int64_t f, int64_t g,
+               int64_t h) {
+    CHECK_ALIGNMENT();
+    clobber_caller_saved_registers();
+    return a + 2 * b + 3 * c + 4 * d + 5 * e + 6 * f + 7 * g + 8 * h;
+}
+
+int64_t weigh9(int64_t a, int64_t b, int64_t c, int64_t d, int64_t e, int64_t f, int64_t g,
+               int64_t h, int64_t i) {
+    CHECK_ALIGNMENT();
+    clobber_caller_saved_registers();
+    return a + 2 * b + 3 * c + 4 * d + 5 * e + 6 * f + 7 * g + 8 * h + 9 * i;
+}
@@ -0,0 +1,17 @@
+/* A C program calling the functions exported by export.tig. */
+
+#include <stdio.h>
+
+#include "export.h"
+
+int main(void) {
+    printf("%ld\n", (long)factorial(10));
+    printf("%ld\n", (long)sumOfFactorials(4));
+    printf("%s\n", greet("C"));
+    void *point = newPoint(-3, 4);
+    printf("%ld\n", (long)manhattan(point));
+    printf("%ld\n", (long)weigh(1, 2, 3, 4, 5, 6, 7));
+    fflush(stdout);
+    show(42);
+    return 0;
+}
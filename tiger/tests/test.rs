@@ -19,66 +19,400 @@
  * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
  */
 
-use std::fs::{self, remove_file};
+use std::env::temp_dir;
+use std::fs::{self, remove_file, File};
 use std::io::{Read, Write};
 use std::path::Path;
 use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const FILES: [&str; 36] = [
+    "addressing_modes",
+    "array",
+    "array_assignment",
+    "comments",
+    "conditions",
+    "dataflow",
+    "division",
+    "escapes",
+    "exceptions",
+    "extern",
+    "functions",
+    "hello",
+    "hello1",
+    "hello2",
+    "hello3",
+    "hello5",
+    "inlining",
+    "integers",
+    "leaf_functions",
+    "lib",
+    "loop_optimizations",
+    "loops",
+    "merge",
+    "min_int",
+    "nested",
+    "operators",
+    "prettyprint",
+    "queens",
+    "record",
+    "spill",
+    "spill_costs",
+    "ssa",
+    "strings",
+    "tail_calls",
+    "unicode",
+    "vars",
+];
+
+/// Every program must behave the same with and without optimizations, with both instruction
+/// selectors, with the instructions scheduled before or after the register allocation, and with
+/// the graph-coloring and linear-scan register allocators.
+const OPTIONS: [&[&str]; 6] = [
+    &["-O0"],
+    &["-O2"],
+    &["-O2", "--omit-frame-pointer"],
+    &["-O2", "-maxmunch"],
+    &["-O2", "-fno-schedule", "-flate-schedule"],
+    &["-O2", "-linearscan"],
+];
 
 #[test]
 fn test_execution() {
-    let files = [
-        "array",
-        "array_assignment",
-        "comments",
-        "conditions",
-        "escapes",
-        "functions",
-        "hello",
-        "hello1",
-        "hello2",
-        "hello3",
-        "hello5",
-        "integers",
-        "lib",
-        "loops",
-        "merge",
-        "nested",
-        "prettyprint",
-        "queens",
-        "record",
-        "spill",
-        "strings",
-        "vars",
-    ];
-
-    for file in &files {
-        println!("{}", file);
-        let _ = remove_file(format!("./tests/{}", file));
-        Command::new("./target/debug/tiger")
-            .arg(&format!("tests/{}.tig", file))
+    for optimization in &OPTIONS {
+        let optimization = optimization.join(" ");
+        for file in &FILES {
+            println!("{} {}", file, optimization);
+            let _ = remove_file(format!("./tests/{}", file));
+            Command::new("./target/debug/tiger")
+                .args(optimization.split(' '))
+                .arg(&format!("tests/{}.tig", file))
+                .status()
+                .expect("compile");
+            let child = Command::new(format!("./tests/{}", file))
+                .stdout(Stdio::piped())
+                .stdin(Stdio::piped())
+                .spawn()
+                .expect("spawn");
+            if Path::new(&format!("./tests/{}.stdin", file)).exists() {
+                let input = fs::read(format!("./tests/{}.stdin", file)).expect("read");
+                child
+                    .stdin
+                    .expect("stdin")
+                    .write_all(&input)
+                    .expect("write stdin");
+            }
+            let mut buffer = vec![];
+            let read_size = child
+                .stdout
+                .expect("stdout")
+                .read_to_end(&mut buffer)
+                .expect("output");
+            let output = &buffer[..read_size];
+            let expected_output = fs::read(format!("./tests/{}.stdout", file)).expect("read");
+            assert_eq!(output, &*expected_output, "{}.tig {}", file, optimization);
+        }
+    }
+}
+
+/// The functions of `tests/abi.c` check the calling convention of the calls from Tiger.
+#[test]
+fn test_abi() {
+    let directory = temp_dir().join("tiger-abi");
+    fs::create_dir_all(&directory).expect("create directory");
+    let object = directory.join("abi_c.o");
+    let status = Command::new("cc")
+        .args(["-c", "-fno-omit-frame-pointer", "tests/abi.c", "-o"])
+        .arg(&object)
+        .status()
+        .expect("cc");
+    assert!(status.success());
+    let expected_output = fs::read_to_string("./tests/abi.stdout").expect("read");
+    for optimization in &OPTIONS {
+        let mut options = optimization.to_vec();
+        options.push(object.to_str().expect("object path"));
+        compile_copy("abi", "abi", &options);
+        let output = Command::new(directory.join("abi")).output().expect("run");
+        assert!(output.status.success(), "{:?}", optimization);
+        assert_eq!(
+            String::from_utf8(output.stdout).expect("utf-8"),
+            expected_output,
+            "{:?}",
+            optimization
+        );
+    }
+}
+
+/// The C program `tests/export.c` calls the functions exported by `tests/export.tig`, declared in
+/// the generated header.
+#[test]
+fn test_export() {
+    let directory = temp_dir().join("tiger-export");
+    let executable = directory.join("export_c");
+    let expected_output = fs::read_to_string("./tests/export.stdout").expect("read");
+    for optimization in &OPTIONS {
+        let mut options = optimization.to_vec();
+        options.push("-c");
+        compile_copy("export", "export", &options);
+        let status = Command::new("cc")
+            .args(["-no-pie", "tests/export.c", "-I"])
+            .arg(&directory)
+            .arg(directory.join("export.o"))
+            .args(["target/debug/libruntime.a", "-lpthread", "-ldl", "-o"])
+            .arg(&executable)
             .status()
-            .expect("compile");
-        let child = Command::new(format!("./tests/{}", file))
-            .stdout(Stdio::piped())
-            .stdin(Stdio::piped())
-            .spawn()
-            .expect("spawn");
-        if Path::new(&format!("./tests/{}.stdin", file)).exists() {
-            let input = fs::read(format!("./tests/{}.stdin", file)).expect("read");
-            child
-                .stdin
-                .expect("stdin")
-                .write_all(&input)
-                .expect("write stdin");
+            .expect("cc");
+        assert!(status.success(), "{:?}", optimization);
+        let output = Command::new(&executable).output().expect("run");
+        assert!(output.status.success(), "{:?}", optimization);
+        assert_eq!(
+            String::from_utf8(output.stdout).expect("utf-8"),
+            expected_output,
+            "{:?}",
+            optimization
+        );
+    }
+}
+
+/// With `--overflow-checks`, the operations which overflow raise `Overflow`, even when the
+/// optimizations fold their constant operands.
+#[test]
+fn test_overflow_checks() {
+    let directory = temp_dir().join("tiger-overflow");
+    let expected_output = fs::read_to_string("./tests/overflow.stdout").expect("read");
+    for optimization in &OPTIONS {
+        let mut options = optimization.to_vec();
+        options.push("--overflow-checks");
+        compile_copy("overflow", "overflow", &options);
+        let output = Command::new(directory.join("overflow"))
+            .output()
+            .expect("run");
+        assert!(output.status.success(), "{:?}", optimization);
+        assert_eq!(
+            String::from_utf8(output.stdout).expect("utf-8"),
+            expected_output,
+            "{:?}",
+            optimization
+        );
+    }
+}
+
+/// Every program compiles to AArch64 assembly accepted by the assembler of LLVM.
+#[test]
+fn test_aarch64() {
+    check_assembly("aarch64", &["-triple=aarch64-linux-gnu"]);
+}
+
+/// Every program compiles to RV64 assembly accepted by the assembler of LLVM.
+#[test]
+fn test_riscv64() {
+    check_assembly("riscv64", &["-triple=riscv64-linux-gnu", "-mattr=+m"]);
+}
+
+/// Every program compiles to a WebAssembly module, which Node validates and runs with the runtime
+/// of `src/runtime.js`. The external functions of `tests/extern.tig` are the ones of the C library.
+#[test]
+fn test_wasm32() {
+    let directory = temp_dir().join("tiger-wasm32");
+    for optimization in &["-O0", "-O2"] {
+        for file in FILES.iter().filter(|&&file| file != "extern") {
+            compile_copy("wasm32", file, &[optimization, "--target=wasm32"]);
+            let wat = fs::read_to_string(directory.join(format!("{}.wat", file))).expect("read");
+            assert!(
+                wat.starts_with("(module\n"),
+                "{}.tig {}",
+                file,
+                optimization
+            );
+            let stdin_path = format!("./tests/{}.stdin", file);
+            let stdin = if Path::new(&stdin_path).exists() {
+                Stdio::from(File::open(&stdin_path).expect("open"))
+            } else {
+                Stdio::null()
+            };
+            let output = Command::new("node")
+                .arg("src/runtime.js")
+                .arg(directory.join(format!("{}.wasm", file)))
+                .stdin(stdin)
+                .output()
+                .expect("node");
+            let expected_output = fs::read(format!("./tests/{}.stdout", file)).expect("read");
+            assert_eq!(
+                output.stdout, expected_output,
+                "{}.tig {}",
+                file, optimization
+            );
+        }
+    }
+}
+
+/// Compile every program for `target` and assemble it with llvm-mc.
+fn check_assembly(target: &str, llvm_mc_args: &[&str]) {
+    let directory = temp_dir().join(format!("tiger-{}", target));
+    let target_option = format!("--target={}", target);
+    for optimization in &OPTIONS {
+        let mut options = optimization.to_vec();
+        options.push(&target_option);
+        for file in &FILES {
+            compile_copy(target, file, &options);
+            let status = Command::new("llvm-mc")
+                .args(llvm_mc_args)
+                .args(["-filetype=obj", "-o"])
+                .arg(directory.join(format!("{}.o", file)))
+                .arg(directory.join(format!("{}.s", file)))
+                .status()
+                .expect("llvm-mc");
+            assert!(status.success(), "{}.tig {:?}", file, options);
+        }
+    }
+}
+
+/// Compile a copy of the program in a directory of the test, so that the tests running in parallel
+/// do not overwrite the files of each other, and return the output of the compiler and the
+/// compilation time. The files of the previous compilation are removed first, so that a failure is
+/// not hidden by them: the compiler reports the errors of the program without failing.
+fn compile_copy(test: &str, file: &str, options: &[&str]) -> (String, Duration) {
+    let directory = temp_dir().join(format!("tiger-{}", test));
+    fs::create_dir_all(&directory).expect("create directory");
+    let prefix = format!("{}.", file);
+    for entry in fs::read_dir(&directory).expect("read directory") {
+        let entry = entry.expect("directory entry");
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name == file || name.starts_with(&prefix) {
+            remove_file(entry.path()).expect("remove");
+        }
+    }
+    let path = directory.join(format!("{}.tig", file));
+    fs::copy(format!("./tests/{}.tig", file), &path).expect("copy");
+    let start = Instant::now();
+    let output = Command::new("./target/debug/tiger")
+        .args(options)
+        .arg(&path)
+        .output()
+        .expect("compile");
+    let duration = start.elapsed();
+    assert!(
+        output.status.success(),
+        "{}.tig {:?}: {}",
+        file,
+        options,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    (String::from_utf8(output.stdout).expect("utf-8"), duration)
+}
+
+/// Sum the numbers of the unit in the lines `# function <number> <unit>, <number> <unit>...`
+/// printed by the compiler.
+fn sum_reported(output: &str, unit: &str) -> u64 {
+    output
+        .lines()
+        .filter(|line| line.starts_with("# "))
+        .flat_map(|line| line.splitn(3, ' ').nth(2).unwrap_or("").split(", "))
+        .filter_map(|item| {
+            let mut words = item.splitn(2, ' ');
+            let number = words.next()?;
+            if words.next() == Some(unit) {
+                Some(number.parse::<u64>().expect("number"))
+            } else {
+                None
+            }
+        })
+        .sum()
+}
+
+/// Compare the cycles estimated by the compiler with and without instruction scheduling.
+#[test]
+fn test_schedule() {
+    let mut totals = [0, 0];
+    for file in &FILES {
+        let mut cycles = [0, 0];
+        for (index, schedule) in ["-fno-schedule", "-fschedule"].iter().enumerate() {
+            let (output, _) =
+                compile_copy("schedule", file, &["-O2", schedule, "--estimate-cycles"]);
+            cycles[index] = sum_reported(&output, "cycles");
         }
-        let mut buffer = vec![];
-        let read_size = child
-            .stdout
-            .expect("stdout")
-            .read_to_end(&mut buffer)
-            .expect("output");
-        let output = &buffer[..read_size];
-        let expected_output = fs::read(format!("./tests/{}.stdout", file)).expect("read");
-        assert_eq!(output, &*expected_output, "{}.tig", file);
+        println!("{:20} {:6} {:6}", file, cycles[0], cycles[1]);
+        totals[0] += cycles[0];
+        totals[1] += cycles[1];
+    }
+    println!("{:20} {:6} {:6}", "total", totals[0], totals[1]);
+    assert!(totals[1] < totals[0], "{:?}", totals);
+}
+
+/// Compare the compilation time and the number of spilled temporaries of the register allocators.
+/// The times are only printed, since they depend on the load of the machine: both allocators get
+/// the same temporaries, and graph coloring spills no more of them than linear scan.
+#[test]
+fn test_register_allocation() {
+    let allocators = ["-coloralloc", "-linearscan"];
+    let mut total_durations = [Duration::default(); 2];
+    let mut total_spills = [0; 2];
+    println!("{:20} {:>22} {:>22}", "", allocators[0], allocators[1]);
+    for file in &FILES {
+        let mut results = vec![];
+        let mut temps = [0; 2];
+        for (index, allocator) in allocators.iter().enumerate() {
+            let (output, duration) = compile_copy(
+                "register-allocation",
+                file,
+                &["-O2", allocator, "--regalloc-stats"],
+            );
+            let spills = sum_reported(&output, "spills");
+            results.push(format!(
+                "{:8.3}s {:4} spills",
+                duration.as_secs_f64(),
+                spills
+            ));
+            temps[index] = sum_reported(&output, "temps");
+            total_durations[index] += duration;
+            total_spills[index] += spills;
+        }
+        println!("{:20} {:>22} {:>22}", file, results[0], results[1]);
+        assert_eq!(temps[0], temps[1], "{}.tig", file);
+    }
+    println!(
+        "{:20} {:8.3}s {:4} spills {:8.3}s {:4} spills",
+        "total",
+        total_durations[0].as_secs_f64(),
+        total_spills[0],
+        total_durations[1].as_secs_f64(),
+        total_spills[1]
+    );
+    assert!(total_spills[0] <= total_spills[1], "{:?}", total_spills);
+}
+
+/// The interference graphs are printed in the DOT language, one graph by function.
+#[test]
+fn test_interference_graph() {
+    let (output, _) = compile_copy("interference", "spill", &["-O0", "--emit=interference"]);
+    assert!(output.starts_with("graph \"main\" {\n"), "{}", output);
+    assert!(output.ends_with("}\n"), "{}", output);
+    assert!(output.contains("    rbp [style=filled, fillcolor=lightblue];\n"));
+    assert!(output.contains(" [style=dashed];\n"));
+    let nodes: Vec<_> = output
+        .lines()
+        .filter(|line| !line.contains(" -- ") && line.ends_with(';'))
+        .map(|line| line.trim().split([' ', ';']).next().expect("node"))
+        .collect();
+    for line in output.lines().filter(|line| line.contains(" -- ")) {
+        for temp in line.trim().split([' ', ';']).step_by(2).take(2) {
+            assert!(nodes.contains(&temp), "{} in {}", temp, line);
+        }
+    }
+}
+
+/// The control-flow graphs are written in the DOT language, at the IR and instruction levels.
+#[test]
+fn test_control_flow_graph() {
+    compile_copy("cfg", "loops", &["-O0", "--emit=cfg"]);
+    let directory = temp_dir().join("tiger-cfg");
+    for level in &["ir", "asm"] {
+        let dot = fs::read_to_string(directory.join(format!("loops.main.{}.dot", level)))
+            .expect("read dot");
+        assert!(dot.starts_with("digraph \"main\" {\n"), "{}", dot);
+        assert!(dot.ends_with("}\n"), "{}", dot);
+        assert!(dot.contains("trace order 0\\lin: "), "{}", dot);
+        assert!(dot.contains("\\lout: "), "{}", dot);
+        assert!(dot.contains(" -> "), "{}", dot);
     }
 }